@@ -1,231 +1,1813 @@
 use khttp::{Headers, Method::*, Server, Status};
-use sha2::Digest;
-use std::sync::Arc;
-use std::time::Duration;
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
-
-type DbPool = Pool<SqliteConnectionManager>;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use user_token_core::{required_scope, retry_after_secs, tls_server_config, AccessLog, AccessRecord, AccountError, AdminAccess, AdminGuard, Admission, ApiKeyAccess, ApiKeyError, AuditLog, AuditPage, AuditQuery, AuthEvent, AuthOutcome, BulkFormat, Config, CorsPolicy, ImportError, ImportMode, ImportParams, ImportReport, JobStatus, LoginOutcome, LoginThrottle, Metrics, MfaError, Permit, PoolState, Readiness, SeedJobs, SeedParams, Session, SessionError, StartError, StoreError, TotpEnrollment, UserStore, ALPN_HTTP1, BODY_TOO_LARGE_MESSAGE, BUSY_MESSAGE, METRICS_CONTENT_TYPE, MFA_CHALLENGE_TTL, SHED_RETRY_AFTER_SECS, UNMATCHED_ROUTE};
 
 // Simple JSON parsing helpers
-fn parse_json_field<'a>(json: &'a str, field: &str) -> Option<&'a str> {
+// String value of `field`, escapes decoded like the serde servers do; borrowed when it has none
+fn parse_json_field<'a>(json: &'a str, field: &str) -> Option<Cow<'a, str>> {
     let key = format!("\"{}\"", field);
     let start = json.find(&key)?;
     let colon = json[start..].find(':')?;
     let value_start = start + colon + 1;
     let trimmed = json[value_start..].trim_start();
     
-    if trimmed.starts_with('"') {
-        let end = trimmed[1..].find('"')?;
-        Some(&trimmed[1..end + 1])
-    } else {
-        None
+    let value = trimmed.strip_prefix('"')?;
+    // The closing quote is the first one not escaped by a backslash
+    let mut escaped = false;
+    let end = value.bytes().position(|b| {
+        let closing = b == b'"' && !escaped;
+        escaped = b == b'\\' && !escaped;
+        closing
+    })?;
+    if !value[..end].contains('\\') {
+        return Some(Cow::Borrowed(&value[..end]));
     }
+    serde_json::from_str(&trimmed[..end + 2]).ok().map(Cow::Owned)
+}
+
+fn parse_json_value<'a>(json: &'a str, field: &str) -> Option<&'a str> {
+    let key = format!("\"{}\"", field);
+    let start = json.find(&key)? + key.len();
+    Some(json[start..].trim_start().strip_prefix(':')?.trim_start())
+}
+
+fn parse_json_number(json: &str, field: &str) -> Option<u64> {
+    let value = parse_json_value(json, field)?;
+    let end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+// Array of plain strings, e.g. "Scopes":["login","session"]
+fn parse_json_string_array<'a>(json: &'a str, field: &str) -> Option<Vec<&'a str>> {
+    let value = parse_json_value(json, field)?.strip_prefix('[')?;
+    let items = value[..value.find(']')?].trim();
+    if items.is_empty() {
+        return Some(Vec::new());
+    }
+    items
+        .split(',')
+        .map(|item| item.trim().strip_prefix('"')?.strip_suffix('"'))
+        .collect()
 }
 
 fn json_response(_success: bool, user_id: Option<i64>, error: Option<&str>) -> String {
     match (user_id, error) {
-        (Some(id), _) => format!(r#"{{"Success":true,"UserId":{}}}"#, id),
-        (None, Some(err)) => format!(r#"{{"Success":false,"ErrorMessage":"{}"}}"#, err),
-        _ => r#"{"Success":false}"#.to_string(),
+        (Some(id), _) => format!(r#"{{"Success":true,"Status":"Ok","UserId":{}}}"#, id),
+        (None, Some(err)) => format!(r#"{{"Success":false,"Status":"Failed","UserId":null,"ErrorMessage":"{}"}}"#, json_escape(err)),
+        _ => r#"{"Success":false,"Status":"Failed","UserId":null}"#.to_string(),
     }
 }
 
-// SQLite database wrapper
-struct Database {
-    pool: DbPool,
+fn session_json(session: &Session) -> String {
+    format!(
+        r#"{{"Success":true,"Status":"Ok","UserId":{},"RefreshToken":"{}","ExpiresIn":{}}}"#,
+        session.user_id,
+        session.refresh_token,
+        session.expires_in.as_secs()
+    )
 }
 
-impl Database {
-    fn new(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let cpus = num_cpus::get() as u32;
-        let manager = SqliteConnectionManager::file(path);
-        let pool = Pool::builder().max_size(cpus).build(manager)?;
-        
-        let conn = pool.get()?;
-        
-        // Configure SQLite for better performance and concurrency
-        conn.pragma_update(None, "journal_mode", "WAL")?;        // Enable WAL mode for better concurrency
-        conn.pragma_update(None, "synchronous", "NORMAL")?;      // Balance durability vs performance
-        conn.pragma_update(None, "cache_size", "-64000")?;       // 64MB cache (negative = KB)
-        conn.pragma_update(None, "temp_store", "MEMORY")?;       // Store temp tables in memory
-        conn.pragma_update(None, "mmap_size", "268435456")?;     // 256MB memory map
-        
-        // Set busy timeout for handling concurrent access
-        conn.busy_timeout(Duration::from_millis(30000))?;        // 30 second timeout
-        
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                mail TEXT NOT NULL UNIQUE,
-                hashed_password TEXT NOT NULL
-            )",
-            [],
-        )?;
-        
-        // Create index for faster lookups
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_user_mail_password ON user(mail, hashed_password)",
-            [],
-        )?;
-        
-        Ok(Database { pool })
+fn mfa_challenge_json(challenge_id: &str) -> String {
+    format!(r#"{{"Success":false,"Status":"MfaRequired","UserId":null,"ChallengeId":"{}"}}"#, challenge_id)
+}
+
+fn enrollment_json(enrollment: &TotpEnrollment) -> String {
+    format!(
+        r#"{{"Success":true,"Status":"Ok","UserId":{},"Secret":"{}","OtpauthUri":"{}"}}"#,
+        enrollment.user_id, enrollment.secret, enrollment.otpauth_uri
+    )
+}
+
+// Contents of a JSON string holding `value`: quotes, backslashes and control characters escaped
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\u{0}'..='\u{1f}' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    escaped
+}
 
-    fn get_user(&self, username: &str, hashed_password: &str) -> Result<Option<i64>, Box<dyn std::error::Error>> {
-        // Special case for testing
-        if username == "no_db" {
-            return Ok(Some(12345));
+fn json_string_or_null(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), |v| format!(r#""{}""#, json_escape(v)))
+}
+
+fn audit_page_json(page: &AuditPage) -> String {
+    let events: Vec<String> = page
+        .events
+        .iter()
+        .map(|record| {
+            let event = &record.event;
+            format!(
+                r#"{{"Id":{},"Timestamp":{},"Mail":{},"Outcome":"{}","ClientIp":{},"UserAgent":{}}}"#,
+                record.id,
+                event.timestamp,
+                json_string_or_null(event.mail.as_deref()),
+                event.outcome,
+                json_string_or_null(event.client_ip.map(|ip| ip.to_string()).as_deref()),
+                json_string_or_null(event.user_agent.as_deref())
+            )
+        })
+        .collect();
+    let next_before = page.next_before.map_or("null".to_string(), |id| id.to_string());
+    format!(r#"{{"Events":[{}],"NextBefore":{}}}"#, events.join(","), next_before)
+}
+
+// Queues a login attempt for the audit log; `client` is None when it is off
+fn audit_login(audit: &Option<Arc<AuditLog>>, client: &Option<(IpAddr, Option<String>)>, mail: Option<&str>, outcome: AuthOutcome) {
+    if let (Some(audit), Some((ip, user_agent))) = (audit, client) {
+        let mut event = AuthEvent::new(mail.map(str::to_owned), outcome);
+        event.client_ip = Some(*ip);
+        event.user_agent = user_agent.clone();
+        audit.record(event);
+    }
+}
+
+// Reports what a password check amounted to to the throttle and the audit log; `failed` marks
+// a database error, which only the audit log records
+fn report_credentials(
+    throttle: &Option<Arc<LoginThrottle>>,
+    audit: &Option<Arc<AuditLog>>,
+    client: &Option<(IpAddr, Option<String>)>,
+    mail: &str,
+    ip: Option<IpAddr>,
+    outcome: Option<LoginOutcome>,
+    failed: bool,
+) {
+    if let (Some(throttle), Some(outcome)) = (throttle, outcome) {
+        throttle.record(mail, ip, outcome);
+    }
+    match outcome {
+        Some(outcome) => audit_login(audit, client, Some(mail), outcome.into()),
+        None if failed => audit_login(audit, client, Some(mail), AuthOutcome::Error),
+        // Refused before the password was checked
+        None => {}
+    }
+}
+
+// Set once in main when MAXREQ_METRICS is on; handlers reach it through `Metered` and `record_login`
+static METRICS: OnceLock<Arc<Metrics>> = OnceLock::new();
+
+thread_local! {
+    // Status of the response the current worker thread is writing, 200 unless `sent` says otherwise
+    static RESPONSE_STATUS: Cell<u16> = const { Cell::new(200) };
+    // Whether the connection of the current request closes after its response: asked by the
+    // request, or answered with `Connection: close`
+    static CONNECTION_CLOSES: Cell<bool> = const { Cell::new(false) };
+    // CORS headers of the response the current worker thread is writing, set by `metered!` from
+    // the Origin of the request and cleared with its `Metered`
+    static CORS_HEADERS: RefCell<Vec<(&'static str, &'static str)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Notes the status of the response for `Metered`; wraps every status passed to `send`
+fn sent(status: &'static Status) -> &'static Status {
+    RESPONSE_STATUS.set(status.code);
+    status
+}
+
+// Set once in main when MAXREQ_ACCESS_LOG is set
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+// Set once in main when MAXREQ_MAX_IN_FLIGHT is set
+static ADMISSION: OnceLock<Admission> = OnceLock::new();
+
+// Set once in main when MAXREQ_CORS_ORIGINS is set
+static CORS: OnceLock<CorsPolicy> = OnceLock::new();
+
+// Set once in main from MAXREQ_MAX_BODY_BYTES, read by `read_body!`
+static MAX_BODY_BYTES: OnceLock<usize> = OnceLock::new();
+
+// Set by the signal handler: handlers then answer 503 while the running ones finish
+static DRAINING: AtomicBool = AtomicBool::new(false);
+// Requests inside a handler, counted by `Metered`
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// Set once in main; its thread cuts the connections past their deadline
+static CONN_WATCHDOG: OnceLock<ConnWatchdog> = OnceLock::new();
+// How often the watchdog looks for connections past their deadline
+const WATCH_TICK: Duration = Duration::from_millis(100);
+// Left to khttp to finish writing the last response of a connection that closes after it
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+// Deadlines khttp does not keep, like the actix server's `ConnWatch`: a request head within
+// MAXREQ_HEADER_TIMEOUT_MS of the accept, and the next one within MAXREQ_KEEP_ALIVE_SECS plus
+// that of the last response. The socket read timeout alone lets a client that sends a byte at
+// a time hold its worker thread for good. Connections are known by their peer address, which
+// the stream setup hook and `metered!` both have
+struct ConnWatchdog {
+    conns: Mutex<HashMap<SocketAddr, ConnWatch>>,
+    header_timeout: Duration,
+    // Keep-alive plus header timeout, `None` when keep-alive is off
+    after_response: Option<Duration>,
+}
+
+struct ConnWatch {
+    // `None` while a request runs
+    deadline: Option<Instant>,
+    // A duplicate of the connection's socket: shutting it down ends the blocked read of khttp
+    socket: TcpStream,
+    // Shared with the connection's `HeadLimit`, re-armed for the next request head
+    reading_head: Arc<AtomicBool>,
+}
+
+impl ConnWatchdog {
+    // Watches a connection from its accept; a peer address reused by a new connection replaces
+    // the entry of the old one. Returns the flag its `HeadLimit` counts the head bytes under
+    fn accepted(&self, stream: &TcpStream) -> std::io::Result<Arc<AtomicBool>> {
+        let reading_head = Arc::new(AtomicBool::new(true));
+        let watch = ConnWatch {
+            deadline: Some(Instant::now() + self.header_timeout),
+            socket: stream.try_clone()?,
+            reading_head: reading_head.clone(),
+        };
+        self.conns.lock().unwrap().insert(stream.peer_addr()?, watch);
+        Ok(reading_head)
+    }
+
+    // Suspends the deadline while a request runs: khttp serves one at a time per connection
+    fn request_started(&self, peer: SocketAddr) {
+        if let Some(watch) = self.conns.lock().unwrap().get_mut(&peer) {
+            watch.deadline = None;
         }
+    }
 
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached(
-            "SELECT id FROM user WHERE mail = ?1 AND hashed_password = ?2 LIMIT 1"
-        )?;
-        
-        let result = stmt.query_row([username, hashed_password], |row| row.get(0));
-        
-        match result {
-            Ok(id) => Ok(Some(id)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e.into()),
+    // Restarts the deadline once the response is sent. A connection that closes after it is cut
+    // soon after, as the duplicate socket would otherwise keep it open until the deadline
+    fn request_ended(&self, peer: SocketAddr, closes: bool) {
+        if let Some(watch) = self.conns.lock().unwrap().get_mut(&peer) {
+            let after = self.after_response.filter(|_| !closes).unwrap_or(CLOSE_LINGER);
+            watch.deadline = Some(Instant::now() + after);
+            watch.reading_head.store(true, Ordering::Relaxed);
         }
     }
 
-    fn create_users(&self, count: usize) -> Result<usize, Box<dyn std::error::Error>> {
-        let conn = self.pool.get()?;
-        
-        conn.execute("DELETE FROM user", [])?;
-        
-        // Use WAL checkpoint for better performance before bulk insert
-        let _ = conn.execute("PRAGMA wal_checkpoint(TRUNCATE)", []);
-        
-        let tx = conn.unchecked_transaction()?;
-        
-        let mut stmt = tx.prepare_cached("INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)")?;
-        
-        for i in 1..=count {
-            let email = format!("user{}@example.com", i);
-            let password = format!("password{}", i);
-            let hashed = format!("{:x}", sha2::Sha256::digest(password.as_bytes()));
-            
-            stmt.execute([&email, &hashed])?;
+    // The watchdog thread: shuts down and forgets the connections past their deadline
+    fn run(&self) {
+        loop {
+            std::thread::sleep(WATCH_TICK);
+            let now = Instant::now();
+            self.conns.lock().unwrap().retain(|_, watch| match watch.deadline {
+                Some(deadline) if deadline <= now => {
+                    let _ = watch.socket.shutdown(std::net::Shutdown::Both);
+                    false
+                }
+                _ => true,
+            });
+        }
+    }
+}
+
+// The accepted stream as khttp reads it, since khttp has no limit on the size of a request head:
+// counts the bytes of a head up to its blank line and answers 431 past MAXREQ_MAX_HEADER_BYTES,
+// like the other servers. The body is not counted; the count restarts once the watchdog sees the
+// response sent
+struct HeadLimit<S> {
+    inner: S,
+    reading_head: Arc<AtomicBool>,
+    limit: usize,
+    seen: usize,
+    // How much of "\r\n\r\n" the last bytes matched
+    matched: usize,
+}
+
+impl<S: Read + Write> HeadLimit<S> {
+    fn new(inner: S, reading_head: Arc<AtomicBool>, limit: usize) -> Self {
+        HeadLimit { inner, reading_head, limit, seen: 0, matched: 0 }
+    }
+
+    // Counts `bytes` while the head is read; false once it is over the limit
+    fn count(&mut self, bytes: &[u8]) -> bool {
+        if !self.reading_head.load(Ordering::Relaxed) {
+            return true;
+        }
+        for &byte in bytes {
+            self.seen += 1;
+            self.matched = match (self.matched, byte) {
+                (0 | 2, b'\r') => self.matched + 1,
+                (1 | 3, b'\n') => self.matched + 1,
+                (_, b'\r') => 1,
+                _ => 0,
+            };
+            if self.matched == 4 {
+                self.reading_head.store(false, Ordering::Relaxed);
+                (self.seen, self.matched) = (0, 0);
+                return true;
+            }
+            if self.seen > self.limit {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl<S: Read + Write> Read for HeadLimit<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if self.count(&buf[..read]) {
+            return Ok(read);
+        }
+        // No handler runs, so the response is written here and khttp drops the connection
+        let _ = self.inner.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let _ = self.inner.flush();
+        if let Some(metrics) = METRICS.get() {
+            metrics.observe_request(UNMATCHED_ROUTE, 431, Duration::ZERO);
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request head too large"))
+    }
+}
+
+impl<S: Write> Write for HeadLimit<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// An accepted connection, in TLS when MAXREQ_TLS_CERT is set. khttp only sees the plaintext; the
+// handshake runs on the first read, under the same read timeout and watchdog as a request head
+enum ConnStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl ConnStream {
+    fn accept(stream: TcpStream, tls: Option<&Arc<ServerConfig>>) -> std::io::Result<Self> {
+        match tls {
+            Some(config) => {
+                let conn = ServerConnection::new(config.clone()).map_err(std::io::Error::other)?;
+                Ok(ConnStream::Tls(Box::new(StreamOwned::new(conn, stream))))
+            }
+            None => Ok(ConnStream::Plain(stream)),
+        }
+    }
+}
+
+impl Read for ConnStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ConnStream::Plain(stream) => stream.read(buf),
+            ConnStream::Tls(stream) => stream.read(buf),
         }
-        
-        drop(stmt);
-        tx.commit()?;
-        
-        Ok(count)
     }
 }
 
+impl Write for ConnStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ConnStream::Plain(stream) => stream.write(buf),
+            ConnStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ConnStream::Plain(stream) => stream.flush(),
+            ConnStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// Tells a TLS client the connection ends on purpose, rather than leaving it to guess a truncation
+impl Drop for ConnStream {
+    fn drop(&mut self) {
+        if let ConnStream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+    }
+}
+
+// Guard taken at the top of a handler: counts the request under `route` once the response is sent,
+// and writes it to the access log when sampled
+struct Metered {
+    route: &'static str,
+    start: Instant,
+    // Peer address of the connection, for the watchdog
+    peer: SocketAddr,
+    access: Option<AccessRecord>,
+    // Admission slot of an `/api` request, freed with the guard
+    permit: Option<Permit<'static>>,
+}
+
+impl Metered {
+    // `client` gives the path, client IP and user agent; only called for sampled requests
+    // `closes` is whether the request asked for `Connection: close`
+    fn start(
+        method: &'static str,
+        route: &'static str,
+        peer: SocketAddr,
+        closes: bool,
+        client: impl FnOnce() -> (String, IpAddr, Option<String>),
+    ) -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        RESPONSE_STATUS.set(200);
+        CONNECTION_CLOSES.set(closes);
+        if let Some(watchdog) = CONN_WATCHDOG.get() {
+            watchdog.request_started(peer);
+        }
+        let access = ACCESS_LOG.get().filter(|log| log.sampled()).map(|_| {
+            let (path, client_ip, user_agent) = client();
+            let mut record = AccessRecord::new(method, &path, route, 0, Duration::ZERO);
+            record.client_ip = Some(client_ip);
+            record.user_agent = user_agent;
+            record
+        });
+        Metered { route, start: Instant::now(), peer, access, permit: None }
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        let (status, elapsed) = (RESPONSE_STATUS.get(), self.start.elapsed());
+        if let Some(metrics) = METRICS.get() {
+            metrics.observe_request(self.route, status, elapsed);
+        }
+        if let (Some(mut record), Some(log)) = (self.access.take(), ACCESS_LOG.get()) {
+            record.status = status;
+            record.duration_us = elapsed.as_micros() as u64;
+            log.record(record);
+        }
+        if let Some(permit) = &mut self.permit
+            && status == 503
+        {
+            permit.overloaded();
+        }
+        CORS_HEADERS.with_borrow_mut(Vec::clear);
+        if let Some(watchdog) = CONN_WATCHDOG.get() {
+            watchdog.request_ended(self.peer, CONNECTION_CLOSES.get());
+        }
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// `Headers::new()` with the CORS headers of the current request, for every response of a handler
+macro_rules! response_headers {
+    () => {{
+        let mut headers = Headers::new();
+        CORS_HEADERS.with_borrow(|cors| {
+            for &(name, value) in cors {
+                headers.add(name, value.as_bytes());
+            }
+        });
+        headers
+    }};
+}
+
+// `Metered::start` for a handler whose request is `ctx`; returns 503 from the handler once
+// the server is draining, or when admission control sheds the request. Counted in flight
+// first, so the drain cannot miss a request
+macro_rules! metered {
+    ($ctx:ident, $res:ident, $method:literal, $route:expr) => {{
+        let closes = $ctx.headers.get("Connection").is_some_and(|v| v.eq_ignore_ascii_case(b"close"));
+        let mut metered = Metered::start($method, $route, $ctx.remote_addr(), closes, || {
+            let user_agent = $ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            ($ctx.uri.path().to_owned(), $ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+        if let Some(policy) = CORS.get()
+            && let Some(origin) = $ctx.headers.get("Origin").and_then(|v| std::str::from_utf8(v).ok())
+        {
+            CORS_HEADERS.set(policy.response_headers(origin));
+        }
+        if DRAINING.load(Ordering::SeqCst) {
+            let mut headers = response_headers!();
+            headers.add("Connection", b"close");
+            CONNECTION_CLOSES.set(true);
+            headers.add("Retry-After", b"1");
+            return $res.send(sent(&Status::SERVICE_UNAVAILABLE), &headers, "Server is shutting down");
+        }
+        if let Some(admission) = ADMISSION.get().filter(|_| Admission::applies_to($route)) {
+            match admission.acquire_blocking() {
+                Some(permit) => metered.permit = Some(permit),
+                None => {
+                    let retry_after = SHED_RETRY_AFTER_SECS.to_string();
+                    let mut headers = response_headers!();
+                    headers.add("Content-Type", b"application/json");
+                    headers.add("Retry-After", retry_after.as_bytes());
+                    let json = json_response(false, None, Some(BUSY_MESSAGE));
+                    return $res.send(sent(&Status::SERVICE_UNAVAILABLE), &headers, json);
+                }
+            }
+        }
+        metered
+    }};
+}
+
+// Reads the body of `ctx` or returns from the handler: `413` past MAXREQ_MAX_BODY_BYTES, told by
+// the Content-Length before anything is read when there is one, `400` when it cannot be read.
+// The import streams its body instead
+macro_rules! read_body {
+    ($ctx:ident, $res:ident) => {{
+        let limit = MAX_BODY_BYTES.get().copied().unwrap_or(usize::MAX);
+        let declared = $ctx.headers.get("Content-Length")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.trim().parse::<usize>().ok());
+        let too_large = declared.is_some_and(|length| length > limit);
+        let mut body = Vec::new();
+        let read = if too_large { Ok(0) } else { $ctx.body().take(limit as u64 + 1).read_to_end(&mut body) };
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+        if too_large || body.len() > limit {
+            // The rest of the body is left unread, so the connection cannot serve another request
+            headers.add("Connection", b"close");
+            CONNECTION_CLOSES.set(true);
+            let json = json_response(false, None, Some(BODY_TOO_LARGE_MESSAGE));
+            return $res.send(sent(&Status::PAYLOAD_TOO_LARGE), &headers, json);
+        }
+        if read.is_err() {
+            let json = json_response(false, None, Some("Invalid request body"));
+            return $res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        }
+        body
+    }};
+}
+
+// Sends a failure from `store_failure` and friends, with its `Retry-After` when it has one
+macro_rules! send_failure {
+    ($res:ident, $headers:ident, $failure:expr) => {{
+        let (status, retry_after, json): (&'static Status, Option<u64>, String) = $failure;
+        let retry_after = retry_after.map(|secs| secs.to_string());
+        if let Some(retry_after) = &retry_after {
+            $headers.add("Retry-After", retry_after.as_bytes());
+        }
+        $res.send(sent(status), &$headers, json)
+    }};
+}
+
+// Answers 429 from the handler when the account or client IP is locked out, before the
+// database is touched. Every route that checks a password starts with it
+macro_rules! refuse_locked {
+    ($res:ident, $throttle:expr, $audit:expr, $client:expr, $mail:expr, $ip:expr) => {
+        if let Some(Err(retry_after)) = $throttle.as_ref().map(|throttle| throttle.check($mail, $ip)) {
+            let retry_after = retry_after_secs(retry_after).to_string();
+            let mut headers = response_headers!();
+            headers.add("Content-Type", b"application/json");
+            headers.add("Retry-After", retry_after.as_bytes());
+            let json = json_response(false, None, Some("Too many failed login attempts"));
+            audit_login($audit, $client, Some($mail), AuthOutcome::Locked);
+            record_login(AuthOutcome::Locked);
+            return $res.send(sent(&Status::TOO_MANY_REQUESTS), &headers, json);
+        }
+    };
+}
+
+// Waits for the running requests, up to `timeout`, then flushes the access and audit logs and
+// the persisted lockouts, and checkpoints the database. khttp cannot stop accepting, so
+// handlers answer 503 meanwhile
+fn drain(timeout: Duration, audit: Option<&AuditLog>, throttle: Option<&LoginThrottle>, store: UserStore) {
+    DRAINING.store(true, Ordering::SeqCst);
+    println!("Shutting down: draining in-flight requests for up to {:?}", timeout);
+    let deadline = Instant::now() + timeout;
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            println!("{} requests still running after {:?}, stopping anyway", IN_FLIGHT.load(Ordering::SeqCst), timeout);
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if let Some(log) = ACCESS_LOG.get() {
+        log.flush();
+        let stats = log.stats();
+        println!("Access log flushed: {} lines written, {} dropped, {} failed", stats.written, stats.dropped, stats.failed);
+    }
+    if let Some(audit) = audit {
+        audit.flush();
+        let stats = audit.stats();
+        println!("Audit log flushed: {} events written, {} dropped, {} failed", stats.written, stats.dropped, stats.failed);
+    }
+    if let Some(throttle) = throttle {
+        throttle.flush();
+    }
+    match store.close() {
+        Ok(()) => println!("users.db checkpointed"),
+        Err(e) => eprintln!("Failed to checkpoint users.db: {}", e),
+    }
+}
+
+fn record_login(outcome: AuthOutcome) {
+    if let Some(metrics) = METRICS.get() {
+        metrics.record_login(outcome);
+    }
+}
+
+fn readiness_json(readiness: &Readiness) -> String {
+    let pool = |pool: &PoolState| format!(r#"{{"MaxSize":{},"Connections":{},"Idle":{}}}"#, pool.max_size, pool.connections, pool.idle);
+    let schema_version = readiness.schema_version.map_or("null".to_string(), |version| version.to_string());
+    let error = match &readiness.error {
+        Some(e) => format!(r#","Error":"{}""#, json_escape(e)),
+        None => String::new(),
+    };
+    format!(
+        r#"{{"Ready":{},"LatencyUs":{},"SchemaVersion":{},"ExpectedSchemaVersion":{},"Pool":{{"Read":{},"Write":{}}}{}}}"#,
+        readiness.ready, readiness.latency_us, schema_version, readiness.expected_schema_version,
+        pool(&readiness.pool.read), pool(&readiness.pool.write), error
+    )
+}
+
+fn job_json(job: &JobStatus) -> String {
+    let error = match &job.error {
+        Some(e) => format!(r#","Error":"{}""#, json_escape(e)),
+        None => String::new(),
+    };
+    format!(
+        r#"{{"JobId":{},"State":"{:?}","Total":{},"Inserted":{},"ElapsedMs":{}{}}}"#,
+        job.job_id, job.state, job.total, job.inserted, job.elapsed_ms, error
+    )
+}
+
+fn import_report_json(report: &ImportReport) -> String {
+    let errors: Vec<String> = report
+        .errors
+        .iter()
+        .map(|e| {
+            format!(
+                r#"{{"Line":{},"Mail":{},"Error":"{}"}}"#,
+                e.line,
+                json_string_or_null(e.mail.as_deref()),
+                json_escape(&e.error)
+            )
+        })
+        .collect();
+    format!(
+        r#"{{"Rows":{},"Inserted":{},"Updated":{},"Unchanged":{},"Failed":{},"Chunks":{},"ElapsedMs":{},"Errors":[{}]}}"#,
+        report.rows, report.inserted, report.updated, report.unchanged, report.failed, report.chunks, report.elapsed_ms, errors.join(",")
+    )
+}
+
+// Status and body for a request refused by the admin guard, None when it may proceed
+fn admin_rejection(access: AdminAccess) -> Option<(&'static Status, &'static str)> {
+    match access {
+        AdminAccess::Granted => None,
+        AdminAccess::Unauthorized => Some((&Status::UNAUTHORIZED, "Missing or invalid admin token")),
+        AdminAccess::Disabled => Some((
+            &Status::FORBIDDEN,
+            "Admin endpoints are disabled: set MAXREQ_ADMIN_TOKEN or MAXREQ_BENCHMARK_MODE",
+        )),
+    }
+}
+
+// Status and body for a request refused for its API key, None when it may proceed
+fn api_key_rejection(
+    store: &UserStore,
+    required: bool,
+    authorization: Option<&str>,
+    path: &str,
+) -> Option<(&'static Status, Option<u64>, String)> {
+    let scope = required_scope(path).filter(|_| required)?;
+    match store.check_api_key(authorization, scope) {
+        Ok(ApiKeyAccess::Granted(_)) => None,
+        Ok(ApiKeyAccess::Missing | ApiKeyAccess::Invalid) => {
+            Some((&Status::UNAUTHORIZED, None, "Missing or invalid API key".to_string()))
+        }
+        Ok(ApiKeyAccess::Forbidden) => Some((&Status::FORBIDDEN, None, format!("API key lacks the {} scope", scope))),
+        Err(e) => Some(store_failure(e)),
+    }
+}
+
+fn api_key_json(id: i64, name: &str, scopes: &[String], expires_at: Option<i64>, revoked: bool) -> String {
+    let scopes: Vec<String> = scopes.iter().map(|s| format!(r#""{}""#, s)).collect();
+    let expires_at = expires_at.map_or("null".to_string(), |t| t.to_string());
+    format!(
+        r#"{{"Id":{},"Name":"{}","Scopes":[{}],"ExpiresAt":{},"Revoked":{}}}"#,
+        id, json_escape(name), scopes.join(","), expires_at, revoked
+    )
+}
+
+// Status, `Retry-After` and JSON body for a database error: 503 when the store is
+// overloaded (pool exhausted, database busy), 500 otherwise
+fn store_failure(e: StoreError) -> (&'static Status, Option<u64>, String) {
+    let status = if e.is_overloaded() {
+        eprintln!("Database overloaded: {}", e);
+        &Status::SERVICE_UNAVAILABLE
+    } else {
+        eprintln!("Database error: {}", e);
+        &Status::INTERNAL_SERVER_ERROR
+    };
+    (status, e.retry_after(), json_response(false, None, Some(e.client_message())))
+}
+
+// Status, `Retry-After` and JSON body for a failed register / change-password / delete
+fn account_failure(e: AccountError) -> (&'static Status, Option<u64>, String) {
+    let (status, retry_after, message) = match e {
+        AccountError::Invalid(reason) => (&Status::BAD_REQUEST, None, reason),
+        AccountError::MailTaken => (&Status::CONFLICT, None, "A user with this mail already exists"),
+        AccountError::InvalidCredentials => (&Status::UNAUTHORIZED, None, "Invalid username or password"),
+        AccountError::InvalidCode => (&Status::UNAUTHORIZED, None, "Invalid or missing code"),
+        AccountError::TooManyAttempts => (&Status::TOO_MANY_REQUESTS, Some(MFA_CHALLENGE_TTL.as_secs()), "Too many invalid codes"),
+        AccountError::Store(e) => return store_failure(e),
+    };
+    (status, retry_after, json_response(false, None, Some(message)))
+}
+
+// Status, `Retry-After` and JSON body for a failed refresh / logout
+fn session_failure(e: SessionError) -> (&'static Status, Option<u64>, String) {
+    let (status, message) = match e {
+        SessionError::Disabled => (&Status::NOT_FOUND, "Sessions are disabled"),
+        SessionError::InvalidToken => (&Status::UNAUTHORIZED, "Invalid or expired refresh token"),
+        SessionError::Store(e) => return store_failure(e),
+    };
+    (status, None, json_response(false, None, Some(message)))
+}
+
+// Status, `Retry-After` and JSON body for a failed MFA enrol / confirm / verify
+fn mfa_failure(e: MfaError) -> (&'static Status, Option<u64>, String) {
+    let (status, retry_after, message) = match e {
+        MfaError::InvalidCredentials => (&Status::UNAUTHORIZED, None, "Invalid username or password"),
+        MfaError::NotEnrolled => (&Status::BAD_REQUEST, None, "No TOTP enrolment is pending"),
+        MfaError::AlreadyEnrolled => (&Status::CONFLICT, None, "TOTP is already enabled"),
+        MfaError::InvalidChallenge => (&Status::UNAUTHORIZED, None, "Invalid or expired MFA challenge"),
+        MfaError::InvalidCode => (&Status::UNAUTHORIZED, None, "Invalid code"),
+        MfaError::TooManyAttempts => (&Status::TOO_MANY_REQUESTS, Some(MFA_CHALLENGE_TTL.as_secs()), "Too many invalid codes"),
+        MfaError::Store(e) => return store_failure(e),
+    };
+    (status, retry_after, json_response(false, None, Some(message)))
+}
+
+// Query string helpers (no URL crate dependency)
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then(|| percent_decode(value))
+    })
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 fn main() {
+    let config = Config::from_env();
+
+    // Bring users.db to the current schema (and mail policy) and exit, e.g. before a deploy
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let version = UserStore::open("users.db", 1)
+            .and_then(|store| store.with_mail_config(&config.mail))
+            .and_then(|store| store.schema_version())
+            .expect("Migration failed");
+        println!("users.db is at schema version {}", version);
+        return;
+    }
+
+    // khttp serves plain TCP streams: TLS is added by the stream setup hook
+    let tls = config.tls.as_ref().map(|tls| {
+        println!("TLS enabled: {} ({} cached sessions, tickets: {})", tls.cert_path, tls.session_cache, tls.tickets);
+        Arc::new(tls_server_config(tls, &[ALPN_HTTP1]).expect("Failed to load the TLS certificate"))
+    });
     println!("Initializing database with connection pool...");
-    let db = Arc::new(
-        Database::new("users.db").expect("Failed to initialize database")
-    );
     let cpus = num_cpus::get();
-    println!("Database ready with {} connection pool", cpus);
+    let mut store = UserStore::open("users.db", cpus as u32)
+        .and_then(|store| store.with_mail_config(&config.mail))
+        .expect("Failed to initialize database")
+        .with_pool_timeout(config.pool_timeout);
+    if config.mail.lowercase || config.mail.punycode {
+        println!(
+            "Mail normalisation: lowercase whole address: {}, punycode domains: {}",
+            config.mail.lowercase, config.mail.punycode
+        );
+    }
+    if let Some(cache) = &config.cache {
+        println!("Credential cache enabled: {} entries, {:?} TTL", cache.capacity, cache.ttl);
+        store = store.with_cache(cache);
+    }
+    if let Some(sessions) = &config.sessions {
+        println!(
+            "Sessions enabled: {:?} refresh tokens, expired ones purged every {:?}",
+            sessions.ttl, sessions.gc_interval
+        );
+        store = store.with_sessions(sessions);
+        store.spawn_session_gc(sessions.gc_interval).expect("Failed to start session cleanup");
+    }
+    if let Some(access_log) = &config.access_log {
+        println!("Access log enabled: {} ({}% of requests)", access_log.path, access_log.sample_ratio * 100.0);
+        ACCESS_LOG.get_or_init(|| AccessLog::start(access_log).expect("Failed to open the access log"));
+    }
+    if let Some(admission) = &config.admission {
+        println!(
+            "Admission control enabled: {} requests in flight (adaptive: {}), {} queued for up to {:?}",
+            admission.max_in_flight, admission.adaptive, admission.max_queued, admission.queue_timeout
+        );
+        ADMISSION.get_or_init(|| Admission::new(admission));
+    }
+    if config.metrics {
+        let metrics = METRICS.get_or_init(|| Arc::new(Metrics::new()));
+        store = store.with_metrics(metrics);
+    }
+    let db = Arc::new(store);
+    let throttle = config.throttle.as_ref().map(|throttle| {
+        println!(
+            "Login throttling enabled: {} failures per account, {} per IP, in {:?} (persisted: {})",
+            throttle.account_failures, throttle.ip_failures, throttle.window, throttle.persist
+        );
+        Arc::new(LoginThrottle::open(throttle, &db).expect("Failed to initialize login throttle"))
+    });
+    let audit = config.audit.as_ref().map(|audit| {
+        println!(
+            "Audit log enabled: batches of {} events, flushed every {:?}",
+            audit.batch_size, audit.flush_interval
+        );
+        Arc::new(AuditLog::start(audit, &db).expect("Failed to start the audit writer"))
+    });
+    let jobs = Arc::new(SeedJobs::new());
+    let admin = Arc::new(AdminGuard::new(&config));
+    if config.benchmark_mode {
+        println!("Benchmark mode: /admin endpoints are open without a token");
+    }
+    let require_api_key = config.require_api_key;
+    if require_api_key {
+        println!("API keys required on /api/auth endpoints");
+    }
+    let limits = &config.limits;
+    println!(
+        "Request limits: {} byte bodies, {} byte request heads within {:?}, {:?} keep-alive",
+        limits.max_body_bytes, limits.max_header_bytes, limits.header_timeout, limits.keep_alive
+    );
+    MAX_BODY_BYTES.get_or_init(|| limits.max_body_bytes);
+    let watchdog = CONN_WATCHDOG.get_or_init(|| ConnWatchdog {
+        conns: Mutex::new(HashMap::new()),
+        header_timeout: limits.header_timeout,
+        after_response: Some(limits.keep_alive + limits.header_timeout).filter(|_| !limits.keep_alive.is_zero()),
+    });
+    std::thread::spawn(move || watchdog.run());
+    if let Some(cors) = &config.cors {
+        println!(
+            "CORS enabled: origins {:?}, methods {:?}, headers {:?}, preflight cached {:?}",
+            cors.allowed_origins, cors.allowed_methods, cors.allowed_headers, cors.max_age
+        );
+        CORS.get_or_init(|| CorsPolicy::new(cors));
+    }
+    println!("Database ready with {} read connections and 1 writer", cpus);
 
     let mut app = Server::builder("0.0.0.0:8080").unwrap();
 
     // POST /api/auth/get-user-token
     let db_clone = db.clone();
+    let throttle_clone = throttle.clone();
+    let audit_clone = audit.clone();
     app.route(Post, "/api/auth/get-user-token", move |mut ctx, res| {
-        let body = match ctx.body().vec() {
-            Ok(b) => b,
-            Err(_) => {
-                let json = json_response(false, None, Some("Invalid request body"));
-                let mut headers = Headers::new();
-                headers.add("Content-Type", b"application/json");
-                return res.send(&Status::BAD_REQUEST, &headers, json);
-            }
-        };
+        let _metered = metered!(ctx, res, "POST", "/api/auth/get-user-token");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/get-user-token") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let body = read_body!(ctx, res);
         
         let json_str = match std::str::from_utf8(&body) {
             Ok(s) => s,
             Err(_) => {
                 let json = json_response(false, None, Some("Invalid UTF-8"));
-                let mut headers = Headers::new();
+                let mut headers = response_headers!();
                 headers.add("Content-Type", b"application/json");
-                return res.send(&Status::BAD_REQUEST, &headers, json);
+                return res.send(sent(&Status::BAD_REQUEST), &headers, json);
             }
         };
         
-        let username = parse_json_field(json_str, "UserName").unwrap_or("");
-        let hashed_password = parse_json_field(json_str, "HashedPassword").unwrap_or("");
+        let username = parse_json_field(json_str, "UserName").unwrap_or_default();
+        let hashed_password = parse_json_field(json_str, "HashedPassword").unwrap_or_default();
+        let (username, hashed_password) = (&*username, &*hashed_password);
         
         if username.is_empty() || hashed_password.is_empty() {
             let json = json_response(false, None, Some("Missing UserName or HashedPassword"));
-            let mut headers = Headers::new();
+            let mut headers = response_headers!();
             headers.add("Content-Type", b"application/json");
-            return res.send(&Status::BAD_REQUEST, &headers, json);
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
         }
         
-        let mut headers = Headers::new();
+        let ip = throttle_clone.as_ref().map(|_| ctx.remote_addr().ip());
+        refuse_locked!(res, throttle_clone, &audit_clone, &client, username, ip);
+
+        let mut headers = response_headers!();
         headers.add("Content-Type", b"application/json");
         
-        match db_clone.get_user(username, hashed_password) {
-            Ok(Some(user_id)) => {
-                //println!("User authenticated: {} -> {}", username, user_id);
-                let json = json_response(true, Some(user_id), None);
-                res.ok(&headers, json)
+        let login = db_clone.get_user_by_credentials(username, hashed_password).and_then(|user| match user {
+            // The password is right but the login waits for a TOTP code
+            Some(user) if user.mfa => Ok(Some((AuthOutcome::MfaRequired, mfa_challenge_json(&db_clone.start_mfa_challenge(user.id, username))))),
+            Some(user) => Ok(Some((AuthOutcome::Success, match db_clone.start_session(user.id)? {
+                Some(session) => session_json(&session),
+                None => json_response(true, Some(user.id), None),
+            }))),
+            None => Ok(None),
+        });
+
+        let outcome = match &login {
+            Ok(Some((outcome, _))) => *outcome,
+            Ok(None) => AuthOutcome::Failure,
+            Err(_) => AuthOutcome::Error,
+        };
+        audit_login(&audit_clone, &client, Some(username), outcome);
+        record_login(outcome);
+
+        if let Some(throttle) = &throttle_clone {
+            match &login {
+                // `MfaRequired` too: the password was right, codes are limited by the store
+                Ok(Some(_)) => throttle.record(username, ip, LoginOutcome::Authenticated),
+                Ok(None) => throttle.record(username, ip, LoginOutcome::Rejected),
+                Err(_) => {}
             }
+        }
+
+        match login {
+            Ok(Some((_, json))) => res.ok(&headers, json),
             Ok(None) => {
                 let json = json_response(false, None, Some("Invalid username or password"));
                 res.ok(&headers, json)
             }
+            Err(e) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // POST /api/auth/register
+    let db_clone = db.clone();
+    app.route(Post, "/api/auth/register", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/register");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/register") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(username), Some(password)) =
+            (parse_json_field(json_str, "UserName"), parse_json_field(json_str, "Password"))
+        else {
+            let json = json_response(false, None, Some("Missing UserName or Password"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (username, password) = (&*username, &*password);
+
+        match db_clone.register(username, password) {
+            Ok(user) => res.send(sent(&Status::CREATED), &headers, json_response(true, Some(user.id), None)),
             Err(e) => {
-                eprintln!("Database error: {}", e);
-                let json = json_response(false, None, Some("Database error"));
-                res.send(&Status::INTERNAL_SERVER_ERROR, &headers, json)
+                send_failure!(res, headers, account_failure(e))
             }
         }
     });
 
-    // GET /api/auth/create-db
+    // POST /api/auth/change-password
     let db_clone = db.clone();
-    app.route(Get, "/api/auth/create-db", move |_, res| {
-        let mut headers = Headers::new();
+    let throttle_clone = throttle.clone();
+    let audit_clone = audit.clone();
+    app.route(Post, "/api/auth/change-password", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/change-password");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/change-password") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let mut headers = response_headers!();
         headers.add("Content-Type", b"application/json");
-        
-        println!("Creating 10000 test users...");
-        match db_clone.create_users(10000) {
-            Ok(count) => {
-                println!("Successfully created {} users", count);
-                let response = format!("Successfully created {} users in the database", count);
-                res.ok(&headers, response)
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(username), Some(hashed_password), Some(new_password)) = (
+            parse_json_field(json_str, "UserName"),
+            parse_json_field(json_str, "HashedPassword"),
+            parse_json_field(json_str, "NewPassword"),
+        ) else {
+            let json = json_response(false, None, Some("Missing UserName, HashedPassword or NewPassword"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (username, hashed_password, new_password) = (&*username, &*hashed_password, &*new_password);
+
+        let ip = throttle_clone.as_ref().map(|_| ctx.remote_addr().ip());
+        refuse_locked!(res, throttle_clone, &audit_clone, &client, username, ip);
+
+        let code = parse_json_field(json_str, "Code");
+        let code = code.as_deref();
+        let result = db_clone.change_password(username, hashed_password, new_password, code);
+        let failed = matches!(result, Err(AccountError::Store(_)));
+        report_credentials(&throttle_clone, &audit_clone, &client, username, ip, LoginOutcome::of_account(&result), failed);
+        match result {
+            Ok(user) => res.ok(&headers, json_response(true, Some(user.id), None)),
+            Err(e) => {
+                send_failure!(res, headers, account_failure(e))
             }
+        }
+    });
+
+    // DELETE /api/auth/user: authenticated with the same body as a login
+    let db_clone = db.clone();
+    let throttle_clone = throttle.clone();
+    let audit_clone = audit.clone();
+    app.route(Delete, "/api/auth/user", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "DELETE", "/api/auth/user");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/user") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(username), Some(hashed_password)) =
+            (parse_json_field(json_str, "UserName"), parse_json_field(json_str, "HashedPassword"))
+        else {
+            let json = json_response(false, None, Some("Missing UserName or HashedPassword"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (username, hashed_password) = (&*username, &*hashed_password);
+
+        let ip = throttle_clone.as_ref().map(|_| ctx.remote_addr().ip());
+        refuse_locked!(res, throttle_clone, &audit_clone, &client, username, ip);
+
+        let code = parse_json_field(json_str, "Code");
+        let code = code.as_deref();
+        let result = db_clone.delete_account(username, hashed_password, code);
+        let failed = matches!(result, Err(AccountError::Store(_)));
+        report_credentials(&throttle_clone, &audit_clone, &client, username, ip, LoginOutcome::of_account(&result), failed);
+        match result {
+            Ok(user) => res.ok(&headers, json_response(true, Some(user.id), None)),
             Err(e) => {
-                eprintln!("Error creating users: {}", e);
-                res.send(
-                    &Status::INTERNAL_SERVER_ERROR,
-                    &headers,
-                    "Error creating database"
-                )
+                send_failure!(res, headers, account_failure(e))
             }
         }
     });
 
-    // Health check
-    app.route(Get, "/api/auth/health", |_, res| {
-        let mut headers = Headers::new();
+    // POST /api/auth/refresh
+    let db_clone = db.clone();
+    app.route(Post, "/api/auth/refresh", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/refresh");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/refresh") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let Some(token) = parse_json_field(std::str::from_utf8(&body).unwrap_or(""), "RefreshToken") else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json_response(false, None, Some("Missing RefreshToken")));
+        };
+
+        match db_clone.refresh_session(&token) {
+            Ok(session) => res.ok(&headers, session_json(&session)),
+            Err(e) => {
+                send_failure!(res, headers, session_failure(e))
+            }
+        }
+    });
+
+    // POST /api/auth/logout
+    let db_clone = db.clone();
+    app.route(Post, "/api/auth/logout", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/logout");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/logout") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let Some(token) = parse_json_field(std::str::from_utf8(&body).unwrap_or(""), "RefreshToken") else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json_response(false, None, Some("Missing RefreshToken")));
+        };
+
+        match db_clone.end_session(&token) {
+            Ok(user_id) => res.ok(&headers, json_response(true, Some(user_id), None)),
+            Err(e) => {
+                send_failure!(res, headers, session_failure(e))
+            }
+        }
+    });
+
+    // POST /api/auth/mfa/enroll: the secret is only active once confirmed
+    let db_clone = db.clone();
+    let throttle_clone = throttle.clone();
+    let audit_clone = audit.clone();
+    app.route(Post, "/api/auth/mfa/enroll", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/mfa/enroll");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/mfa/enroll") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(username), Some(hashed_password)) =
+            (parse_json_field(json_str, "UserName"), parse_json_field(json_str, "HashedPassword"))
+        else {
+            let json = json_response(false, None, Some("Missing UserName or HashedPassword"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (username, hashed_password) = (&*username, &*hashed_password);
+
+        let ip = throttle_clone.as_ref().map(|_| ctx.remote_addr().ip());
+        refuse_locked!(res, throttle_clone, &audit_clone, &client, username, ip);
+
+        let result = db_clone.enroll_totp(username, hashed_password);
+        let failed = matches!(result, Err(MfaError::Store(_)));
+        report_credentials(&throttle_clone, &audit_clone, &client, username, ip, LoginOutcome::of_mfa(&result), failed);
+        match result {
+            Ok(enrollment) => res.ok(&headers, enrollment_json(&enrollment)),
+            Err(e) => {
+                send_failure!(res, headers, mfa_failure(e))
+            }
+        }
+    });
+
+    // POST /api/auth/mfa/confirm
+    let db_clone = db.clone();
+    let throttle_clone = throttle.clone();
+    let audit_clone = audit.clone();
+    app.route(Post, "/api/auth/mfa/confirm", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/mfa/confirm");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/mfa/confirm") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(username), Some(hashed_password), Some(code)) = (
+            parse_json_field(json_str, "UserName"),
+            parse_json_field(json_str, "HashedPassword"),
+            parse_json_field(json_str, "Code"),
+        ) else {
+            let json = json_response(false, None, Some("Missing UserName, HashedPassword or Code"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (username, hashed_password, code) = (&*username, &*hashed_password, &*code);
+
+        let ip = throttle_clone.as_ref().map(|_| ctx.remote_addr().ip());
+        refuse_locked!(res, throttle_clone, &audit_clone, &client, username, ip);
+
+        let result = db_clone.confirm_totp(username, hashed_password, code);
+        let failed = matches!(result, Err(MfaError::Store(_)));
+        report_credentials(&throttle_clone, &audit_clone, &client, username, ip, LoginOutcome::of_mfa(&result), failed);
+        match result {
+            Ok(user) => res.ok(&headers, json_response(true, Some(user.id), None)),
+            Err(e) => {
+                send_failure!(res, headers, mfa_failure(e))
+            }
+        }
+    });
+
+    // POST /api/auth/mfa/verify: second step of a login that answered MfaRequired
+    let db_clone = db.clone();
+    let audit_clone = audit.clone();
+    app.route(Post, "/api/auth/mfa/verify", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/api/auth/mfa/verify");
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(rejection) = api_key_rejection(&db_clone, require_api_key, authorization, "/api/auth/mfa/verify") {
+            let mut headers = response_headers!();
+            headers.add("WWW-Authenticate", b"ApiKey");
+            return send_failure!(res, headers, rejection);
+        }
+
+        let client = audit_clone.as_ref().map(|_| {
+            let user_agent = ctx.headers.get("User-Agent").and_then(|v| std::str::from_utf8(v).ok());
+            (ctx.remote_addr().ip(), user_agent.map(str::to_owned))
+        });
+
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(challenge_id), Some(code)) =
+            (parse_json_field(json_str, "ChallengeId"), parse_json_field(json_str, "Code"))
+        else {
+            let json = json_response(false, None, Some("Missing ChallengeId or Code"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        };
+        let (challenge_id, code) = (&*challenge_id, &*code);
+
+        // Read before verifying: a completed challenge is gone
+        let mail = client.as_ref().and_then(|_| db_clone.mfa_challenge_mail(challenge_id));
+        let login = db_clone
+            .verify_mfa(challenge_id, code)
+            .and_then(|user| Ok((user.id, db_clone.start_session(user.id)?)));
+        let outcome = login.as_ref().map_or_else(AuthOutcome::from, |_| AuthOutcome::Success);
+        audit_login(&audit_clone, &client, mail.as_deref(), outcome);
+        record_login(outcome);
+        match login {
+            Ok((_, Some(session))) => res.ok(&headers, session_json(&session)),
+            Ok((user_id, None)) => res.ok(&headers, json_response(true, Some(user_id), None)),
+            Err(e) => send_failure!(res, headers, mfa_failure(e)),
+        }
+    });
+
+    // POST /bench/no-db: parses the login payload but never touches the database
+    #[cfg(feature = "bench")]
+    app.route(Post, "/bench/no-db", |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/bench/no-db");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        if parse_json_field(json_str, "UserName").is_none() {
+            let json = json_response(false, None, Some("Missing UserName or HashedPassword"));
+            return res.send(sent(&Status::BAD_REQUEST), &headers, json);
+        }
+        res.ok(&headers, json_response(true, Some(12345), None))
+    });
+
+    // POST /admin/create-db
+    let db_clone = db.clone();
+    let jobs_clone = jobs.clone();
+    let admin_clone = admin.clone();
+    app.route(Post, "/admin/create-db", move |ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/admin/create-db");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        // Every parameter falls back to the historical 10,000 `password{i}` users
+        let query = ctx.uri.query();
+        let defaults = SeedParams::default();
+        let count = match query_param(query, "count").map(|c| c.parse()) {
+            None => Ok(defaults.count),
+            Some(parsed) => parsed,
+        };
+        let seed = match query_param(query, "seed").map(|s| s.parse()) {
+            None => Ok(None),
+            Some(parsed) => parsed.map(Some),
+        };
+        let (Ok(count), Ok(seed)) = (count, seed) else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, "count and seed must be integers");
+        };
+        let params = SeedParams {
+            count,
+            password_pattern: query_param(query, "password").unwrap_or(defaults.password_pattern),
+            seed,
+        };
+
+        match jobs_clone.start(&db_clone, params) {
+            Ok(job) => {
+                println!("Seed job {} started: {} users", job.job_id, job.total);
+                res.send(sent(&Status::ACCEPTED), &headers, job_json(&job))
+            }
+            Err(e @ StartError::Invalid(_)) => res.send(sent(&Status::BAD_REQUEST), &headers, e.to_string()),
+            Err(e @ StartError::AlreadyRunning(_)) => res.send(sent(&Status::CONFLICT), &headers, e.to_string()),
+        }
+    });
+
+    // GET /admin/create-db/:id
+    let jobs_clone = jobs.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/create-db/:id", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/create-db/:id");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let job = ctx.params.get("id").and_then(|id| id.parse().ok()).and_then(|id| jobs_clone.status(id));
+        match job {
+            Some(job) => res.ok(&headers, job_json(&job)),
+            None => res.send(sent(&Status::NOT_FOUND), &headers, "404"),
+        }
+    });
+
+    // POST /admin/import-users?format=&mode=&chunk=: rows that fail are listed in the report
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Post, "/admin/import-users", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/admin/import-users");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        // Defaults: CSV, in chunks of 10,000 rows, next to the existing users
+        let query = ctx.uri.query();
+        let defaults = ImportParams::default();
+        let format = query_param(query, "format").map(|f| f.parse::<BulkFormat>()).transpose();
+        let mode = query_param(query, "mode").map(|m| m.parse::<ImportMode>()).transpose();
+        let chunk = query_param(query, "chunk").map(|c| c.parse::<usize>()).transpose();
+        let (Ok(format), Ok(mode), Ok(chunk)) = (format, mode, chunk) else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, "format must be csv or ndjson, mode insert, upsert or replace, chunk an integer");
+        };
+        let params = ImportParams {
+            format: format.unwrap_or(defaults.format),
+            mode: mode.unwrap_or(defaults.mode),
+            chunk_size: chunk.unwrap_or(defaults.chunk_size),
+        };
+
+        // The body is read as it arrives, never buffered whole
+        let report = db_clone.import_users(params).and_then(|mut import| {
+            import.read_from(ctx.body())?;
+            import.finish()
+        });
+        match report {
+            Ok(report) => res.ok(&headers, import_report_json(&report)),
+            Err(ImportError::Invalid(reason)) => res.send(sent(&Status::BAD_REQUEST), &headers, json_response(false, None, Some(reason))),
+            Err(ImportError::Store(e)) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // GET /admin/export-users?format=: streamed a page at a time
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/export-users", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/export-users");
+        let mut headers = response_headers!();
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("Content-Type", b"application/json");
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let Ok(format) = query_param(ctx.uri.query(), "format").map_or(Ok(BulkFormat::Csv), |f| f.parse::<BulkFormat>()) else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, "format must be csv or ndjson");
+        };
+        headers.add("Content-Type", format.content_type().as_bytes());
+        res.send_chunked(&Status::OK, &headers, db_clone.export_users(format))
+    });
+
+    // GET /admin/cache-stats
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/cache-stats", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/cache-stats");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let stats = db_clone.cache_stats();
+        let json = format!(
+            r#"{{"Enabled":{},"Hits":{},"Misses":{},"Entries":{}}}"#,
+            stats.enabled, stats.hits, stats.misses, stats.entries
+        );
+        res.ok(&headers, json)
+    });
+
+    // GET /admin/throttle-stats
+    let throttle_clone = throttle.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/throttle-stats", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/throttle-stats");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let stats = throttle_clone.as_ref().map(|throttle| throttle.stats()).unwrap_or_default();
+        let json = format!(
+            r#"{{"Enabled":{},"Lockouts":{},"Rejected":{},"Locked":{}}}"#,
+            stats.enabled, stats.lockouts, stats.rejected, stats.locked
+        );
+        res.ok(&headers, json)
+    });
+
+    // GET /admin/admission-stats
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/admission-stats", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/admission-stats");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let stats = ADMISSION.get().map(|admission| admission.stats()).unwrap_or_default();
+        let json = format!(
+            r#"{{"Enabled":{},"Adaptive":{},"Limit":{},"MaxInFlight":{},"InFlight":{},"Queued":{},"Admitted":{},"Rejected":{},"Expired":{}}}"#,
+            stats.enabled, stats.adaptive, stats.limit, stats.max_in_flight, stats.in_flight, stats.queued, stats.admitted, stats.rejected, stats.expired
+        );
+        res.ok(&headers, json)
+    });
+
+    // GET /admin/audit?mail=&outcome=&ip=&since=&until=&before=&limit=: newest first
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/audit", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/audit");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let query = ctx.uri.query();
+        let number = |name| query_param(query, name).map(|v| v.parse::<i64>()).transpose();
+        let outcome = query_param(query, "outcome").map(|v| v.parse::<AuthOutcome>()).transpose();
+        let limit = query_param(query, "limit").map(|v| v.parse::<u32>()).transpose();
+        let (Ok(since), Ok(until), Ok(before), Ok(limit), Ok(outcome)) =
+            (number("since"), number("until"), number("before"), limit, outcome)
+        else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, "since, until, before and limit must be integers, outcome a known outcome");
+        };
+        let filters = AuditQuery {
+            mail: query_param(query, "mail"),
+            outcome,
+            ip: query_param(query, "ip"),
+            since,
+            until,
+            before,
+            limit,
+        };
+
+        match db_clone.audit_events(&filters) {
+            Ok(page) => res.ok(&headers, audit_page_json(&page)),
+            Err(e) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // GET /admin/audit-stats
+    let audit_clone = audit.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/audit-stats", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/audit-stats");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let stats = audit_clone.as_ref().map(|audit| audit.stats()).unwrap_or_default();
+        let json = format!(
+            r#"{{"Enabled":{},"Written":{},"Dropped":{},"Failed":{},"Batches":{}}}"#,
+            stats.enabled, stats.written, stats.dropped, stats.failed, stats.batches
+        );
+        res.ok(&headers, json)
+    });
+
+    // POST /admin/api-keys: the response is the only time the key is shown
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Post, "/admin/api-keys", move |mut ctx, res| {
+        let _metered = metered!(ctx, res, "POST", "/admin/api-keys");
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let body = read_body!(ctx, res);
+        let json_str = std::str::from_utf8(&body).unwrap_or("");
+        let (Some(name), Some(scopes)) =
+            (parse_json_field(json_str, "Name"), parse_json_string_array(json_str, "Scopes"))
+        else {
+            return res.send(sent(&Status::BAD_REQUEST), &headers, "Missing Name or Scopes");
+        };
+        let scopes: Vec<String> = scopes.into_iter().map(str::to_owned).collect();
+        let ttl = parse_json_number(json_str, "ExpiresInSecs").map(std::time::Duration::from_secs);
+
+        match db_clone.create_api_key(&name, &scopes, ttl) {
+            Ok(new_key) => {
+                let key = &new_key.api_key;
+                let json = api_key_json(key.id, &key.name, &key.scopes, key.expires_at, key.revoked);
+                let json = format!(r#"{},"Key":"{}"}}"#, json.trim_end_matches('}'), new_key.key);
+                res.send(sent(&Status::CREATED), &headers, json)
+            }
+            Err(e @ ApiKeyError::Invalid(_)) => res.send(sent(&Status::BAD_REQUEST), &headers, e.to_string()),
+            Err(ApiKeyError::Store(e)) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // GET /admin/api-keys
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Get, "/admin/api-keys", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/admin/api-keys");
+        let mut headers = response_headers!();
         headers.add("Content-Type", b"application/json");
-        res.ok(&headers, r#"{"status":"ok"}"#)
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        match db_clone.list_api_keys() {
+            Ok(keys) => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .map(|key| api_key_json(key.id, &key.name, &key.scopes, key.expires_at, key.revoked))
+                    .collect();
+                res.ok(&headers, format!("[{}]", keys.join(",")))
+            }
+            Err(e) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // DELETE /admin/api-keys/:id: revoked keys stay listed
+    let db_clone = db.clone();
+    let admin_clone = admin.clone();
+    app.route(Delete, "/admin/api-keys/:id", move |ctx, res| {
+        let _metered = metered!(ctx, res, "DELETE", "/admin/api-keys/:id");
+        let mut headers = response_headers!();
+
+        let authorization = ctx.headers.get("Authorization").and_then(|v| std::str::from_utf8(v).ok());
+        if let Some((status, message)) = admin_rejection(admin_clone.check(authorization)) {
+            headers.add("WWW-Authenticate", b"Bearer");
+            return res.send(sent(status), &headers, message);
+        }
+
+        let Some(id) = ctx.params.get("id").and_then(|id| id.parse().ok()) else {
+            return res.send(sent(&Status::NOT_FOUND), &headers, "404");
+        };
+        match db_clone.revoke_api_key(id) {
+            Ok(true) => res.send(sent(&Status::NO_CONTENT), &headers, ""),
+            Ok(false) => res.send(sent(&Status::NOT_FOUND), &headers, "404"),
+            Err(e) => send_failure!(res, headers, store_failure(e)),
+        }
+    });
+
+    // GET /metrics: Prometheus exposition, 404 when MAXREQ_METRICS is off
+    let db_clone = db.clone();
+    let throttle_clone = throttle.clone();
+    app.route(Get, "/metrics", move |_, res| {
+        let Some(metrics) = METRICS.get() else {
+            return res.send(&Status::NOT_FOUND, &Headers::new(), "404");
+        };
+        let mut headers = response_headers!();
+        headers.add("Content-Type", METRICS_CONTENT_TYPE.as_bytes());
+        res.ok(&headers, metrics.render(&db_clone, throttle_clone.as_deref()))
+    });
+
+    // Liveness, at the paths of the other servers and the one khttp used to have
+    for route in ["/health/live", "/health", "/api/auth/health"] {
+        app.route(Get, route, move |ctx, res| {
+            let _metered = metered!(ctx, res, "GET", route);
+            let mut headers = response_headers!();
+            headers.add("Content-Type", b"text/plain; charset=utf-8");
+            res.ok(&headers, "UserTokenApi Rust server is running")
+        });
+    }
+
+    // Readiness: a database round trip within MAXREQ_READY_TIMEOUT_MS
+    let db_clone = db.clone();
+    let ready_timeout = config.ready_timeout;
+    app.route(Get, "/health/ready", move |ctx, res| {
+        let _metered = metered!(ctx, res, "GET", "/health/ready");
+        let readiness = db_clone.readiness(ready_timeout);
+        let status = if readiness.ready { &Status::OK } else { &Status::SERVICE_UNAVAILABLE };
+        let mut headers = response_headers!();
+        headers.add("Content-Type", b"application/json");
+        res.send(sent(status), &headers, readiness_json(&readiness))
     });
 
     // Configure server
     app.thread_count(16);
-    app.fallback_route(|_, r| {
-        let mut headers = Headers::new();
+    // khttp has no deadline nor size limit on a request head. A worker thread is held by its
+    // connection: a socket read timeout frees it from a client that stopped sending, and
+    // `CONN_WATCHDOG` from one that sends too slowly or idles past the keep-alive. `HeadLimit`
+    // refuses a head that is too large, counted in plaintext
+    let (read_timeout, max_header_bytes) = (config.limits.header_timeout, config.limits.max_header_bytes);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    app.stream_setup_hook(move |stream| {
+        stream.set_read_timeout(Some(read_timeout))?;
+        let reading_head = watchdog.accepted(&stream)?;
+        Ok(HeadLimit::new(ConnStream::accept(stream, tls.as_ref())?, reading_head, max_header_bytes))
+    });
+    app.fallback_route(|ctx, r| {
+        let _metered = metered!(ctx, r, "-", UNMATCHED_ROUTE);
+        // No route takes OPTIONS, so preflights land here, told apart by Access-Control-Request-Method
+        let header = |name| ctx.headers.get(name).and_then(|v| std::str::from_utf8(v).ok());
+        if let Some(policy) = CORS.get()
+            && let (Some(origin), Some(method)) = (header("Origin"), header("Access-Control-Request-Method"))
+        {
+            let Some(allowed) = policy.preflight(origin, method, header("Access-Control-Request-Headers")) else {
+                return r.send(sent(&Status::FORBIDDEN), &Headers::new(), "");
+            };
+            let mut headers = Headers::new();
+            for (name, value) in allowed {
+                headers.add(name, value.as_bytes());
+            }
+            return r.send(sent(&Status::NO_CONTENT), &headers, "");
+        }
+        let mut headers = response_headers!();
         headers.add("Content-Type", b"application/json");
-        r.send(&Status::NOT_FOUND, &headers, "404")
+        r.send(sent(&Status::NOT_FOUND), &headers, "404")
     });
     
-    println!("Server starting on http://0.0.0.0:8080");
-    println!("  GET  /api/auth/health");
+    println!("Server starting on {}://0.0.0.0:8080", scheme);
+    println!("  GET  /health/live (also /health and /api/auth/health)");
+    println!("  GET  /health/ready");
     println!("  POST /api/auth/get-user-token");
-    println!("  GET  /api/auth/create-db");
-    
+    println!("  POST /api/auth/register");
+    println!("  POST /api/auth/change-password");
+    println!("  DELETE /api/auth/user");
+    println!("  POST /api/auth/refresh");
+    println!("  POST /api/auth/logout");
+    println!("  POST /api/auth/mfa/enroll");
+    println!("  POST /api/auth/mfa/confirm");
+    println!("  POST /api/auth/mfa/verify");
+    #[cfg(feature = "bench")]
+    println!("  POST /bench/no-db");
+    println!("  POST /admin/create-db?count=&password=&seed=");
+    println!("  GET  /admin/create-db/:id");
+    println!("  POST /admin/import-users?format=&mode=&chunk=");
+    println!("  GET  /admin/export-users?format=");
+    println!("  GET  /admin/cache-stats");
+    println!("  GET  /admin/throttle-stats");
+    println!("  GET  /admin/admission-stats");
+    println!("  POST /admin/api-keys");
+    println!("  GET  /admin/api-keys");
+    println!("  DELETE /admin/api-keys/:id");
+    println!("  GET  /admin/audit?mail=&outcome=&ip=&since=&until=&before=&limit=");
+    println!("  GET  /admin/audit-stats");
+    println!("  GET  /metrics");
+
+    // Drain on SIGINT or SIGTERM, then exit: `serve` never returns
+    let shutdown_timeout = config.shutdown_timeout;
+    ctrlc::set_handler(move || {
+        drain(shutdown_timeout, audit.as_deref(), throttle.as_deref(), db.as_ref().clone());
+        println!("Server stopped");
+        std::process::exit(0);
+    })
+    .expect("Failed to install the SIGINT/SIGTERM handler");
+
     app.build().serve().unwrap();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use user_token_core::hash_password;
+
+    #[test]
+    fn escaped_password_registers_as_decoded() {
+        let body = r#"{"UserName":"quote@example.com","Password":"abcdefg1\"x\\y\u00e9"}"#;
+        let password = parse_json_field(body, "Password").unwrap();
+        assert_eq!(password, "abcdefg1\"x\\yé");
+        assert!(matches!(parse_json_field(body, "UserName"), Some(Cow::Borrowed("quote@example.com"))));
+
+        let dir = std::env::temp_dir().join(format!("khttp-api-register-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = UserStore::open(&dir.join("users.db").to_string_lossy(), 1).unwrap();
+        let user = store.register("quote@example.com", &password).unwrap();
+
+        // Logs in with the hash of the decoded password, as the other servers register it
+        let login = store.get_user_by_credentials("quote@example.com", &hash_password("abcdefg1\"x\\yé")).unwrap();
+        assert_eq!(login.map(|login| login.id), Some(user.id));
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let agent = "curl\u{0}/8\t\"x\"\\\n\u{1f}é";
+        let json = json_string_or_null(Some(agent));
+        assert_eq!(json, r#""curl\u0000/8\u0009\"x\"\\\u000a\u001fé""#);
+        assert_eq!(serde_json::from_str::<String>(&json).unwrap(), agent);
+    }
+
+    // A connection that hands its input out a few bytes at a time
+    struct Conn {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(7);
+            self.input.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn head_over_the_limit_gets_431() {
+        let body = "x".repeat(2000);
+        let small = format!("POST /api/auth/register HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let large = format!("GET /health HTTP/1.1\r\nCookie: {}\r\n\r\n", "y".repeat(1100));
+        let conn = Conn { input: std::io::Cursor::new(format!("{}{}", small, large).into_bytes()), output: Vec::new() };
+        let reading_head = Arc::new(AtomicBool::new(true));
+        let mut stream = HeadLimit::new(conn, reading_head.clone(), 1024);
+
+        // A body longer than the limit is not counted
+        let mut request = vec![0; small.len()];
+        stream.read_exact(&mut request).unwrap();
+        assert!(!reading_head.load(Ordering::Relaxed));
+        assert!(stream.inner.output.is_empty());
+
+        // Counted again once the response is sent
+        reading_head.store(true, Ordering::Relaxed);
+        let error = stream.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(stream.inner.output.starts_with(b"HTTP/1.1 431 "));
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use reqwest::{Certificate, Client, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::sync::Arc;
@@ -24,17 +24,53 @@ pub struct LoginResponse {
     pub error_message: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SeedJobStatus {
+    #[serde(rename = "JobId")]
+    pub job_id: u64,
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "Total")]
+    pub total: usize,
+    #[serde(rename = "Inserted")]
+    pub inserted: usize,
+    #[serde(rename = "Error")]
+    pub error: Option<String>,
+}
+
+/// How a no-DB load test bypasses the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoDbMode {
+    /// POST bench/no-db (Rust servers built with `--features bench`)
+    BenchRoute,
+    /// Legacy "no_db" user name on the login route
+    MagicUser,
+}
+
+/// What became of one request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    /// 503: shed by admission control or the store was overloaded
+    Shed,
+    Failed,
+}
+
 #[derive(Debug)]
 pub struct LoadTestResult {
     pub total_duration: Duration,
     pub total_requests: usize,
     pub successful_requests: usize,
     pub failed_requests: usize,
+    /// Part of `failed_requests` answered 503
+    pub shed_requests: usize,
     pub mean_response_time_ms: f64,
     pub requests_per_second: f64,
     pub min_response_time_ms: f64,
     pub max_response_time_ms: f64,
     pub response_times: Vec<f64>,
+    /// Response times of every answered request, successful or not
+    pub all_response_times: Vec<f64>,
 }
 
 pub struct ApiClient {
@@ -43,13 +79,18 @@ pub struct ApiClient {
 }
 
 impl ApiClient {
-    /// Create a new API client with connection pooling
+    /// Create a new API client with connection pooling. HTTPS servers are
+    /// trusted from the built-in roots, plus the PEM CA in MAXREQ_CA_CERT if set
     pub fn new(base_url: impl Into<String>, max_concurrent_connections: usize) -> Self {
-        let client = Client::builder()
+        let mut builder = Client::builder()
             .pool_max_idle_per_host(max_concurrent_connections)
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
+            .timeout(Duration::from_secs(30));
+        if let Ok(path) = std::env::var("MAXREQ_CA_CERT") {
+            let pem = std::fs::read(&path).unwrap_or_else(|e| panic!("Cannot read MAXREQ_CA_CERT {}: {}", path, e));
+            let ca = Certificate::from_pem(&pem).unwrap_or_else(|e| panic!("Invalid MAXREQ_CA_CERT {}: {}", path, e));
+            builder = builder.add_root_certificate(ca);
+        }
+        let client = builder.build().unwrap();
 
         Self {
             client,
@@ -85,17 +126,33 @@ impl ApiClient {
     /// Create database with test users
     pub async fn create_db(&self) -> Result<String> {
         let api_url = self.base_url.replace("%", "");
-        let url = format!("{}api/auth/create-db", api_url);
-        
-        println!("   Initializing database with: {}", url);
-        
+
+        // The Rust servers expose seeding as an admin route; the other
+        // implementations still use the open GET endpoint.
+        let admin_url = format!("{}admin/create-db", api_url);
+        println!("   Initializing database with: {}", admin_url);
+
         let response = self
-            .client
-            .get(&url)
+            .admin_request(self.client.post(&admin_url))
             .send()
             .await
             .context("Failed to send create-db request")?;
 
+        let (url, response) = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {
+                let url = format!("{}api/auth/create-db", api_url);
+                println!("   Initializing database with: {}", url);
+                let response = self
+                    .client
+                    .get(&url)
+                    .send()
+                    .await
+                    .context("Failed to send create-db request")?;
+                (url, response)
+            }
+            _ => (admin_url, response),
+        };
+
         let status = response.status();
         let text = response
             .text()
@@ -108,7 +165,47 @@ impl ApiClient {
             anyhow::bail!("Failed to create database: HTTP {}", status);
         }
 
-        Ok(text)
+        // The Rust servers seed in the background and answer with a job id;
+        // the other implementations answer once the database is ready.
+        match serde_json::from_str::<SeedJobStatus>(&text) {
+            Ok(job) => self.wait_for_seed_job(&url, job).await,
+            Err(_) => Ok(text),
+        }
+    }
+
+    /// Attach the admin token (MAXREQ_ADMIN_TOKEN), if any, to an admin request
+    fn admin_request(&self, request: RequestBuilder) -> RequestBuilder {
+        match std::env::var("MAXREQ_ADMIN_TOKEN") {
+            Ok(token) if !token.is_empty() => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+
+    /// Poll a background create-db job until it is no longer running
+    async fn wait_for_seed_job(&self, create_db_url: &str, mut job: SeedJobStatus) -> Result<String> {
+        let url = format!("{}/{}", create_db_url, job.job_id);
+
+        while job.state == "Running" {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            job = self
+                .admin_request(self.client.get(&url))
+                .send()
+                .await
+                .context("Failed to send create-db status request")?
+                .json()
+                .await
+                .context("Failed to read create-db status response")?;
+            println!("   Seeding: {}/{} users", job.inserted, job.total);
+        }
+
+        if job.state != "Completed" {
+            anyhow::bail!(
+                "Failed to create database: {}",
+                job.error.unwrap_or_else(|| job.state.clone())
+            );
+        }
+
+        Ok(format!("Successfully created {} users in the database", job.inserted))
     }
 
     /// Execute a single request
@@ -116,7 +213,8 @@ impl ApiClient {
         &self,
         abs: usize,
         request_id: i32,
-    ) -> (bool, f64) {
+        no_db: Option<NoDbMode>,
+    ) -> (Outcome, f64) {
         let request_stopwatch = Instant::now();
 
         let mut api_url = self.base_url.clone();
@@ -138,9 +236,12 @@ impl ApiClient {
             }
         }
 
-        let url = format!("{}api/auth/get-user-token", api_url);
+        let url = match no_db {
+            Some(NoDbMode::BenchRoute) => format!("{}bench/no-db", api_url),
+            _ => format!("{}api/auth/get-user-token", api_url),
+        };
 
-        let test_data = if request_id == -1 {
+        let test_data = if no_db == Some(NoDbMode::MagicUser) {
             LoginRequest {
                 user_name: "no_db".to_string(),
                 hashed_password: "no_db".to_string(),
@@ -176,33 +277,58 @@ impl ApiClient {
 
                         let elapsed = request_stopwatch.elapsed().as_secs_f64() * 1000.0;
 
+                        if status == StatusCode::SERVICE_UNAVAILABLE {
+                            return (Outcome::Shed, elapsed);
+                        }
                         if !status.is_success() {
-                            return (false, elapsed);
+                            return (Outcome::Failed, elapsed);
                         }
 
                         match serde_json::from_str::<LoginResponse>(&response_body) {
                             Ok(login_response) => {
                                 if login_response.success && login_response.user_id.is_some() {
-                                    (true, elapsed)
+                                    (Outcome::Success, elapsed)
                                 } else {
                                     if let Some(err_msg) = login_response.error_message {
                                         println!("{}", err_msg);
                                     }
-                                    (false, elapsed)
+                                    (Outcome::Failed, elapsed)
                                 }
                             }
-                            Err(_) => (false, elapsed),
+                            Err(_) => (Outcome::Failed, elapsed),
                         }
                     }
                     Err(e) => {
                         println!("Request {} failed to read response: {}", request_id, e);
-                        (false, 0.0)
+                        (Outcome::Failed, 0.0)
                     }
                 }
             }
             Err(e) => {
                 println!("Request {} failed: {}", request_id, e);
-                (false, 0.0)
+                (Outcome::Failed, 0.0)
+            }
+        }
+    }
+
+    /// Pick how to bypass the database: the Rust servers built with the
+    /// `bench` feature expose POST bench/no-db, the other implementations
+    /// still recognise the magic "no_db" user name.
+    async fn detect_no_db_mode(&self) -> NoDbMode {
+        let url = format!("{}bench/no-db", self.base_url.replace("%", ""));
+        let probe = LoginRequest {
+            user_name: "user1@example.com".to_string(),
+            hashed_password: Self::hash_password("password1"),
+        };
+
+        match self.client.post(&url).json(&probe).send().await {
+            Ok(response) if response.status().is_success() => {
+                println!("   No-DB mode: {}", url);
+                NoDbMode::BenchRoute
+            }
+            _ => {
+                println!("   No-DB mode: \"no_db\" user name");
+                NoDbMode::MagicUser
             }
         }
     }
@@ -224,6 +350,12 @@ impl ApiClient {
         // Initialize database
         self.create_db().await?;
 
+        let no_db = if no_db {
+            Some(self.detect_no_db_mode().await)
+        } else {
+            None
+        };
+
         let semaphore = Arc::new(Semaphore::new(max_concurrency));
         // Share a single HTTP client across all tasks to avoid recreating connection pools
         let shared_client = Arc::new(ApiClient::new(self.base_url.clone(), max_concurrency));
@@ -233,19 +365,19 @@ impl ApiClient {
         // Create all tasks
         for i in 0..total_requests {
             let sem = semaphore.clone();
-            let request_id = if no_db { -1 } else { ((i % 10000) + 1) as i32 };
+            let request_id = ((i % 10000) + 1) as i32;
             let client = shared_client.clone();
 
             let task = tokio::spawn(async move {
                 let _permit = sem.acquire().await.unwrap();
-                client.execute_request(i, request_id).await
+                client.execute_request(i, request_id, no_db).await
             });
 
             tasks.push(task);
         }
 
         // Wait for all tasks to complete
-        let results: Vec<(bool, f64)> = futures::future::join_all(tasks)
+        let results: Vec<(Outcome, f64)> = futures::future::join_all(tasks)
             .await
             .into_iter()
             .filter_map(|r| r.ok())
@@ -254,16 +386,21 @@ impl ApiClient {
         let elapsed = stopwatch.elapsed();
 
         // Process results
-        let successful: Vec<_> = results.iter().filter(|r| r.0).collect();
-        let failed: Vec<_> = results.iter().filter(|r| !r.0).collect();
+        let successful: Vec<_> = results.iter().filter(|r| r.0 == Outcome::Success).collect();
+        let failed: Vec<_> = results.iter().filter(|r| r.0 != Outcome::Success).collect();
+        let shed = results.iter().filter(|r| r.0 == Outcome::Shed).count();
         let response_times: Vec<f64> = successful.iter().map(|r| r.1).collect();
+        // Connection errors have no response time
+        let all_response_times: Vec<f64> = results.iter().filter(|r| r.1 > 0.0).map(|r| r.1).collect();
 
         let load_test_result = LoadTestResult {
             total_duration: elapsed,
             total_requests,
             successful_requests: successful.len(),
             failed_requests: failed.len(),
+            shed_requests: shed,
             response_times: response_times.clone(),
+            all_response_times,
             mean_response_time_ms: if !response_times.is_empty() {
                 response_times.iter().sum::<f64>() / response_times.len() as f64
             } else {
@@ -296,6 +433,13 @@ impl ApiClient {
             format_number(result.failed_requests),
             (result.failed_requests as f64 / result.total_requests as f64) * 100.0
         );
+        if result.shed_requests > 0 {
+            println!(
+                "   Shed (503): {} ({:.1}%)",
+                format_number(result.shed_requests),
+                (result.shed_requests as f64 / result.total_requests as f64) * 100.0
+            );
+        }
         println!();
 
         println!("⚡ Performance Metrics:");
@@ -318,6 +462,18 @@ impl ApiClient {
             println!("   95th percentile: {:.1} ms", p95);
             println!("   99th percentile: {:.1} ms", p99);
         }
+
+        // With admission control, shed requests answer fast: compare p99 over every answer too
+        if result.all_response_times.len() > result.response_times.len() {
+            let mut sorted_times = result.all_response_times.clone();
+            sorted_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            println!();
+            println!("📉 Including failed and shed requests:");
+            println!("   50th percentile (median): {:.1} ms", get_percentile(&sorted_times, 50));
+            println!("   95th percentile: {:.1} ms", get_percentile(&sorted_times, 95));
+            println!("   99th percentile: {:.1} ms", get_percentile(&sorted_times, 99));
+        }
     }
 }
 
@@ -431,6 +587,9 @@ async fn main() -> Result<()> {
     const CPP_API_URL: &str = "http://localhost:8081/";
     const GO_API_URL: &str = "http://localhost:8082/";
     const BUN_API_URL: &str = "http://localhost:8084/";
+    // TLS in the Rust server itself (MAXREQ_TLS_CERT), or terminated by nginx in front of it
+    const RUST_TLS_API_URL: &str = "https://localhost:8080/";
+    const NGINX_TLS_API_URL: &str = "https://localhost:8443/";
 
     if test_filter == "dotnet" || test_filter == "all" {
         test_api(".NET API", DOTNET_API_URL, total_requests, max_concurrency, no_db).await;
@@ -447,6 +606,13 @@ async fn main() -> Result<()> {
     if test_filter == "rust" || test_filter == "all" {
         test_api("Rust API", RUST_API_URL, total_requests, max_concurrency, no_db).await;
     }
+    // Not part of "all": they test the same server as "rust", set up differently
+    if test_filter == "rust-tls" {
+        test_api("Rust API (TLS)", RUST_TLS_API_URL, total_requests, max_concurrency, no_db).await;
+    }
+    if test_filter == "nginx-tls" {
+        test_api("Rust API (TLS at nginx)", NGINX_TLS_API_URL, total_requests, max_concurrency, no_db).await;
+    }
     if test_filter == "php" || test_filter == "all" {
         test_api("PHP API", PHP_API_URL, total_requests, max_concurrency, no_db).await;
     }
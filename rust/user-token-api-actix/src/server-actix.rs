@@ -1,21 +1,79 @@
 use actix_web::{
-    web, App, HttpServer, HttpResponse, Result as ActixResult,
-    //middleware::Logger,
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{Extensions, Payload, ServiceFactory, ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{header::{self, HeaderName, HeaderValue}, Method, StatusCode},
+    middleware::{from_fn, Condition, Next},
+    web, App, FromRequest, HttpMessage, HttpRequest, HttpServer, HttpResponse, HttpResponseBuilder, Resource, Result as ActixResult,
 };
-use actix_cors::Cors;
-use rusqlite::{Result as SqliteResult};
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use futures_util::future::{self, Either, LocalBoxFuture};
+use futures_util::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::borrow::Cow;
-use std::time::Duration;
-use tracing::{info, error};
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
+use std::cell::Cell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug_span, field, info, error, warn, Instrument};
+use user_token_core::{required_scope, retry_after_secs, tls_server_config, AccessLog, AccessRecord, AccountError, AdminAccess, AdminGuard, Admission, ApiKeyAccess, ApiKeyError, AuditLog, AuditQuery, AuthEvent, AuthOutcome, BulkFormat, Config, CorsPolicy, ImportError, ImportMode, ImportParams, LimitsConfig, LoginOutcome, LoginStatus, LoginThrottle, Metrics, MfaError, SeedJobs, SeedParams, Session, SessionError, StartError, StoreError, TlsConfig, TotpEnrollment, User, UserStore, BODY_TOO_LARGE_MESSAGE, BUSY_MESSAGE, METRICS_CONTENT_TYPE, MFA_CHALLENGE_TTL, SHED_RETRY_AFTER_SECS, UNMATCHED_ROUTE};
 
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-type DbPool = Pool<SqliteConnectionManager>;
+/// `web::Json`, with the body read and parsed in a `json.parse` span.
+struct Json<T>(T);
+
+impl<T> Json<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Json<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let parse = web::Json::<T>::from_request(req, payload).instrument(debug_span!("json.parse"));
+        Box::pin(async move {
+            let json = parse.await.map_err(rejection)?;
+            Ok(Json(json.into_inner()))
+        })
+    }
+}
+
+/// An extractor error, answered with its status and a failed `LoginResponse`.
+fn rejection(e: actix_web::Error) -> actix_web::Error {
+    let status = e.as_response_error().status_code();
+    let message = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => Cow::Borrowed(BODY_TOO_LARGE_MESSAGE),
+        _ => Cow::Owned(e.to_string()),
+    };
+    let response = HttpResponse::build(status).traced_json(LoginResponse::failed(message));
+    InternalError::from_response(e, response).into()
+}
+
+/// `HttpResponseBuilder::json`, serializing in a `json.serialize` span.
+trait TracedJson {
+    fn traced_json(&mut self, value: impl Serialize) -> HttpResponse;
+}
+
+impl TracedJson for HttpResponseBuilder {
+    fn traced_json(&mut self, value: impl Serialize) -> HttpResponse {
+        let _span = debug_span!("json.serialize").entered();
+        self.json(value)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct LoginRequest {
@@ -29,211 +87,1273 @@ struct LoginRequest {
 struct LoginResponse {
     #[serde(rename = "Success")]
     success: bool,
+    #[serde(rename = "Status")]
+    status: LoginStatus,
     #[serde(rename = "UserId")]
     user_id: Option<i64>,
     #[serde(rename = "ErrorMessage")]
     #[serde(skip_serializing_if = "Option::is_none")]
     error_message: Option<Cow<'static, str>>,
+    #[serde(flatten)]
+    details: Option<LoginDetails>,
+}
+
+impl LoginResponse {
+    fn ok(user_id: i64, details: Option<LoginDetails>) -> Self {
+        LoginResponse { success: true, status: LoginStatus::Ok, user_id: Some(user_id), error_message: None, details }
+    }
+
+    fn failed(error_message: impl Into<Cow<'static, str>>) -> Self {
+        LoginResponse { success: false, status: LoginStatus::Failed, user_id: None, error_message: Some(error_message.into()), details: None }
+    }
+}
+
+/// `503` with `Retry-After` when the store is overloaded, `500` otherwise,
+/// with the same failed `LoginResponse` body as every other failure.
+fn store_failure(e: StoreError) -> HttpResponse {
+    if e.is_overloaded() {
+        warn!("Database overloaded: {}", e);
+    } else {
+        error!("Database error: {}", e);
+    }
+    let mut response = HttpResponse::build(StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+    if let Some(secs) = e.retry_after() {
+        response.insert_header((header::RETRY_AFTER, secs));
+    }
+    response.traced_json(LoginResponse::failed(e.client_message()))
 }
 
-#[derive(Debug)]
-struct User {
-    id: i64,
+/// Fields added next to `Success` by some responses.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum LoginDetails {
+    /// Refresh token returned with a successful login when sessions are enabled.
+    Session {
+        #[serde(rename = "RefreshToken")]
+        refresh_token: String,
+        #[serde(rename = "ExpiresIn")]
+        expires_in: u64,
+    },
+    /// Returned with `MfaRequired`, to be sent back to `/api/auth/mfa/verify`.
+    MfaChallenge {
+        #[serde(rename = "ChallengeId")]
+        challenge_id: String,
+    },
+    TotpEnrollment {
+        #[serde(rename = "Secret")]
+        secret: String,
+        #[serde(rename = "OtpauthUri")]
+        otpauth_uri: String,
+    },
+}
+
+impl From<Session> for LoginDetails {
+    fn from(session: Session) -> Self {
+        LoginDetails::Session {
+            refresh_token: session.refresh_token,
+            expires_in: session.expires_in.as_secs(),
+        }
+    }
+}
+
+impl From<TotpEnrollment> for LoginDetails {
+    fn from(enrollment: TotpEnrollment) -> Self {
+        LoginDetails::TotpEnrollment {
+            secret: enrollment.secret,
+            otpauth_uri: enrollment.otpauth_uri,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    #[serde(rename = "RefreshToken")]
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MfaConfirmRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MfaVerifyRequest {
+    #[serde(rename = "ChallengeId")]
+    challenge_id: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "Password")]
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangePasswordRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    /// Current password, hashed like in `LoginRequest`.
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    #[serde(rename = "NewPassword")]
+    new_password: String,
+    /// Current TOTP code, for accounts that have TOTP enabled.
+    #[serde(rename = "Code")]
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteUserRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    /// Current TOTP code, for accounts that have TOTP enabled.
+    #[serde(rename = "Code")]
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateApiKeyRequest {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Scopes")]
+    scopes: Vec<String>,
+    /// Lifetime of the key, `None` for a key that does not expire.
+    #[serde(rename = "ExpiresInSecs")]
+    expires_in_secs: Option<u64>,
 }
 
 #[derive(Clone)]
 struct  AppState {
-    db: DbPool,
+    store: UserStore,
+    jobs: Arc<SeedJobs>,
+    admin: AdminGuard,
+    throttle: Option<Arc<LoginThrottle>>,
+    require_api_key: bool,
+    audit: Option<Arc<AuditLog>>,
+    metrics: Option<Arc<Metrics>>,
+    access_log: Option<Arc<AccessLog>>,
+    admission: Option<Arc<Admission>>,
+    ready_timeout: Duration,
+    limits: LimitsConfig,
+    cors: Option<Arc<CorsPolicy>>,
 }
 
 impl AppState {
     fn new() -> Result<Self, Box<dyn std::error::Error>> {
         let cpus = num_cpus::get() as u32;
-        let manager = SqliteConnectionManager::file("users.db");
         // Actix spawns one worker per CPU; match pool size so no worker ever blocks waiting
         let pool_size = cpus;
-        let pool = Pool::builder().max_size(pool_size).build(manager)?;
-        let conn = pool.get()?;
-
-        // Configure SQLite for better performance and concurrency
-        conn.pragma_update(None, "journal_mode", "WAL")?;        // Enable WAL mode for better concurrency
-        conn.pragma_update(None, "synchronous", "NORMAL")?;      // Balance durability vs performance
-        conn.pragma_update(None, "cache_size", "-64000")?;       // 64MB cache (negative = KB)
-        conn.pragma_update(None, "temp_store", "MEMORY")?;       // Store temp tables in memory
-        conn.pragma_update(None, "mmap_size", "268435456")?;     // 256MB memory map
-        
-        // Set busy timeout for handling concurrent access
-        conn.busy_timeout(Duration::from_millis(30000))?;        // 30 second timeout
-        
-        // Create table if it doesn't exist
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                mail TEXT NOT NULL UNIQUE,
-                hashed_password TEXT NOT NULL
-            )",
-            [],
-        )?;
-        
-        // Create index for faster lookups (if not exists)
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_user_mail_password ON user(mail, hashed_password)",
-            [],
-        )?;
-
-        // Run ANALYZE to update query planner statistics
-        let _ = conn.execute("ANALYZE", []);
-
-        // Run PRAGMA optimize for automatic database optimization
-        let _ = conn.pragma_update(None, "optimize", "");
+        let config = Config::from_env();
+        let mut store = UserStore::open("users.db", pool_size)?.with_mail_config(&config.mail)?.with_pool_timeout(config.pool_timeout);
+        if config.mail.lowercase || config.mail.punycode {
+            info!("Mail normalisation: lowercase whole address: {}, punycode domains: {}", config.mail.lowercase, config.mail.punycode);
+        }
+        if let Some(cache) = &config.cache {
+            info!("Credential cache enabled: {} entries, {:?} TTL", cache.capacity, cache.ttl);
+            store = store.with_cache(cache);
+        }
+        if let Some(sessions) = &config.sessions {
+            info!("Sessions enabled: {:?} refresh tokens, expired ones purged every {:?}", sessions.ttl, sessions.gc_interval);
+            store = store.with_sessions(sessions);
+            store.spawn_session_gc(sessions.gc_interval)?;
+        }
+        let metrics = config.metrics.then(|| Arc::new(Metrics::new()));
+        if let Some(metrics) = &metrics {
+            store = store.with_metrics(metrics);
+        }
+        let throttle = match &config.throttle {
+            Some(throttle) => {
+                info!("Login throttling enabled: {} failures per account, {} per IP, in {:?} (persisted: {})",
+                    throttle.account_failures, throttle.ip_failures, throttle.window, throttle.persist);
+                Some(Arc::new(LoginThrottle::open(throttle, &store)?))
+            }
+            None => None,
+        };
+        let audit = match &config.audit {
+            Some(audit) => {
+                info!("Audit log enabled: batches of {} events, flushed every {:?}", audit.batch_size, audit.flush_interval);
+                Some(Arc::new(AuditLog::start(audit, &store)?))
+            }
+            None => None,
+        };
+        let access_log = match &config.access_log {
+            Some(access_log) => {
+                info!("Access log enabled: {} ({}% of requests)", access_log.path, access_log.sample_ratio * 100.0);
+                Some(Arc::new(AccessLog::start(access_log)?))
+            }
+            None => None,
+        };
+        let admission = config.admission.as_ref().map(|admission| {
+            info!("Admission control enabled: {} requests in flight (adaptive: {}), {} queued for up to {:?}",
+                admission.max_in_flight, admission.adaptive, admission.max_queued, admission.queue_timeout);
+            Arc::new(Admission::new(admission))
+        });
+        if config.benchmark_mode {
+            info!("Benchmark mode: /admin endpoints are open without a token");
+        }
+        if config.require_api_key {
+            info!("API keys required on /api/auth endpoints");
+        }
+        info!(
+            "Request limits: {} byte bodies, {} byte heads sent within {:?}, {:?} keep-alive",
+            config.limits.max_body_bytes, config.limits.max_header_bytes, config.limits.header_timeout, config.limits.keep_alive
+        );
+        let cors = config.cors.as_ref().map(|cors| {
+            info!("CORS enabled: origins {:?}, methods {:?}, headers {:?}, preflight cached {:?}",
+                cors.allowed_origins, cors.allowed_methods, cors.allowed_headers, cors.max_age);
+            Arc::new(CorsPolicy::new(cors))
+        });
 
         Ok(AppState{
-            db: pool,
+            store,
+            jobs: Arc::new(SeedJobs::new()),
+            admin: AdminGuard::new(&config),
+            throttle,
+            require_api_key: config.require_api_key,
+            audit,
+            metrics,
+            access_log,
+            admission,
+            ready_timeout: config.ready_timeout,
+            limits: config.limits,
+            cors,
         })
     }
 
-    fn get_user_by_credentials(&self, request: &LoginRequest) -> SqliteResult<Option<User>> {
-        // Handle special test case
-        if request.user_name == "no_db" {
-            return Ok(Some(User { id: 12345 }));
+    /// Flushes the background writers, then checkpoints the database; the
+    /// audit writer goes first since it writes to it.
+    fn close(self) {
+        // The stopped workers have dropped their clones by now
+        if let Some(access_log) = self.access_log {
+            match Arc::try_unwrap(access_log) {
+                Ok(access_log) => {
+                    let stats = access_log.close();
+                    info!("Access log closed: {} lines written, {} dropped, {} failed", stats.written, stats.dropped, stats.failed);
+                }
+                // Flushed instead: only what its last user logs afterwards is lost
+                Err(access_log) => {
+                    warn!("Access log still in use; flushing it");
+                    access_log.flush();
+                }
+            }
         }
-        
-        let conn = self.db.get().unwrap();
-        
-        // Use prepare_cached for automatic statement caching
-        // Optimized query: only select the id column we need
-        let mut stmt = conn.prepare_cached(
-            "SELECT id FROM user WHERE mail = ?1 AND hashed_password = ?2 LIMIT 1"
-        )?;
-
-        // get scalar response
-        let user = stmt.query_row([&request.user_name, &request.hashed_password], |row| {
-            Ok(User { id: row.get(0)? })
-        });
-      
-        match user {
-            Ok(u) => Ok(Some(u)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e)
-        }
-    }
-
-    fn create_test_users(&self, count: usize) -> Result<usize, Box<dyn std::error::Error>> {
-        let conn = self.db.get().unwrap();
-        
-        // Clear existing users
-        conn.execute("DELETE FROM user", [])?;
-        
-        // Use WAL checkpoint for better performance before bulk insert
-        let _ = conn.execute("PRAGMA wal_checkpoint(TRUNCATE)", []);
-        
-        let tx = conn.unchecked_transaction()?;
-        let mut inserted = 0;
-        
-        {
-            // Use prepare_cached for the INSERT statement as well
-            let mut stmt = tx.prepare_cached("INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)")?;
-            
-            for i in 1..=count {
-                let email = format!("user{}@example.com", i);
-                let password = format!("password{}", i);
-                let hashed_password = hash_password(&password);
-                
-                match stmt.execute([&email, &hashed_password]) {
-                    Ok(_) => inserted += 1,
-                    Err(e) => error!("Failed to insert user {}: {}", i, e),
+        if let Some(audit) = self.audit {
+            match Arc::try_unwrap(audit) {
+                Ok(audit) => {
+                    let stats = audit.close();
+                    info!("Audit log closed: {} events written, {} dropped, {} failed", stats.written, stats.dropped, stats.failed);
+                }
+                Err(audit) => {
+                    warn!("Audit log still in use; flushing it");
+                    audit.flush();
                 }
             }
-        } // stmt is dropped here
-        
-        tx.commit()?;
-        
-        // Run ANALYZE to update query planner statistics
-        let _ = conn.execute("ANALYZE", []);
-        
-        Ok(inserted)
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.flush();
+        }
+        match self.store.close() {
+            Ok(()) => info!("users.db checkpointed"),
+            Err(e) => error!("Failed to checkpoint users.db: {}", e),
+        }
     }
 }
 
-fn hash_password(password: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    hex::encode(hasher.finalize())
-}
-
 async fn get_user_token(
     data: web::Data<AppState>,
-    request: web::Json<LoginRequest>,
+    request: Json<LoginRequest>,
 ) -> ActixResult<HttpResponse> {
-    match data.get_user_by_credentials(&request) {
-        Ok(Some(user)) => Ok(HttpResponse::Ok().json(LoginResponse {
-            success: true,
-            user_id: Some(user.id),
-            error_message: None,
-        })),
-        Ok(None) => Ok(HttpResponse::Ok().json(LoginResponse {
-            success: false,
-            user_id: None,
-            error_message: Some(Cow::Borrowed("Invalid username or password")),
-        })),
-        Err(e) => {
-            info!("Database error: {}", e);
-            Ok(HttpResponse::Ok().json(LoginResponse {
+    let login = data.store
+        .get_user_by_credentials(&request.user_name, &request.hashed_password)
+        .and_then(|user| match user {
+            // The password is right but the login waits for a TOTP code
+            Some(user) if user.mfa => Ok(Some(LoginResponse {
                 success: false,
+                status: LoginStatus::MfaRequired,
                 user_id: None,
-                error_message: Some(Cow::Borrowed("An error occurred during authentication")),
-            }))
+                error_message: None,
+                details: Some(LoginDetails::MfaChallenge { challenge_id: data.store.start_mfa_challenge(user.id, &request.user_name) }),
+            })),
+            Some(user) => {
+                let session = data.store.start_session(user.id)?;
+                Ok(Some(LoginResponse::ok(user.id, session.map(Into::into))))
+            }
+            None => Ok(None),
+        });
+
+    let (outcome, audited, mut response) = match login {
+        // `MfaRequired` too: the password was right, codes are limited by the store
+        Ok(Some(body)) => {
+            let audited = if body.status == LoginStatus::MfaRequired { AuthOutcome::MfaRequired } else { AuthOutcome::Success };
+            (Some(LoginOutcome::Authenticated), audited, HttpResponse::Ok().traced_json(body))
+        }
+        Ok(None) => (Some(LoginOutcome::Rejected), AuthOutcome::Failure, HttpResponse::Ok().traced_json(LoginResponse::failed("Invalid username or password"))),
+        Err(e) => (None, AuthOutcome::Error, store_failure(e)),
+    };
+
+    if let Some(metrics) = &data.metrics {
+        metrics.record_login(audited);
+    }
+    // The outcome is only read by `throttle_login`; skip the insert when it is off
+    if let (Some(outcome), Some(_)) = (outcome, &data.throttle) {
+        response.extensions_mut().insert(outcome);
+    }
+    if data.audit.is_some() {
+        response.extensions_mut().insert(AuthEvent::new(Some(request.into_inner().user_name), audited));
+    }
+    Ok(response)
+}
+
+/// Refuses password checks for locked-out accounts and client IPs, and
+/// reports the outcome of the others (set by the handlers) to the throttle.
+/// Every route it wraps has `UserName` in its body.
+async fn throttle_login(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let throttle = req.app_data::<web::Data<AppState>>().and_then(|data| data.throttle.clone());
+    let Some(throttle) = throttle else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+
+    // The account is in the body: buffer it, peek at UserName and hand it on
+    let bytes = req.extract::<web::Bytes>().await.map_err(rejection)?;
+    let mail = serde_json::from_slice::<LoginRequest>(&bytes).ok().map(|login| login.user_name);
+    req.set_payload(bytes.into());
+    let Some(mail) = mail else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    if let Err(retry_after) = throttle.check(&mail, ip) {
+        let mut response = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after_secs(retry_after)))
+            .traced_json(LoginResponse::failed("Too many failed login attempts"));
+        if let Some(metrics) = req.app_data::<web::Data<AppState>>().and_then(|data| data.metrics.as_ref()) {
+            metrics.record_login(AuthOutcome::Locked);
+        }
+        if req.app_data::<web::Data<AppState>>().is_some_and(|data| data.audit.is_some()) {
+            response.extensions_mut().insert(AuthEvent::new(Some(mail), AuthOutcome::Locked));
+        }
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let res = next.call(req).await?;
+    if let Some(outcome) = res.response().extensions().get::<LoginOutcome>() {
+        throttle.record(&mail, ip, *outcome);
+    }
+    Ok(res.map_into_left_body())
+}
+
+/// `LoginResponse` for a failed account operation, with the matching status.
+fn account_failure(e: AccountError) -> HttpResponse {
+    let (mut response, error_message) = match e {
+        AccountError::Invalid(reason) => (HttpResponse::BadRequest(), reason),
+        AccountError::MailTaken => (HttpResponse::Conflict(), "A user with this mail already exists"),
+        AccountError::InvalidCredentials => (HttpResponse::Unauthorized(), "Invalid username or password"),
+        AccountError::InvalidCode => (HttpResponse::Unauthorized(), "Invalid or missing code"),
+        AccountError::TooManyAttempts => {
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header((header::RETRY_AFTER, MFA_CHALLENGE_TTL.as_secs()));
+            (response, "Too many invalid codes")
+        }
+        AccountError::Store(e) => return store_failure(e),
+    };
+    response.traced_json(LoginResponse::failed(error_message))
+}
+
+fn account_success(user: User) -> LoginResponse {
+    LoginResponse::ok(user.id, None)
+}
+
+/// Creates an account; answers `201 Created` with the new `UserId`.
+async fn register(
+    data: web::Data<AppState>,
+    request: Json<RegisterRequest>,
+) -> ActixResult<HttpResponse> {
+    match data.store.register(&request.user_name, &request.password) {
+        Ok(user) => Ok(HttpResponse::Created().traced_json(account_success(user))),
+        Err(e) => Ok(account_failure(e)),
+    }
+}
+
+/// Leaves what a password check amounted to on `response` for `throttle_login`
+/// and `audit_login`, like `get_user_token` does.
+fn report_credentials(data: &AppState, response: &mut HttpResponse, mail: String, outcome: Option<LoginOutcome>) {
+    let audited = match outcome {
+        Some(outcome) => AuthOutcome::from(outcome),
+        None if response.status().is_server_error() => AuthOutcome::Error,
+        // Refused before the password was checked
+        None => return,
+    };
+    if let (Some(outcome), Some(_)) = (outcome, &data.throttle) {
+        response.extensions_mut().insert(outcome);
+    }
+    if data.audit.is_some() {
+        response.extensions_mut().insert(AuthEvent::new(Some(mail), audited));
+    }
+}
+
+async fn change_password(
+    data: web::Data<AppState>,
+    request: Json<ChangePasswordRequest>,
+) -> ActixResult<HttpResponse> {
+    let result = data.store.change_password(&request.user_name, &request.hashed_password, &request.new_password, request.code.as_deref());
+    let outcome = LoginOutcome::of_account(&result);
+    let mut response = match result {
+        Ok(user) => HttpResponse::Ok().traced_json(account_success(user)),
+        Err(e) => account_failure(e),
+    };
+    report_credentials(&data, &mut response, request.into_inner().user_name, outcome);
+    Ok(response)
+}
+
+/// Deletes the account named in the body, authenticated like a login (with a
+/// TOTP code when it has TOTP enabled).
+async fn delete_user(
+    data: web::Data<AppState>,
+    request: Json<DeleteUserRequest>,
+) -> ActixResult<HttpResponse> {
+    let result = data.store.delete_account(&request.user_name, &request.hashed_password, request.code.as_deref());
+    let outcome = LoginOutcome::of_account(&result);
+    let mut response = match result {
+        Ok(user) => HttpResponse::Ok().traced_json(account_success(user)),
+        Err(e) => account_failure(e),
+    };
+    report_credentials(&data, &mut response, request.into_inner().user_name, outcome);
+    Ok(response)
+}
+
+/// `LoginResponse` for a failed refresh or logout, with the matching status.
+fn session_failure(e: SessionError) -> HttpResponse {
+    let (mut response, error_message) = match e {
+        SessionError::Disabled => (HttpResponse::NotFound(), "Sessions are disabled"),
+        SessionError::InvalidToken => (HttpResponse::Unauthorized(), "Invalid or expired refresh token"),
+        SessionError::Store(e) => return store_failure(e),
+    };
+    response.traced_json(LoginResponse::failed(error_message))
+}
+
+/// Rotates a refresh token: the old one stops working, a new one is returned.
+async fn refresh(
+    data: web::Data<AppState>,
+    request: Json<RefreshRequest>,
+) -> ActixResult<HttpResponse> {
+    match data.store.refresh_session(&request.refresh_token) {
+        Ok(session) => Ok(HttpResponse::Ok().traced_json(LoginResponse::ok(session.user_id, Some(session.into())))),
+        Err(e) => Ok(session_failure(e)),
+    }
+}
+
+async fn logout(
+    data: web::Data<AppState>,
+    request: Json<RefreshRequest>,
+) -> ActixResult<HttpResponse> {
+    match data.store.end_session(&request.refresh_token) {
+        Ok(user_id) => Ok(HttpResponse::Ok().traced_json(LoginResponse::ok(user_id, None))),
+        Err(e) => Ok(session_failure(e)),
+    }
+}
+
+/// `LoginResponse` for a failed MFA operation, with the matching status.
+fn mfa_failure(e: MfaError) -> HttpResponse {
+    let (mut response, error_message) = match e {
+        MfaError::InvalidCredentials => (HttpResponse::Unauthorized(), "Invalid username or password"),
+        MfaError::NotEnrolled => (HttpResponse::BadRequest(), "No TOTP enrolment is pending"),
+        MfaError::AlreadyEnrolled => (HttpResponse::Conflict(), "TOTP is already enabled"),
+        MfaError::InvalidChallenge => (HttpResponse::Unauthorized(), "Invalid or expired MFA challenge"),
+        MfaError::InvalidCode => (HttpResponse::Unauthorized(), "Invalid code"),
+        MfaError::TooManyAttempts => {
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header((header::RETRY_AFTER, MFA_CHALLENGE_TTL.as_secs()));
+            (response, "Too many invalid codes")
+        }
+        MfaError::Store(e) => return store_failure(e),
+    };
+    response.traced_json(LoginResponse::failed(error_message))
+}
+
+/// Starts a TOTP enrolment; the secret is only active once confirmed.
+async fn mfa_enroll(
+    data: web::Data<AppState>,
+    request: Json<LoginRequest>,
+) -> ActixResult<HttpResponse> {
+    let result = data.store.enroll_totp(&request.user_name, &request.hashed_password);
+    let outcome = LoginOutcome::of_mfa(&result);
+    let mut response = match result {
+        Ok(enrollment) => HttpResponse::Ok().traced_json(LoginResponse::ok(enrollment.user_id, Some(enrollment.into()))),
+        Err(e) => mfa_failure(e),
+    };
+    report_credentials(&data, &mut response, request.into_inner().user_name, outcome);
+    Ok(response)
+}
+
+async fn mfa_confirm(
+    data: web::Data<AppState>,
+    request: Json<MfaConfirmRequest>,
+) -> ActixResult<HttpResponse> {
+    let result = data.store.confirm_totp(&request.user_name, &request.hashed_password, &request.code);
+    let outcome = LoginOutcome::of_mfa(&result);
+    let mut response = match result {
+        Ok(user) => HttpResponse::Ok().traced_json(LoginResponse::ok(user.id, None)),
+        Err(e) => mfa_failure(e),
+    };
+    report_credentials(&data, &mut response, request.into_inner().user_name, outcome);
+    Ok(response)
+}
+
+/// Second step of a login that answered `MfaRequired`; answers like a
+/// successful `get-user-token`.
+async fn mfa_verify(
+    data: web::Data<AppState>,
+    request: Json<MfaVerifyRequest>,
+) -> ActixResult<HttpResponse> {
+    // Read before verifying: a completed challenge is gone
+    let mail = data.audit.as_ref().and_then(|_| data.store.mfa_challenge_mail(&request.challenge_id));
+    let login = data.store
+        .verify_mfa(&request.challenge_id, &request.code)
+        .and_then(|user| Ok((user, data.store.start_session(user.id)?)));
+    let outcome = login.as_ref().map_or_else(AuthOutcome::from, |_| AuthOutcome::Success);
+    if let Some(metrics) = &data.metrics {
+        metrics.record_login(outcome);
+    }
+    let mut response = match login {
+        Ok((user, session)) => HttpResponse::Ok().traced_json(LoginResponse::ok(user.id, session.map(Into::into))),
+        Err(e) => mfa_failure(e),
+    };
+    if data.audit.is_some() {
+        response.extensions_mut().insert(AuthEvent::new(mail, outcome));
+    }
+    Ok(response)
+}
+
+/// Completes the `AuthEvent` left by the login handlers (or the throttle)
+/// with the client address and user agent, and queues it for the audit log.
+async fn audit_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(audit) = req.app_data::<web::Data<AppState>>().and_then(|data| data.audit.clone()) else {
+        return next.call(req).await;
+    };
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let user_agent = req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()).map(str::to_owned);
+
+    let mut res = next.call(req).await?;
+    if let Some(mut event) = res.response_mut().extensions_mut().remove::<AuthEvent>() {
+        event.client_ip = ip;
+        event.user_agent = user_agent;
+        audit.record(event);
+    }
+    Ok(res)
+}
+
+/// Counts and times every request by matched route and status, for `/metrics`.
+async fn track_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(metrics) = req.app_data::<web::Data<AppState>>().and_then(|data| data.metrics.clone()) else {
+        return next.call(req).await;
+    };
+    let start = Instant::now();
+    let res = next.call(req).await?;
+    let route = res.request().match_pattern();
+    metrics.observe_request(route.as_deref().unwrap_or(UNMATCHED_ROUTE), res.status().as_u16(), start.elapsed());
+    Ok(res)
+}
+
+/// Writes the sampled requests to the access log once their response is ready.
+async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let log = req.app_data::<web::Data<AppState>>().and_then(|data| data.access_log.clone());
+    let Some(log) = log.filter(|log| log.sampled()) else {
+        return next.call(req).await;
+    };
+    let start = Instant::now();
+    let res = next.call(req).await?;
+    let request = res.request();
+    let route = request.match_pattern();
+    let mut record = AccessRecord::new(request.method().as_str(), request.path(), route.as_deref().unwrap_or(UNMATCHED_ROUTE), res.status().as_u16(), start.elapsed());
+    record.client_ip = request.peer_addr().map(|addr| addr.ip());
+    record.user_agent = request.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()).map(str::to_owned);
+    log.record(record);
+    Ok(res)
+}
+
+/// Opens the `http.request` span the spans of the handler nest under; only
+/// wrapped when spans are exported.
+async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let span = debug_span!(
+        "http.request",
+        http.request.method = %req.method(),
+        http.route = field::Empty,
+        http.response.status_code = field::Empty,
+    );
+    let res = next.call(req).instrument(span.clone()).await?;
+    span.record("http.route", res.request().match_pattern().as_deref().unwrap_or(UNMATCHED_ROUTE));
+    span.record("http.response.status_code", res.status().as_u16());
+    Ok(res)
+}
+
+/// Prometheus text exposition; `404` when metrics are turned off.
+async fn metrics(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    match &data.metrics {
+        Some(metrics) => Ok(HttpResponse::Ok().content_type(METRICS_CONTENT_TYPE).body(metrics.render(&data.store, data.throttle.as_deref()))),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Parses the login payload like `get_user_token` but never touches the database.
+#[cfg(feature = "bench")]
+async fn bench_no_db(_request: Json<LoginRequest>) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok().traced_json(LoginResponse::ok(12345, None)))
+}
+
+/// Query string of `create-db`; every field falls back to the historical
+/// 10,000 `password{i}` users.
+#[derive(Debug, Deserialize)]
+struct CreateDbQuery {
+    count: Option<usize>,
+    password: Option<String>,
+    seed: Option<u64>,
+}
+
+async fn create_db(
+    data: web::Data<AppState>,
+    query: web::Query<CreateDbQuery>,
+) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let defaults = SeedParams::default();
+    let params = SeedParams {
+        count: query.count.unwrap_or(defaults.count),
+        password_pattern: query.password.unwrap_or(defaults.password_pattern),
+        seed: query.seed,
+    };
+
+    match data.jobs.start(&data.store, params) {
+        Ok(job) => Ok(HttpResponse::Accepted().traced_json(job)),
+        Err(e @ StartError::Invalid(_)) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        Err(e @ StartError::AlreadyRunning(_)) => Ok(HttpResponse::Conflict().body(e.to_string())),
+    }
+}
+
+async fn create_db_status(data: web::Data<AppState>, id: web::Path<u64>) -> ActixResult<HttpResponse> {
+    match data.jobs.status(id.into_inner()) {
+        Some(job) => Ok(HttpResponse::Ok().traced_json(job)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Query string of `import-users`; the defaults import CSV, in chunks of
+/// 10,000 rows, next to the existing users.
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    format: Option<BulkFormat>,
+    mode: Option<ImportMode>,
+    chunk: Option<usize>,
+}
+
+/// Streams the body into the store chunk by chunk; rows that fail are listed
+/// in the report rather than failing the request.
+async fn import_users(
+    data: web::Data<AppState>,
+    query: web::Query<ImportQuery>,
+    mut payload: web::Payload,
+) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let defaults = ImportParams::default();
+    let params = ImportParams {
+        format: query.format.unwrap_or(defaults.format),
+        mode: query.mode.unwrap_or(defaults.mode),
+        chunk_size: query.chunk.unwrap_or(defaults.chunk_size),
+    };
+
+    let mut import = match data.store.import_users(params) {
+        Ok(import) => import,
+        Err(e) => return Ok(import_failure(e)),
+    };
+    // Parsing and the chunk writes block on SQLite, so they run on the blocking pool
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return Ok(HttpResponse::BadRequest().traced_json(LoginResponse::failed(e.to_string()))),
+        };
+        import = match web::block(move || import.feed(&chunk).map(|()| import)).await? {
+            Ok(import) => import,
+            Err(e) => return Ok(import_failure(e)),
+        };
+    }
+    match web::block(move || import.finish()).await? {
+        Ok(report) => Ok(HttpResponse::Ok().traced_json(report)),
+        Err(e) => Ok(import_failure(e)),
+    }
+}
+
+fn import_failure(e: ImportError) -> HttpResponse {
+    match e {
+        ImportError::Invalid(reason) => HttpResponse::BadRequest().traced_json(LoginResponse::failed(reason)),
+        ImportError::Store(e) => store_failure(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<BulkFormat>,
+}
+
+/// Streams every user, a page at a time, in a format `import-users` reads back.
+async fn export_users(data: web::Data<AppState>, query: web::Query<ExportQuery>) -> ActixResult<HttpResponse> {
+    let format = query.format.unwrap_or_default();
+    // Every page is a query, read on the blocking pool
+    let pages = futures_util::stream::unfold(data.store.export_users(format), |mut export| async move {
+        match web::block(move || (export.next(), export)).await {
+            Ok((page, export)) => Some((page?, export)),
+            Err(e) => {
+                error!("Export failed: {}", e);
+                None
+            }
         }
+    })
+    .map(|page| page.map(web::Bytes::from).inspect_err(|e| error!("Export failed: {}", e)));
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(pages))
+}
+
+async fn cache_stats(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok().traced_json(data.store.cache_stats()))
+}
+
+async fn throttle_stats(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    let stats = data.throttle.as_ref().map(|throttle| throttle.stats()).unwrap_or_default();
+    Ok(HttpResponse::Ok().traced_json(stats))
+}
+
+/// Authentication attempts, newest first; `NextBefore` is the cursor of the next page.
+async fn audit_events(data: web::Data<AppState>, query: web::Query<AuditQuery>) -> ActixResult<HttpResponse> {
+    match data.store.audit_events(&query) {
+        Ok(page) => Ok(HttpResponse::Ok().traced_json(page)),
+        Err(e) => Ok(store_failure(e)),
+    }
+}
+
+async fn admission_stats(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    let stats = data.admission.as_ref().map(|admission| admission.stats()).unwrap_or_default();
+    Ok(HttpResponse::Ok().traced_json(stats))
+}
+
+async fn audit_stats(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    let stats = data.audit.as_ref().map(|audit| audit.stats()).unwrap_or_default();
+    Ok(HttpResponse::Ok().traced_json(stats))
+}
+
+/// Rejects `/admin/*` requests that do not carry the admin credential.
+async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let access = req
+        .app_data::<web::Data<AppState>>()
+        .map_or(AdminAccess::Disabled, |data| data.admin.check(authorization));
+
+    let response = match access {
+        AdminAccess::Granted => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+        AdminAccess::Unauthorized => HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body("Missing or invalid admin token"),
+        AdminAccess::Disabled => HttpResponse::Forbidden()
+            .body("Admin endpoints are disabled: set MAXREQ_ADMIN_TOKEN or MAXREQ_BENCHMARK_MODE"),
+    };
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Mints a key; the response is the only time its plaintext is shown.
+async fn create_api_key(
+    data: web::Data<AppState>,
+    request: Json<CreateApiKeyRequest>,
+) -> ActixResult<HttpResponse> {
+    let ttl = request.expires_in_secs.map(Duration::from_secs);
+    match data.store.create_api_key(&request.name, &request.scopes, ttl) {
+        Ok(key) => Ok(HttpResponse::Created().traced_json(key)),
+        Err(e @ ApiKeyError::Invalid(_)) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        Err(ApiKeyError::Store(e)) => Ok(store_failure(e)),
+    }
+}
+
+async fn list_api_keys(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    match data.store.list_api_keys() {
+        Ok(keys) => Ok(HttpResponse::Ok().traced_json(keys)),
+        Err(e) => Ok(store_failure(e)),
+    }
+}
+
+async fn revoke_api_key(data: web::Data<AppState>, id: web::Path<i64>) -> ActixResult<HttpResponse> {
+    match data.store.revoke_api_key(id.into_inner()) {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Ok(store_failure(e)),
+    }
+}
+
+/// Waits up to the queue budget for an admission slot; `503` when there is
+/// none. A `503` from the handler is reported to the adaptive limit.
+async fn admit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(admission) = req.app_data::<web::Data<AppState>>().and_then(|data| data.admission.clone()) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let Ok(Some(mut permit)) = actix_web::rt::time::timeout(admission.queue_timeout(), admission.acquire()).await else {
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, SHED_RETRY_AFTER_SECS))
+            .traced_json(LoginResponse::failed(BUSY_MESSAGE));
+        return Ok(req.into_response(response).map_into_right_body());
+    };
+    let res = next.call(req).await?;
+    if res.status() == StatusCode::SERVICE_UNAVAILABLE {
+        permit.overloaded();
     }
+    Ok(res.map_into_left_body())
 }
 
-async fn create_db(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
-    match data.create_test_users(10000) {
-        Ok(count) => {
-            info!("Created {} test users", count);
-            Ok(HttpResponse::Ok().body(format!("Successfully created {} users in the database", count)))
+/// Answers CORS preflights and adds the CORS headers to the responses of
+/// allowed origins, errors included; only wrapped when `MAXREQ_CORS_ORIGINS`
+/// is set.
+async fn cors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let policy = req.app_data::<web::Data<AppState>>().and_then(|data| data.cors.clone());
+    let origin = req.headers().get(header::ORIGIN).map(|v| v.to_str().unwrap_or_default().to_owned());
+    let (Some(policy), Some(origin)) = (policy, origin) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    let preflight_method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD).filter(|_| req.method() == Method::OPTIONS);
+    if let Some(method) = preflight_method {
+        let requested_headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS).and_then(|v| v.to_str().ok());
+        let response = match policy.preflight(&origin, method.to_str().unwrap_or_default(), requested_headers) {
+            Some(headers) => {
+                let mut response = HttpResponse::NoContent().finish();
+                add_headers(&mut response, headers);
+                response
+            }
+            None => HttpResponse::Forbidden().finish(),
+        };
+        return Ok(req.into_response(response));
+    }
+
+    match next.call(req).await {
+        Ok(mut res) => {
+            add_headers(res.response_mut(), policy.response_headers(&origin));
+            Ok(res.map_into_boxed_body())
         }
+        // Turned into its response by actix past this middleware: render it here
         Err(e) => {
-            error!("Failed to create test users: {}", e);
-            Ok(HttpResponse::InternalServerError().body("Failed to create test users"))
+            let mut response = e.error_response();
+            add_headers(&mut response, policy.response_headers(&origin));
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+/// Appends `headers`, so a `Vary` set by the handler is kept.
+fn add_headers<B>(response: &mut HttpResponse<B>, headers: Vec<(&'static str, &str)>) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            response.headers_mut().append(HeaderName::from_static(name), value);
         }
     }
 }
 
+/// `431` for a request line and headers over `MAXREQ_MAX_HEADER_BYTES`, like
+/// hyper; actix itself only refuses heads past its 128 KiB read buffer.
+async fn limit_head(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limit = req.app_data::<web::Data<AppState>>().map_or(usize::MAX, |data| data.limits.max_header_bytes);
+    // As sent: `METHOD target HTTP/1.1\r\n`, then `name: value\r\n` per header
+    let request_line = req.method().as_str().len() + req.uri().path_and_query().map_or(1, |target| target.as_str().len()) + 11;
+    let headers: usize = req.headers().iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum();
+    if request_line + headers > limit {
+        let response = HttpResponse::build(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).insert_header((header::CONNECTION, "close")).finish();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Deadlines of a connection that actix does not keep: it times the first
+/// head from its first byte only, so a connection that sends nothing stays
+/// open, and it times no head that follows a keep-alive. A watchdog task
+/// closes the socket once `deadline` is past with no request running.
+struct ConnWatch {
+    /// `MAXREQ_HEADER_TIMEOUT_MS` after the accept, then keep-alive plus
+    /// header timeout after the last response; `None` while requests run.
+    deadline: Cell<Option<Instant>>,
+    /// Requests whose response is not fully sent.
+    running: Cell<usize>,
+    after_response: Duration,
+    /// A duplicate of the connection's socket, closed with the connection.
+    socket: std::net::TcpStream,
+}
+
+/// `on_connect` hook: hands the connection's `ConnWatch` to `watch_requests`
+/// and starts its watchdog.
+#[cfg(unix)]
+fn watch_connection(conn: &dyn std::any::Any, data: &mut Extensions, limits: &LimitsConfig) {
+    use actix_web::rt::net::TcpStream;
+    use std::os::fd::AsFd;
+    let stream = match conn.downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<TcpStream>>() {
+        Some(tls) => tls.get_ref().0,
+        None => match conn.downcast_ref::<TcpStream>() {
+            Some(stream) => stream,
+            None => return,
+        },
+    };
+    let Ok(socket) = stream.as_fd().try_clone_to_owned() else {
+        return;
+    };
+    let watch = Rc::new(ConnWatch {
+        deadline: Cell::new(Some(Instant::now() + limits.header_timeout)),
+        running: Cell::new(0),
+        after_response: limits.keep_alive + limits.header_timeout,
+        socket: socket.into(),
+    });
+    let recheck = limits.header_timeout;
+    let weak = Rc::downgrade(&watch);
+    data.insert(watch);
+    actix_web::rt::spawn(async move {
+        loop {
+            let Some(deadline) = weak.upgrade().map(|watch| watch.deadline.get()) else {
+                return;
+            };
+            actix_web::rt::time::sleep_until(deadline.unwrap_or_else(|| Instant::now() + recheck).into()).await;
+            // Gone with the connection, or the deadline moved in the meantime
+            let Some(watch) = weak.upgrade() else {
+                return;
+            };
+            if watch.deadline.get().is_some_and(|deadline| deadline <= Instant::now()) {
+                let _ = watch.socket.shutdown(std::net::Shutdown::Both);
+                return;
+            }
+        }
+    });
+}
+
+/// Suspends the connection deadline while a request runs, up to the end of
+/// its response body.
+async fn watch_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(watch) = req.request().conn_data::<Rc<ConnWatch>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_boxed_body());
+    };
+    watch.running.set(watch.running.get() + 1);
+    watch.deadline.set(None);
+    let end = RequestEnd(watch);
+    let res = next.call(req).await?;
+    Ok(res.map_body(|_, body| BoxBody::new(WatchedBody { body: body.boxed(), _end: end })))
+}
+
+/// Restarts the connection deadline once the last running request ends.
+struct RequestEnd(Rc<ConnWatch>);
+
+impl Drop for RequestEnd {
+    fn drop(&mut self) {
+        let watch = &self.0;
+        watch.running.set(watch.running.get() - 1);
+        if watch.running.get() == 0 {
+            watch.deadline.set(Some(Instant::now() + watch.after_response));
+        }
+    }
+}
+
+/// A response body that ends its request when dropped, once fully sent.
+struct WatchedBody {
+    body: BoxBody,
+    _end: RequestEnd,
+}
+
+impl MessageBody for WatchedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+/// Rejects `/api/auth/*` requests without an API key carrying the route's scope.
+/// The key is handed on to the handler as a request extension.
+async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let (Some(scope), Some(data)) = (required_scope(req.path()), req.app_data::<web::Data<AppState>>().cloned()) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+
+    let response = match data.store.check_api_key(authorization, scope) {
+        Ok(ApiKeyAccess::Granted(key)) => {
+            req.extensions_mut().insert(key);
+            return next.call(req).await.map(ServiceResponse::map_into_left_body);
+        }
+        Ok(ApiKeyAccess::Missing | ApiKeyAccess::Invalid) => HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "ApiKey"))
+            .body("Missing or invalid API key"),
+        Ok(ApiKeyAccess::Forbidden) => HttpResponse::Forbidden().body(format!("API key lacks the {} scope", scope)),
+        Err(e) => store_failure(e),
+    };
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Resource of a route that checks a password: throttled when `throttled`,
+/// and audited outside the throttle when `audited`.
+fn checks_password(
+    path: &str,
+    throttled: bool,
+    audited: bool,
+) -> Resource<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    web::resource(path)
+        .wrap(Condition::new(throttled, from_fn(throttle_login)))
+        .wrap(Condition::new(audited, from_fn(audit_login)))
+}
+
+/// rustls configuration of `MAXREQ_TLS_*`. actix offers `h2` and `http/1.1`
+/// over ALPN itself, and serves both.
+fn rustls_config(tls: &TlsConfig) -> std::io::Result<actix_tls::accept::rustls_0_23::reexports::ServerConfig> {
+    info!("TLS enabled: {} ({} cached sessions, tickets: {})", tls.cert_path, tls.session_cache, tls.tickets);
+    tls_server_config(tls, &[]).map_err(std::io::Error::other)
+}
+
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM. Actix stops abruptly on
+/// SIGINT by default, so its own handling is disabled in favour of this.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = actix_web::rt::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    match future::select(std::pin::pin!(interrupt), std::pin::pin!(terminate)).await {
+        Either::Left(_) => info!("SIGINT received"),
+        Either::Right(_) => info!("SIGTERM received"),
+    }
+}
+
 async fn health() -> ActixResult<HttpResponse> {
     Ok(HttpResponse::Ok().body("UserTokenApi Rust server is running"))
 }
 
+async fn ready(data: web::Data<AppState>) -> ActixResult<HttpResponse> {
+    // Off the worker: with the pool exhausted, the check waits out its timeout
+    let (store, timeout) = (data.store.clone(), data.ready_timeout);
+    let readiness = web::block(move || store.readiness(timeout)).await?;
+    let mut response = if readiness.ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    Ok(response.traced_json(readiness))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing; dropping `telemetry` on exit flushes the exported spans
+    let config = Config::from_env();
+    let telemetry = user_token_core::init_tracing(&config, "user-token-api-actix")
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    // Bring users.db to the current schema (and mail policy) and exit, e.g. before a deploy
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let version = UserStore::open("users.db", 1)
+            .and_then(|store| store.with_mail_config(&config.mail))
+            .and_then(|store| store.schema_version())
+            .map_err(|e| {
+                error!("Migration failed: {}", e);
+                std::io::Error::other(e.to_string())
+            })?;
+        info!("users.db is at schema version {}", version);
+        return Ok(());
+    }
 
     // Initialize application state
     let app_state = AppState::new().map_err(|e| {
         error!("Failed to initialize app state: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+        std::io::Error::other(e.to_string())
     })?;
 
-    info!("🦀 Rust UserTokenApiActix server running on http://localhost:8080");
+    info!("🦀 Rust UserTokenApiActix server running on {}://localhost:8080", if config.tls.is_some() { "https" } else { "http" });
     info!("Available endpoints:");
     info!("  POST /api/auth/get-user-token - Authenticate user");
-    info!("  GET /api/auth/create-db - Create test database");
-    info!("  GET /health - Health check");
+    info!("  POST /api/auth/register - Create a user");
+    info!("  POST /api/auth/change-password - Change a user's password");
+    info!("  DELETE /api/auth/user - Delete a user");
+    info!("  POST /api/auth/refresh - Rotate a refresh token");
+    info!("  POST /api/auth/logout - Revoke a refresh token");
+    info!("  POST /api/auth/mfa/enroll - Start a TOTP enrolment");
+    info!("  POST /api/auth/mfa/confirm - Enable TOTP with a first code");
+    info!("  POST /api/auth/mfa/verify - Complete a login with a TOTP code");
+    info!("  POST /admin/create-db?count=&password=&seed= - Start a test database job");
+    info!("  GET /admin/create-db/{{id}} - Test database job progress");
+    info!("  POST /admin/import-users?format=&mode=&chunk= - Import users from CSV or NDJSON");
+    info!("  GET /admin/export-users?format= - Export users as CSV or NDJSON");
+    info!("  GET /admin/cache-stats - Credential cache hit/miss counters");
+    info!("  GET /admin/throttle-stats - Login lockout counters");
+    info!("  GET /admin/admission-stats - Admission limit, queue and shed counters");
+    info!("  POST/GET /admin/api-keys - Mint and list API keys");
+    info!("  DELETE /admin/api-keys/{{id}} - Revoke an API key");
+    info!("  GET /admin/audit?mail=&outcome=&ip=&since=&until=&before=&limit= - Login audit log");
+    info!("  GET /admin/audit-stats - Audit writer counters");
+    #[cfg(feature = "bench")]
+    info!("  POST /bench/no-db - Authenticate without touching the database");
+    info!("  GET /health/live - Liveness: the process is up (also GET /health)");
+    info!("  GET /health/ready - Readiness: database check, pool and schema version");
+    info!("  GET /metrics - Prometheus metrics");
 
-    // Start HTTP server
-    HttpServer::new(move || {
-        let cors = Cors::permissive();
+    let throttled = app_state.throttle.is_some();
+    let api_keys = app_state.require_api_key;
+    let admitted = app_state.admission.is_some();
+    let audited = app_state.audit.is_some();
+    let metered = app_state.metrics.is_some();
+    let logged = app_state.access_log.is_some();
+    let cors_enabled = app_state.cors.is_some();
+    let traced = telemetry.exporting();
+    let closing = app_state.clone();
+    let shutdown_timeout = config.shutdown_timeout;
+    let limits = config.limits.clone();
 
-        App::new()
+    // Start HTTP server
+    let server = HttpServer::new(move || {
+        let app = App::new()
             .app_data(web::Data::new(app_state.clone()))
-            .wrap(cors)
-            // .wrap(Logger::default()) //to avoid to lose time in outputting logs
+            // `web::Payload`, which the import streams, is not limited
+            .app_data(web::JsonConfig::default().limit(limits.max_body_bytes))
+            .app_data(web::PayloadConfig::new(limits.max_body_bytes))
+            // Inside CORS, so the status of throttled and rejected requests is counted
+            .wrap(Condition::new(metered, from_fn(track_metrics)))
+            // Outside metrics, so the span covers everything the request is timed for
+            .wrap(Condition::new(traced, from_fn(trace_request)))
+            // Sampled and written off the request path, unlike `Logger`, which costs throughput
+            .wrap(Condition::new(logged, from_fn(access_log)))
+            // Outside everything but the connection limits, so preflights are answered
+            // before anything else runs and every other response carries the headers
+            .wrap(Condition::new(cors_enabled, from_fn(cors)))
+            // Suspends the connection deadline for everything inside it; a refused
+            // head needs no suspending, its connection is closed
+            .wrap(from_fn(watch_requests))
+            // Outermost, so an oversized head costs nothing else
+            .wrap(from_fn(limit_head))
             .route("/health", web::get().to(health))
-            .route("/api/auth/get-user-token", web::post().to(get_user_token))
-            .route("/api/auth/create-db", web::get().to(create_db))
+            .route("/health/live", web::get().to(health))
+            .route("/health/ready", web::get().to(ready))
+            .route("/metrics", web::get().to(metrics))
+            .service(
+                // Public API, behind API keys only when they are required
+                web::scope("/api/auth")
+                    .wrap(Condition::new(api_keys, from_fn(require_api_key)))
+                    // Outermost of the API routes, so a shed request costs no database access
+                    .wrap(Condition::new(admitted, from_fn(admit)))
+                    // Every route that checks a password, wrapped by the throttle only when
+                    // it is configured, so a lockout on one holds on all of them, and
+                    // audited outside the throttle so lockouts are too
+                    .service(checks_password("/get-user-token", throttled, audited).route(web::post().to(get_user_token)))
+                    .service(checks_password("/change-password", throttled, audited).route(web::post().to(change_password)))
+                    .service(checks_password("/user", throttled, audited).route(web::delete().to(delete_user)))
+                    .service(checks_password("/mfa/enroll", throttled, audited).route(web::post().to(mfa_enroll)))
+                    .service(checks_password("/mfa/confirm", throttled, audited).route(web::post().to(mfa_confirm)))
+                    .service(
+                        web::resource("/mfa/verify")
+                            .wrap(Condition::new(audited, from_fn(audit_login)))
+                            .route(web::post().to(mfa_verify)),
+                    )
+                    .route("/register", web::post().to(register))
+                    .route("/refresh", web::post().to(refresh))
+                    .route("/logout", web::post().to(logout)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(require_admin))
+                    .route("/create-db", web::post().to(create_db))
+                    .route("/create-db/{id}", web::get().to(create_db_status))
+                    .route("/import-users", web::post().to(import_users))
+                    .route("/export-users", web::get().to(export_users))
+                    .route("/cache-stats", web::get().to(cache_stats))
+                    .route("/throttle-stats", web::get().to(throttle_stats))
+                    .route("/admission-stats", web::get().to(admission_stats))
+                    .route("/api-keys", web::post().to(create_api_key))
+                    .route("/api-keys", web::get().to(list_api_keys))
+                    .route("/api-keys/{id}", web::delete().to(revoke_api_key))
+                    .route("/audit", web::get().to(audit_events))
+                    .route("/audit-stats", web::get().to(audit_stats)),
+            );
+
+        // DB-bypass login, only compiled into benchmark builds
+        #[cfg(feature = "bench")]
+        let app = app.route("/bench/no-db", web::post().to(bench_no_db));
+
+        app
     })
-    .bind("0.0.0.0:8080")?
+    // From the first byte of the first head; `watch_connection` covers the rest
+    .client_request_timeout(config.limits.header_timeout)
+    .tls_handshake_timeout(config.limits.header_timeout)
+    .keep_alive(config.limits.keep_alive);
+    #[cfg(unix)]
+    let server = {
+        let limits = config.limits.clone();
+        server.on_connect(move |conn, data| watch_connection(conn, data, &limits))
+    };
+    let server = match &config.tls {
+        Some(tls) => server.listen_rustls_0_23(std::net::TcpListener::bind("0.0.0.0:8080")?, rustls_config(tls)?)?,
+        None => server.bind("0.0.0.0:8080")?,
+    };
+    server
+    .disable_signals()
+    .shutdown_signal(async move {
+        shutdown_signal().await;
+        info!("Shutting down: draining in-flight requests for up to {:?}", shutdown_timeout);
+    })
+    .shutdown_timeout(shutdown_timeout.as_secs())
     .run()
-    .await
+    .await?;
+
+    closing.close();
+    info!("Server stopped");
+    Ok(())
 }
\ No newline at end of file
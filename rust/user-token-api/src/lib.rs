@@ -1,22 +1,68 @@
 use axum::{
-    extract::Json,
-    http::StatusCode,
-    response::Json as ResponseJson,
-    routing::{get, post},
+    body::Body,
+    extract::{
+        rejection::JsonRejection, ConnectInfo, DefaultBodyLimit, FromRequest, MatchedPath, Path,
+        Query, Request,
+    },
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Router,
 };
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Result as SqliteResult;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use futures_util::StreamExt;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::borrow::Cow;
-use std::sync::Arc;
-use std::time::Duration;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use tracing::{debug, debug_span, error, field, info, warn, Instrument};
+use user_token_core::{
+    required_scope, retry_after_secs, tls_server_config, AccessLog, AccessRecord, AccountError,
+    AdminAccess, AdminGuard, Admission, AdmissionStats, ApiKey, ApiKeyAccess, ApiKeyError,
+    AuditLog, AuditPage, AuditQuery, AuditStats, AuthEvent, AuthOutcome, BulkFormat, CacheStats,
+    Config, CorsPolicy, ImportError, ImportMode, ImportParams, ImportReport, JobStatus,
+    LimitsConfig, LoginOutcome, LoginStatus, LoginThrottle, Metrics, MfaError, SeedJobs,
+    SeedParams, Session, SessionError, StartError, StoreError, Telemetry, ThrottleStats,
+    TotpEnrollment, User, UserStore, ALPN_HTTP1, BODY_TOO_LARGE_MESSAGE, BUSY_MESSAGE,
+    METRICS_CONTENT_TYPE, MFA_CHALLENGE_TTL, SHED_RETRY_AFTER_SECS, UNMATCHED_ROUTE,
+};
+
+/// `axum::Json`, with the body read and parsed in a `json.parse` span and
+/// the response serialized in a `json.serialize` span.
+struct Json<T>(T);
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
 
-type DbPool = Pool<SqliteConnectionManager>;
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(request, state)
+            .instrument(debug_span!("json.parse"))
+            .await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        let _span = debug_span!("json.serialize").entered();
+        axum::Json(self.0).into_response()
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct LoginRequest {
@@ -30,188 +76,1227 @@ struct LoginRequest {
 struct LoginResponse<'a> {
     #[serde(rename = "Success")]
     success: bool,
+    #[serde(rename = "Status")]
+    status: LoginStatus,
     #[serde(rename = "UserId")]
     user_id: Option<i64>,
     #[serde(rename = "ErrorMessage")]
     #[serde(skip_serializing_if = "Option::is_none")]
     error_message: Option<Cow<'a, str>>,
+    #[serde(flatten)]
+    details: Option<LoginDetails>,
+}
+
+impl<'a> LoginResponse<'a> {
+    fn ok(user_id: i64, details: Option<LoginDetails>) -> Self {
+        LoginResponse {
+            success: true,
+            status: LoginStatus::Ok,
+            user_id: Some(user_id),
+            error_message: None,
+            details,
+        }
+    }
+
+    fn failed(error_message: &'a str) -> Self {
+        LoginResponse {
+            success: false,
+            status: LoginStatus::Failed,
+            user_id: None,
+            error_message: Some(Cow::Borrowed(error_message)),
+            details: None,
+        }
+    }
+}
+
+/// A failed request, answered with its status, an optional `Retry-After` and
+/// a failed `LoginResponse`, so every route reports failures the same way.
+struct ApiError {
+    status: StatusCode,
+    retry_after: Option<u64>,
+    message: Cow<'static, str>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError {
+            status,
+            retry_after: None,
+            message: message.into(),
+        }
+    }
+
+    fn retry_after(self, secs: u64) -> Self {
+        ApiError {
+            retry_after: Some(secs),
+            ..self
+        }
+    }
+}
+
+/// `503` with `Retry-After` when the store is overloaded, `500` otherwise.
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        if e.is_overloaded() {
+            warn!("Database overloaded: {}", e);
+        } else {
+            error!("Database error: {}", e);
+        }
+        ApiError {
+            status: StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            retry_after: e.retry_after(),
+            message: Cow::Borrowed(e.client_message()),
+        }
+    }
+}
+
+/// A blocking task that panicked; the panic itself has been reported.
+impl From<tokio::task::JoinError> for ApiError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        error!("Blocking task failed: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => body_too_large(),
+            status => ApiError::new(status, rejection.body_text()),
+        }
+    }
+}
+
+/// `413` for a body over `MAXREQ_MAX_BODY_BYTES`.
+fn body_too_large() -> ApiError {
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, BODY_TOO_LARGE_MESSAGE)
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(LoginResponse::failed(&self.message)).into_response();
+        match self.retry_after {
+            Some(secs) => {
+                (self.status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+            }
+            None => (self.status, body).into_response(),
+        }
+    }
 }
 
-#[derive(Debug)]
-struct User {
-    id: i64,
+/// Fields added next to `Success` by some responses.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum LoginDetails {
+    /// Refresh token returned with a successful login when sessions are enabled.
+    Session {
+        #[serde(rename = "RefreshToken")]
+        refresh_token: String,
+        #[serde(rename = "ExpiresIn")]
+        expires_in: u64,
+    },
+    /// Returned with `MfaRequired`, to be sent back to `/api/auth/mfa/verify`.
+    MfaChallenge {
+        #[serde(rename = "ChallengeId")]
+        challenge_id: String,
+    },
+    TotpEnrollment {
+        #[serde(rename = "Secret")]
+        secret: String,
+        #[serde(rename = "OtpauthUri")]
+        otpauth_uri: String,
+    },
+}
+
+impl From<Session> for LoginDetails {
+    fn from(session: Session) -> Self {
+        LoginDetails::Session {
+            refresh_token: session.refresh_token,
+            expires_in: session.expires_in.as_secs(),
+        }
+    }
+}
+
+impl From<TotpEnrollment> for LoginDetails {
+    fn from(enrollment: TotpEnrollment) -> Self {
+        LoginDetails::TotpEnrollment {
+            secret: enrollment.secret,
+            otpauth_uri: enrollment.otpauth_uri,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    #[serde(rename = "RefreshToken")]
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MfaConfirmRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MfaVerifyRequest {
+    #[serde(rename = "ChallengeId")]
+    challenge_id: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "Password")]
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangePasswordRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    /// Current password, hashed like in `LoginRequest`.
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    #[serde(rename = "NewPassword")]
+    new_password: String,
+    /// Current TOTP code, for accounts that have TOTP enabled.
+    #[serde(rename = "Code")]
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteUserRequest {
+    #[serde(rename = "UserName")]
+    user_name: String,
+    #[serde(rename = "HashedPassword")]
+    hashed_password: String,
+    /// Current TOTP code, for accounts that have TOTP enabled.
+    #[serde(rename = "Code")]
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateApiKeyRequest {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Scopes")]
+    scopes: Vec<String>,
+    /// Lifetime of the key, `None` for a key that does not expire.
+    #[serde(rename = "ExpiresInSecs")]
+    expires_in_secs: Option<u64>,
 }
 
 struct AppState {
-    db: DbPool,
+    store: UserStore,
+    jobs: SeedJobs,
+    admin: AdminGuard,
+    throttle: Option<LoginThrottle>,
+    require_api_key: bool,
+    audit: Option<AuditLog>,
+    metrics: Option<Arc<Metrics>>,
+    access_log: Option<AccessLog>,
+    admission: Option<Admission>,
+    shutdown_timeout: Duration,
+    ready_timeout: Duration,
+    limits: LimitsConfig,
+    cors: Option<CorsPolicy>,
+    tls: Option<TlsAcceptor>,
 }
 
 impl AppState {
     fn new() -> Result<Self, Box<dyn std::error::Error>> {
         let cpus = num_cpus::get() as u32;
-        let manager = SqliteConnectionManager::file("users.db");
         // Increase pool size for better concurrency under load
         // Original: cpus, New: cpus * 2 (but cap at reasonable limit)
-        let pool_size = std::cmp::min(cpus * 2, 16); // Max 16 connections
-        let pool = Pool::builder().max_size(pool_size).build(manager)?;
-        let conn = pool.get()?;
-
-        // Configure SQLite for better performance and concurrency
-        conn.pragma_update(None, "journal_mode", "WAL")?; // Enable WAL mode for better concurrency
-        conn.pragma_update(None, "synchronous", "NORMAL")?; // Balance durability vs performance
-        conn.pragma_update(None, "cache_size", "-64000")?; // 64MB cache (negative = KB)
-        conn.pragma_update(None, "temp_store", "MEMORY")?; // Store temp tables in memory
-        conn.pragma_update(None, "mmap_size", "268435456")?; // 256MB memory map
-
-        // Set busy timeout for handling concurrent access
-        conn.busy_timeout(Duration::from_millis(30000))?; // 30 second timeout
-
-        // Create table if it doesn't exist
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                mail TEXT NOT NULL UNIQUE,
-                hashed_password TEXT NOT NULL
-            )",
-            [],
-        )?;
-
-        // Create index for faster lookups (if not exists)
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_user_mail_password ON user(mail, hashed_password)",
-            [],
-        )?;
-
-        // Run ANALYZE to update query planner statistics
-        let _ = conn.execute("ANALYZE", []);
-
-        // Run PRAGMA optimize for automatic database optimization
-        let _ = conn.pragma_update(None, "optimize", "");
-
-        Ok(AppState { db: pool })
-    }
-
-    fn get_user_by_credentials(&self, request: &LoginRequest) -> SqliteResult<Option<User>> {
-        // Handle special test case
-        if request.user_name == "no_db" {
-            return Ok(Some(User { id: 12345 }));
-        }
-
-        let conn = self.db.get().unwrap();
-
-        // Use prepare_cached for automatic statement caching
-        // Optimized query: only select the id column we need
-        let mut stmt = conn.prepare_cached(
-            "SELECT id FROM user WHERE mail = ?1 AND hashed_password = ?2 LIMIT 1",
-        )?;
-
-        // get scalar response
-        let user = stmt.query_row([&request.user_name, &request.hashed_password], |row| {
-            Ok(User { id: row.get(0)? })
+        let pool_size = std::cmp::min(cpus * 2, 16); // Max 16 read connections
+        let config = Config::from_env();
+        let mut store = UserStore::open("users.db", pool_size)?
+            .with_mail_config(&config.mail)?
+            .with_pool_timeout(config.pool_timeout);
+        if config.mail.lowercase || config.mail.punycode {
+            info!(
+                "Mail normalisation: lowercase whole address: {}, punycode domains: {}",
+                config.mail.lowercase, config.mail.punycode
+            );
+        }
+        if let Some(cache) = &config.cache {
+            info!(
+                "Credential cache enabled: {} entries, {:?} TTL",
+                cache.capacity, cache.ttl
+            );
+            store = store.with_cache(cache);
+        }
+        if let Some(sessions) = &config.sessions {
+            info!(
+                "Sessions enabled: {:?} refresh tokens, expired ones purged every {:?}",
+                sessions.ttl, sessions.gc_interval
+            );
+            store = store.with_sessions(sessions);
+            store.spawn_session_gc(sessions.gc_interval)?;
+        }
+        let metrics = config.metrics.then(|| Arc::new(Metrics::new()));
+        if let Some(metrics) = &metrics {
+            store = store.with_metrics(metrics);
+        }
+        let throttle = match &config.throttle {
+            Some(throttle) => {
+                info!(
+                    "Login throttling enabled: {} failures per account, {} per IP, in {:?} (persisted: {})",
+                    throttle.account_failures, throttle.ip_failures, throttle.window, throttle.persist
+                );
+                Some(LoginThrottle::open(throttle, &store)?)
+            }
+            None => None,
+        };
+        let audit = match &config.audit {
+            Some(audit) => {
+                info!(
+                    "Audit log enabled: batches of {} events, flushed every {:?}",
+                    audit.batch_size, audit.flush_interval
+                );
+                Some(AuditLog::start(audit, &store)?)
+            }
+            None => None,
+        };
+        let access_log = match &config.access_log {
+            Some(access_log) => {
+                info!(
+                    "Access log enabled: {} ({}% of requests)",
+                    access_log.path,
+                    access_log.sample_ratio * 100.0
+                );
+                Some(AccessLog::start(access_log)?)
+            }
+            None => None,
+        };
+        let admission = config.admission.as_ref().map(|admission| {
+            info!(
+                "Admission control enabled: {} requests in flight (adaptive: {}), {} queued for up to {:?}",
+                admission.max_in_flight, admission.adaptive, admission.max_queued, admission.queue_timeout
+            );
+            Admission::new(admission)
         });
+        if config.benchmark_mode {
+            info!("Benchmark mode: /admin endpoints are open without a token");
+        }
+        if config.require_api_key {
+            info!("API keys required on /api/auth endpoints");
+        }
+        info!(
+            "Request limits: {} byte bodies, {} byte heads sent within {:?}, {:?} keep-alive",
+            config.limits.max_body_bytes,
+            config.limits.max_header_bytes,
+            config.limits.header_timeout,
+            config.limits.keep_alive
+        );
+        let cors = config.cors.as_ref().map(|cors| {
+            info!(
+                "CORS enabled: origins {:?}, methods {:?}, headers {:?}, preflight cached {:?}",
+                cors.allowed_origins, cors.allowed_methods, cors.allowed_headers, cors.max_age
+            );
+            CorsPolicy::new(cors)
+        });
+
+        let tls = match &config.tls {
+            Some(tls) => {
+                info!(
+                    "TLS enabled: {} ({} cached sessions, tickets: {})",
+                    tls.cert_path, tls.session_cache, tls.tickets
+                );
+                let server = tls_server_config(tls, &[ALPN_HTTP1])?;
+                Some(TlsAcceptor::from(Arc::new(server)))
+            }
+            None => None,
+        };
+
+        Ok(AppState {
+            store,
+            jobs: SeedJobs::new(),
+            admin: AdminGuard::new(&config),
+            throttle,
+            require_api_key: config.require_api_key,
+            audit,
+            metrics,
+            access_log,
+            admission,
+            shutdown_timeout: config.shutdown_timeout,
+            ready_timeout: config.ready_timeout,
+            limits: config.limits,
+            cors,
+            tls,
+        })
+    }
+
+    /// Flushes the background writers, then checkpoints the database; the
+    /// audit and lockout writers go first since they write to it.
+    fn close(self) {
+        if let Some(access_log) = self.access_log {
+            let stats = access_log.close();
+            info!(
+                "Access log closed: {} lines written, {} dropped, {} failed",
+                stats.written, stats.dropped, stats.failed
+            );
+        }
+        if let Some(audit) = self.audit {
+            let stats = audit.close();
+            info!(
+                "Audit log closed: {} events written, {} dropped, {} failed",
+                stats.written, stats.dropped, stats.failed
+            );
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.flush();
+        }
+        match self.store.close() {
+            Ok(()) => info!("users.db checkpointed"),
+            Err(e) => error!("Failed to checkpoint users.db: {}", e),
+        }
+    }
 
-        match user {
-            Ok(u) => Ok(Some(u)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e),
+    /// What `close` does for a state still shared with a running task: the
+    /// writers are flushed rather than stopped, so only what that task logs
+    /// afterwards misses the checkpoint.
+    fn flush(&self) {
+        if let Some(access_log) = &self.access_log {
+            access_log.flush();
+            let stats = access_log.stats();
+            info!(
+                "Access log flushed: {} lines written, {} dropped, {} failed",
+                stats.written, stats.dropped, stats.failed
+            );
+        }
+        if let Some(audit) = &self.audit {
+            audit.flush();
+            let stats = audit.stats();
+            info!(
+                "Audit log flushed: {} events written, {} dropped, {} failed",
+                stats.written, stats.dropped, stats.failed
+            );
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.flush();
+        }
+        match self.store.clone().close() {
+            Ok(()) => info!("users.db checkpointed"),
+            Err(e) => error!("Failed to checkpoint users.db: {}", e),
         }
     }
+}
+
+async fn get_user_token(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<LoginRequest>,
+) -> Response {
+    let login = state
+        .store
+        .get_user_by_credentials(&request.user_name, &request.hashed_password)
+        .and_then(|user| match user {
+            // The password is right but the login waits for a TOTP code
+            Some(user) if user.mfa => Ok(Some(LoginResponse {
+                success: false,
+                status: LoginStatus::MfaRequired,
+                user_id: None,
+                error_message: None,
+                details: Some(LoginDetails::MfaChallenge {
+                    challenge_id: state.store.start_mfa_challenge(user.id, &request.user_name),
+                }),
+            })),
+            Some(user) => {
+                let session = state.store.start_session(user.id)?;
+                Ok(Some(LoginResponse::ok(user.id, session.map(Into::into))))
+            }
+            None => Ok(None),
+        });
 
-    fn create_test_users(&self, count: usize) -> Result<usize, Box<dyn std::error::Error>> {
-        let conn = self.db.get().unwrap();
+    // Success case: no heap allocation needed unless a session or challenge was opened
+    // Error case: use static string literal (stack-allocated)
+    let (outcome, response) = match login {
+        // `MfaRequired` too: the password was right, codes are limited by the store
+        Ok(Some(body)) => (Some(LoginOutcome::Authenticated), Ok(body)),
+        Ok(None) => (
+            Some(LoginOutcome::Rejected),
+            Ok(LoginResponse::failed("Invalid username or password")),
+        ),
+        Err(e) => (None, Err(ApiError::from(e))),
+    };
 
-        // Clear existing users
-        conn.execute("DELETE FROM user", [])?;
+    let auth_outcome = match (outcome, &response) {
+        (None, _) | (_, Err(_)) => AuthOutcome::Error,
+        (_, Ok(body)) => match body.status {
+            LoginStatus::Ok => AuthOutcome::Success,
+            LoginStatus::MfaRequired => AuthOutcome::MfaRequired,
+            LoginStatus::Failed => AuthOutcome::Failure,
+        },
+    };
+    if let Some(metrics) = &state.metrics {
+        metrics.record_login(auth_outcome);
+    }
 
-        // Use WAL checkpoint for better performance before bulk insert
-        let _ = conn.execute("PRAGMA wal_checkpoint(TRUNCATE)", []);
+    let mut response = match response {
+        Ok(body) => Json(body).into_response(),
+        Err(e) => e.into_response(),
+    };
+    // The outcome is only read by `throttle_login`; skip the insert when it is off
+    if let (Some(outcome), Some(_)) = (outcome, &state.throttle) {
+        response.extensions_mut().insert(outcome);
+    }
+    if state.audit.is_some() {
+        let event = AuthEvent::new(Some(request.user_name), auth_outcome);
+        response.extensions_mut().insert(event);
+    }
+    response
+}
 
-        let tx = conn.unchecked_transaction()?;
-        let mut inserted = 0;
+/// Refuses password checks for locked-out accounts and client IPs, and
+/// reports the outcome of the others (set by the handlers) to the throttle.
+/// Every route it wraps has `UserName` in its body.
+async fn throttle_login(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(throttle) = &state.throttle else {
+        return next.run(request).await;
+    };
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
 
-        {
-            // Use prepare_cached for the INSERT statement as well
-            let mut stmt =
-                tx.prepare_cached("INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)")?;
+    // The account is in the body: buffer it, peek at UserName and hand it on
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, state.limits.max_body_bytes).await else {
+        return body_too_large().into_response();
+    };
+    let mail = serde_json::from_slice::<LoginRequest>(&bytes)
+        .ok()
+        .map(|login| login.user_name);
+    let request = Request::from_parts(parts, Body::from(bytes));
+    let Some(mail) = mail else {
+        return next.run(request).await;
+    };
+
+    if let Err(retry_after) = throttle.check(&mail, ip) {
+        let mut response = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many failed login attempts",
+        )
+        .retry_after(retry_after_secs(retry_after))
+        .into_response();
+        if let Some(metrics) = &state.metrics {
+            metrics.record_login(AuthOutcome::Locked);
+        }
+        if state.audit.is_some() {
+            let event = AuthEvent::new(Some(mail), AuthOutcome::Locked);
+            response.extensions_mut().insert(event);
+        }
+        return response;
+    }
 
-            for i in 1..=count {
-                let email = format!("user{}@example.com", i);
-                let password = format!("password{}", i);
-                let hashed_password = hash_password(&password);
+    let response = next.run(request).await;
+    if let Some(outcome) = response.extensions().get::<LoginOutcome>() {
+        throttle.record(&mail, ip, *outcome);
+    }
+    response
+}
 
-                match stmt.execute([&email, &hashed_password]) {
-                    Ok(_) => inserted += 1,
-                    Err(e) => error!("Failed to insert user {}: {}", i, e),
-                }
-            }
-        } // stmt is dropped here
+fn account_failure(e: AccountError) -> ApiError {
+    match e {
+        AccountError::Invalid(reason) => ApiError::new(StatusCode::BAD_REQUEST, reason),
+        AccountError::MailTaken => {
+            ApiError::new(StatusCode::CONFLICT, "A user with this mail already exists")
+        }
+        AccountError::InvalidCredentials => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid username or password")
+        }
+        AccountError::InvalidCode => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or missing code")
+        }
+        AccountError::TooManyAttempts => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Too many invalid codes")
+                .retry_after(MFA_CHALLENGE_TTL.as_secs())
+        }
+        AccountError::Store(e) => e.into(),
+    }
+}
+
+fn account_success(status: StatusCode, user: User) -> (StatusCode, Json<LoginResponse<'static>>) {
+    (status, Json(LoginResponse::ok(user.id, None)))
+}
+
+/// Creates an account; answers `201 Created` with the new `UserId`.
+async fn register(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<LoginResponse<'static>>), ApiError> {
+    match state.store.register(&request.user_name, &request.password) {
+        Ok(user) => Ok(account_success(StatusCode::CREATED, user)),
+        Err(e) => Err(account_failure(e)),
+    }
+}
+
+/// Leaves what a password check amounted to on `response` for `throttle_login`
+/// and `audit_login`, like `get_user_token` does.
+fn report_credentials(
+    state: &AppState,
+    response: &mut Response,
+    mail: String,
+    outcome: Option<LoginOutcome>,
+) {
+    let audited = match outcome {
+        Some(outcome) => AuthOutcome::from(outcome),
+        None if response.status().is_server_error() => AuthOutcome::Error,
+        // Refused before the password was checked
+        None => return,
+    };
+    if let (Some(outcome), Some(_)) = (outcome, &state.throttle) {
+        response.extensions_mut().insert(outcome);
+    }
+    if state.audit.is_some() {
+        response
+            .extensions_mut()
+            .insert(AuthEvent::new(Some(mail), audited));
+    }
+}
 
-        tx.commit()?;
+async fn change_password(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Response {
+    let result = state.store.change_password(
+        &request.user_name,
+        &request.hashed_password,
+        &request.new_password,
+        request.code.as_deref(),
+    );
+    let outcome = LoginOutcome::of_account(&result);
+    let mut response = match result {
+        Ok(user) => account_success(StatusCode::OK, user).into_response(),
+        Err(e) => account_failure(e).into_response(),
+    };
+    report_credentials(&state, &mut response, request.user_name, outcome);
+    response
+}
 
-        // Run ANALYZE to update query planner statistics
-        let _ = conn.execute("ANALYZE", []);
+/// Deletes the account named in the body, authenticated like a login (with a
+/// TOTP code when it has TOTP enabled).
+async fn delete_user(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<DeleteUserRequest>,
+) -> Response {
+    let result = state.store.delete_account(
+        &request.user_name,
+        &request.hashed_password,
+        request.code.as_deref(),
+    );
+    let outcome = LoginOutcome::of_account(&result);
+    let mut response = match result {
+        Ok(user) => account_success(StatusCode::OK, user).into_response(),
+        Err(e) => account_failure(e).into_response(),
+    };
+    report_credentials(&state, &mut response, request.user_name, outcome);
+    response
+}
 
-        // Run PRAGMA optimize after bulk insert
-        let _ = conn.pragma_update(None, "optimize", "");
+fn session_failure(e: SessionError) -> ApiError {
+    match e {
+        SessionError::Disabled => ApiError::new(StatusCode::NOT_FOUND, "Sessions are disabled"),
+        SessionError::InvalidToken => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired refresh token")
+        }
+        SessionError::Store(e) => e.into(),
+    }
+}
 
-        Ok(inserted)
+/// Rotates a refresh token: the old one stops working, a new one is returned.
+async fn refresh(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<LoginResponse<'static>>, ApiError> {
+    match state.store.refresh_session(&request.refresh_token) {
+        Ok(session) => Ok(Json(LoginResponse::ok(
+            session.user_id,
+            Some(session.into()),
+        ))),
+        Err(e) => Err(session_failure(e)),
     }
 }
 
-fn hash_password(password: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    hex::encode(hasher.finalize())
+async fn logout(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<LoginResponse<'static>>, ApiError> {
+    match state.store.end_session(&request.refresh_token) {
+        Ok(user_id) => Ok(Json(LoginResponse::ok(user_id, None))),
+        Err(e) => Err(session_failure(e)),
+    }
 }
 
-async fn get_user_token(
+fn mfa_failure(e: MfaError) -> Response {
+    let error = match e {
+        MfaError::InvalidCredentials => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid username or password")
+        }
+        MfaError::NotEnrolled => {
+            ApiError::new(StatusCode::BAD_REQUEST, "No TOTP enrolment is pending")
+        }
+        MfaError::AlreadyEnrolled => ApiError::new(StatusCode::CONFLICT, "TOTP is already enabled"),
+        MfaError::InvalidChallenge => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired MFA challenge")
+        }
+        MfaError::InvalidCode => ApiError::new(StatusCode::UNAUTHORIZED, "Invalid code"),
+        MfaError::TooManyAttempts => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Too many invalid codes")
+                .retry_after(MFA_CHALLENGE_TTL.as_secs())
+        }
+        MfaError::Store(e) => e.into(),
+    };
+    error.into_response()
+}
+
+/// Starts a TOTP enrolment; the secret is only active once confirmed.
+async fn mfa_enroll(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
     Json(request): Json<LoginRequest>,
-) -> Result<ResponseJson<LoginResponse<'static>>, StatusCode> {
-    match state.get_user_by_credentials(&request) {
-        Ok(Some(user)) => {
-            // Success case: no heap allocation needed
-            Ok(ResponseJson(LoginResponse {
-                success: true,
-                user_id: Some(user.id),
-                error_message: None,
-            }))
+) -> Response {
+    let result = state
+        .store
+        .enroll_totp(&request.user_name, &request.hashed_password);
+    let outcome = LoginOutcome::of_mfa(&result);
+    let mut response = match result {
+        Ok(enrollment) => {
+            let user_id = enrollment.user_id;
+            Json(LoginResponse::ok(user_id, Some(enrollment.into()))).into_response()
         }
-        Ok(None) => {
-            // Error case: use static string literal (stack-allocated)
-            Ok(ResponseJson(LoginResponse {
-                success: false,
-                user_id: None,
-                error_message: Some(Cow::Borrowed("Invalid username or password")),
-            }))
+        Err(e) => mfa_failure(e),
+    };
+    report_credentials(&state, &mut response, request.user_name, outcome);
+    response
+}
+
+async fn mfa_confirm(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<MfaConfirmRequest>,
+) -> Response {
+    let result =
+        state
+            .store
+            .confirm_totp(&request.user_name, &request.hashed_password, &request.code);
+    let outcome = LoginOutcome::of_mfa(&result);
+    let mut response = match result {
+        Ok(user) => Json(LoginResponse::ok(user.id, None)).into_response(),
+        Err(e) => mfa_failure(e),
+    };
+    report_credentials(&state, &mut response, request.user_name, outcome);
+    response
+}
+
+/// Second step of a login that answered `MfaRequired`; answers like a
+/// successful `get-user-token`.
+async fn mfa_verify(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<MfaVerifyRequest>,
+) -> Response {
+    // Read before verifying: a completed challenge is gone
+    let mail = state
+        .audit
+        .as_ref()
+        .and_then(|_| state.store.mfa_challenge_mail(&request.challenge_id));
+    let login = state
+        .store
+        .verify_mfa(&request.challenge_id, &request.code)
+        .and_then(|user| Ok((user, state.store.start_session(user.id)?)));
+    let outcome = match &login {
+        Ok(_) => AuthOutcome::Success,
+        Err(e) => AuthOutcome::from(e),
+    };
+    if let Some(metrics) = &state.metrics {
+        metrics.record_login(outcome);
+    }
+    let mut response = match login {
+        Ok((user, session)) => {
+            Json(LoginResponse::ok(user.id, session.map(Into::into))).into_response()
         }
-        Err(e) => {
-            error!("Database error: {}", e);
-            // Error case: use static string literal (stack-allocated)
-            Ok(ResponseJson(LoginResponse {
-                success: false,
-                user_id: None,
-                error_message: Some(Cow::Borrowed("An error occurred during authentication")),
-            }))
+        Err(e) => mfa_failure(e),
+    };
+    if state.audit.is_some() {
+        response
+            .extensions_mut()
+            .insert(AuthEvent::new(mail, outcome));
+    }
+    response
+}
+
+/// Completes the `AuthEvent` left by the login handlers (or the throttle)
+/// with the client address and user agent, and queues it for the audit log.
+async fn audit_login(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(audit) = &state.audit else {
+        return next.run(request).await;
+    };
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let mut response = next.run(request).await;
+    if let Some(mut event) = response.extensions_mut().remove::<AuthEvent>() {
+        event.client_ip = ip;
+        event.user_agent = user_agent;
+        audit.record(event);
+    }
+    response
+}
+
+/// Counts and times every request by matched route and status, for `/metrics`.
+async fn track_metrics(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(metrics) = &state.metrics else {
+        return next.run(request).await;
+    };
+    let start = Instant::now();
+    let route = request.extensions().get::<MatchedPath>().cloned();
+    let response = next.run(request).await;
+    metrics.observe_request(
+        route.as_ref().map_or(UNMATCHED_ROUTE, MatchedPath::as_str),
+        response.status().as_u16(),
+        start.elapsed(),
+    );
+    response
+}
+
+/// Writes the sampled requests to the access log once their response is ready.
+async fn access_log(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = state.access_log.as_ref().filter(|log| log.sampled()) else {
+        return next.run(request).await;
+    };
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let route = request.extensions().get::<MatchedPath>().cloned();
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    let response = next.run(request).await;
+    let mut record = AccessRecord::new(
+        method.as_str(),
+        &path,
+        route.as_ref().map_or(UNMATCHED_ROUTE, MatchedPath::as_str),
+        response.status().as_u16(),
+        start.elapsed(),
+    );
+    record.client_ip = client_ip;
+    record.user_agent = user_agent;
+    log.record(record);
+    response
+}
+
+/// Opens the `http.request` span the spans of the handler nest under; only
+/// layered when spans are exported.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, MatchedPath::as_str);
+    let span = debug_span!(
+        "http.request",
+        http.request.method = %request.method(),
+        http.route = route,
+        http.response.status_code = field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+/// Answers CORS preflights and adds the CORS headers to the responses of
+/// allowed origins; only layered when `MAXREQ_CORS_ORIGINS` is set.
+async fn cors(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (Some(policy), Some(origin)) = (&state.cors, request.headers().get(header::ORIGIN)) else {
+        return next.run(request).await;
+    };
+    let origin = origin.to_str().unwrap_or_default().to_owned();
+    let preflight_method = request
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .filter(|_| request.method() == Method::OPTIONS);
+    if let Some(method) = preflight_method {
+        let requested_headers = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|value| value.to_str().ok());
+        let allowed = policy.preflight(
+            &origin,
+            method.to_str().unwrap_or_default(),
+            requested_headers,
+        );
+        return match allowed {
+            Some(headers) => {
+                let mut response = StatusCode::NO_CONTENT.into_response();
+                add_headers(&mut response, headers);
+                response
+            }
+            None => StatusCode::FORBIDDEN.into_response(),
+        };
+    }
+
+    let mut response = next.run(request).await;
+    add_headers(&mut response, policy.response_headers(&origin));
+    response
+}
+
+/// Appends `headers`, so a `Vary` set by the handler is kept.
+fn add_headers(response: &mut Response, headers: Vec<(&'static str, &str)>) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            response
+                .headers_mut()
+                .append(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Prometheus text exposition; `404` when metrics are turned off.
+async fn metrics(axum::extract::State(state): axum::extract::State<Arc<AppState>>) -> Response {
+    match &state.metrics {
+        Some(metrics) => (
+            [(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)],
+            metrics.render(&state.store, state.throttle.as_ref()),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Parses the login payload like `get_user_token` but never touches the database.
+#[cfg(feature = "bench")]
+async fn bench_no_db(Json(_request): Json<LoginRequest>) -> Json<LoginResponse<'static>> {
+    Json(LoginResponse::ok(12345, None))
+}
+
+/// Query string of `create-db`; every field falls back to the historical
+/// 10,000 `password{i}` users.
+#[derive(Debug, Deserialize)]
+struct CreateDbQuery {
+    count: Option<usize>,
+    password: Option<String>,
+    seed: Option<u64>,
+}
+
+impl From<CreateDbQuery> for SeedParams {
+    fn from(query: CreateDbQuery) -> Self {
+        let defaults = SeedParams::default();
+        SeedParams {
+            count: query.count.unwrap_or(defaults.count),
+            password_pattern: query.password.unwrap_or(defaults.password_pattern),
+            seed: query.seed,
         }
     }
 }
 
 async fn create_db(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-) -> Result<String, StatusCode> {
-    match state.create_test_users(10000) {
-        Ok(count) => {
-            info!("Created {} test users", count);
-            Ok(format!(
-                "Successfully created {} users in the database",
-                count
-            ))
+    Query(query): Query<CreateDbQuery>,
+) -> Result<(StatusCode, Json<JobStatus>), (StatusCode, String)> {
+    match state.jobs.start(&state.store, query.into()) {
+        Ok(job) => Ok((StatusCode::ACCEPTED, Json(job))),
+        Err(e @ StartError::Invalid(_)) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+        Err(e @ StartError::AlreadyRunning(_)) => Err((StatusCode::CONFLICT, e.to_string())),
+    }
+}
+
+async fn create_db_status(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobStatus>, StatusCode> {
+    state.jobs.status(id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Query string of `import-users`; the defaults import CSV, in chunks of
+/// 10,000 rows, next to the existing users.
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    format: Option<BulkFormat>,
+    mode: Option<ImportMode>,
+    chunk: Option<usize>,
+}
+
+impl From<ImportQuery> for ImportParams {
+    fn from(query: ImportQuery) -> Self {
+        let defaults = ImportParams::default();
+        ImportParams {
+            format: query.format.unwrap_or(defaults.format),
+            mode: query.mode.unwrap_or(defaults.mode),
+            chunk_size: query.chunk.unwrap_or(defaults.chunk_size),
         }
-        Err(e) => {
-            error!("Failed to create test users: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Streams the body into the store chunk by chunk; rows that fail are listed
+/// in the report rather than failing the request.
+async fn import_users(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Query(query): Query<ImportQuery>,
+    body: Body,
+) -> Result<Json<ImportReport>, ApiError> {
+    let mut import = state
+        .store
+        .import_users(query.into())
+        .map_err(import_failure)?;
+    // Parsing and the chunk writes block on SQLite, so they run on the blocking pool
+    let mut body = body.into_data_stream();
+    while let Some(data) = body.next().await {
+        let data = data.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+        import = tokio::task::spawn_blocking(move || import.feed(&data).map(|()| import))
+            .await?
+            .map_err(import_failure)?;
+    }
+    let report = tokio::task::spawn_blocking(move || import.finish()).await?;
+    report.map(Json).map_err(import_failure)
+}
+
+fn import_failure(e: ImportError) -> ApiError {
+    match e {
+        ImportError::Invalid(reason) => ApiError::new(StatusCode::BAD_REQUEST, reason),
+        ImportError::Store(e) => e.into(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<BulkFormat>,
+}
+
+/// Streams every user, a page at a time, in a format `import-users` reads back.
+async fn export_users(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let format = query.format.unwrap_or_default();
+    // Every page is a query, read on the blocking pool
+    let pages =
+        futures_util::stream::unfold(state.store.export_users(format), |mut export| async move {
+            match tokio::task::spawn_blocking(move || (export.next(), export)).await {
+                Ok((page, export)) => Some((page?, export)),
+                Err(e) => {
+                    error!("Export failed: {}", e);
+                    None
+                }
+            }
+        })
+        .inspect(|page| {
+            if let Err(e) = page {
+                error!("Export failed: {}", e);
+            }
+        });
+    (
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(pages),
+    )
+        .into_response()
+}
+
+async fn cache_stats(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<CacheStats> {
+    Json(state.store.cache_stats())
+}
+
+async fn throttle_stats(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<ThrottleStats> {
+    Json(
+        state
+            .throttle
+            .as_ref()
+            .map(LoginThrottle::stats)
+            .unwrap_or_default(),
+    )
+}
+
+async fn admission_stats(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<AdmissionStats> {
+    Json(
+        state
+            .admission
+            .as_ref()
+            .map(Admission::stats)
+            .unwrap_or_default(),
+    )
+}
+
+/// Authentication attempts, newest first; `NextBefore` is the cursor of the next page.
+async fn audit_events(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, ApiError> {
+    Ok(Json(state.store.audit_events(&query)?))
+}
+
+async fn audit_stats(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Json<AuditStats> {
+    Json(
+        state
+            .audit
+            .as_ref()
+            .map(AuditLog::stats)
+            .unwrap_or_default(),
+    )
+}
+
+/// Rejects `/admin/*` requests that do not carry the admin credential.
+async fn require_admin(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    match state.admin.check(authorization) {
+        AdminAccess::Granted => next.run(request).await,
+        AdminAccess::Unauthorized => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Missing or invalid admin token",
+        )
+            .into_response(),
+        AdminAccess::Disabled => (
+            StatusCode::FORBIDDEN,
+            "Admin endpoints are disabled: set MAXREQ_ADMIN_TOKEN or MAXREQ_BENCHMARK_MODE",
+        )
+            .into_response(),
+    }
+}
+
+/// Mints a key; the response is the only time its plaintext is shown.
+async fn create_api_key(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Response {
+    let ttl = request.expires_in_secs.map(Duration::from_secs);
+    match state
+        .store
+        .create_api_key(&request.name, &request.scopes, ttl)
+    {
+        Ok(key) => (StatusCode::CREATED, Json(key)).into_response(),
+        Err(e @ ApiKeyError::Invalid(_)) => {
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+        Err(ApiKeyError::Store(e)) => ApiError::from(e).into_response(),
+    }
+}
+
+async fn list_api_keys(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    Ok(Json(state.store.list_api_keys()?))
+}
+
+async fn revoke_api_key(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    match state.store.revoke_api_key(id)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Waits up to the queue budget for an admission slot; `503` when there is
+/// none. A `503` from the handler is reported to the adaptive limit.
+async fn admit(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admission) = &state.admission else {
+        return next.run(request).await;
+    };
+    let Ok(Some(mut permit)) =
+        tokio::time::timeout(admission.queue_timeout(), admission.acquire()).await
+    else {
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, BUSY_MESSAGE)
+            .retry_after(SHED_RETRY_AFTER_SECS)
+            .into_response();
+    };
+    let response = next.run(request).await;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        permit.overloaded();
+    }
+    response
+}
+
+/// Rejects `/api/auth/*` requests without an API key carrying the route's scope.
+/// The key is handed on to the handler as a request extension.
+async fn require_api_key(
+    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(scope) = required_scope(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    match state.store.check_api_key(authorization, scope) {
+        Ok(ApiKeyAccess::Granted(key)) => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
         }
+        Ok(ApiKeyAccess::Missing | ApiKeyAccess::Invalid) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "ApiKey")],
+            "Missing or invalid API key",
+        )
+            .into_response(),
+        Ok(ApiKeyAccess::Forbidden) => (
+            StatusCode::FORBIDDEN,
+            format!("API key lacks the {} scope", scope),
+        )
+            .into_response(),
+        Err(e) => ApiError::from(e).into_response(),
     }
 }
 
@@ -219,30 +1304,474 @@ async fn health() -> &'static str {
     "UserTokenApi Rust server is running"
 }
 
+async fn ready(axum::extract::State(state): axum::extract::State<Arc<AppState>>) -> Response {
+    // Off the runtime: with the pool exhausted, the check waits out its timeout
+    let readiness = tokio::task::spawn_blocking(move || state.store.readiness(state.ready_timeout));
+    match readiness.await {
+        Ok(readiness) if readiness.ready => Json(readiness).into_response(),
+        Ok(readiness) => (StatusCode::SERVICE_UNAVAILABLE, Json(readiness)).into_response(),
+        Err(e) => {
+            error!("Readiness check failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing; dropping `telemetry` on exit flushes the exported spans
+    let config = Config::from_env();
+    let telemetry = user_token_core::init_tracing(&config, "user-token-api")?;
+
+    // Bring users.db to the current schema (and mail policy) and exit, e.g. before a deploy
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let store = UserStore::open("users.db", 1)?.with_mail_config(&config.mail)?;
+        info!("users.db is at schema version {}", store.schema_version()?);
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind("0.0.0.0:8080").await?;
+    serve(listener, &telemetry, shutdown_signal()).await
+}
+
+/// Where a connection stands between requests, for its deadline.
+enum ConnPhase {
+    /// Waiting for a next request since this instant.
+    Idle(Instant),
+    /// Receiving a request head, which must be complete by this instant.
+    Head(Instant),
+    /// A request is being handled: no deadline.
+    Busy,
+}
+
+/// A connection whose reads fail past the deadline of its phase, which makes
+/// hyper close it: `MAXREQ_KEEP_ALIVE_SECS` after the last response, and
+/// `MAXREQ_HEADER_TIMEOUT_MS` after the first byte of a request head (or the
+/// accept, for the first request). hyper's own header timer also runs while
+/// the connection is idle, so it cannot keep the two apart.
+struct Deadlined {
+    stream: tokio::net::TcpStream,
+    phase: Arc<Mutex<ConnPhase>>,
+    header_timeout: Duration,
+    keep_alive: Duration,
+    timer: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl Deadlined {
+    fn new(
+        stream: tokio::net::TcpStream,
+        phase: Arc<Mutex<ConnPhase>>,
+        limits: &LimitsConfig,
+    ) -> Self {
+        Deadlined {
+            stream,
+            phase,
+            header_timeout: limits.header_timeout,
+            keep_alive: limits.keep_alive,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        }
+    }
+
+    /// Resolves once the deadline of the current phase is past.
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let deadline = match *self.phase.lock().unwrap() {
+            ConnPhase::Idle(since) => since + self.keep_alive,
+            ConnPhase::Head(deadline) => deadline,
+            ConnPhase::Busy => return Poll::Pending,
+        };
+        // A timer due earlier is kept and re-armed when it fires, which saves
+        // a reset per request on a busy keep-alive connection
+        let deadline = deadline.into();
+        if self.timer.deadline() > deadline {
+            self.timer.as_mut().reset(deadline);
+        }
+        while self.timer.as_mut().poll(cx).is_ready() {
+            if self.timer.deadline() >= deadline {
+                return Poll::Ready(());
+            }
+            self.timer.as_mut().reset(deadline);
+        }
+        Poll::Pending
+    }
+
+    /// A response still being written keeps the connection out of idle.
+    fn wrote(&mut self, cx: &mut Context<'_>) {
+        let mut phase = self.phase.lock().unwrap();
+        if let ConnPhase::Idle(_) = *phase {
+            *phase = ConnPhase::Idle(Instant::now());
+            drop(phase);
+            // hyper may not read again until woken: arm the keep-alive timer now
+            let _ = self.poll_deadline(cx);
+        }
+    }
+}
+
+impl AsyncRead for Deadlined {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let read = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if read.is_ready() {
+            let mut phase = this.phase.lock().unwrap();
+            if buf.filled().len() > filled {
+                if let ConnPhase::Idle(_) = *phase {
+                    *phase = ConnPhase::Head(Instant::now() + this.header_timeout);
+                }
+            }
+            return read;
+        }
+        match this.poll_deadline(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for Deadlined {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(1..)) = written {
+            this.wrote(cx);
+        }
+        written
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.stream).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(1..)) = written {
+            this.wrote(cx);
+        }
+        written
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
 
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("SIGINT received"),
+        _ = terminate => info!("SIGTERM received"),
+    }
+}
+
+/// Serves the API on `listener`, configured from the `MAXREQ_*` variables,
+/// with `users.db` in the working directory. Requests get an `http.request`
+/// span when `telemetry` exports spans.
+///
+/// Once `shutdown` resolves, the listener is closed and in-flight requests get
+/// `MAXREQ_SHUTDOWN_TIMEOUT_SECS` to finish. The access and audit logs are
+/// then flushed and the WAL checkpointed before this returns.
+pub async fn serve(
+    listener: TcpListener,
+    telemetry: &Telemetry,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Initialize application state
     let app_state = Arc::new(AppState::new()?);
 
+    // Destructive and diagnostic routes, behind the admin credential
+    let admin = Router::new()
+        .route("/admin/create-db", post(create_db))
+        .route("/admin/create-db/{id}", get(create_db_status))
+        // Streams its body, so `MAXREQ_MAX_BODY_BYTES` does not apply
+        .route("/admin/import-users", post(import_users))
+        .route("/admin/export-users", get(export_users))
+        .route("/admin/cache-stats", get(cache_stats))
+        .route("/admin/throttle-stats", get(throttle_stats))
+        .route("/admin/admission-stats", get(admission_stats))
+        .route("/admin/api-keys", post(create_api_key).get(list_api_keys))
+        .route("/admin/api-keys/{id}", delete(revoke_api_key))
+        .route("/admin/audit", get(audit_events))
+        .route("/admin/audit-stats", get(audit_stats))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_admin,
+        ));
+
+    // Every route that checks a password, wrapped by the throttle only when it
+    // is configured, so a lockout on one holds on all of them
+    let login = Router::new()
+        .route("/api/auth/get-user-token", post(get_user_token))
+        .route("/api/auth/change-password", post(change_password))
+        .route("/api/auth/user", delete(delete_user))
+        .route("/api/auth/mfa/enroll", post(mfa_enroll))
+        .route("/api/auth/mfa/confirm", post(mfa_confirm));
+    let login = if app_state.throttle.is_some() {
+        login.route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            throttle_login,
+        ))
+    } else {
+        login
+    };
+
+    // Audited with both login steps, outside the throttle so lockouts are too
+    let login = login.route("/api/auth/mfa/verify", post(mfa_verify));
+    let login = if app_state.audit.is_some() {
+        login.route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            audit_login,
+        ))
+    } else {
+        login
+    };
+
+    // Public API, behind API keys only when they are required
+    let auth = Router::new()
+        .merge(login)
+        .route("/api/auth/register", post(register))
+        .route("/api/auth/refresh", post(refresh))
+        .route("/api/auth/logout", post(logout));
+    let auth = if app_state.require_api_key {
+        auth.route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_api_key,
+        ))
+    } else {
+        auth
+    };
+
+    // Outermost of the API routes, so a shed request costs no database access
+    let auth = if app_state.admission.is_some() {
+        auth.route_layer(middleware::from_fn_with_state(app_state.clone(), admit))
+    } else {
+        auth
+    };
+
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(health))
-        .route("/api/auth/get-user-token", post(get_user_token))
-        .route("/api/auth/create-db", get(create_db))
-        .layer(CorsLayer::permissive())
-        .with_state(app_state);
+        .route("/health/live", get(health))
+        .route("/health/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .merge(auth)
+        .merge(admin);
+
+    // DB-bypass login, only compiled into benchmark builds
+    #[cfg(feature = "bench")]
+    let app = app.route("/bench/no-db", post(bench_no_db));
+
+    // Outermost but CORS, so the status of throttled and rejected requests is counted
+    let app = if app_state.metrics.is_some() {
+        app.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            track_metrics,
+        ))
+    } else {
+        app
+    };
+
+    // Next to metrics, so it logs the same status and a comparable duration
+    let app = if app_state.access_log.is_some() {
+        app.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            access_log,
+        ))
+    } else {
+        app
+    };
+
+    // Outside metrics, so the span covers everything the request is timed for
+    let app = if telemetry.exporting() {
+        app.layer(middleware::from_fn(trace_request))
+    } else {
+        app
+    };
+
+    // Applies to the extractors that buffer the body: `Json` and `Bytes`
+    let app = app.layer(DefaultBodyLimit::max(app_state.limits.max_body_bytes));
+
+    // Outermost, so preflights are answered before anything else runs and
+    // every other response carries the headers
+    let app = if app_state.cors.is_some() {
+        app.layer(middleware::from_fn_with_state(app_state.clone(), cors))
+    } else {
+        app
+    }
+    .with_state(app_state.clone());
 
     // Run the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
-    info!("🦀 Rust UserTokenApi server running on http://localhost:8080");
+    info!(
+        "🦀 Rust UserTokenApi server running on {}://{}",
+        if app_state.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        listener.local_addr()?
+    );
     info!("Available endpoints:");
     info!("  POST /api/auth/get-user-token - Authenticate user");
-    info!("  GET /api/auth/create-db - Create test database");
-    info!("  GET /health - Health check");
+    info!("  POST /api/auth/register - Create a user");
+    info!("  POST /api/auth/change-password - Change a user's password");
+    info!("  DELETE /api/auth/user - Delete a user");
+    info!("  POST /api/auth/refresh - Rotate a refresh token");
+    info!("  POST /api/auth/logout - Revoke a refresh token");
+    info!("  POST /api/auth/mfa/enroll - Start a TOTP enrolment");
+    info!("  POST /api/auth/mfa/confirm - Enable TOTP with a first code");
+    info!("  POST /api/auth/mfa/verify - Complete a login with a TOTP code");
+    info!("  POST /admin/create-db?count=&password=&seed= - Start a test database job");
+    info!("  GET /admin/create-db/{{id}} - Test database job progress");
+    info!("  POST /admin/import-users?format=&mode=&chunk= - Import users from CSV or NDJSON");
+    info!("  GET /admin/export-users?format= - Export users as CSV or NDJSON");
+    info!("  GET /admin/cache-stats - Credential cache hit/miss counters");
+    info!("  GET /admin/throttle-stats - Login lockout counters");
+    info!("  GET /admin/admission-stats - Admission limit, queue and shed counters");
+    info!("  POST/GET /admin/api-keys - Mint and list API keys");
+    info!("  DELETE /admin/api-keys/{{id}} - Revoke an API key");
+    info!("  GET /admin/audit?mail=&outcome=&ip=&since=&until=&before=&limit= - Login audit log");
+    info!("  GET /admin/audit-stats - Audit writer counters");
+    #[cfg(feature = "bench")]
+    info!("  POST /bench/no-db - Authenticate without touching the database");
+    info!("  GET /health/live - Liveness: the process is up (also GET /health)");
+    info!("  GET /health/ready - Readiness: database check, pool and schema version");
+    info!("  GET /metrics - Prometheus metrics");
 
-    axum::serve(listener, app).await?;
+    // Connections are served by hyper directly: `axum::serve` sets no limit
+    // on the request head nor any timeout
+    let limits = app_state.limits.clone();
+    let mut http = http1::Builder::new();
+    http.max_header_size(limits.max_header_bytes)
+        .keep_alive(!limits.keep_alive.is_zero());
+    let graceful = GracefulShutdown::new();
+    // Connection tasks hold the router, and through it the state: they are
+    // kept to be ended before the state is closed
+    let mut connections = tokio::task::JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Typically out of file descriptors: give connections time to close
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            // Reaps finished connections, so the set only holds open ones
+            Some(_) = connections.join_next() => continue,
+            () = &mut shutdown => break,
+        };
+        let phase = Arc::new(Mutex::new(ConnPhase::Head(
+            Instant::now() + limits.header_timeout,
+        )));
+        let io = Deadlined::new(stream, phase.clone(), &limits);
+        let app = app.clone();
+        // Client addresses are needed by the per-IP login throttle
+        let service = service_fn(move |mut request: hyper::Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(addr));
+            *phase.lock().unwrap() = ConnPhase::Busy;
+            let phase = phase.clone();
+            // The router is always ready: no need to poll it first
+            let response = app.clone().call(request);
+            async move {
+                let response = response.await;
+                *phase.lock().unwrap() = ConnPhase::Idle(Instant::now());
+                response
+            }
+        });
+        let (http, watcher, tls) = (http.clone(), graceful.watcher(), app_state.tls.clone());
+        connections.spawn(async move {
+            // The handshake is bounded by the header timeout, like a request head
+            let served = match tls {
+                Some(tls) => match tls.accept(io).await {
+                    Ok(io) => {
+                        watcher
+                            .watch(http.serve_connection(TokioIo::new(io), service))
+                            .await
+                    }
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                },
+                None => {
+                    watcher
+                        .watch(http.serve_connection(TokioIo::new(io), service))
+                        .await
+                }
+            };
+            if let Err(e) = served {
+                debug!("Connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+    drop(listener);
+
+    // The drain timeout only starts once the listener is closed
+    info!(
+        "Shutting down: draining in-flight requests for up to {:?}",
+        app_state.shutdown_timeout
+    );
+    if tokio::time::timeout(app_state.shutdown_timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Requests still running after {:?}, closing their connections",
+            app_state.shutdown_timeout
+        );
+    }
 
+    // Connections cut by the timeout are aborted, then the router dropped, so
+    // that the state has no other owner and its logs are flushed before the
+    // checkpoint
+    connections.shutdown().await;
+    drop(app);
+    match Arc::try_unwrap(app_state) {
+        Ok(state) => tokio::task::spawn_blocking(move || state.close()).await?,
+        Err(state) => {
+            // Only a blocking task, which cannot be aborted, still holds it
+            warn!("Closing with requests still running; their log lines may be lost");
+            tokio::task::spawn_blocking(move || state.flush()).await?;
+        }
+    }
+    info!("Server stopped");
     Ok(())
 }
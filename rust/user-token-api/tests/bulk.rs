@@ -0,0 +1,188 @@
+//! Bulk import and export: row errors of both formats are reported by line,
+//! and an export imports back into the same users.
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use user_token_core::{hash_password, init_tracing, Config};
+
+const ADMIN_TOKEN: &str = "bulk-test-admin-token";
+
+/// Sends `method path` with `body` over HTTP/1.0, so a streamed response is
+/// delimited by the end of the connection; returns the status and the body.
+fn send(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        ADMIN_TOKEN,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head[9..12].parse().unwrap();
+    (status, body.to_owned())
+}
+
+fn import(addr: SocketAddr, query: &str, body: &str) -> Value {
+    let (status, report) = send(
+        addr,
+        "POST",
+        &format!("/admin/import-users?{}", query),
+        body,
+    );
+    assert_eq!(status, 200, "{}", report);
+    serde_json::from_str(&report).unwrap()
+}
+
+/// `(line, mail)` of every row error of `report`.
+fn errors(report: &Value) -> Vec<(u64, Option<&str>)> {
+    report["Errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["Line"].as_u64().unwrap(), e["Mail"].as_str()))
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn import_and_export_round_trip() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-bulk-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_ADMIN_TOKEN", ADMIN_TOKEN);
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        let hash = hash_password("correct horse 42");
+        let csv = format!(
+            "Mail,HashedPassword\n\
+             alice@example.com,{hash}\n\
+             bob@example.com,{hash}\n\
+             \"carol@example.com,{hash}\n\
+             dave@example.com,not-a-hash\n\
+             erin@example.com\n\
+             \n\
+             frank@example.com,{hash}\n\
+             alice@example.com,{hash}\n"
+        );
+        let csv_report = import(addr, "format=csv&chunk=2", &csv);
+
+        let ndjson = format!(
+            "{{\"Mail\":\"grace@example.com\",\"Password\":\"correct horse 42\"}}\n\
+             {{\"Mail\":\"heidi@example.com\"\n\
+             not json\n\
+             {{\"Mail\":\"not-a-mail\",\"HashedPassword\":\"{hash}\"}}"
+        );
+        let ndjson_report = import(addr, "format=ndjson", &ndjson);
+
+        let bad_header = send(
+            addr,
+            "POST",
+            "/admin/import-users?format=csv",
+            "Mail,Name\nivan@example.com,Ivan\n",
+        );
+
+        let (csv_status, exported_csv) = send(addr, "GET", "/admin/export-users", "");
+        let (ndjson_status, exported_ndjson) =
+            send(addr, "GET", "/admin/export-users?format=ndjson", "");
+        assert_eq!(csv_status, 200, "{}", exported_csv);
+        assert_eq!(ndjson_status, 200, "{}", exported_ndjson);
+
+        // Both exports replace the users with themselves
+        let reimported_csv = import(addr, "format=csv&mode=replace", &exported_csv);
+        let (_, csv_again) = send(addr, "GET", "/admin/export-users", "");
+        let reimported_ndjson = import(addr, "format=ndjson&mode=replace", &exported_ndjson);
+        let (_, ndjson_again) = send(addr, "GET", "/admin/export-users?format=ndjson", "");
+
+        (
+            hash,
+            csv_report,
+            ndjson_report,
+            bad_header,
+            exported_csv,
+            exported_ndjson,
+            reimported_csv,
+            csv_again,
+            reimported_ndjson,
+            ndjson_again,
+        )
+    });
+    let (
+        hash,
+        csv_report,
+        ndjson_report,
+        bad_header,
+        exported_csv,
+        exported_ndjson,
+        reimported_csv,
+        csv_again,
+        reimported_ndjson,
+        ndjson_again,
+    ) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        responses = client => responses.unwrap(),
+    };
+
+    // The blank line is not a row; the duplicate is found when its chunk is written
+    assert_eq!(csv_report["Rows"], 7, "{}", csv_report);
+    assert_eq!(csv_report["Inserted"], 3, "{}", csv_report);
+    assert_eq!(csv_report["Failed"], 4, "{}", csv_report);
+    assert_eq!(
+        errors(&csv_report),
+        [
+            (4, None),
+            (5, Some("dave@example.com")),
+            (6, Some("erin@example.com")),
+            (9, Some("alice@example.com")),
+        ]
+    );
+
+    assert_eq!(ndjson_report["Rows"], 4, "{}", ndjson_report);
+    assert_eq!(ndjson_report["Inserted"], 1, "{}", ndjson_report);
+    assert_eq!(
+        errors(&ndjson_report),
+        [(2, None), (3, None), (4, Some("not-a-mail"))]
+    );
+    for line in 0..2 {
+        let error = ndjson_report["Errors"][line]["Error"].as_str().unwrap();
+        assert!(error.starts_with("invalid JSON"), "{}", error);
+    }
+
+    assert_eq!(bad_header.0, 400, "{}", bad_header.1);
+
+    let expected: String = ["alice", "bob", "frank", "grace"]
+        .iter()
+        .map(|name| format!("{}@example.com,{}\n", name, hash))
+        .collect();
+    assert_eq!(exported_csv, format!("Mail,HashedPassword\n{}", expected));
+    let lines: Vec<Value> = exported_ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3]["Mail"], "grace@example.com");
+    assert_eq!(lines[3]["HashedPassword"], hash.as_str());
+
+    for report in [&reimported_csv, &reimported_ndjson] {
+        assert_eq!(report["Inserted"], 4, "{}", report);
+        assert_eq!(report["Failed"], 0, "{}", report);
+    }
+    assert_eq!(csv_again, exported_csv);
+    assert_eq!(ndjson_again, exported_ndjson);
+}
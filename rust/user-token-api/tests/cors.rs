@@ -0,0 +1,108 @@
+//! CORS: preflights are answered from the configured policy, and only the
+//! responses to allowed origins carry `Access-Control-Allow-Origin`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use user_token_core::{init_tracing, Config};
+
+/// Sends `head` with an empty body on a fresh connection; returns the
+/// response head, lowercased.
+fn send(addr: SocketAddr, head: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(format!("{}Host: localhost\r\nConnection: close\r\n\r\n", head).as_bytes())
+        .unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response).to_ascii_lowercase();
+    response.split("\r\n\r\n").next().unwrap().to_owned()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn cors_policy_is_applied() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-cors-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_REQUIRE_API_KEY", "1");
+    std::env::set_var("MAXREQ_CORS_ORIGINS", "https://app.example.com");
+    std::env::set_var("MAXREQ_CORS_MAX_AGE_SECS", "120");
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        let preflight = send(
+            addr,
+            "OPTIONS /api/auth/get-user-token HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: content-type\r\n",
+        );
+        let foreign_preflight = send(
+            addr,
+            "OPTIONS /api/auth/get-user-token HTTP/1.1\r\nOrigin: https://evil.example.com\r\nAccess-Control-Request-Method: POST\r\n",
+        );
+        let refused_method = send(
+            addr,
+            "OPTIONS /api/auth/get-user-token HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\n",
+        );
+        let unauthorized = send(
+            addr,
+            "POST /api/auth/get-user-token HTTP/1.1\r\nOrigin: https://app.example.com\r\nContent-Type: application/json\r\nContent-Length: 0\r\n",
+        );
+        let foreign = send(
+            addr,
+            "GET /health/live HTTP/1.1\r\nOrigin: https://evil.example.com\r\n",
+        );
+        let same_origin = send(addr, "GET /health/live HTTP/1.1\r\n");
+        (
+            preflight,
+            foreign_preflight,
+            refused_method,
+            unauthorized,
+            foreign,
+            same_origin,
+        )
+    });
+    let (preflight, foreign_preflight, refused_method, unauthorized, foreign, same_origin) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        responses = client => responses.unwrap(),
+    };
+
+    assert!(preflight.starts_with("http/1.1 204 "), "{}", preflight);
+    for header in [
+        "access-control-allow-origin: https://app.example.com",
+        "access-control-allow-methods: get, post, delete",
+        "access-control-allow-headers: content-type, authorization",
+        "access-control-max-age: 120",
+        "vary: origin, access-control-request-method, access-control-request-headers",
+    ] {
+        assert!(preflight.contains(header), "{}", preflight);
+    }
+    for refused in [&foreign_preflight, &refused_method] {
+        assert!(refused.starts_with("http/1.1 403 "), "{}", refused);
+        assert!(!refused.contains("access-control-"), "{}", refused);
+    }
+
+    // Errors are readable by the allowed origin too
+    assert!(
+        unauthorized.starts_with("http/1.1 401 "),
+        "{}",
+        unauthorized
+    );
+    for header in [
+        "access-control-allow-origin: https://app.example.com",
+        "access-control-expose-headers: retry-after",
+        "vary: origin",
+    ] {
+        assert!(unauthorized.contains(header), "{}", unauthorized);
+    }
+    for response in [&foreign, &same_origin] {
+        assert!(response.starts_with("http/1.1 200 "), "{}", response);
+        assert!(!response.contains("access-control-"), "{}", response);
+    }
+}
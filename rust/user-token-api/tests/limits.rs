@@ -0,0 +1,159 @@
+//! Request limits: slow or idle clients are disconnected, oversized bodies
+//! and heads are rejected, and the streamed import is not capped.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use user_token_core::{hash_password, init_tracing, Config};
+
+/// Sends `head` then `body` on a fresh connection; returns the response.
+fn send(addr: SocketAddr, head: &str, body: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.write_all(head.as_bytes()).unwrap();
+    // The server may answer and close before the whole body is sent
+    let _ = stream.write_all(body);
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+fn post(addr: SocketAddr, path: &str, body: &[u8]) -> String {
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        body.len()
+    );
+    send(addr, &head, body)
+}
+
+/// How long the server keeps `stream` open, writing `dribble` a byte at a
+/// time in the meantime; panics after 10 seconds.
+fn time_to_close(stream: &mut TcpStream, dribble: &[u8]) -> Duration {
+    stream
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let started = Instant::now();
+    let mut dribble = dribble.iter().cycle();
+    let mut buf = [0; 1024];
+    loop {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "still open after 10s"
+        );
+        if let Some(byte) = dribble.next() {
+            if stream.write_all(&[*byte]).is_err() {
+                return started.elapsed();
+            }
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return started.elapsed(),
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => return started.elapsed(),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn limits_are_enforced() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-limits-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_BENCHMARK_MODE", "1");
+    std::env::set_var("MAXREQ_MAX_BODY_BYTES", "1024");
+    std::env::set_var("MAXREQ_MAX_HEADER_BYTES", "4096");
+    std::env::set_var("MAXREQ_HEADER_TIMEOUT_MS", "300");
+    std::env::set_var("MAXREQ_KEEP_ALIVE_SECS", "1");
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        // Slowloris: a head sent a byte at a time never completes
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /api/auth/register HTTP/1.1\r\nHost: localhost\r\n")
+            .unwrap();
+        let slowloris = time_to_close(&mut stream, b"X-Slow: a\r\n");
+        assert!(slowloris < Duration::from_secs(2), "{:?}", slowloris);
+
+        // A connection that never sends anything
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let silent = time_to_close(&mut stream, b"");
+        assert!(silent < Duration::from_secs(2), "{:?}", silent);
+
+        // An idle keep-alive connection lasts the keep-alive, not the header timeout
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /health/live HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut buf = [0; 1024];
+        let read = stream.read(&mut buf).unwrap();
+        assert!(buf[..read].starts_with(b"HTTP/1.1 200 "));
+        let idle = time_to_close(&mut stream, b"");
+        assert!(
+            idle > Duration::from_millis(700) && idle < Duration::from_secs(3),
+            "{:?}",
+            idle
+        );
+
+        let oversized_body = post(addr, "/api/auth/register", &[b' '; 4096]);
+        let chunked_body = send(
+            addr,
+            "POST /api/auth/get-user-token HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            format!("1000\r\n{}\r\n0\r\n\r\n", " ".repeat(4096)).as_bytes(),
+        );
+        let oversized_head = send(
+            addr,
+            &format!(
+                "GET /health/live HTTP/1.1\r\nHost: localhost\r\nX-Filler: {}\r\nConnection: close\r\n\r\n",
+                "a".repeat(8192)
+            ),
+            b"",
+        );
+
+        let users: String = (0..100)
+            .map(|i| {
+                format!(
+                    "{{\"Mail\":\"bulk{}@example.com\",\"HashedPassword\":\"{}\"}}\n",
+                    i,
+                    hash_password("correct horse battery 9")
+                )
+            })
+            .collect();
+        assert!(users.len() > 1024);
+        let import = post(addr, "/admin/import-users?format=ndjson", users.as_bytes());
+        (oversized_body, chunked_body, oversized_head, import)
+    });
+    let (oversized_body, chunked_body, oversized_head, import) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        responses = client => responses.unwrap(),
+    };
+
+    for response in [&oversized_body, &chunked_body] {
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        assert!(
+            response.ends_with(
+                r#"{"Success":false,"Status":"Failed","UserId":null,"ErrorMessage":"Request body too large"}"#
+            ),
+            "{}",
+            response
+        );
+    }
+    assert!(
+        oversized_head.starts_with("HTTP/1.1 431 "),
+        "{}",
+        oversized_head
+    );
+    assert!(import.starts_with("HTTP/1.1 200 "), "{}", import);
+    assert!(import.contains(r#""Inserted":100"#), "{}", import);
+}
@@ -0,0 +1,225 @@
+//! Spans of a registration and a login, exported to an in-process OTLP/HTTP
+//! receiver: `cargo test --features otlp`.
+#![cfg(feature = "otlp")]
+
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use prost::Message;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use user_token_core::{hash_password, init_tracing, Config};
+
+/// What the receiver got: service names and spans.
+#[derive(Default)]
+struct Received {
+    services: Vec<String>,
+    spans: Vec<Span>,
+}
+
+/// Answers `POST /v1/traces` with protobuf bodies, like a collector would.
+fn start_receiver() -> (SocketAddr, Arc<Mutex<Received>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Received::default()));
+    let sink = received.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sink = sink.clone();
+            std::thread::spawn(move || receive_exports(stream, &sink));
+        }
+    });
+    (addr, received)
+}
+
+/// Reads keep-alive requests off one exporter connection.
+fn receive_exports(mut stream: TcpStream, received: &Mutex<Received>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        assert!(
+            request_line.starts_with("POST /v1/traces "),
+            "unexpected request: {}",
+            request_line
+        );
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let export = ExportTraceServiceRequest::decode(body.as_slice()).unwrap();
+        let mut received = received.lock().unwrap();
+        for resource in export.resource_spans {
+            let attributes = resource.resource.map(|r| r.attributes).unwrap_or_default();
+            for attribute in attributes {
+                if attribute.key == "service.name" {
+                    received.services.push(string_value(&attribute.value));
+                }
+            }
+            for scope in resource.scope_spans {
+                received.spans.extend(scope.spans);
+            }
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+    }
+}
+
+fn string_value(value: &Option<opentelemetry_proto::tonic::common::v1::AnyValue>) -> String {
+    match value.as_ref().and_then(|v| v.value.as_ref()) {
+        Some(Value::StringValue(s)) => s.clone(),
+        Some(Value::IntValue(i)) => i.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn attribute(span: &Span, key: &str) -> Option<String> {
+    span.attributes
+        .iter()
+        .find(|attribute| attribute.key == key)
+        .map(|attribute| string_value(&attribute.value))
+}
+
+/// A `Connection: close` JSON POST; returns the status line.
+fn post(addr: SocketAddr, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn login_spans_reach_the_collector() {
+    let (collector, received) = start_receiver();
+
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-otlp-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_OTLP_ENDPOINT", format!("http://{}", collector));
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    assert!(telemetry.exporting());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        let register = post(
+            addr,
+            "/api/auth/register",
+            r#"{"UserName":"otlp@example.com","Password":"correct horse battery 9"}"#,
+        );
+        let login = post(
+            addr,
+            "/api/auth/get-user-token",
+            &format!(
+                r#"{{"UserName":"otlp@example.com","HashedPassword":"{}"}}"#,
+                hash_password("correct horse battery 9")
+            ),
+        );
+        (register, login)
+    });
+    let (register, login) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => panic!("server stopped: {:?}", result),
+        responses = client => responses.unwrap(),
+    };
+    assert!(register.contains(" 201 "), "{}", register);
+    assert!(login.contains(" 200 "), "{}", login);
+
+    // Shutting the provider down flushes the batch
+    tokio::task::spawn_blocking(move || drop(telemetry))
+        .await
+        .unwrap();
+
+    let received = received.lock().unwrap();
+    assert!(received.services.iter().all(|s| s == "user-token-api"));
+    let spans = &received.spans;
+    // Only this workspace's spans, not those of the exporter's own HTTP client
+    for span in spans {
+        assert!(
+            [
+                "http.request",
+                "json.parse",
+                "json.serialize",
+                "db.pool.checkout",
+                "db.query"
+            ]
+            .contains(&span.name.as_str()),
+            "unexpected span {}",
+            span.name
+        );
+    }
+
+    let request = spans
+        .iter()
+        .find(|span| {
+            span.name == "http.request"
+                && attribute(span, "http.route").as_deref() == Some("/api/auth/get-user-token")
+        })
+        .expect("no http.request span for the login");
+    assert_eq!(
+        attribute(request, "http.response.status_code").as_deref(),
+        Some("200")
+    );
+    let children: Vec<&Span> = spans
+        .iter()
+        .filter(|span| span.parent_span_id == request.span_id)
+        .collect();
+    for name in [
+        "json.parse",
+        "db.pool.checkout",
+        "db.query",
+        "json.serialize",
+    ] {
+        let child = children
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no {} span under the login request", name));
+        assert_eq!(child.trace_id, request.trace_id);
+    }
+    let query = children
+        .iter()
+        .find(|span| span.name == "db.query")
+        .unwrap();
+    assert_eq!(attribute(query, "query").as_deref(), Some("login"));
+    let checkout = children
+        .iter()
+        .find(|span| span.name == "db.pool.checkout")
+        .unwrap();
+    assert_eq!(attribute(checkout, "pool").as_deref(), Some("read"));
+
+    // The registration went through the writer
+    assert!(spans.iter().any(|span| span.name == "db.query"
+        && attribute(span, "query").as_deref() == Some("insert_user")));
+    drop(received);
+    let _ = std::fs::remove_dir_all(&dir);
+}
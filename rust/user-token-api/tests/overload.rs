@@ -0,0 +1,100 @@
+//! A request that finds the pool exhausted is answered `503` with
+//! `Retry-After` and a failed `LoginResponse`, instead of panicking.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use user_token_core::{init_tracing, Config};
+
+/// A `Connection: close` request; returns the status line, headers and body.
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn exhausted_pool_is_answered_503() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-overload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_BENCHMARK_MODE", "1");
+    std::env::set_var("MAXREQ_POOL_TIMEOUT_MS", "1");
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        // A seed job holds the only writer connection for each chunk it inserts
+        let started = request(addr, "POST", "/admin/create-db?count=10000000", "");
+        assert!(started.starts_with("HTTP/1.1 202 "), "{}", started);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while body(&request(addr, "GET", "/admin/create-db/1", "")).contains("\"Inserted\":0,") {
+            assert!(Instant::now() < deadline, "the seed job did not start");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Until one lands while a chunk is being inserted
+        let register = (0..)
+            .map(|i| {
+                assert!(Instant::now() < deadline, "no registration found the writer busy");
+                request(
+                    addr,
+                    "POST",
+                    "/api/auth/register",
+                    &format!(
+                        r#"{{"UserName":"busy{}@example.com","Password":"correct horse battery 9"}}"#,
+                        i
+                    ),
+                )
+            })
+            .find(|register| !register.starts_with("HTTP/1.1 201 "))
+            .unwrap();
+        // Reads go through their own pool and are not held up
+        let login = request(
+            addr,
+            "POST",
+            "/api/auth/get-user-token",
+            r#"{"UserName":"busy@example.com","HashedPassword":"0"}"#,
+        );
+        (register, login)
+    });
+    let (register, login) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        responses = client => responses.unwrap(),
+    };
+
+    assert!(register.starts_with("HTTP/1.1 503 "), "{}", register);
+    assert!(
+        register
+            .to_ascii_lowercase()
+            .contains("\r\nretry-after: 1\r\n"),
+        "{}",
+        register
+    );
+    assert_eq!(
+        body(&register),
+        r#"{"Success":false,"Status":"Failed","UserId":null,"ErrorMessage":"The server is busy, try again later"}"#
+    );
+    assert!(login.starts_with("HTTP/1.1 200 "), "{}", login);
+}
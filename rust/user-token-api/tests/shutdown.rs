@@ -0,0 +1,149 @@
+//! Graceful shutdown: buffered log lines and audit events are written, then
+//! the WAL is checkpointed, before `serve` returns, even with an import cut
+//! off by the drain timeout.
+
+use rusqlite::Connection;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use user_token_core::{hash_password, init_tracing, Config};
+
+const ADMIN_TOKEN: &str = "shutdown-test-admin-token";
+
+/// Starts an import of one row per chunk whose body never ends; returns
+/// whatever the server answers before closing the connection.
+fn unfinished_import(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    let rows = format!(
+        "Mail,HashedPassword\nimported1@example.com,{hash}\nimported2@example.com,{hash}\n",
+        hash = hash_password("correct horse 42")
+    );
+    write!(
+        stream,
+        "POST /admin/import-users?format=csv&chunk=1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 1000000\r\n\r\n{}",
+        ADMIN_TOKEN, rows
+    )
+    .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+/// A `Connection: close` JSON POST; returns the status line.
+fn post(addr: SocketAddr, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn shutdown_flushes_the_logs_and_checkpoints() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-shutdown-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    let access_log = dir.join("access.jsonl");
+    std::env::set_var("MAXREQ_ACCESS_LOG", &access_log);
+    // Long enough that only the shutdown can flush them
+    std::env::set_var("MAXREQ_ACCESS_LOG_FLUSH_MS", "600000");
+    std::env::set_var("MAXREQ_AUDIT_LOG", "1");
+    std::env::set_var("MAXREQ_AUDIT_FLUSH_MS", "600000");
+    std::env::set_var("MAXREQ_SHUTDOWN_TIMEOUT_SECS", "1");
+    std::env::set_var("MAXREQ_ADMIN_TOKEN", ADMIN_TOKEN);
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+        user_token_api::serve(listener, &telemetry, shutdown)
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let (register, login) = tokio::task::spawn_blocking(move || {
+        let register = post(
+            addr,
+            "/api/auth/register",
+            r#"{"UserName":"drain@example.com","Password":"correct horse battery 9"}"#,
+        );
+        let login = post(
+            addr,
+            "/api/auth/get-user-token",
+            &format!(
+                r#"{{"UserName":"drain@example.com","HashedPassword":"{}"}}"#,
+                hash_password("correct horse battery 9")
+            ),
+        );
+        (register, login)
+    })
+    .await
+    .unwrap();
+    assert!(register.contains(" 201 "), "{}", register);
+    assert!(login.contains(" 200 "), "{}", login);
+
+    // Held across the shutdown, so that the server closing its last
+    // connection does not checkpoint again whatever was written late
+    let reader = Connection::open("users.db").unwrap();
+    let users: i64 = reader
+        .query_row("SELECT COUNT(*) FROM user", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(users, 1);
+
+    // Still reading its body when the drain times out, with its chunks written
+    let import = std::thread::spawn(move || unfinished_import(addr));
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while reader
+        .query_row("SELECT COUNT(*) FROM user", [], |row| row.get::<_, i64>(0))
+        .unwrap()
+        < 3
+    {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the import wrote nothing"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("serve did not return")
+        .unwrap()
+        .unwrap();
+
+    assert!(
+        TcpStream::connect(addr).is_err(),
+        "the listener is still open"
+    );
+    // Cut without a response
+    assert_eq!(import.join().unwrap(), "");
+    let lines = std::fs::read_to_string(&access_log).unwrap();
+    assert_eq!(lines.lines().count(), 2, "{}", lines);
+    let wal = std::fs::metadata(dir.join("users.db-wal")).map_or(0, |wal| wal.len());
+    assert_eq!(wal, 0, "the WAL was not checkpointed last");
+
+    // An empty WAL holds nothing written after the checkpoint
+    let events: i64 = reader
+        .query_row("SELECT COUNT(*) FROM auth_event", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(events, 1, "the audit event was not written");
+}
@@ -0,0 +1,158 @@
+//! Login throttle: every route that checks a password counts failures and is
+//! refused once the account is locked, whichever route locked it.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use user_token_core::{hash_password, init_tracing, Config};
+
+const ADMIN_TOKEN: &str = "throttle-test-admin-token";
+
+/// Sends `method path` with a JSON `body` on a fresh connection; returns the
+/// whole response.
+fn send(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        ADMIN_TOKEN,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+fn credentials(mail: &str, hashed_password: &str) -> String {
+    format!(
+        r#"{{"UserName":"{}","HashedPassword":"{}"}}"#,
+        mail, hashed_password
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn lockout_holds_on_every_password_route() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-throttle-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_THROTTLE_ACCOUNT_FAILURES", "2");
+    std::env::set_var("MAXREQ_THROTTLE_LOCKOUT_SECS", "60");
+    std::env::set_var("MAXREQ_AUDIT_LOG", "1");
+    std::env::set_var("MAXREQ_AUDIT_FLUSH_MS", "10");
+    std::env::set_var("MAXREQ_ADMIN_TOKEN", ADMIN_TOKEN);
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        let password = "correct horse 42";
+        let hashed = hash_password(password);
+        for mail in ["alice@example.com", "bob@example.com"] {
+            let registered = send(
+                addr,
+                "POST",
+                "/api/auth/register",
+                &format!(r#"{{"UserName":"{}","Password":"{}"}}"#, mail, password),
+            );
+            assert!(registered.starts_with("HTTP/1.1 201 "), "{}", registered);
+        }
+
+        // Two wrong passwords on change-password and on enroll lock the account
+        let wrong_change = send(
+            addr,
+            "POST",
+            "/api/auth/change-password",
+            r#"{"UserName":"alice@example.com","HashedPassword":"wrong","NewPassword":"another one 43"}"#,
+        );
+        let wrong_enroll = send(
+            addr,
+            "POST",
+            "/api/auth/mfa/enroll",
+            &credentials("alice@example.com", "wrong"),
+        );
+        let login = send(
+            addr,
+            "POST",
+            "/api/auth/get-user-token",
+            &credentials("alice@example.com", &hashed),
+        );
+        let delete = send(
+            addr,
+            "DELETE",
+            "/api/auth/user",
+            &credentials("alice@example.com", &hashed),
+        );
+        let other = send(
+            addr,
+            "POST",
+            "/api/auth/get-user-token",
+            &credentials("bob@example.com", &hashed),
+        );
+
+        // The audit writer flushes every 10 ms
+        std::thread::sleep(Duration::from_millis(300));
+        let audit = send(addr, "GET", "/admin/audit?mail=alice@example.com", "");
+        let metrics = send(addr, "GET", "/metrics", "");
+        (
+            wrong_change,
+            wrong_enroll,
+            login,
+            delete,
+            other,
+            audit,
+            metrics,
+        )
+    });
+    let (wrong_change, wrong_enroll, login, delete, other, audit, metrics) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        responses = client => responses.unwrap(),
+    };
+
+    for rejected in [&wrong_change, &wrong_enroll] {
+        assert!(rejected.starts_with("HTTP/1.1 401 "), "{}", rejected);
+    }
+    // Locked on the other routes, even with the right password
+    for locked in [&login, &delete] {
+        assert!(locked.starts_with("HTTP/1.1 429 "), "{}", locked);
+        assert!(
+            locked.to_ascii_lowercase().contains("retry-after: "),
+            "{}",
+            locked
+        );
+    }
+    assert!(other.contains(r#""Success":true"#), "{}", other);
+
+    // Both failures and both lockouts are audited
+    assert!(audit.starts_with("HTTP/1.1 200 "), "{}", audit);
+    assert_eq!(
+        audit.matches(r#""Outcome":"Failure""#).count(),
+        2,
+        "{}",
+        audit
+    );
+    assert_eq!(
+        audit.matches(r#""Outcome":"Locked""#).count(),
+        2,
+        "{}",
+        audit
+    );
+
+    // One lockout, and the two refused attempts, are exported
+    for series in [
+        "maxreq_login_lockouts_total 1\n",
+        "maxreq_login_lockout_rejections_total 2\n",
+        "maxreq_login_locked 1\n",
+    ] {
+        assert!(metrics.contains(series), "{}", metrics);
+    }
+}
@@ -0,0 +1,110 @@
+//! TLS: the server speaks HTTPS with the configured certificate, offers
+//! HTTP/1.1 over ALPN and resumes sessions.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::{
+    ClientConfig, ClientConnection, HandshakeKind, RootCertStore, StreamOwned,
+};
+use user_token_core::{init_tracing, Config};
+
+/// The test certificates of user-token-core.
+fn fixture(name: &str) -> String {
+    format!(
+        "{}/../user-token-core/tests/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    )
+}
+
+/// Sends `GET path` over TLS on a fresh connection; returns the response and
+/// how the handshake went.
+fn get(
+    addr: SocketAddr,
+    client: &Arc<ClientConfig>,
+    path: &str,
+) -> (String, Option<Vec<u8>>, Option<HandshakeKind>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let connection =
+        ClientConnection::new(client.clone(), "localhost".try_into().unwrap()).unwrap();
+    let mut tls = StreamOwned::new(connection, stream);
+    write!(
+        tls,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .unwrap();
+    let mut response = Vec::new();
+    // Without close_notify from the server, the read ends with an error
+    let _ = tls.read_to_end(&mut response);
+    (
+        String::from_utf8_lossy(&response).into_owned(),
+        tls.conn.alpn_protocol().map(<[u8]>::to_vec),
+        tls.conn.handshake_kind(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn serves_https() {
+    // The server opens users.db in the working directory
+    let dir = std::env::temp_dir().join(format!("user-token-api-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    std::env::set_var("MAXREQ_TLS_CERT", fixture("server.pem"));
+    std::env::set_var("MAXREQ_TLS_KEY", fixture("server.key"));
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap())
+        .unwrap();
+    let mut client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let client = Arc::new(client);
+
+    let telemetry = init_tracing(&Config::from_env(), "user-token-api").unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = tokio::task::spawn_blocking(move || {
+        let first = get(addr, &client, "/health/live");
+        let resumed = get(addr, &client, "/health/ready");
+
+        // Plain HTTP gets no HTTP response
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+            .write_all(b"GET /health/live HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut plain = Vec::new();
+        let _ = stream.read_to_end(&mut plain);
+        (first, resumed, plain)
+    });
+    let (first, resumed, plain) = tokio::select! {
+        result = user_token_api::serve(listener, &telemetry, std::future::pending()) => {
+            panic!("server stopped: {:?}", result)
+        }
+        requests = requests => requests.unwrap(),
+    };
+
+    let (response, alpn, handshake) = first;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+    assert_eq!(handshake, Some(HandshakeKind::Full));
+    let (response, _, handshake) = resumed;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert_eq!(handshake, Some(HandshakeKind::Resumed));
+    assert!(!plain.starts_with(b"HTTP/1.1"), "{:?}", plain);
+}
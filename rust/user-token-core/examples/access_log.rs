@@ -0,0 +1,78 @@
+//! Cost of the sampled access log on the request path.
+//!
+//! Runs `OPS` simulated requests (default 2,000,000) at sampling ratios of
+//! 0%, 1% and 100%, doing what the servers do for each: the sampling draw
+//! and, for sampled requests, building and queueing the record. Reports the
+//! request-path cost, then how long the writer took to drain and what it
+//! wrote. At 100% the loop queues records faster than one writer thread
+//! serializes them, so the queue fills and drops; `lines/s` is the writer's
+//! own rate:
+//!
+//! ```bash
+//! cargo run --release --example access_log [OPS]
+//! ```
+
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use user_token_core::{AccessLog, AccessLogConfig, AccessRecord};
+
+const RATIOS: [f64; 3] = [0.0, 0.01, 1.0];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let ops: usize = match std::env::args().nth(1) {
+        Some(count) => count.parse()?,
+        None => 2_000_000,
+    };
+
+    println!(
+        "{:>6}  {:>10}  {:>10}  {:>10}  {:>8}  {:>12}  {:>10}",
+        "sample", "ns/request", "written", "dropped", "drain", "lines/s", "file"
+    );
+    for ratio in RATIOS {
+        let path =
+            std::env::temp_dir().join(format!("access-log-{}-{}.jsonl", std::process::id(), ratio));
+        let config = AccessLogConfig {
+            path: path.to_string_lossy().into_owned(),
+            sample_ratio: ratio,
+            queue_capacity: 65536,
+            flush_interval: Duration::from_millis(1000),
+        };
+        let log = AccessLog::start(&config)?;
+
+        let start = Instant::now();
+        for i in 0..ops {
+            if log.sampled() {
+                let mut record = AccessRecord::new(
+                    "POST",
+                    "/api/auth/get-user-token",
+                    "/api/auth/get-user-token",
+                    200,
+                    Duration::from_micros(i as u64 % 500),
+                );
+                record.client_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)));
+                record.user_agent = Some("rust-mini/1.0".to_string());
+                log.record(record);
+            }
+            black_box(i);
+        }
+        let elapsed = start.elapsed();
+
+        let drain = Instant::now();
+        let stats = log.close();
+        let drain = drain.elapsed();
+        let size = std::fs::metadata(&path)?.len();
+        std::fs::remove_file(&path)?;
+        println!(
+            "{:>5}%  {:>10.1}  {:>10}  {:>10}  {:>6}ms  {:>12.0}  {:>8}KB",
+            ratio * 100.0,
+            elapsed.as_nanos() as f64 / ops as f64,
+            stats.written,
+            stats.dropped,
+            drain.as_millis(),
+            stats.written as f64 / (elapsed + drain).as_secs_f64(),
+            size / 1024
+        );
+    }
+    Ok(())
+}
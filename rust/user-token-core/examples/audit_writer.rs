@@ -0,0 +1,76 @@
+//! Throughput of the batching audit writer.
+//!
+//! Queues `EVENTS` login events (default 200,000) into a fresh database for a
+//! few batch sizes, and reports the cost of `AuditLog::record` on the request
+//! path and the end-to-end write rate:
+//!
+//! ```bash
+//! cargo run --release --example audit_writer [EVENTS]
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use user_token_core::{AuditConfig, AuditLog, AuthEvent, AuthOutcome, UserStore};
+
+const BATCH_SIZES: [usize; 5] = [1, 16, 64, 256, 1024];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let events: usize = match std::env::args().nth(1) {
+        Some(count) => count.parse()?,
+        None => 200_000,
+    };
+
+    println!(
+        "{:>6}  {:>12}  {:>14}  {:>12}  {:>8}",
+        "batch", "record ns/op", "written ev/s", "transactions", "dropped"
+    );
+    for batch_size in BATCH_SIZES {
+        let path = std::env::temp_dir().join(format!(
+            "audit-writer-{}-{}.db",
+            std::process::id(),
+            batch_size
+        ));
+        let path = path.to_string_lossy().into_owned();
+        let store = UserStore::open(&path, 1)?;
+        let config = AuditConfig {
+            batch_size,
+            flush_interval: Duration::from_millis(200),
+            // Room for every event: the run measures the writer, not the drops
+            queue_capacity: events,
+        };
+
+        let audit = AuditLog::start(&config, &store)?;
+        let start = Instant::now();
+        for i in 0..events {
+            let mut event = AuthEvent::new(
+                Some(format!("user{}@example.com", i % 10_000)),
+                if i % 10 == 0 {
+                    AuthOutcome::Failure
+                } else {
+                    AuthOutcome::Success
+                },
+            );
+            event.client_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8)));
+            event.user_agent = Some("audit-writer-bench/1.0".to_string());
+            audit.record(event);
+        }
+        let recorded = start.elapsed();
+        let stats = audit.close();
+        let written = start.elapsed();
+
+        println!(
+            "{:>6}  {:>12.0}  {:>14.0}  {:>12}  {:>8}",
+            batch_size,
+            recorded.as_nanos() as f64 / events as f64,
+            stats.written as f64 / written.as_secs_f64(),
+            stats.batches,
+            stats.dropped
+        );
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+    Ok(())
+}
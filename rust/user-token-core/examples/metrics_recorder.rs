@@ -0,0 +1,83 @@
+//! Cost of recording what `/metrics` reports.
+//!
+//! Each thread records `OPS` logins (default 5,000,000) the way the servers
+//! do: the clock read around the request, the route count and latency, and
+//! the login outcome. Run with a few thread counts to see the contention on
+//! the shared counters:
+//!
+//! ```bash
+//! cargo run --release --example metrics_recorder [OPS]
+//! ```
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use user_token_core::{AuthOutcome, Metrics, UserStore};
+
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let ops: usize = match std::env::args().nth(1) {
+        Some(count) => count.parse()?,
+        None => 5_000_000,
+    };
+
+    // Baseline: the two clock reads every recorded request pays for
+    let start = Instant::now();
+    for _ in 0..ops {
+        black_box(Instant::now().elapsed());
+    }
+    let clock = start.elapsed().as_nanos() as f64 / ops as f64;
+    println!("clock reads alone: {:.1} ns/op", clock);
+
+    println!("{:>7}  {:>10}  {:>14}", "threads", "ns/op", "total ops/s");
+    for threads in THREADS {
+        let metrics = Arc::new(Metrics::new());
+        let start = Instant::now();
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let metrics = metrics.clone();
+                std::thread::spawn(move || {
+                    for i in 0..ops {
+                        let request = Instant::now();
+                        metrics.record_login(if i % 10 == 0 {
+                            AuthOutcome::Failure
+                        } else {
+                            AuthOutcome::Success
+                        });
+                        metrics.observe_request(
+                            "/api/auth/get-user-token",
+                            200,
+                            request.elapsed() + Duration::from_micros(i as u64 % 500),
+                        );
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().expect("worker panicked");
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:>7}  {:>10.1}  {:>14.0}",
+            threads,
+            elapsed.as_nanos() as f64 / ops as f64,
+            (ops * threads) as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    // Rendering is per scrape, not per request, but should stay small
+    let path = std::env::temp_dir().join(format!("metrics-recorder-{}.db", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let metrics = Arc::new(Metrics::new());
+    let store = UserStore::open(&path, 1)?.with_metrics(&metrics);
+    metrics.observe_request("/api/auth/get-user-token", 200, Duration::from_micros(80));
+    let start = Instant::now();
+    let body = metrics.render(&store, None);
+    println!("render: {} bytes in {:?}", body.len(), start.elapsed());
+    drop(store);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use serde::Serialize;
+use std::cell::Cell;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+use crate::audit::{flush_queue, now_ms, Queued};
+use crate::config::AccessLogConfig;
+
+/// Longest path and user agent kept; the rest is cut off.
+const MAX_FIELD_LEN: usize = 512;
+/// Bytes buffered in front of the file between flushes.
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// One served request, written as a JSON line.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccessRecord {
+    /// Unix time in milliseconds at which the request arrived.
+    pub timestamp: i64,
+    pub method: String,
+    pub path: String,
+    /// Matched route pattern, as in the `route` label of `/metrics`.
+    pub route: String,
+    pub status: u16,
+    /// Time from the request reaching the router to the response headers.
+    pub duration_us: u64,
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessRecord {
+    /// A record of a request answered `elapsed` after it arrived; the servers
+    /// fill in the client afterwards.
+    pub fn new(method: &str, path: &str, route: &str, status: u16, elapsed: Duration) -> Self {
+        AccessRecord {
+            timestamp: now_ms() - elapsed.as_millis() as i64,
+            method: method.to_string(),
+            path: truncated(path).to_string(),
+            route: route.to_string(),
+            status,
+            duration_us: u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+            client_ip: None,
+            user_agent: None,
+        }
+    }
+}
+
+fn truncated(value: &str) -> &str {
+    if value.len() <= MAX_FIELD_LEN {
+        return value;
+    }
+    let mut end = MAX_FIELD_LEN;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Counters of `AccessLog`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccessLogStats {
+    pub enabled: bool,
+    pub written: u64,
+    /// Records thrown away because the queue was full.
+    pub dropped: u64,
+    /// Records lost because the file could not be written.
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+thread_local! {
+    /// xorshift64 state of the sampler, seeded per thread.
+    static SAMPLER: Cell<u64> = Cell::new(getrandom::u64().unwrap_or(0x9E37_79B9_7F4A_7C15) | 1);
+}
+
+/// Asynchronous, sampled access log in JSON lines.
+///
+/// The servers ask [`AccessLog::sampled`] first, which is a thread-local
+/// random draw, and only build an [`AccessRecord`] for the requests it keeps.
+/// [`AccessLog::record`] pushes onto a bounded queue and never blocks the
+/// request: when the queue is full the record is dropped and counted. A
+/// background thread serializes the records into a buffer in front of the
+/// file, which it flushes at most `flush_interval` after the first
+/// unflushed line, or when the buffer is full.
+pub struct AccessLog {
+    /// Draws below this are sampled: `0` = none, `u64::MAX` = all.
+    threshold: u64,
+    sender: Option<SyncSender<Queued<AccessRecord>>>,
+    writer: Option<JoinHandle<()>>,
+    counters: Arc<Counters>,
+}
+
+impl AccessLog {
+    /// Opens `config.path` for appending and starts the writer thread.
+    pub fn start(config: &AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let (sender, receiver) = mpsc::sync_channel::<Queued<AccessRecord>>(config.queue_capacity);
+        let counters = Arc::new(Counters::default());
+
+        let (flush_interval, thread_counters) = (config.flush_interval, counters.clone());
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                let mut out = BufWriter::with_capacity(WRITE_BUFFER, file);
+                // Lines since the last flush, and when they are due
+                let mut pending = 0;
+                let mut deadline: Option<Instant> = None;
+                loop {
+                    let received = match deadline {
+                        Some(deadline) => receiver
+                            .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    let record = match received {
+                        Ok(Queued::Item(record)) => Some(record),
+                        Ok(Queued::Flush(done)) => {
+                            flush(&mut out, pending, &thread_counters);
+                            pending = 0;
+                            deadline = None;
+                            let _ = done.send(());
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            flush(&mut out, pending, &thread_counters);
+                            return;
+                        }
+                    };
+                    if let Some(record) = record {
+                        let line = serde_json::to_writer(&mut out, &record)
+                            .map_err(io::Error::from)
+                            .and_then(|()| out.write_all(b"\n"));
+                        match line {
+                            Ok(()) => pending += 1,
+                            Err(_) => {
+                                thread_counters.failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        deadline.get_or_insert_with(|| Instant::now() + flush_interval);
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        flush(&mut out, pending, &thread_counters);
+                        pending = 0;
+                        deadline = None;
+                    }
+                }
+            })?;
+
+        Ok(AccessLog {
+            threshold: sample_threshold(config.sample_ratio),
+            sender: Some(sender),
+            writer: Some(writer),
+            counters,
+        })
+    }
+
+    /// Whether to log the current request; a `sample_ratio` share of calls
+    /// answer `true`.
+    pub fn sampled(&self) -> bool {
+        match self.threshold {
+            0 => false,
+            u64::MAX => true,
+            threshold => SAMPLER.with(|state| {
+                let mut x = state.get();
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                state.set(x);
+                x < threshold
+            }),
+        }
+    }
+
+    /// Queues a record for writing; drops it if the queue is full.
+    pub fn record(&self, mut record: AccessRecord) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Some(agent) = &mut record.user_agent {
+            agent.truncate(truncated(agent).len());
+        }
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) =
+            sender.try_send(Queued::Item(record))
+        {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> AccessLogStats {
+        AccessLogStats {
+            enabled: true,
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Waits until the records queued so far are written to the file, for a
+    /// server that cannot hand the log over to [`AccessLog::close`].
+    pub fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            flush_queue(sender);
+        }
+    }
+
+    /// Stops accepting records and waits until the queued ones are written.
+    pub fn close(mut self) -> AccessLogStats {
+        self.shutdown();
+        self.stats()
+    }
+
+    fn shutdown(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Writes the buffer out; its `pending` lines count as written or failed.
+fn flush(out: &mut BufWriter<std::fs::File>, pending: u64, counters: &Counters) {
+    match out.flush() {
+        Ok(()) => {
+            counters.written.fetch_add(pending, Ordering::Relaxed);
+        }
+        Err(e) => {
+            counters.failed.fetch_add(pending, Ordering::Relaxed);
+            error!("Failed to write {} access log lines: {}", pending, e);
+        }
+    }
+}
+
+fn sample_threshold(ratio: f64) -> u64 {
+    if ratio >= 1.0 {
+        u64::MAX
+    } else if ratio > 0.0 {
+        (ratio * u64::MAX as f64) as u64
+    } else {
+        0
+    }
+}
@@ -0,0 +1,215 @@
+use rusqlite::Connection;
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::config::MailConfig;
+use crate::error::StoreError;
+use crate::mfa::MfaError;
+use crate::store::{hash_password, User, UserStore};
+
+/// Longest address accepted, per RFC 5321 (path limit minus the angle brackets).
+pub const MAX_MAIL_LEN: usize = 254;
+/// Longest local part (before the `@`), per RFC 5321.
+pub const MAX_MAIL_LOCAL_LEN: usize = 64;
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+/// Checks that `mail` looks like a deliverable `local@domain` address.
+///
+/// This is the practical subset of RFC 5322 that real providers hand out:
+/// unquoted dot-atom local parts and an ASCII domain with at least two labels.
+pub fn validate_mail(mail: &str) -> Result<(), &'static str> {
+    if mail.is_empty() || mail.len() > MAX_MAIL_LEN {
+        return Err("mail must be between 1 and 254 characters");
+    }
+    let Some((local, domain)) = mail.rsplit_once('@') else {
+        return Err("mail must contain an @");
+    };
+
+    if local.is_empty() || local.len() > MAX_MAIL_LOCAL_LEN {
+        return Err("mail local part must be between 1 and 64 characters");
+    }
+    let local_char_ok = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-.".contains(c);
+    if !local.chars().all(local_char_ok)
+        || local.starts_with('.')
+        || local.ends_with('.')
+        || local.contains("..")
+    {
+        return Err("mail local part contains invalid characters");
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 {
+        return Err("mail domain must contain a dot");
+    }
+    let label_ok = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !labels.iter().all(|label| label_ok(label)) {
+        return Err("mail domain is invalid");
+    }
+    Ok(())
+}
+
+/// Normalises `mail` as `config` says, borrowing it when it is already normal.
+///
+/// Whitespace around the address is trimmed and the domain lowercased. The
+/// local part is lowercased and a non-ASCII domain converted to punycode only
+/// when enabled; a domain IDNA rejects is kept as is, so validation fails on it.
+pub fn normalize_mail<'a>(mail: &'a str, config: &MailConfig) -> Cow<'a, str> {
+    let mail = mail.trim();
+    let Some((local, domain)) = mail.rsplit_once('@') else {
+        return if config.lowercase && mail.chars().any(char::is_uppercase) {
+            Cow::Owned(mail.to_lowercase())
+        } else {
+            Cow::Borrowed(mail)
+        };
+    };
+
+    let lower_local = config.lowercase && local.chars().any(char::is_uppercase);
+    let idn = config.punycode && !domain.is_ascii();
+    if !lower_local && !idn && !domain.chars().any(char::is_uppercase) {
+        return Cow::Borrowed(mail);
+    }
+
+    let local = if lower_local {
+        Cow::Owned(local.to_lowercase())
+    } else {
+        Cow::Borrowed(local)
+    };
+    let domain = idn
+        .then(|| idna::domain_to_ascii(domain).ok())
+        .flatten()
+        .unwrap_or_else(|| domain.to_lowercase());
+    Cow::Owned(format!("{}@{}", local, domain))
+}
+
+/// Records the mail policy the stored mails were normalised with, so
+/// [`UserStore::with_mail_config`] only rewrites them when it changes.
+pub(crate) fn create_mail_policy(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE mail_policy (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            lowercase INTEGER NOT NULL,
+            punycode INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Password policy: 8 to 128 characters, with at least one letter and one digit.
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err("password must be between 8 and 128 characters");
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err("password must contain at least one letter and one digit");
+    }
+    Ok(())
+}
+
+/// Error returned by the account operations of [`UserStore`].
+#[derive(Debug)]
+pub enum AccountError {
+    /// The request was rejected by validation; carries the reason.
+    Invalid(&'static str),
+    /// Another account already uses this mail.
+    MailTaken,
+    /// No account matches the mail and current password.
+    InvalidCredentials,
+    /// The account has TOTP enabled and the code is missing, wrong or already used.
+    InvalidCode,
+    /// Too many wrong codes for this account; retry after `MFA_CHALLENGE_TTL`.
+    TooManyAttempts,
+    Store(StoreError),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Invalid(reason) => f.write_str(reason),
+            AccountError::MailTaken => f.write_str("a user with this mail already exists"),
+            AccountError::InvalidCredentials => f.write_str("invalid username or password"),
+            AccountError::InvalidCode => f.write_str("invalid or missing code"),
+            AccountError::TooManyAttempts => f.write_str("too many invalid codes"),
+            AccountError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AccountError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StoreError> for AccountError {
+    fn from(e: StoreError) -> Self {
+        AccountError::Store(e)
+    }
+}
+
+impl From<MfaError> for AccountError {
+    fn from(e: MfaError) -> Self {
+        match e {
+            MfaError::TooManyAttempts => AccountError::TooManyAttempts,
+            MfaError::Store(e) => AccountError::Store(e),
+            _ => AccountError::InvalidCode,
+        }
+    }
+}
+
+impl UserStore {
+    /// Creates an account from a plaintext password, hashed with the same
+    /// scheme the login route expects in `HashedPassword`.
+    pub fn register(&self, mail: &str, password: &str) -> Result<User, AccountError> {
+        let mail = &*self.normalize_mail(mail);
+        validate_mail(mail).map_err(AccountError::Invalid)?;
+        validate_password(password).map_err(AccountError::Invalid)?;
+
+        self.insert_user(mail, &hash_password(password))?
+            .ok_or(AccountError::MailTaken)
+    }
+
+    /// Replaces the password of `mail`. The caller proves it owns the account
+    /// with the current `hashed_password`, as sent to the login route, and
+    /// with a TOTP `code` when the account has TOTP enabled; the new password
+    /// is plaintext so the policy can be enforced.
+    pub fn change_password(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+        new_password: &str,
+        code: Option<&str>,
+    ) -> Result<User, AccountError> {
+        validate_password(new_password).map_err(AccountError::Invalid)?;
+
+        let mail = &*self.normalize_mail(mail);
+        self.verify_account_code(mail, hashed_password, code)?;
+        self.update_password(mail, hashed_password, &hash_password(new_password))?
+            .ok_or(AccountError::InvalidCredentials)
+    }
+
+    /// Deletes the account of `mail`, authenticated with its current
+    /// `hashed_password` and, when it has TOTP enabled, a `code`.
+    pub fn delete_account(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+        code: Option<&str>,
+    ) -> Result<User, AccountError> {
+        let mail = &*self.normalize_mail(mail);
+        self.verify_account_code(mail, hashed_password, code)?;
+        self.delete_user(mail, hashed_password)?
+            .ok_or(AccountError::InvalidCredentials)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::Config;
+
+/// Outcome of checking a request against the admin credential.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAccess {
+    Granted,
+    /// Missing or wrong `Authorization: Bearer <token>` header (401).
+    Unauthorized,
+    /// No admin token is configured and benchmark mode is off (403).
+    Disabled,
+}
+
+/// Gate in front of the destructive `/admin/*` routes.
+///
+/// Requests must carry `Authorization: Bearer <MAXREQ_ADMIN_TOKEN>`. Without a
+/// configured token the routes are closed, unless the server runs in
+/// benchmark mode, which opts back into unauthenticated access.
+#[derive(Debug, Clone)]
+pub struct AdminGuard {
+    token: Option<String>,
+    open: bool,
+}
+
+impl AdminGuard {
+    pub fn new(config: &Config) -> Self {
+        AdminGuard {
+            token: config.admin_token.clone(),
+            open: config.benchmark_mode,
+        }
+    }
+
+    /// Checks the raw value of the `Authorization` header.
+    pub fn check(&self, authorization: Option<&str>) -> AdminAccess {
+        if self.open {
+            return AdminAccess::Granted;
+        }
+        let Some(expected) = &self.token else {
+            return AdminAccess::Disabled;
+        };
+        match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(presented)
+                if constant_time_eq(presented.trim().as_bytes(), expected.as_bytes()) =>
+            {
+                AdminAccess::Granted
+            }
+            _ => AdminAccess::Unauthorized,
+        }
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
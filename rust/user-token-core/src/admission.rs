@@ -0,0 +1,305 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use crate::config::AdmissionConfig;
+
+/// `Retry-After`, in seconds, of the `503` answered to a shed request.
+pub const SHED_RETRY_AFTER_SECS: u64 = 1;
+
+/// Releases the baseline latency is the minimum of.
+const BASELINE_WINDOW: u32 = 100;
+/// A release slower than this many baselines (plus `LATENCY_SLACK`) shrinks the limit.
+const LATENCY_TOLERANCE: u32 = 2;
+/// Keeps jitter on sub-millisecond requests from reading as congestion.
+const LATENCY_SLACK: Duration = Duration::from_millis(1);
+/// Factor applied to the limit on congestion, at most once per round of `limit` releases.
+const DECREASE_FACTOR: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AdmissionStats {
+    pub enabled: bool,
+    pub adaptive: bool,
+    /// Requests let through at once right now; `MaxInFlight` unless adaptive.
+    pub limit: u64,
+    pub max_in_flight: u64,
+    pub in_flight: u64,
+    pub queued: u64,
+    /// Requests admitted since the server started.
+    pub admitted: u64,
+    /// Requests answered `503` at once because the queue was full.
+    pub rejected: u64,
+    /// Requests answered `503` after waiting the whole queue budget.
+    pub expired: u64,
+}
+
+struct State {
+    in_flight: usize,
+    /// Current limit; fractional so the additive increase can be `1 / limit`.
+    limit: f64,
+    /// Waiting requests, oldest first.
+    queue: VecDeque<(u64, Waker)>,
+    next_ticket: u64,
+    /// Minimum latency of the previous window: the latency of an idle server.
+    baseline: Option<Duration>,
+    window_min: Duration,
+    window_len: u32,
+    /// Releases since the limit last shrank.
+    since_decrease: usize,
+}
+
+impl State {
+    /// Slots a queued request may take: the queue is served in order.
+    fn free_slots(&self) -> usize {
+        (self.limit as usize).saturating_sub(self.in_flight)
+    }
+
+    /// Wakers of the queued requests that now fit, woken once the lock is released.
+    fn runnable(&self) -> Vec<Waker> {
+        self.queue
+            .iter()
+            .take(self.free_slots())
+            .map(|(_, waker)| waker.clone())
+            .collect()
+    }
+
+    /// AIMD on the latency of a finished request: shrink on congestion, grow
+    /// by one per round of releases while the limit is being used.
+    fn adapt(&mut self, latency: Duration, overloaded: bool, max_in_flight: usize) {
+        self.window_min = self.window_min.min(latency);
+        self.window_len += 1;
+        if self.window_len >= BASELINE_WINDOW {
+            self.baseline = Some(self.window_min);
+            self.window_min = Duration::MAX;
+            self.window_len = 0;
+        }
+        let baseline = *self.baseline.get_or_insert(latency);
+
+        self.since_decrease += 1;
+        let congested = overloaded || latency > baseline * LATENCY_TOLERANCE + LATENCY_SLACK;
+        if congested {
+            if self.since_decrease >= self.limit as usize {
+                self.limit = (self.limit * DECREASE_FACTOR).max(1.0);
+                self.since_decrease = 0;
+            }
+        } else if self.in_flight + 1 >= self.limit as usize {
+            self.limit = (self.limit + 1.0 / self.limit).min(max_in_flight as f64);
+        }
+    }
+}
+
+/// Admission control in front of the request handlers.
+///
+/// At most `limit` requests run at once. The others wait in a FIFO queue of
+/// `max_queued` entries for up to `queue_timeout`, then get `503`; a request
+/// finding the queue full gets it at once. With `adaptive`, the limit moves
+/// between 1 and `max_in_flight` (AIMD): it shrinks when requests take more
+/// than twice the idle latency or report an overloaded store, and grows back
+/// while they do not.
+///
+/// [`acquire`](Self::acquire) is a future for the async servers, which bound
+/// it with their own timer; [`acquire_blocking`](Self::acquire_blocking)
+/// parks the calling thread instead.
+pub struct Admission {
+    config: AdmissionConfig,
+    state: Mutex<State>,
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl Admission {
+    pub fn new(config: &AdmissionConfig) -> Self {
+        let config = AdmissionConfig {
+            max_in_flight: config.max_in_flight.max(1),
+            ..config.clone()
+        };
+        Admission {
+            state: Mutex::new(State {
+                in_flight: 0,
+                limit: config.max_in_flight as f64,
+                queue: VecDeque::new(),
+                next_ticket: 0,
+                baseline: None,
+                window_min: Duration::MAX,
+                window_len: 0,
+                since_decrease: 0,
+            }),
+            config,
+            admitted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+        }
+    }
+
+    /// Routes subject to admission: the `/api` ones. Probes, metrics and admin
+    /// routes must still answer when the API is shedding.
+    pub fn applies_to(path: &str) -> bool {
+        path.starts_with("/api/") && path != "/api/auth/health"
+    }
+
+    /// How long a request may wait in the queue.
+    pub fn queue_timeout(&self) -> Duration {
+        self.config.queue_timeout
+    }
+
+    /// Waits for a slot. Resolves to `None` when the queue is full; dropping
+    /// it while queued, when `queue_timeout` is up, counts as expired.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            admission: self,
+            ticket: None,
+        }
+    }
+
+    /// [`acquire`](Self::acquire) for thread-per-connection servers: parks
+    /// for up to `queue_timeout`, `None` when the request is shed.
+    pub fn acquire_blocking(&self) -> Option<Permit<'_>> {
+        let deadline = Instant::now() + self.config.queue_timeout;
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut acquire = self.acquire();
+        loop {
+            if let Poll::Ready(permit) = Pin::new(&mut acquire).poll(&mut cx) {
+                return permit;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        let state = self.state.lock().unwrap();
+        AdmissionStats {
+            enabled: true,
+            adaptive: self.config.adaptive,
+            limit: state.limit as u64,
+            max_in_flight: self.config.max_in_flight as u64,
+            in_flight: state.in_flight as u64,
+            queued: state.queue.len() as u64,
+            admitted: self.admitted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    fn release(&self, latency: Duration, overloaded: bool) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.in_flight -= 1;
+            if self.config.adaptive {
+                state.adapt(latency, overloaded, self.config.max_in_flight);
+            }
+            state.runnable()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Future returned by [`Admission::acquire`].
+pub struct Acquire<'a> {
+    admission: &'a Admission,
+    /// Place in the queue, once the request had to wait.
+    ticket: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Option<Permit<'a>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let admission = self.admission;
+        let mut state = admission.state.lock().unwrap();
+        let position = match self.ticket {
+            Some(ticket) => state.queue.iter().position(|(t, _)| *t == ticket),
+            None => None,
+        };
+        // Only the oldest `free_slots` waiters may go; a newcomer waits behind them
+        let ahead = position.unwrap_or(state.queue.len());
+        if ahead < state.free_slots() {
+            if let Some(position) = position {
+                state.queue.remove(position);
+            }
+            state.in_flight += 1;
+            drop(state);
+            self.ticket = None;
+            admission.admitted.fetch_add(1, Ordering::Relaxed);
+            return Poll::Ready(Some(Permit {
+                admission,
+                started: Instant::now(),
+                overloaded: false,
+            }));
+        }
+
+        match position {
+            Some(position) => state.queue[position].1.clone_from(cx.waker()),
+            None if state.queue.len() >= admission.config.max_queued => {
+                drop(state);
+                admission.rejected.fetch_add(1, Ordering::Relaxed);
+                return Poll::Ready(None);
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.queue.push_back((ticket, cx.waker().clone()));
+                self.ticket = Some(ticket);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        let wakers = {
+            let mut state = self.admission.state.lock().unwrap();
+            state.queue.retain(|(t, _)| *t != ticket);
+            state.runnable()
+        };
+        self.admission.expired.fetch_add(1, Ordering::Relaxed);
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// A running request; dropping it frees the slot and, when adaptive, feeds
+/// its latency to the limit.
+pub struct Permit<'a> {
+    admission: &'a Admission,
+    started: Instant,
+    overloaded: bool,
+}
+
+impl Permit<'_> {
+    /// Reports that the request failed because the store was overloaded, a
+    /// congestion signal whatever its latency.
+    pub fn overloaded(&mut self) {
+        self.overloaded = true;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.admission
+            .release(self.started.elapsed(), self.overloaded);
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
@@ -0,0 +1,239 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{StoreError, StoreResult};
+use crate::session::new_secret;
+use crate::store::{hash_password, UserStore};
+
+/// Prefix of every key, so leaked keys are easy to grep for.
+const KEY_PREFIX: &str = "mrk_";
+pub const MAX_API_KEY_NAME_LEN: usize = 64;
+
+/// Calls the login itself: `get-user-token` and `mfa/verify`.
+pub const SCOPE_LOGIN: &str = "login";
+/// Refresh and logout.
+pub const SCOPE_SESSION: &str = "session";
+/// Every other `/api/auth` route: registration, password changes, deletion, MFA enrolment.
+pub const SCOPE_ACCOUNT: &str = "account";
+pub const API_KEY_SCOPES: [&str; 3] = [SCOPE_LOGIN, SCOPE_SESSION, SCOPE_ACCOUNT];
+
+/// Scope an API key needs to call `path`, `None` for routes outside `/api/auth`.
+pub fn required_scope(path: &str) -> Option<&'static str> {
+    match path {
+        "/api/auth/get-user-token" | "/api/auth/mfa/verify" => Some(SCOPE_LOGIN),
+        "/api/auth/refresh" | "/api/auth/logout" => Some(SCOPE_SESSION),
+        path if path.starts_with("/api/auth/") => Some(SCOPE_ACCOUNT),
+        _ => None,
+    }
+}
+
+/// An API key as listed by the admin routes; the key itself is never stored.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Unix time, `None` for keys that do not expire.
+    pub expires_at: Option<i64>,
+    pub revoked: bool,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// A freshly minted key: the only time the plaintext is available.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Outcome of checking a request against the API keys.
+#[derive(Debug)]
+pub enum ApiKeyAccess {
+    Granted(ApiKey),
+    /// No `Authorization: ApiKey <key>` header (401).
+    Missing,
+    /// Unknown, revoked or expired key (401).
+    Invalid,
+    /// Valid key without the scope of the route (403).
+    Forbidden,
+}
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    /// The mint request was rejected by validation; carries the reason.
+    Invalid(&'static str),
+    Store(StoreError),
+}
+
+impl fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiKeyError::Invalid(reason) => f.write_str(reason),
+            ApiKeyError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ApiKeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiKeyError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Into<StoreError>> From<E> for ApiKeyError {
+    fn from(e: E) -> Self {
+        ApiKeyError::Store(e.into())
+    }
+}
+
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_key (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            expires_at INTEGER,
+            revoked INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn api_key_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: row
+            .get::<_, String>(2)?
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+        expires_at: row.get(3)?,
+        revoked: row.get(4)?,
+    })
+}
+
+impl UserStore {
+    /// Mints a key for `name` with the given scopes, valid for `ttl` (or forever).
+    pub fn create_api_key(
+        &self,
+        name: &str,
+        scopes: &[String],
+        ttl: Option<Duration>,
+    ) -> Result<NewApiKey, ApiKeyError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LEN {
+            return Err(ApiKeyError::Invalid(
+                "name must be between 1 and 64 characters",
+            ));
+        }
+        if scopes.is_empty() {
+            return Err(ApiKeyError::Invalid("at least one scope is required"));
+        }
+        if !scopes.iter().all(|s| API_KEY_SCOPES.contains(&s.as_str())) {
+            return Err(ApiKeyError::Invalid(
+                "scopes must be among login, session and account",
+            ));
+        }
+        let mut scopes = scopes.to_vec();
+        scopes.sort();
+        scopes.dedup();
+
+        let key = format!("{}{}", KEY_PREFIX, new_secret());
+        let expires_at = ttl.map(|ttl| now().saturating_add(ttl.as_secs() as i64));
+        let conn = self.writer()?;
+        let id = conn
+            .prepare_cached(
+                "INSERT INTO api_key (name, key_hash, scopes, expires_at) VALUES (?1, ?2, ?3, ?4) RETURNING id",
+            )?
+            .query_row(
+                (name, hash_password(&key), scopes.join(" "), expires_at),
+                |row| row.get(0),
+            )?;
+
+        Ok(NewApiKey {
+            api_key: ApiKey {
+                id,
+                name: name.to_owned(),
+                scopes,
+                expires_at,
+                revoked: false,
+            },
+            key,
+        })
+    }
+
+    /// Revokes a key; returns whether it existed. Revoked keys stay listed.
+    pub fn revoke_api_key(&self, id: i64) -> StoreResult<bool> {
+        let conn = self.writer()?;
+        let updated = conn
+            .prepare_cached("UPDATE api_key SET revoked = 1 WHERE id = ?1")?
+            .execute([id])?;
+        Ok(updated > 0)
+    }
+
+    pub fn list_api_keys(&self) -> StoreResult<Vec<ApiKey>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, scopes, expires_at, revoked FROM api_key ORDER BY id",
+        )?;
+        let keys = stmt
+            .query_map([], api_key_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(keys)
+    }
+
+    /// Looks a presented key up by its hash, through the read pool like a login.
+    /// Revoked and expired keys are not returned.
+    pub fn authenticate_api_key(&self, key: &str) -> StoreResult<Option<ApiKey>> {
+        if !key.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, scopes, expires_at, revoked FROM api_key
+             WHERE key_hash = ?1 AND revoked = 0 AND (expires_at IS NULL OR expires_at > ?2)",
+        )?;
+        let key = stmt
+            .query_row((hash_password(key), now()), api_key_from_row)
+            .optional()?;
+        Ok(key)
+    }
+
+    /// Checks the raw value of the `Authorization` header against `scope`.
+    pub fn check_api_key(
+        &self,
+        authorization: Option<&str>,
+        scope: &str,
+    ) -> StoreResult<ApiKeyAccess> {
+        let Some(presented) = authorization.and_then(|value| value.strip_prefix("ApiKey ")) else {
+            return Ok(ApiKeyAccess::Missing);
+        };
+        Ok(match self.authenticate_api_key(presented.trim())? {
+            Some(key) if key.allows(scope) => ApiKeyAccess::Granted(key),
+            Some(_) => ApiKeyAccess::Forbidden,
+            None => ApiKeyAccess::Invalid,
+        })
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
@@ -0,0 +1,440 @@
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::config::AuditConfig;
+use crate::error::StoreResult;
+use crate::mfa::MfaError;
+use crate::store::UserStore;
+use crate::throttle::LoginOutcome;
+
+/// Longest user agent kept; the rest is cut off.
+const MAX_USER_AGENT_LEN: usize = 512;
+/// Page size of `/admin/audit` when `limit` is not given, and its upper bound.
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthOutcome {
+    Success,
+    /// Wrong mail, password or code.
+    Failure,
+    /// Password accepted, waiting for a TOTP code.
+    MfaRequired,
+    /// Refused by the login throttle.
+    Locked,
+    /// The attempt could not be checked (database error).
+    Error,
+}
+
+impl AuthOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthOutcome::Success => "Success",
+            AuthOutcome::Failure => "Failure",
+            AuthOutcome::MfaRequired => "MfaRequired",
+            AuthOutcome::Locked => "Locked",
+            AuthOutcome::Error => "Error",
+        }
+    }
+}
+
+impl fmt::Display for AuthOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuthOutcome {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            AuthOutcome::Success,
+            AuthOutcome::Failure,
+            AuthOutcome::MfaRequired,
+            AuthOutcome::Locked,
+            AuthOutcome::Error,
+        ]
+        .into_iter()
+        .find(|outcome| outcome.as_str() == value)
+        .ok_or("unknown outcome")
+    }
+}
+
+impl From<&MfaError> for AuthOutcome {
+    fn from(e: &MfaError) -> Self {
+        match e {
+            MfaError::TooManyAttempts => AuthOutcome::Locked,
+            MfaError::Store(_) => AuthOutcome::Error,
+            _ => AuthOutcome::Failure,
+        }
+    }
+}
+
+impl From<LoginOutcome> for AuthOutcome {
+    fn from(outcome: LoginOutcome) -> Self {
+        match outcome {
+            LoginOutcome::Authenticated => AuthOutcome::Success,
+            LoginOutcome::Rejected => AuthOutcome::Failure,
+        }
+    }
+}
+
+/// One authentication attempt, as written to the `auth_event` table.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuthEvent {
+    /// Unix time in milliseconds.
+    pub timestamp: i64,
+    pub mail: Option<String>,
+    pub outcome: AuthOutcome,
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl AuthEvent {
+    /// An event stamped now; the servers fill in the client afterwards.
+    pub fn new(mail: Option<String>, outcome: AuthOutcome) -> Self {
+        AuthEvent {
+            timestamp: now_ms(),
+            mail,
+            outcome,
+            client_ip: None,
+            user_agent: None,
+        }
+    }
+}
+
+/// A stored event, as listed by `/admin/audit`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditRecord {
+    pub id: i64,
+    #[serde(flatten)]
+    pub event: AuthEvent,
+}
+
+/// Filters of `/admin/audit`, all optional; parsed from its query string.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub mail: Option<String>,
+    pub outcome: Option<AuthOutcome>,
+    pub ip: Option<String>,
+    /// Unix milliseconds, inclusive.
+    pub since: Option<i64>,
+    /// Unix milliseconds, exclusive.
+    pub until: Option<i64>,
+    /// Cursor: only events with a smaller id (the `NextBefore` of the previous page).
+    pub before: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+/// A page of events, newest first.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditPage {
+    pub events: Vec<AuditRecord>,
+    /// `before` of the next page, `None` on the last one.
+    pub next_before: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditStats {
+    pub enabled: bool,
+    pub written: u64,
+    /// Events thrown away because the queue was full.
+    pub dropped: u64,
+    /// Events lost because their batch could not be written.
+    pub failed: u64,
+    pub batches: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    batches: AtomicU64,
+}
+
+/// What the background writers receive: an item to write, or a request to
+/// write out everything queued before it and answer on the channel.
+pub(crate) enum Queued<T> {
+    Item(T),
+    Flush(SyncSender<()>),
+}
+
+/// Asks the writer behind `sender` to flush and waits until it has.
+pub(crate) fn flush_queue<T>(sender: &SyncSender<Queued<T>>) {
+    let (done, flushed) = mpsc::sync_channel(1);
+    if sender.send(Queued::Flush(done)).is_ok() {
+        let _ = flushed.recv();
+    }
+}
+
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS auth_event (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            mail TEXT,
+            outcome TEXT NOT NULL,
+            client_ip TEXT,
+            user_agent TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_auth_event_mail ON auth_event(mail, id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_auth_event_timestamp ON auth_event(timestamp)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Asynchronous, batched writer of [`AuthEvent`]s.
+///
+/// [`AuditLog::record`] only pushes onto a bounded queue and never blocks the
+/// request: when the queue is full the event is dropped and counted. A
+/// background thread drains the queue and writes up to `batch_size` events
+/// per transaction on the store's writer, waiting at most `flush_interval`
+/// for a batch to fill.
+pub struct AuditLog {
+    sender: Option<SyncSender<Queued<AuthEvent>>>,
+    writer: Option<JoinHandle<()>>,
+    counters: Arc<Counters>,
+}
+
+impl AuditLog {
+    pub fn start(config: &AuditConfig, store: &UserStore) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Queued<AuthEvent>>(config.queue_capacity);
+        let counters = Arc::new(Counters::default());
+
+        let (store, config, thread_counters) = (store.clone(), config.clone(), counters.clone());
+        let writer = std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || {
+                let mut batch = Vec::with_capacity(config.batch_size);
+                // Block until an event arrives, then give the batch a chance to fill
+                while let Ok(first) = receiver.recv() {
+                    match first {
+                        Queued::Item(event) => batch.push(event),
+                        // Earlier batches are written already
+                        Queued::Flush(done) => {
+                            let _ = done.send(());
+                            continue;
+                        }
+                    }
+                    let deadline = Instant::now() + config.flush_interval;
+                    let mut flushed = None;
+                    while batch.len() < config.batch_size {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        match receiver.recv_timeout(wait) {
+                            Ok(Queued::Item(event)) => batch.push(event),
+                            Ok(Queued::Flush(done)) => {
+                                flushed = Some(done);
+                                break;
+                            }
+                            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                                break
+                            }
+                        }
+                    }
+
+                    let count = batch.len() as u64;
+                    match store.insert_auth_events(&batch) {
+                        Ok(()) => {
+                            thread_counters.written.fetch_add(count, Ordering::Relaxed);
+                            thread_counters.batches.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            thread_counters.failed.fetch_add(count, Ordering::Relaxed);
+                            error!("Failed to write {} audit events: {}", count, e);
+                        }
+                    }
+                    batch.clear();
+                    if let Some(done) = flushed {
+                        let _ = done.send(());
+                    }
+                }
+            })?;
+
+        Ok(AuditLog {
+            sender: Some(sender),
+            writer: Some(writer),
+            counters,
+        })
+    }
+
+    /// Queues an event for writing; drops it if the queue is full.
+    pub fn record(&self, mut event: AuthEvent) {
+        if let Some(agent) = &mut event.user_agent {
+            if agent.len() > MAX_USER_AGENT_LEN {
+                let mut end = MAX_USER_AGENT_LEN;
+                while !agent.is_char_boundary(end) {
+                    end -= 1;
+                }
+                agent.truncate(end);
+            }
+        }
+        let Some(sender) = &self.sender else { return };
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) =
+            sender.try_send(Queued::Item(event))
+        {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> AuditStats {
+        AuditStats {
+            enabled: true,
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            batches: self.counters.batches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Waits until the events queued so far are written, for a server that
+    /// cannot hand the log over to [`AuditLog::close`].
+    pub fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            flush_queue(sender);
+        }
+    }
+
+    /// Stops accepting events and waits until the queued ones are written.
+    pub fn close(mut self) -> AuditStats {
+        self.shutdown();
+        self.stats()
+    }
+
+    fn shutdown(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl UserStore {
+    /// Writes a batch of events in one transaction on the writer.
+    pub(crate) fn insert_auth_events(&self, events: &[AuthEvent]) -> StoreResult<()> {
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO auth_event (timestamp, mail, outcome, client_ip, user_agent) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for event in events {
+                // Normalised here rather than on the request path
+                let mail = event.mail.as_deref().map(|mail| self.normalize_mail(mail));
+                stmt.execute((
+                    event.timestamp,
+                    mail.as_deref(),
+                    event.outcome.as_str(),
+                    event.client_ip.map(|ip| ip.to_string()),
+                    &event.user_agent,
+                ))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Reads a page of events matching `query`, newest first, from the read pool.
+    pub fn audit_events(&self, query: &AuditQuery) -> StoreResult<AuditPage> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let mut sql = String::from(
+            "SELECT id, timestamp, mail, outcome, client_ip, user_agent FROM auth_event WHERE 1 = 1",
+        );
+        let mut params: Vec<Value> = Vec::new();
+        let mut filter = |clause: &str, value: Value| {
+            params.push(value);
+            sql.push_str(&format!(" AND {} ?{}", clause, params.len()));
+        };
+        if let Some(mail) = &query.mail {
+            filter(
+                "mail =",
+                Value::Text(self.normalize_mail(mail).into_owned()),
+            );
+        }
+        if let Some(outcome) = query.outcome {
+            filter("outcome =", Value::Text(outcome.as_str().to_owned()));
+        }
+        if let Some(ip) = &query.ip {
+            filter("client_ip =", Value::Text(ip.clone()));
+        }
+        if let Some(since) = query.since {
+            filter("timestamp >=", Value::Integer(since));
+        }
+        if let Some(until) = query.until {
+            filter("timestamp <", Value::Integer(until));
+        }
+        if let Some(before) = query.before {
+            filter("id <", Value::Integer(before));
+        }
+        // One extra row tells whether there is a next page
+        sql.push_str(&format!(" ORDER BY id DESC LIMIT {}", limit + 1));
+
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let mut events = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                let outcome: String = row.get(3)?;
+                let client_ip: Option<String> = row.get(4)?;
+                Ok(AuditRecord {
+                    id: row.get(0)?,
+                    event: AuthEvent {
+                        timestamp: row.get(1)?,
+                        mail: row.get(2)?,
+                        outcome: outcome.parse().unwrap_or(AuthOutcome::Error),
+                        client_ip: client_ip.and_then(|ip| ip.parse().ok()),
+                        user_agent: row.get(5)?,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_before = if events.len() > limit as usize {
+            events.truncate(limit as usize);
+            events.last().map(|record| record.id)
+        } else {
+            None
+        };
+        Ok(AuditPage {
+            events,
+            next_before,
+        })
+    }
+}
+
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
@@ -0,0 +1,643 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::mem;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::info;
+
+use crate::account::validate_mail;
+use crate::error::{StoreError, StoreResult};
+use crate::session;
+use crate::store::{hash_password, UserStore};
+
+/// Rows committed per transaction when `chunk` is not given, and its upper bound.
+pub const DEFAULT_IMPORT_CHUNK: usize = 10_000;
+pub const MAX_IMPORT_CHUNK: usize = 100_000;
+/// Longest line accepted by an import; longer ones are rejected as a row error.
+pub const MAX_IMPORT_LINE_LEN: usize = 64 * 1024;
+/// Row errors kept in an [`ImportReport`]; the rest are only counted.
+pub const MAX_REPORTED_ERRORS: usize = 1000;
+/// Rows read per query by an export.
+const EXPORT_PAGE_SIZE: u32 = 10_000;
+
+/// Wire format of `/admin/import-users` and `/admin/export-users`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkFormat {
+    /// A `Mail,HashedPassword` (or `Password`) header, then one row per line.
+    #[default]
+    Csv,
+    /// One `{"Mail":..,"HashedPassword":..}` (or `"Password"`) object per line.
+    Ndjson,
+}
+
+impl BulkFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "text/csv",
+            BulkFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+impl FromStr for BulkFormat {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(BulkFormat::Csv),
+            "ndjson" => Ok(BulkFormat::Ndjson),
+            _ => Err("format must be csv or ndjson"),
+        }
+    }
+}
+
+/// What an import does with the users already in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Keep them; a row whose mail is taken is a row error.
+    #[default]
+    Insert,
+    /// Keep them; a row whose mail is taken replaces that account's password
+    /// and revokes its sessions.
+    Upsert,
+    /// Delete every user and session in the first chunk, then insert.
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "insert" => Ok(ImportMode::Insert),
+            "upsert" => Ok(ImportMode::Upsert),
+            "replace" => Ok(ImportMode::Replace),
+            _ => Err("mode must be insert, upsert or replace"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportParams {
+    pub format: BulkFormat,
+    pub mode: ImportMode,
+    /// Rows committed per transaction.
+    pub chunk_size: usize,
+}
+
+impl Default for ImportParams {
+    fn default() -> Self {
+        ImportParams {
+            format: BulkFormat::default(),
+            mode: ImportMode::default(),
+            chunk_size: DEFAULT_IMPORT_CHUNK,
+        }
+    }
+}
+
+impl ImportParams {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.chunk_size == 0 || self.chunk_size > MAX_IMPORT_CHUNK {
+            return Err("chunk must be between 1 and 100000");
+        }
+        Ok(())
+    }
+}
+
+/// A row that was not imported.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RowError {
+    /// 1-based line of the input, header included.
+    pub line: u64,
+    pub mail: Option<String>,
+    pub error: String,
+}
+
+/// Outcome of an import, as returned by `/admin/import-users`.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImportReport {
+    /// Data rows read; blank lines and the CSV header are not counted.
+    pub rows: u64,
+    pub inserted: u64,
+    /// Existing accounts whose password was replaced (`upsert`).
+    pub updated: u64,
+    /// Existing accounts that already had this password (`upsert`).
+    pub unchanged: u64,
+    pub failed: u64,
+    /// Transactions committed.
+    pub chunks: u64,
+    pub elapsed_ms: u128,
+    /// The first [`MAX_REPORTED_ERRORS`] row errors, by line.
+    pub errors: Vec<RowError>,
+}
+
+/// Error that stops an import as a whole; chunks committed before it stay.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The input cannot be imported at all (bad CSV header); carries the reason.
+    Invalid(&'static str),
+    Store(StoreError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Invalid(reason) => f.write_str(reason),
+            ImportError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImportError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StoreError> for ImportError {
+    fn from(e: StoreError) -> Self {
+        ImportError::Store(e)
+    }
+}
+
+impl From<rusqlite::Error> for ImportError {
+    fn from(e: rusqlite::Error) -> Self {
+        ImportError::Store(e.into())
+    }
+}
+
+/// An NDJSON import line; CSV columns carry the same names.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ImportLine {
+    mail: Option<String>,
+    hashed_password: Option<String>,
+    password: Option<String>,
+}
+
+/// Positions of the known columns in a CSV header.
+struct CsvColumns {
+    mail: usize,
+    hashed_password: Option<usize>,
+    password: Option<usize>,
+}
+
+impl CsvColumns {
+    fn parse(header: &str) -> Result<Self, ImportError> {
+        let names = split_csv_line(header).map_err(ImportError::Invalid)?;
+        let find = |name: &str| {
+            names
+                .iter()
+                .position(|column| column.trim().eq_ignore_ascii_case(name))
+        };
+        let columns = CsvColumns {
+            mail: find("Mail").ok_or(ImportError::Invalid(MISSING_COLUMNS))?,
+            hashed_password: find("HashedPassword"),
+            password: find("Password"),
+        };
+        if columns.hashed_password.is_none() && columns.password.is_none() {
+            return Err(ImportError::Invalid(MISSING_COLUMNS));
+        }
+        Ok(columns)
+    }
+
+    fn row(&self, fields: Vec<String>) -> ImportLine {
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| fields.get(index))
+                .filter(|value| !value.is_empty())
+                .cloned()
+        };
+        ImportLine {
+            mail: field(Some(self.mail)),
+            hashed_password: field(self.hashed_password),
+            password: field(self.password),
+        }
+    }
+}
+
+const LINE_TOO_LONG: &str = "line is longer than 65536 bytes";
+const MISSING_COLUMNS: &str =
+    "the CSV header must name a Mail column and a HashedPassword or Password column";
+
+/// A streaming import, fed with the request body as it arrives.
+///
+/// Input is split into lines as it comes in, so memory stays bounded by one
+/// chunk of parsed rows whatever the size of the file. Every `chunk_size`
+/// valid rows are written in one transaction on the writer, which is checked
+/// out per chunk so other writes can interleave with a long import.
+pub struct UserImport {
+    store: UserStore,
+    params: ImportParams,
+    /// Start of a line whose end has not arrived yet.
+    pending: Vec<u8>,
+    /// Dropping the rest of a line that was too long.
+    skipping: bool,
+    line: u64,
+    columns: Option<CsvColumns>,
+    rows: Vec<(u64, String, String)>,
+    /// `replace` has cleared the table.
+    cleared: bool,
+    report: ImportReport,
+    started: Instant,
+}
+
+impl UserImport {
+    /// Consumes the next bytes of the input.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<(), ImportError> {
+        while let Some(end) = data.iter().position(|&b| b == b'\n') {
+            if self.skipping {
+                self.skipping = false;
+            } else if self.pending.is_empty() {
+                self.parse_line(&data[..end])?;
+            } else {
+                self.pending.extend_from_slice(&data[..end]);
+                let line = mem::take(&mut self.pending);
+                self.parse_line(&line)?;
+            }
+            data = &data[end + 1..];
+        }
+
+        if !self.skipping {
+            self.pending.extend_from_slice(data);
+            if self.pending.len() > MAX_IMPORT_LINE_LEN {
+                self.pending.clear();
+                self.skipping = true;
+                self.line += 1;
+                self.report.rows += 1;
+                self.reject(None, LINE_TOO_LONG.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `input` to the end; for callers holding a blocking reader.
+    pub fn read_from(&mut self, mut input: impl Read) -> Result<(), ImportError> {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match input.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.feed(&buffer[..n])?,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => return Err(ImportError::Invalid("could not read the request body")),
+            }
+        }
+    }
+
+    /// Imports the last line and the rows still buffered, and reports.
+    pub fn finish(mut self) -> Result<ImportReport, ImportError> {
+        if !self.skipping && !self.pending.is_empty() {
+            let line = mem::take(&mut self.pending);
+            self.parse_line(&line)?;
+        }
+        if !self.rows.is_empty() || (self.params.mode == ImportMode::Replace && !self.cleared) {
+            self.write_chunk()?;
+        }
+
+        let conn = self.store.writer()?;
+        let _ = conn.pragma_update(None, "optimize", "");
+        drop(conn);
+
+        // Conflicts are only found when their chunk is written, after the
+        // parse errors of the rows that follow them
+        self.report.errors.sort_by_key(|e| e.line);
+        self.report.elapsed_ms = self.started.elapsed().as_millis();
+        info!(
+            "Imported users: {} rows, {} inserted, {} updated, {} failed in {} ms",
+            self.report.rows,
+            self.report.inserted,
+            self.report.updated,
+            self.report.failed,
+            self.report.elapsed_ms
+        );
+        Ok(self.report)
+    }
+
+    fn parse_line(&mut self, raw: &[u8]) -> Result<(), ImportError> {
+        self.line += 1;
+        if raw.len() > MAX_IMPORT_LINE_LEN {
+            self.report.rows += 1;
+            self.reject(None, LINE_TOO_LONG.to_string());
+            return Ok(());
+        }
+        let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+        let raw = if self.line == 1 {
+            raw.strip_prefix("\u{feff}".as_bytes()).unwrap_or(raw)
+        } else {
+            raw
+        };
+        if raw.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let Ok(text) = std::str::from_utf8(raw) else {
+            self.report.rows += 1;
+            self.reject(None, "line is not valid UTF-8".to_string());
+            return Ok(());
+        };
+
+        let parsed = match self.params.format {
+            BulkFormat::Csv => match &self.columns {
+                None => {
+                    self.columns = Some(CsvColumns::parse(text)?);
+                    return Ok(());
+                }
+                Some(columns) => split_csv_line(text)
+                    .map(|fields| columns.row(fields))
+                    .map_err(str::to_string),
+            },
+            BulkFormat::Ndjson => {
+                serde_json::from_str::<ImportLine>(text).map_err(|e| format!("invalid JSON: {}", e))
+            }
+        };
+        self.report.rows += 1;
+
+        let row = match parsed {
+            Ok(row) => row,
+            Err(e) => {
+                self.reject(None, e);
+                return Ok(());
+            }
+        };
+        match self.check_row(&row) {
+            Ok((mail, hashed_password)) => {
+                self.rows.push((self.line, mail, hashed_password));
+                if self.rows.len() >= self.params.chunk_size {
+                    self.write_chunk()?;
+                }
+            }
+            Err(e) => self.reject(row.mail, e.to_string()),
+        }
+        Ok(())
+    }
+
+    /// The normalised mail and the hash to store for `row`.
+    fn check_row(&self, row: &ImportLine) -> Result<(String, String), &'static str> {
+        let mail = self.store.normalize_mail(row.mail.as_deref().unwrap_or(""));
+        validate_mail(&mail)?;
+        let hashed_password = match (&row.hashed_password, &row.password) {
+            (Some(hash), _) => {
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("HashedPassword must be 64 hex characters (SHA-256)");
+                }
+                hash.to_ascii_lowercase()
+            }
+            (None, Some(password)) if !password.is_empty() => hash_password(password),
+            _ => return Err("row needs a HashedPassword or a Password"),
+        };
+        Ok((mail.into_owned(), hashed_password))
+    }
+
+    fn reject(&mut self, mail: Option<String>, error: String) {
+        self.report.failed += 1;
+        if self.report.errors.len() < MAX_REPORTED_ERRORS {
+            self.report.errors.push(RowError {
+                line: self.line,
+                mail,
+                error,
+            });
+        }
+    }
+
+    /// Writes the buffered rows in one transaction.
+    fn write_chunk(&mut self) -> Result<(), ImportError> {
+        let rows = mem::take(&mut self.rows);
+        let mut updated_mails = Vec::new();
+        let mut failures = Vec::new();
+        let (mut inserted, mut updated, mut unchanged) = (0, 0, 0);
+
+        let conn = self.store.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        let cleared = self.params.mode == ImportMode::Replace && !self.cleared;
+        if cleared {
+            tx.execute("DELETE FROM user", [])?;
+            tx.execute("DELETE FROM session", [])?;
+        }
+        {
+            let mut insert =
+                tx.prepare_cached("INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)")?;
+            let mut update = tx.prepare_cached(
+                "UPDATE user SET hashed_password = ?2 WHERE mail = ?1 AND hashed_password != ?2 RETURNING id",
+            )?;
+            for (line, mail, hashed_password) in rows {
+                match insert.execute([&mail, &hashed_password]) {
+                    Ok(_) => inserted += 1,
+                    Err(rusqlite::Error::SqliteFailure(e, _))
+                        if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+                    {
+                        if self.params.mode != ImportMode::Upsert {
+                            failures.push((line, mail, "a user with this mail already exists"));
+                            continue;
+                        }
+                        match update.query_row([&mail, &hashed_password], |row| row.get(0)) {
+                            Ok(id) => {
+                                session::revoke_user_sessions(&tx, id)?;
+                                updated_mails.push(mail);
+                                updated += 1;
+                            }
+                            Err(rusqlite::Error::QueryReturnedNoRows) => unchanged += 1,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        tx.commit()?;
+        drop(conn);
+
+        if cleared {
+            self.cleared = true;
+            self.store.forget_all_cached();
+        }
+        for mail in &updated_mails {
+            self.store.forget_cached(mail);
+        }
+        self.report.inserted += inserted;
+        self.report.updated += updated;
+        self.report.unchanged += unchanged;
+        self.report.chunks += 1;
+        for (line, mail, error) in failures {
+            self.report.failed += 1;
+            if self.report.errors.len() < MAX_REPORTED_ERRORS {
+                self.report.errors.push(RowError {
+                    line,
+                    mail: Some(mail),
+                    error: error.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A streaming export: every item is the next page of the output, read
+/// with its own query so no connection is held between pages. Rows written
+/// while it runs may or may not be part of it; each one appears at most once.
+pub struct UserExport {
+    store: UserStore,
+    format: BulkFormat,
+    /// Keyset cursor: the last id written.
+    after_id: i64,
+    started: bool,
+    done: bool,
+    /// Page being read through [`Read`], and how much of it was read.
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExportLine<'a> {
+    mail: &'a str,
+    hashed_password: &'a str,
+}
+
+impl UserExport {
+    fn next_page(&mut self) -> StoreResult<Vec<u8>> {
+        let mut out = Vec::new();
+        if !self.started && self.format == BulkFormat::Csv {
+            out.extend_from_slice(b"Mail,HashedPassword\n");
+        }
+        self.started = true;
+
+        let conn = self.store.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, mail, hashed_password FROM user WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let mut rows = stmt.query((self.after_id, EXPORT_PAGE_SIZE))?;
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let mail: String = row.get(1)?;
+            let hashed_password: String = row.get(2)?;
+            match self.format {
+                BulkFormat::Csv => {
+                    write_csv_field(&mut out, &mail);
+                    out.push(b',');
+                    write_csv_field(&mut out, &hashed_password);
+                }
+                BulkFormat::Ndjson => {
+                    let line = ExportLine {
+                        mail: &mail,
+                        hashed_password: &hashed_password,
+                    };
+                    serde_json::to_writer(&mut out, &line).expect("writing to a Vec cannot fail");
+                }
+            }
+            out.push(b'\n');
+            self.after_id = row.get(0)?;
+            count += 1;
+        }
+        self.done = count < EXPORT_PAGE_SIZE;
+        Ok(out)
+    }
+}
+
+impl Iterator for UserExport {
+    type Item = StoreResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = self.next_page();
+        if page.is_err() {
+            self.done = true;
+        }
+        Some(page)
+    }
+}
+
+/// Reads the export as one byte stream, for servers that take a body reader.
+impl Read for UserExport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.next() {
+                None => return Ok(0),
+                Some(page) => {
+                    self.buffer = page.map_err(std::io::Error::other)?;
+                    self.position = 0;
+                }
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl UserStore {
+    /// Starts an import; feed it the input, then [`finish`](UserImport::finish) it.
+    pub fn import_users(&self, params: ImportParams) -> Result<UserImport, ImportError> {
+        params.validate().map_err(ImportError::Invalid)?;
+        Ok(UserImport {
+            store: self.clone(),
+            params,
+            pending: Vec::new(),
+            skipping: false,
+            line: 0,
+            columns: None,
+            rows: Vec::new(),
+            cleared: false,
+            report: ImportReport::default(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Every user, in id order, as `format`; the output of one import is
+    /// the input of another.
+    pub fn export_users(&self, format: BulkFormat) -> UserExport {
+        UserExport {
+            store: self.clone(),
+            format,
+            after_id: 0,
+            started: false,
+            done: false,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+/// Splits one CSV record (RFC 4180, without line breaks inside quotes).
+fn split_csv_line(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field");
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn write_csv_field(out: &mut Vec<u8>, value: &str) {
+    if value.contains([',', '"', '\r', '\n']) {
+        out.push(b'"');
+        out.extend_from_slice(value.replace('"', "\"\"").as_bytes());
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(value.as_bytes());
+    }
+}
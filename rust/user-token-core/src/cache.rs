@@ -0,0 +1,116 @@
+use moka::sync::Cache;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::CacheConfig;
+use crate::store::User;
+
+/// What the cache remembers about an account: enough to answer a login
+/// without touching SQLite.
+#[derive(Clone)]
+struct CachedCredentials {
+    user: User,
+    hashed_password: String,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+}
+
+/// Concurrent, size- and TTL-bounded cache of credentials keyed on `mail`.
+///
+/// Every invalidation bumps a generation counter. Lookups that started
+/// before the bump are not allowed to insert what they read, so a reseed or a
+/// password change can never be undone by a racing login. A full invalidation
+/// also records the generation it flushed at, and older entries are ignored.
+pub(crate) struct CredentialCache {
+    entries: Cache<String, CachedCredentials>,
+    generation: AtomicU64,
+    flushed_at: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CredentialCache {
+    pub(crate) fn new(config: &CacheConfig) -> Self {
+        CredentialCache {
+            entries: Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .build(),
+            generation: AtomicU64::new(0),
+            flushed_at: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Current generation, to be captured before reading from the database
+    /// and handed back to [`CredentialCache::insert`].
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Returns the cached `(user, hashed_password)` for `mail`, counting a hit or a miss.
+    pub(crate) fn get(&self, mail: &str) -> Option<(User, String)> {
+        let flushed_at = self.flushed_at.load(Ordering::Acquire);
+        match self.entries.get(mail) {
+            Some(entry) if entry.generation >= flushed_at => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((entry.user, entry.hashed_password))
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert(&self, mail: &str, user: User, hashed_password: &str, generation: u64) {
+        if generation != self.generation() {
+            return;
+        }
+        self.entries.insert(
+            mail.to_owned(),
+            CachedCredentials {
+                user,
+                hashed_password: hashed_password.to_owned(),
+                generation,
+            },
+        );
+        // An invalidation may have run between the check above and the insert.
+        if generation != self.generation() {
+            self.entries.invalidate(mail);
+        }
+    }
+
+    /// Drops the entry of one account, after its row has been updated or deleted.
+    pub(crate) fn invalidate(&self, mail: &str) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.entries.invalidate(mail);
+    }
+
+    /// Drops every entry, after the user table has been rewritten.
+    pub(crate) fn invalidate_all(&self) {
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.flushed_at.store(generation, Ordering::Release);
+        self.entries.invalidate_all();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        // Entry counts are maintained lazily; flush them so the figure is current.
+        self.entries.run_pending_tasks();
+        CacheStats {
+            enabled: true,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.entry_count(),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Runtime configuration shared by the servers, read from `MAXREQ_*`
+/// environment variables so the three binaries are configured the same way.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Credential cache in front of the user store; `None` disables it.
+    pub cache: Option<CacheConfig>,
+    /// Refresh-token sessions issued at login; `None` disables them.
+    pub sessions: Option<SessionConfig>,
+    /// Failed-login throttling; `None` disables it.
+    pub throttle: Option<ThrottleConfig>,
+    /// Bearer token required by the `/admin/*` routes.
+    pub admin_token: Option<String>,
+    /// Benchmark mode: admin routes are open to anyone.
+    pub benchmark_mode: bool,
+    /// Every `/api/auth` route requires an `Authorization: ApiKey` header.
+    pub require_api_key: bool,
+    /// Audit log of authentication attempts; `None` disables it.
+    pub audit: Option<AuditConfig>,
+    /// Normalisation of mail addresses on insert and lookup.
+    pub mail: MailConfig,
+    /// Serve `/metrics` and record what it reports.
+    pub metrics: bool,
+    /// Sampled JSON-lines access log; `None` disables it.
+    pub access_log: Option<AccessLogConfig>,
+    /// Base URL of the OTLP/HTTP collector spans are exported to; only used
+    /// by builds with the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+    /// How long a stopping server waits for in-flight requests before closing
+    /// their connections.
+    pub shutdown_timeout: Duration,
+    /// How long `/health/ready` waits for a read connection.
+    pub ready_timeout: Duration,
+    /// How long a request waits for a database connection before it is
+    /// answered `503`.
+    pub pool_timeout: Duration,
+    /// Limit on concurrent `/api` requests; `None` admits every request.
+    pub admission: Option<AdmissionConfig>,
+    /// Request size and connection timeouts, enforced by every server.
+    pub limits: LimitsConfig,
+    /// Cross-origin access from browsers; `None` sends no CORS header.
+    pub cors: Option<CorsConfig>,
+    /// TLS on the listener; `None` serves plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Maximum number of cached accounts.
+    pub capacity: u64,
+    /// How long an entry may be served before it is looked up again.
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Lifetime of a refresh token; every refresh issues a new one with a fresh lifetime.
+    pub ttl: Duration,
+    /// How often the background task deletes expired sessions.
+    pub gc_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failed logins per account within `window` before it is locked, `0` = no account limit.
+    pub account_failures: u32,
+    /// Failed logins per client IP within `window` before it is locked, `0` = no IP limit.
+    pub ip_failures: u32,
+    /// Length of the sliding window failures are counted in.
+    pub window: Duration,
+    /// First lockout; every further lockout of the same key doubles it.
+    pub lockout: Duration,
+    /// Upper bound of the exponential lockout.
+    pub max_lockout: Duration,
+    /// Keep lockouts in SQLite so they survive a restart.
+    pub persist: bool,
+}
+
+/// Surrounding whitespace is always trimmed and the domain lowercased; the
+/// rest is opt-in because it can merge accounts that used to be distinct.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MailConfig {
+    /// Lowercase the local part too, so `User1@` and `user1@` are one account.
+    pub lowercase: bool,
+    /// Convert internationalised domains to punycode (`bücher.de` → `xn--bcher-kva.de`).
+    pub punycode: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    /// File the lines are appended to.
+    pub path: String,
+    /// Share of requests logged, from `0.0` to `1.0`.
+    pub sample_ratio: f64,
+    /// Records waiting for the writer before new ones are dropped.
+    pub queue_capacity: usize,
+    /// Longest time a written line stays in the buffer.
+    pub flush_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Most events written in one transaction.
+    pub batch_size: usize,
+    /// Longest an event waits for its batch to fill.
+    pub flush_interval: Duration,
+    /// Events queued for the writer; further events are dropped.
+    pub queue_capacity: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdmissionConfig {
+    /// Requests running at once; the ceiling of the adaptive limit.
+    pub max_in_flight: usize,
+    /// Requests waiting for a slot; further ones get `503` at once.
+    pub max_queued: usize,
+    /// Longest wait for a slot before `503`.
+    pub queue_timeout: Duration,
+    /// Move the limit with the observed latency instead of keeping it at `max_in_flight`.
+    pub adaptive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Largest request body; bigger ones get `413`. The import route streams
+    /// its body and is exempt.
+    pub max_body_bytes: usize,
+    /// Largest request line and headers together; bigger ones get `431`.
+    pub max_header_bytes: usize,
+    /// Time a client has to send the whole request head once it started
+    /// sending it; the connection is then closed.
+    pub header_timeout: Duration,
+    /// Time an idle connection is kept open for a next request; zero turns
+    /// keep-alive off.
+    pub keep_alive: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins such as `https://app.example.com`; `*` allows any.
+    pub allowed_origins: Vec<String>,
+    /// Methods a preflight may ask for.
+    pub allowed_methods: Vec<String>,
+    /// Request headers a preflight may ask for; `*` allows any.
+    pub allowed_headers: Vec<String>,
+    /// How long a browser caches a preflight answer.
+    pub max_age: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the server certificate first.
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: String,
+    /// Sessions kept for resumption by session ID; `0` keeps none.
+    pub session_cache: usize,
+    /// Resumption by encrypted tickets, which keep no state on the server.
+    pub tickets: bool,
+}
+
+impl Config {
+    /// Reads the configuration from the environment.
+    ///
+    /// - `MAXREQ_CACHE_CAPACITY`: number of cached accounts, `0` (default) disables the cache
+    /// - `MAXREQ_CACHE_TTL_SECS`: entry time-to-live, default 60
+    /// - `MAXREQ_SESSION_TTL_SECS`: refresh-token lifetime, `0` (default) disables sessions
+    /// - `MAXREQ_SESSION_GC_SECS`: interval between expired-session sweeps, default 60
+    /// - `MAXREQ_THROTTLE_ACCOUNT_FAILURES` / `MAXREQ_THROTTLE_IP_FAILURES`: failed logins
+    ///   allowed per window, `0` (default) disables that key; both `0` disables throttling
+    /// - `MAXREQ_THROTTLE_WINDOW_SECS`: sliding window, default 300
+    /// - `MAXREQ_THROTTLE_LOCKOUT_SECS` / `MAXREQ_THROTTLE_MAX_LOCKOUT_SECS`: first and
+    ///   longest lockout, default 30 and 3600
+    /// - `MAXREQ_THROTTLE_PERSIST`: `1`/`true` keeps lockouts in SQLite
+    /// - `MAXREQ_ADMIN_TOKEN`: bearer token for the `/admin/*` routes, unset = routes closed
+    /// - `MAXREQ_BENCHMARK_MODE`: `1`/`true` opens the admin routes without a token
+    /// - `MAXREQ_REQUIRE_API_KEY`: `1`/`true` requires a scoped API key on `/api/auth/*`
+    /// - `MAXREQ_AUDIT_LOG`: `1`/`true` records logins in the `auth_event` table
+    /// - `MAXREQ_AUDIT_BATCH` / `MAXREQ_AUDIT_FLUSH_MS` / `MAXREQ_AUDIT_QUEUE`: events per
+    ///   write, longest wait for a batch and queue length, default 256, 200 and 65536
+    /// - `MAXREQ_MAIL_LOWERCASE`: `1`/`true` lowercases whole mail addresses, not just the domain
+    /// - `MAXREQ_MAIL_PUNYCODE`: `1`/`true` converts internationalised domains to punycode
+    /// - `MAXREQ_METRICS`: `0`/`false` turns off `/metrics` and its recording, on by default
+    /// - `MAXREQ_ACCESS_LOG`: file the access log is appended to, unset = no access log
+    /// - `MAXREQ_ACCESS_LOG_SAMPLE`: share of requests logged, `0.0` to `1.0`, default 1
+    /// - `MAXREQ_ACCESS_LOG_QUEUE` / `MAXREQ_ACCESS_LOG_FLUSH_MS`: records waiting for the
+    ///   writer and longest time a line stays buffered, default 65536 and 1000
+    /// - `MAXREQ_OTLP_ENDPOINT`: collector base URL, e.g. `http://localhost:4318`, unset =
+    ///   spans are not exported
+    /// - `MAXREQ_SHUTDOWN_TIMEOUT_SECS`: drain time on SIGTERM/SIGINT, default 20
+    /// - `MAXREQ_READY_TIMEOUT_MS`: connection wait of `/health/ready`, default 500
+    /// - `MAXREQ_POOL_TIMEOUT_MS`: connection wait of a request before it gets `503`,
+    ///   default 5000
+    /// - `MAXREQ_MAX_IN_FLIGHT`: concurrent `/api` requests, `0` (default) disables
+    ///   admission control
+    /// - `MAXREQ_MAX_QUEUED` / `MAXREQ_QUEUE_TIMEOUT_MS`: requests waiting for a slot and
+    ///   longest wait, default `MAXREQ_MAX_IN_FLIGHT` and 50
+    /// - `MAXREQ_ADAPTIVE_CONCURRENCY`: `1`/`true` adapts the limit to latency (AIMD)
+    /// - `MAXREQ_MAX_BODY_BYTES`: largest request body, default 65536
+    /// - `MAXREQ_MAX_HEADER_BYTES`: largest request line and headers, default 16384
+    /// - `MAXREQ_HEADER_TIMEOUT_MS`: time to send a request head, default 5000
+    /// - `MAXREQ_KEEP_ALIVE_SECS`: idle time before a connection is closed, `0` turns
+    ///   keep-alive off, default 5
+    /// - `MAXREQ_CORS_ORIGINS`: comma-separated origins allowed to call the API from a
+    ///   browser, `*` for any, unset = no CORS headers
+    /// - `MAXREQ_CORS_METHODS` / `MAXREQ_CORS_HEADERS`: allowed methods and request
+    ///   headers, default `GET,POST,DELETE` and `Content-Type,Authorization`
+    /// - `MAXREQ_CORS_MAX_AGE_SECS`: preflight cache lifetime, default 600
+    /// - `MAXREQ_TLS_CERT`: PEM certificate chain, unset = plain HTTP
+    /// - `MAXREQ_TLS_KEY`: PEM private key, default the `MAXREQ_TLS_CERT` file
+    /// - `MAXREQ_TLS_SESSION_CACHE`: sessions kept for resumption, `0` keeps none, default 4096
+    /// - `MAXREQ_TLS_TICKETS`: `0`/`false` turns off resumption tickets, on by default
+    pub fn from_env() -> Self {
+        let capacity: u64 = env_or("MAXREQ_CACHE_CAPACITY", 0);
+        let cache = (capacity > 0).then(|| CacheConfig {
+            capacity,
+            ttl: Duration::from_secs(env_or("MAXREQ_CACHE_TTL_SECS", 60)),
+        });
+
+        let session_ttl: u64 = env_or("MAXREQ_SESSION_TTL_SECS", 0);
+        let sessions = (session_ttl > 0).then(|| SessionConfig {
+            ttl: Duration::from_secs(session_ttl),
+            gc_interval: Duration::from_secs(env_or("MAXREQ_SESSION_GC_SECS", 60).max(1)),
+        });
+
+        let account_failures: u32 = env_or("MAXREQ_THROTTLE_ACCOUNT_FAILURES", 0);
+        let ip_failures: u32 = env_or("MAXREQ_THROTTLE_IP_FAILURES", 0);
+        let throttle = (account_failures > 0 || ip_failures > 0).then(|| ThrottleConfig {
+            account_failures,
+            ip_failures,
+            window: Duration::from_secs(env_or("MAXREQ_THROTTLE_WINDOW_SECS", 300)),
+            lockout: Duration::from_secs(env_or("MAXREQ_THROTTLE_LOCKOUT_SECS", 30)),
+            max_lockout: Duration::from_secs(env_or("MAXREQ_THROTTLE_MAX_LOCKOUT_SECS", 3600)),
+            persist: env_flag("MAXREQ_THROTTLE_PERSIST"),
+        });
+
+        let admin_token = std::env::var("MAXREQ_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.trim().is_empty());
+
+        let audit = env_flag("MAXREQ_AUDIT_LOG").then(|| AuditConfig {
+            batch_size: env_or("MAXREQ_AUDIT_BATCH", 256).max(1),
+            flush_interval: Duration::from_millis(env_or("MAXREQ_AUDIT_FLUSH_MS", 200)),
+            queue_capacity: env_or("MAXREQ_AUDIT_QUEUE", 65536).max(1),
+        });
+
+        let access_log = std::env::var("MAXREQ_ACCESS_LOG")
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(|path| AccessLogConfig {
+                path,
+                sample_ratio: env_or("MAXREQ_ACCESS_LOG_SAMPLE", 1.0f64).clamp(0.0, 1.0),
+                queue_capacity: env_or("MAXREQ_ACCESS_LOG_QUEUE", 65536).max(1),
+                flush_interval: Duration::from_millis(env_or("MAXREQ_ACCESS_LOG_FLUSH_MS", 1000)),
+            });
+
+        let max_in_flight: usize = env_or("MAXREQ_MAX_IN_FLIGHT", 0);
+        let admission = (max_in_flight > 0).then(|| AdmissionConfig {
+            max_in_flight,
+            max_queued: env_or("MAXREQ_MAX_QUEUED", max_in_flight),
+            queue_timeout: Duration::from_millis(env_or("MAXREQ_QUEUE_TIMEOUT_MS", 50)),
+            adaptive: env_flag("MAXREQ_ADAPTIVE_CONCURRENCY"),
+        });
+
+        let allowed_origins = env_list("MAXREQ_CORS_ORIGINS", "");
+        let cors = (!allowed_origins.is_empty()).then(|| CorsConfig {
+            allowed_origins,
+            allowed_methods: env_list("MAXREQ_CORS_METHODS", "GET,POST,DELETE"),
+            allowed_headers: env_list("MAXREQ_CORS_HEADERS", "Content-Type,Authorization"),
+            max_age: Duration::from_secs(env_or("MAXREQ_CORS_MAX_AGE_SECS", 600)),
+        });
+
+        let tls = std::env::var("MAXREQ_TLS_CERT")
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(|cert_path| TlsConfig {
+                key_path: std::env::var("MAXREQ_TLS_KEY")
+                    .ok()
+                    .filter(|path| !path.trim().is_empty())
+                    .unwrap_or_else(|| cert_path.clone()),
+                cert_path,
+                session_cache: env_or("MAXREQ_TLS_SESSION_CACHE", 4096),
+                tickets: env_flag_or("MAXREQ_TLS_TICKETS", true),
+            });
+
+        let otlp_endpoint = std::env::var("MAXREQ_OTLP_ENDPOINT")
+            .ok()
+            .map(|endpoint| endpoint.trim().trim_end_matches('/').to_string())
+            .filter(|endpoint| !endpoint.is_empty());
+
+        Config {
+            cache,
+            sessions,
+            throttle,
+            admin_token,
+            benchmark_mode: env_flag("MAXREQ_BENCHMARK_MODE"),
+            require_api_key: env_flag("MAXREQ_REQUIRE_API_KEY"),
+            audit,
+            mail: MailConfig {
+                lowercase: env_flag("MAXREQ_MAIL_LOWERCASE"),
+                punycode: env_flag("MAXREQ_MAIL_PUNYCODE"),
+            },
+            metrics: env_flag_or("MAXREQ_METRICS", true),
+            access_log,
+            otlp_endpoint,
+            shutdown_timeout: Duration::from_secs(env_or("MAXREQ_SHUTDOWN_TIMEOUT_SECS", 20)),
+            ready_timeout: Duration::from_millis(env_or("MAXREQ_READY_TIMEOUT_MS", 500)),
+            pool_timeout: Duration::from_millis(env_or("MAXREQ_POOL_TIMEOUT_MS", 5000).max(1)),
+            admission,
+            limits: LimitsConfig {
+                max_body_bytes: env_or("MAXREQ_MAX_BODY_BYTES", 64 * 1024),
+                max_header_bytes: env_or("MAXREQ_MAX_HEADER_BYTES", 16 * 1024).max(1024),
+                header_timeout: Duration::from_millis(
+                    env_or("MAXREQ_HEADER_TIMEOUT_MS", 5000).max(1),
+                ),
+                keep_alive: Duration::from_secs(env_or("MAXREQ_KEEP_ALIVE_SECS", 5)),
+            },
+            cors,
+            tls,
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// A comma-separated variable, `default` when unset; blank items are dropped.
+fn env_list(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+/// A boolean variable that is `default` unless set to a recognised value.
+fn env_flag_or(name: &str, default: bool) -> bool {
+    match std::env::var(name)
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Ok("1" | "true" | "yes" | "on") => true,
+        Ok("0" | "false" | "no" | "off") => false,
+        _ => default,
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::CorsConfig;
+
+/// Response headers a browser script may read besides the safelisted ones:
+/// the delay of a `429` or `503`.
+pub const CORS_EXPOSED_HEADERS: &str = "Retry-After";
+
+/// The CORS policy of `MAXREQ_CORS_*`, applied the same way by every server.
+///
+/// A request without an `Origin` header is not a CORS request and gets no
+/// CORS header. A preflight (`OPTIONS` with `Access-Control-Request-Method`)
+/// is answered by the server itself: `204` with the allow headers when the
+/// origin, the method and every requested header are allowed, `403`
+/// otherwise. Other requests from an allowed origin get
+/// `Access-Control-Allow-Origin` on their response, whatever its status;
+/// from another origin they get nothing, and the browser hides the response.
+///
+/// Header values borrow from the policy, so a server holding it in a static
+/// gets `&'static str` values.
+#[derive(Debug)]
+pub struct CorsPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: Vec<String>,
+    /// Lowercase, `None` for any header.
+    headers: Option<Vec<String>>,
+    allow_methods: String,
+    allow_headers: String,
+    max_age: String,
+}
+
+impl CorsPolicy {
+    pub fn new(config: &CorsConfig) -> Self {
+        let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| origin.trim_end_matches('/').to_string())
+            .collect();
+        let methods: Vec<String> = config
+            .allowed_methods
+            .iter()
+            .map(|method| method.to_ascii_uppercase())
+            .collect();
+        let headers = if config.allowed_headers.iter().any(|header| header == "*") {
+            None
+        } else {
+            Some(
+                config
+                    .allowed_headers
+                    .iter()
+                    .map(|header| header.to_ascii_lowercase())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        CorsPolicy {
+            any_origin,
+            origins,
+            allow_methods: methods.join(", "),
+            allow_headers: match &headers {
+                Some(headers) => headers.join(", "),
+                None => "*".to_string(),
+            },
+            methods,
+            headers,
+            max_age: config.max_age.as_secs().to_string(),
+        }
+    }
+
+    /// `Access-Control-Allow-Origin` for a request from `origin`: `*` when
+    /// any origin is allowed, the configured origin when it is listed, `None`
+    /// when it is not allowed. Origins compare without regard to case.
+    pub fn allow_origin(&self, origin: &str) -> Option<&str> {
+        if self.any_origin {
+            return Some("*");
+        }
+        self.origins
+            .iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .map(String::as_str)
+    }
+
+    /// Headers to add to the response of a request from `origin`; empty when
+    /// the origin is not allowed.
+    pub fn response_headers(&self, origin: &str) -> Vec<(&'static str, &str)> {
+        let Some(allowed) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        let mut headers = vec![
+            ("access-control-allow-origin", allowed),
+            ("access-control-expose-headers", CORS_EXPOSED_HEADERS),
+        ];
+        // The answer depends on the origin unless every origin gets `*`
+        if !self.any_origin {
+            headers.push(("vary", "Origin"));
+        }
+        headers
+    }
+
+    /// Headers of the `204` answered to a preflight from `origin` for
+    /// `method` with the comma-separated `request_headers`; `None` when the
+    /// preflight is refused, with `403`.
+    pub fn preflight(
+        &self,
+        origin: &str,
+        method: &str,
+        request_headers: Option<&str>,
+    ) -> Option<Vec<(&'static str, &str)>> {
+        let allowed = self.allow_origin(origin)?;
+        if !self
+            .methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method.trim()))
+        {
+            return None;
+        }
+        if let (Some(headers), Some(requested)) = (&self.headers, request_headers) {
+            let refused = requested
+                .split(',')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .any(|header| {
+                    !headers
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(header))
+                });
+            if refused {
+                return None;
+            }
+        }
+        let vary = if self.any_origin {
+            "Access-Control-Request-Method, Access-Control-Request-Headers"
+        } else {
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"
+        };
+        Some(vec![
+            ("access-control-allow-origin", allowed),
+            ("access-control-allow-methods", self.allow_methods.as_str()),
+            ("access-control-allow-headers", self.allow_headers.as_str()),
+            ("access-control-max-age", self.max_age.as_str()),
+            ("vary", vary),
+        ])
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+
+use crate::migrations::SCHEMA_VERSION;
+
+/// Error returned by the user store.
+#[derive(Debug)]
+pub enum StoreError {
+    /// No connection could be checked out of the pool.
+    Pool(r2d2::Error),
+    /// SQLite rejected the statement.
+    Sqlite(rusqlite::Error),
+    /// The database was migrated by a newer build than this one.
+    SchemaTooNew { found: u32 },
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// `Retry-After`, in seconds, of the `503` answered when the store is overloaded.
+pub const STORE_RETRY_AFTER_SECS: u64 = 1;
+/// `ErrorMessage` of every `503`: an overloaded store or a shed request.
+pub const BUSY_MESSAGE: &str = "The server is busy, try again later";
+/// `ErrorMessage` of the `413` answered to a body over `MAXREQ_MAX_BODY_BYTES`.
+pub const BODY_TOO_LARGE_MESSAGE: &str = "Request body too large";
+
+impl StoreError {
+    /// The store is overloaded rather than broken: no connection came free
+    /// within the pool timeout, or SQLite stayed busy or locked past its busy
+    /// timeout. The same request can succeed a moment later.
+    pub fn is_overloaded(&self) -> bool {
+        match self {
+            StoreError::Pool(_) => true,
+            StoreError::Sqlite(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+                e.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            ),
+            _ => false,
+        }
+    }
+
+    /// HTTP status the servers answer with: `503` when overloaded, `500` otherwise.
+    pub fn status(&self) -> u16 {
+        if self.is_overloaded() {
+            503
+        } else {
+            500
+        }
+    }
+
+    /// `Retry-After` seconds to send along, for an overloaded store only.
+    pub fn retry_after(&self) -> Option<u64> {
+        self.is_overloaded().then_some(STORE_RETRY_AFTER_SECS)
+    }
+
+    /// Message for the response body; the error itself is only logged.
+    pub fn client_message(&self) -> &'static str {
+        if self.is_overloaded() {
+            BUSY_MESSAGE
+        } else {
+            "An internal error occurred"
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Pool(e) => write!(f, "connection pool error: {}", e),
+            StoreError::Sqlite(e) => write!(f, "database error: {}", e),
+            StoreError::SchemaTooNew { found } => write!(
+                f,
+                "database schema version {} is newer than version {} of this build",
+                found, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Pool(e) => Some(e),
+            StoreError::Sqlite(e) => Some(e),
+            StoreError::SchemaTooNew { .. } => None,
+        }
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(e: r2d2::Error) -> Self {
+        StoreError::Pool(e)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
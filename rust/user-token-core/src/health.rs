@@ -0,0 +1,57 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::migrations::{self, SCHEMA_VERSION};
+use crate::store::{PoolStats, UserStore};
+
+/// Body of `/health/ready`, answered with `200` when `ready` and `503` otherwise.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Readiness {
+    /// A read connection answered `SELECT 1` in time, and the database is at
+    /// [`SCHEMA_VERSION`].
+    pub ready: bool,
+    /// Time the check took, connection checkout included.
+    pub latency_us: u64,
+    /// `None` when the database could not be read.
+    pub schema_version: Option<u32>,
+    pub expected_schema_version: u32,
+    pub pool: PoolStats,
+    /// Why the server is not ready.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl UserStore {
+    /// Checks out a read connection within `timeout` and runs `SELECT 1` and
+    /// `PRAGMA user_version` on it. An exhausted pool or an unreadable
+    /// `users.db` makes the server not ready.
+    pub fn readiness(&self, timeout: Duration) -> Readiness {
+        let start = Instant::now();
+        let checked = self.reader_within(timeout).and_then(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(migrations::user_version(&conn)?)
+        });
+        let latency_us = start.elapsed().as_micros() as u64;
+
+        let (schema_version, error) = match checked {
+            Ok(version) if version == SCHEMA_VERSION => (Some(version), None),
+            Ok(version) => (
+                Some(version),
+                Some(format!(
+                    "database schema version {} is not version {} of this build",
+                    version, SCHEMA_VERSION
+                )),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Readiness {
+            ready: error.is_none(),
+            latency_us,
+            schema_version,
+            expected_schema_version: SCHEMA_VERSION,
+            pool: self.pool_stats(),
+            error,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Shared building blocks for the Rust UserTokenApi servers (axum, actix and khttp).
+//!
+//! Everything in here is framework-agnostic: the servers only translate HTTP
+//! requests into calls on these types and serialize the results.
+
+mod access_log;
+mod account;
+mod admin;
+mod admission;
+mod apikey;
+mod audit;
+mod bulk;
+mod cache;
+mod config;
+mod cors;
+mod error;
+mod health;
+mod metrics;
+mod mfa;
+mod migrations;
+mod seed;
+mod session;
+mod store;
+mod telemetry;
+mod throttle;
+mod tls;
+mod totp;
+
+pub use access_log::{AccessLog, AccessLogStats, AccessRecord};
+pub use account::{
+    normalize_mail, validate_mail, validate_password, AccountError, MAX_MAIL_LEN,
+    MAX_MAIL_LOCAL_LEN, MAX_PASSWORD_LEN, MIN_PASSWORD_LEN,
+};
+pub use admin::{constant_time_eq, AdminAccess, AdminGuard};
+pub use admission::{Acquire, Admission, AdmissionStats, Permit, SHED_RETRY_AFTER_SECS};
+pub use apikey::{
+    required_scope, ApiKey, ApiKeyAccess, ApiKeyError, NewApiKey, API_KEY_SCOPES,
+    MAX_API_KEY_NAME_LEN, SCOPE_ACCOUNT, SCOPE_LOGIN, SCOPE_SESSION,
+};
+pub use audit::{AuditLog, AuditPage, AuditQuery, AuditRecord, AuditStats, AuthEvent, AuthOutcome};
+pub use bulk::{
+    BulkFormat, ImportError, ImportMode, ImportParams, ImportReport, RowError, UserExport,
+    UserImport, DEFAULT_IMPORT_CHUNK, MAX_IMPORT_CHUNK, MAX_IMPORT_LINE_LEN, MAX_REPORTED_ERRORS,
+};
+pub use cache::CacheStats;
+pub use config::{
+    AccessLogConfig, AdmissionConfig, AuditConfig, CacheConfig, Config, CorsConfig, LimitsConfig,
+    MailConfig, SessionConfig, ThrottleConfig, TlsConfig,
+};
+pub use cors::{CorsPolicy, CORS_EXPOSED_HEADERS};
+pub use error::{
+    StoreError, StoreResult, BODY_TOO_LARGE_MESSAGE, BUSY_MESSAGE, STORE_RETRY_AFTER_SECS,
+};
+pub use health::Readiness;
+pub use metrics::{Metrics, METRICS_CONTENT_TYPE, UNMATCHED_ROUTE};
+pub use mfa::{LoginStatus, MfaError, TotpEnrollment, MFA_CHALLENGE_TTL, TOTP_ISSUER};
+pub use migrations::SCHEMA_VERSION;
+pub use seed::{
+    JobState, JobStatus, SeedJobs, SeedParams, StartError, KEPT_FINISHED_JOBS, MAX_SEED_COUNT,
+};
+pub use session::{Session, SessionError};
+pub use store::{hash_password, PoolState, PoolStats, User, UserStore};
+pub use telemetry::{init_tracing, Telemetry};
+pub use throttle::{retry_after_secs, LoginOutcome, LoginThrottle, ThrottleStats};
+pub use tls::{tls_server_config, TlsError, ALPN_HTTP1};
+pub use totp::{
+    base32_decode, base32_encode, hotp, otpauth_uri, totp, verify_totp, TotpAlgorithm, TOTP_DIGITS,
+    TOTP_STEP_SECS,
+};
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::audit::AuthOutcome;
+use crate::store::UserStore;
+use crate::throttle::LoginThrottle;
+
+/// `Content-Type` of the `/metrics` response.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Route label of requests that matched no route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Upper bounds of the histogram buckets, in nanoseconds: 10µs to 5s.
+const BUCKETS_NS: [u64; 18] = [
+    10_000,
+    25_000,
+    50_000,
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+    2_500_000_000,
+    5_000_000_000,
+];
+
+/// Statements whose duration is measured by the store.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Query {
+    /// Credential lookup of a login (not counted when served by the cache).
+    Login,
+    InsertUser,
+    UpdatePassword,
+    DeleteUser,
+}
+
+const QUERY_LABELS: [&str; 4] = ["login", "insert_user", "update_password", "delete_user"];
+
+impl Query {
+    /// The `query` label of the metrics and of the `db.query` span.
+    pub(crate) fn label(self) -> &'static str {
+        QUERY_LABELS[self as usize]
+    }
+}
+const LOGIN_LABELS: [&str; 5] = ["success", "invalid", "mfa_required", "locked", "error"];
+
+/// Latency histogram: one counter per bucket (not cumulative until rendered)
+/// and the sum, so an observation is two relaxed atomic adds.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS_NS.len() + 1],
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = BUCKETS_NS.partition_point(|&bound| bound < ns);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    /// Writes the `_bucket`, `_sum` and `_count` series; `labels` is empty
+    /// or a `key="value",` list.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut count = 0;
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS_NS) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                labels,
+                bound as f64 / 1e9,
+                count
+            );
+        }
+        count += self.buckets[BUCKETS_NS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+struct RouteMetrics {
+    /// Requests by status code, indexed from 100.
+    statuses: Box<[AtomicU64]>,
+    latency: Histogram,
+}
+
+impl RouteMetrics {
+    fn new() -> Self {
+        RouteMetrics {
+            statuses: (100..600).map(|_| AtomicU64::new(0)).collect(),
+            latency: Histogram::default(),
+        }
+    }
+}
+
+/// Counters and histograms served at `/metrics` in the Prometheus text format.
+///
+/// Recording is a handful of relaxed atomic adds, plus a shared read lock to
+/// find the route; the lock is only taken for writing the first time a route
+/// is seen. Rendering reads the pool gauges and cache counters from the store.
+/// Share it with the store through [`UserStore::with_metrics`] so pool
+/// checkouts and queries are timed too.
+#[derive(Default)]
+pub struct Metrics {
+    routes: RwLock<HashMap<String, RouteMetrics>>,
+    /// Checkout wait of the read pool and of the writer.
+    pool_wait: [Histogram; 2],
+    queries: [Histogram; QUERY_LABELS.len()],
+    logins: [AtomicU64; LOGIN_LABELS.len()],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a served request. `route` is the matched route pattern (not
+    /// the raw path, to bound the number of series), or [`UNMATCHED_ROUTE`].
+    pub fn observe_request(&self, route: &str, status: u16, elapsed: Duration) {
+        let routes = self.routes.read().unwrap();
+        match routes.get(route) {
+            Some(metrics) => Self::observe_route(metrics, status, elapsed),
+            None => {
+                drop(routes);
+                let mut routes = self.routes.write().unwrap();
+                let metrics = routes
+                    .entry(route.to_string())
+                    .or_insert_with(RouteMetrics::new);
+                Self::observe_route(metrics, status, elapsed);
+            }
+        }
+    }
+
+    fn observe_route(metrics: &RouteMetrics, status: u16, elapsed: Duration) {
+        if let Some(count) = metrics.statuses.get(usize::from(status).wrapping_sub(100)) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.latency.observe(elapsed);
+    }
+
+    /// Counts a login attempt (password step, throttle refusal or MFA step).
+    pub fn record_login(&self, outcome: AuthOutcome) {
+        let index = match outcome {
+            AuthOutcome::Success => 0,
+            AuthOutcome::Failure => 1,
+            AuthOutcome::MfaRequired => 2,
+            AuthOutcome::Locked => 3,
+            AuthOutcome::Error => 4,
+        };
+        self.logins[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_pool_wait(&self, writer: bool, elapsed: Duration) {
+        self.pool_wait[usize::from(writer)].observe(elapsed);
+    }
+
+    pub(crate) fn observe_query(&self, query: Query, elapsed: Duration) {
+        self.queries[query as usize].observe(elapsed);
+    }
+
+    /// The whole exposition, read from the counters, from `store` and from
+    /// `throttle` when logins are throttled.
+    pub fn render(&self, store: &UserStore, throttle: Option<&LoginThrottle>) -> String {
+        let mut out = String::with_capacity(16 * 1024);
+
+        header(
+            &mut out,
+            "maxreq_http_requests_total",
+            "counter",
+            "HTTP requests by route and status.",
+        );
+        let routes = self.routes.read().unwrap();
+        let mut names: Vec<&String> = routes.keys().collect();
+        names.sort();
+        for name in &names {
+            let route = escape(name);
+            for (index, count) in routes[*name].statuses.iter().enumerate() {
+                let count = count.load(Ordering::Relaxed);
+                if count > 0 {
+                    let _ = writeln!(
+                        out,
+                        "maxreq_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                        route,
+                        index + 100,
+                        count
+                    );
+                }
+            }
+        }
+        header(
+            &mut out,
+            "maxreq_http_request_duration_seconds",
+            "histogram",
+            "Time from the request reaching the router to the response headers.",
+        );
+        for name in &names {
+            routes[*name].latency.render(
+                &mut out,
+                "maxreq_http_request_duration_seconds",
+                &format!("route=\"{}\",", escape(name)),
+            );
+        }
+        drop(routes);
+
+        header(
+            &mut out,
+            "maxreq_login_attempts_total",
+            "counter",
+            "Login attempts by outcome.",
+        );
+        for (label, count) in LOGIN_LABELS.iter().zip(&self.logins) {
+            let _ = writeln!(
+                out,
+                "maxreq_login_attempts_total{{outcome=\"{}\"}} {}",
+                label,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "maxreq_db_pool_wait_seconds",
+            "histogram",
+            "Time spent waiting for a database connection.",
+        );
+        for (pool, histogram) in ["read", "write"].iter().zip(&self.pool_wait) {
+            histogram.render(
+                &mut out,
+                "maxreq_db_pool_wait_seconds",
+                &format!("pool=\"{}\",", pool),
+            );
+        }
+
+        let pools = store.pool_stats();
+        let (read, write) = (&pools.read, &pools.write);
+        for (name, help, values) in [
+            (
+                "maxreq_db_pool_max_connections",
+                "Configured size of the connection pool.",
+                [read.max_size, write.max_size],
+            ),
+            (
+                "maxreq_db_pool_connections",
+                "Connections currently open.",
+                [read.connections, write.connections],
+            ),
+            (
+                "maxreq_db_pool_idle_connections",
+                "Open connections not checked out.",
+                [read.idle, write.idle],
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            for (pool, value) in ["read", "write"].iter().zip(values) {
+                let _ = writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool, value);
+            }
+        }
+
+        header(
+            &mut out,
+            "maxreq_db_query_duration_seconds",
+            "histogram",
+            "Execution time of the user-table statements, connection checkout excluded.",
+        );
+        for (query, histogram) in QUERY_LABELS.iter().zip(&self.queries) {
+            histogram.render(
+                &mut out,
+                "maxreq_db_query_duration_seconds",
+                &format!("query=\"{}\",", query),
+            );
+        }
+
+        let cache = store.cache_stats();
+        for (name, help, value) in [
+            (
+                "maxreq_credential_cache_hits_total",
+                "Logins answered by the credential cache.",
+                cache.hits,
+            ),
+            (
+                "maxreq_credential_cache_misses_total",
+                "Logins the credential cache sent to the database.",
+                cache.misses,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let throttle = throttle.map(LoginThrottle::stats).unwrap_or_default();
+        for (name, kind, help, value) in [
+            (
+                "maxreq_login_lockouts_total",
+                "counter",
+                "Lockouts started by the login throttle.",
+                throttle.lockouts,
+            ),
+            (
+                "maxreq_login_lockout_rejections_total",
+                "counter",
+                "Login attempts refused with 429 because their account or IP was locked.",
+                throttle.rejected,
+            ),
+            (
+                "maxreq_login_locked",
+                "gauge",
+                "Accounts and IPs locked right now.",
+                throttle.locked,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,337 @@
+use moka::ops::compute::{CompResult, Op};
+use moka::sync::Cache;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::StoreError;
+use crate::session::new_secret;
+use crate::store::{User, UserStore};
+use crate::totp::{base32_decode, base32_encode, otpauth_uri, verify_totp, TOTP_STEP_SECS};
+
+/// Issuer shown by authenticator apps next to the account.
+pub const TOTP_ISSUER: &str = "UserTokenApi";
+/// How long a login has to complete its MFA challenge.
+pub const MFA_CHALLENGE_TTL: Duration = Duration::from_secs(300);
+/// Codes tried against one challenge before it is dropped.
+const CHALLENGE_ATTEMPTS: u32 = 5;
+/// Wrong codes accepted per account and `MFA_CHALLENGE_TTL`, across challenges,
+/// so a known password cannot be turned into unlimited guesses.
+const ACCOUNT_FAILURES: u32 = 10;
+/// 160-bit secrets, as recommended by RFC 4226.
+const TOTP_SECRET_LEN: usize = 20;
+
+/// `Status` of a login response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoginStatus {
+    Ok,
+    Failed,
+    /// Password accepted; the login continues with `/api/auth/mfa/verify`.
+    MfaRequired,
+}
+
+/// A TOTP secret waiting to be confirmed with a first code.
+#[derive(Debug)]
+pub struct TotpEnrollment {
+    pub user_id: i64,
+    /// Base32 secret, for authenticator apps that cannot scan the URI.
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug)]
+pub enum MfaError {
+    /// No account matches the mail and password.
+    InvalidCredentials,
+    /// `confirm` without a pending enrolment.
+    NotEnrolled,
+    /// `enroll` while TOTP is enabled; it cannot be replaced with the password alone.
+    AlreadyEnrolled,
+    /// Unknown, expired or exhausted challenge.
+    InvalidChallenge,
+    /// Wrong, malformed or already used code.
+    InvalidCode,
+    /// Too many wrong codes for this account; retry after `MFA_CHALLENGE_TTL`.
+    TooManyAttempts,
+    Store(StoreError),
+}
+
+impl fmt::Display for MfaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MfaError::InvalidCredentials => f.write_str("invalid username or password"),
+            MfaError::NotEnrolled => f.write_str("no TOTP enrolment is pending"),
+            MfaError::AlreadyEnrolled => f.write_str("TOTP is already enabled"),
+            MfaError::InvalidChallenge => f.write_str("invalid or expired MFA challenge"),
+            MfaError::InvalidCode => f.write_str("invalid code"),
+            MfaError::TooManyAttempts => f.write_str("too many invalid codes"),
+            MfaError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MfaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MfaError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Into<StoreError>> From<E> for MfaError {
+    fn from(e: E) -> Self {
+        MfaError::Store(e.into())
+    }
+}
+
+/// Adds the TOTP columns to databases created before they existed.
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('user')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    // Confirmed secret, and the one waiting for its first code
+    for column in ["totp_secret", "totp_pending"] {
+        if !columns.iter().any(|c| c == column) {
+            conn.execute(&format!("ALTER TABLE user ADD COLUMN {} TEXT", column), [])?;
+        }
+    }
+    Ok(())
+}
+
+struct Challenge {
+    user_id: i64,
+    mail: String,
+    attempts: AtomicU32,
+}
+
+/// Pending login challenges, plus what is needed to bound and de-duplicate
+/// the codes sent against them. Everything expires on its own.
+pub(crate) struct MfaChallenges {
+    challenges: Cache<String, Arc<Challenge>>,
+    /// Wrong codes per user id.
+    failures: Cache<i64, Arc<AtomicU32>>,
+    /// Last accepted time step per user id, so a code is only good once.
+    last_step: Cache<i64, u64>,
+}
+
+impl MfaChallenges {
+    pub(crate) fn new() -> Self {
+        MfaChallenges {
+            challenges: Cache::builder().time_to_live(MFA_CHALLENGE_TTL).build(),
+            failures: Cache::builder().time_to_live(MFA_CHALLENGE_TTL).build(),
+            // Codes are accepted one step either side of the current one
+            last_step: Cache::builder()
+                .time_to_idle(Duration::from_secs(3 * TOTP_STEP_SECS))
+                .build(),
+        }
+    }
+
+    /// Checks `code` and marks its step as used; `Some(step)` only once per step.
+    fn accept(&self, user_id: i64, secret: &[u8], code: &str) -> Option<u64> {
+        let step = verify_totp(secret, code, unix_time())?;
+        let result = self
+            .last_step
+            .entry(user_id)
+            .and_compute_with(|last| match last {
+                Some(last) if *last.value() >= step => Op::Nop,
+                _ => Op::Put(step),
+            });
+        matches!(
+            result,
+            CompResult::Inserted(_) | CompResult::ReplacedWith(_)
+        )
+        .then_some(step)
+    }
+
+    fn failures(&self, user_id: i64) -> Arc<AtomicU32> {
+        self.failures.get_with(user_id, Arc::default)
+    }
+}
+
+impl UserStore {
+    /// Opens a challenge for a user whose password was accepted, and returns
+    /// its id for `/api/auth/mfa/verify`.
+    pub fn start_mfa_challenge(&self, user_id: i64, mail: &str) -> String {
+        let id = new_secret();
+        let challenge = Challenge {
+            user_id,
+            mail: self.normalize_mail(mail).into_owned(),
+            attempts: AtomicU32::new(0),
+        };
+        self.mfa_challenges()
+            .challenges
+            .insert(id.clone(), Arc::new(challenge));
+        id
+    }
+
+    /// Mail of the account a pending challenge belongs to, for the audit log.
+    pub fn mfa_challenge_mail(&self, challenge_id: &str) -> Option<String> {
+        self.mfa_challenges()
+            .challenges
+            .get(challenge_id)
+            .map(|challenge| challenge.mail.clone())
+    }
+
+    /// Completes a login with the code of the challenged account.
+    pub fn verify_mfa(&self, challenge_id: &str, code: &str) -> Result<User, MfaError> {
+        let mfa = self.mfa_challenges();
+        let challenge = mfa
+            .challenges
+            .get(challenge_id)
+            .ok_or(MfaError::InvalidChallenge)?;
+        let failures = mfa.failures(challenge.user_id);
+        if failures.load(Ordering::Relaxed) >= ACCOUNT_FAILURES {
+            return Err(MfaError::TooManyAttempts);
+        }
+        if challenge.attempts.fetch_add(1, Ordering::Relaxed) >= CHALLENGE_ATTEMPTS {
+            mfa.challenges.invalidate(challenge_id);
+            return Err(MfaError::InvalidChallenge);
+        }
+
+        // Re-read the secret: TOTP may have been re-enrolled since the login
+        let conn = self.reader()?;
+        let secret: Option<String> = conn
+            .prepare_cached("SELECT totp_secret FROM user WHERE id = ?1")?
+            .query_row([challenge.user_id], |row| row.get(0))
+            .optional()?
+            .flatten();
+        let Some(secret) = secret.as_deref().and_then(base32_decode) else {
+            mfa.challenges.invalidate(challenge_id);
+            return Err(MfaError::InvalidChallenge);
+        };
+
+        if mfa.accept(challenge.user_id, &secret, code).is_none() {
+            failures.fetch_add(1, Ordering::Relaxed);
+            return Err(MfaError::InvalidCode);
+        }
+        mfa.challenges.invalidate(challenge_id);
+        Ok(User {
+            id: challenge.user_id,
+            mfa: true,
+        })
+    }
+
+    /// Generates a TOTP secret for the account and keeps it pending until
+    /// [`UserStore::confirm_totp`]; logins are unchanged until then. Refused
+    /// while TOTP is enabled, so the password alone cannot replace it.
+    pub fn enroll_totp(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+    ) -> Result<TotpEnrollment, MfaError> {
+        let mut bytes = [0u8; TOTP_SECRET_LEN];
+        getrandom::fill(&mut bytes).expect("operating system RNG unavailable");
+        let secret = base32_encode(&bytes);
+        let mail = &*self.normalize_mail(mail);
+
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        let (user_id, enabled): (i64, bool) = tx
+            .prepare_cached(
+                "SELECT id, totp_secret IS NOT NULL FROM user WHERE mail = ?1 AND hashed_password = ?2",
+            )?
+            .query_row([mail, hashed_password], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+            .ok_or(MfaError::InvalidCredentials)?;
+        if enabled {
+            return Err(MfaError::AlreadyEnrolled);
+        }
+        tx.prepare_cached("UPDATE user SET totp_pending = ?2 WHERE id = ?1")?
+            .execute((user_id, &secret))?;
+        tx.commit()?;
+
+        Ok(TotpEnrollment {
+            user_id,
+            otpauth_uri: otpauth_uri(TOTP_ISSUER, mail, &secret),
+            secret,
+        })
+    }
+
+    /// Checks `code` against the enabled TOTP secret of `mail`, for the account
+    /// changes that must not be possible with the password alone. Passes when
+    /// TOTP is off or the password does not match; the change itself refuses
+    /// the latter. Wrong codes count towards the same limit as logins.
+    pub(crate) fn verify_account_code(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+        code: Option<&str>,
+    ) -> Result<(), MfaError> {
+        let conn = self.reader()?;
+        let user: Option<(i64, Option<String>)> = conn
+            .prepare_cached(
+                "SELECT id, totp_secret FROM user WHERE mail = ?1 AND hashed_password = ?2",
+            )?
+            .query_row([mail, hashed_password], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        let Some((user_id, Some(secret))) = user else {
+            return Ok(());
+        };
+
+        let mfa = self.mfa_challenges();
+        let failures = mfa.failures(user_id);
+        if failures.load(Ordering::Relaxed) >= ACCOUNT_FAILURES {
+            return Err(MfaError::TooManyAttempts);
+        }
+        let secret = base32_decode(&secret).ok_or(MfaError::InvalidCode)?;
+        match code.and_then(|code| mfa.accept(user_id, &secret, code)) {
+            Some(_) => Ok(()),
+            None => {
+                failures.fetch_add(1, Ordering::Relaxed);
+                Err(MfaError::InvalidCode)
+            }
+        }
+    }
+
+    /// Enables the pending secret once the authenticator app proves it has it.
+    /// From then on, password logins answer with an MFA challenge.
+    pub fn confirm_totp(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+        code: &str,
+    ) -> Result<User, MfaError> {
+        let mail = &*self.normalize_mail(mail);
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        let (id, pending): (i64, Option<String>) = tx
+            .prepare_cached(
+                "SELECT id, totp_pending FROM user WHERE mail = ?1 AND hashed_password = ?2",
+            )?
+            .query_row([mail, hashed_password], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+            .ok_or(MfaError::InvalidCredentials)?;
+        let secret = pending
+            .as_deref()
+            .and_then(base32_decode)
+            .ok_or(MfaError::NotEnrolled)?;
+        if self.mfa_challenges().accept(id, &secret, code).is_none() {
+            return Err(MfaError::InvalidCode);
+        }
+
+        tx.prepare_cached(
+            "UPDATE user SET totp_secret = totp_pending, totp_pending = NULL WHERE id = ?1",
+        )?
+        .execute([id])?;
+        tx.commit()?;
+        self.forget_cached(mail);
+        Ok(User { id, mfa: true })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,87 @@
+// Ordered schema migrations, shared by every server.
+//
+// The version of a database is kept in `PRAGMA user_version`: migration `n`
+// (1-based position in `MIGRATIONS`) has been applied when it is `>= n`.
+// Migrations are append-only; a shipped one is never edited or reordered.
+// The first six predate versioning and are idempotent, since databases
+// created before them already have some of their tables at version 0.
+
+use rusqlite::{Connection, TransactionBehavior};
+use tracing::info;
+
+use crate::error::{StoreError, StoreResult};
+use crate::{account, apikey, audit, mfa, session, store, throttle};
+
+struct Migration {
+    description: &'static str,
+    up: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "user table and login index",
+        up: store::create_schema,
+    },
+    Migration {
+        description: "refresh-token sessions",
+        up: session::create_schema,
+    },
+    Migration {
+        description: "persisted login lockouts",
+        up: throttle::create_schema,
+    },
+    Migration {
+        description: "TOTP secret columns",
+        up: mfa::create_schema,
+    },
+    Migration {
+        description: "API keys",
+        up: apikey::create_schema,
+    },
+    Migration {
+        description: "authentication audit log",
+        up: audit::create_schema,
+    },
+    Migration {
+        description: "previous refresh hash of sessions",
+        up: session::add_previous_hash,
+    },
+    Migration {
+        description: "mail normalisation policy",
+        up: account::create_mail_policy,
+    },
+];
+
+/// Schema version this build creates and expects.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub(crate) fn user_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Applies the pending migrations, each in its own `IMMEDIATE` transaction
+/// so servers starting together on one file apply every step exactly once.
+/// Returns the version the database was at.
+pub(crate) fn migrate(conn: &mut Connection) -> StoreResult<u32> {
+    let found = user_version(conn)?;
+    if found > SCHEMA_VERSION {
+        return Err(StoreError::SchemaTooNew { found });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let version = index as u32 + 1;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another process may have got there between our read and the lock
+        if user_version(&tx)? >= version {
+            continue;
+        }
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!(
+            "Applied schema migration {}: {}",
+            version, migration.description
+        );
+    }
+    Ok(found)
+}
@@ -0,0 +1,269 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+use crate::store::UserStore;
+
+/// Largest `count` accepted by a seed job.
+pub const MAX_SEED_COUNT: usize = 100_000_000;
+/// Finished jobs whose status is kept; older ones are forgotten when a job starts.
+pub const KEPT_FINISHED_JOBS: usize = 16;
+
+/// What `create-db` should generate.
+///
+/// Rows are always `user{i}@example.com` for `i` in `1..=count`, so the load
+/// tester can address them; the password is `password_pattern` with `{i}`
+/// replaced by the same index.
+#[derive(Debug, Clone)]
+pub struct SeedParams {
+    pub count: usize,
+    pub password_pattern: String,
+    /// When set, rows are inserted in a seed-dependent shuffled order instead
+    /// of `1..=count`, so that row ids no longer follow the mail order.
+    pub seed: Option<u64>,
+}
+
+impl Default for SeedParams {
+    fn default() -> Self {
+        SeedParams {
+            count: 10000,
+            password_pattern: "password{i}".to_string(),
+            seed: None,
+        }
+    }
+}
+
+impl SeedParams {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.count == 0 || self.count > MAX_SEED_COUNT {
+            return Err("count must be between 1 and 100000000");
+        }
+        if self.password_pattern.is_empty() {
+            return Err("password pattern must not be empty");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn password(&self, i: usize) -> String {
+        self.password_pattern.replace("{i}", &i.to_string())
+    }
+
+    /// User indices in insertion order.
+    pub(crate) fn indices(&self) -> Box<dyn Iterator<Item = usize>> {
+        match self.seed {
+            None => Box::new(1..=self.count),
+            Some(seed) => Box::new(Shuffle::new(self.count, seed)),
+        }
+    }
+}
+
+/// Visits `1..=count` exactly once in a seed-dependent order without
+/// materialising it: a full-period LCG over the next power of two, skipping
+/// values that fall out of range.
+struct Shuffle {
+    count: u64,
+    mask: u64,
+    multiplier: u64,
+    increment: u64,
+    state: u64,
+    remaining: u64,
+}
+
+impl Shuffle {
+    fn new(count: usize, seed: u64) -> Self {
+        let count = count as u64;
+        let mask = count.next_power_of_two() - 1;
+        let mut mix = seed;
+        // Hull-Dobell: a full period modulo 2^k needs an odd increment and a
+        // multiplier congruent to 1 modulo 4.
+        let multiplier = (splitmix64(&mut mix) << 2) | 1;
+        let increment = splitmix64(&mut mix) | 1;
+        let state = splitmix64(&mut mix) & mask;
+        Shuffle {
+            count,
+            mask,
+            multiplier,
+            increment,
+            state,
+            remaining: count,
+        }
+    }
+}
+
+impl Iterator for Shuffle {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            self.state = self
+                .state
+                .wrapping_mul(self.multiplier)
+                .wrapping_add(self.increment)
+                & self.mask;
+            if self.state < self.count {
+                self.remaining -= 1;
+                return Some(self.state as usize + 1);
+            }
+        }
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Snapshot of a seed job, as reported by `/admin/create-db/{id}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JobStatus {
+    pub job_id: u64,
+    pub state: JobState,
+    pub total: usize,
+    pub inserted: usize,
+    pub elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    Invalid(&'static str),
+    /// Only one job may hold the writer at a time; carries the running job id.
+    AlreadyRunning(u64),
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Invalid(reason) => f.write_str(reason),
+            StartError::AlreadyRunning(id) => write!(f, "seed job {} is still running", id),
+        }
+    }
+}
+
+struct SeedJob {
+    id: u64,
+    total: usize,
+    inserted: AtomicUsize,
+    started: Instant,
+    finished: Mutex<Option<(Duration, Result<usize, String>)>>,
+}
+
+impl SeedJob {
+    fn status(&self) -> JobStatus {
+        let finished = self.finished.lock().unwrap();
+        let (state, elapsed, inserted, error) = match &*finished {
+            None => (
+                JobState::Running,
+                self.started.elapsed(),
+                self.inserted.load(Ordering::Relaxed),
+                None,
+            ),
+            Some((elapsed, Ok(inserted))) => (JobState::Completed, *elapsed, *inserted, None),
+            Some((elapsed, Err(e))) => (
+                JobState::Failed,
+                *elapsed,
+                self.inserted.load(Ordering::Relaxed),
+                Some(e.clone()),
+            ),
+        };
+        JobStatus {
+            job_id: self.id,
+            state,
+            total: self.total,
+            inserted,
+            elapsed_ms: elapsed.as_millis(),
+            error,
+        }
+    }
+}
+
+/// Registry of background `create-db` jobs.
+///
+/// Jobs run on their own OS thread so they never occupy a request worker, and
+/// at most one runs at a time since they all go through the single writer.
+/// The last [`KEPT_FINISHED_JOBS`] finished jobs stay queryable.
+#[derive(Default)]
+pub struct SeedJobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, Arc<SeedJob>>>,
+}
+
+impl SeedJobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, store: &UserStore, params: SeedParams) -> Result<JobStatus, StartError> {
+        params.validate().map_err(StartError::Invalid)?;
+
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(running) = jobs
+            .values()
+            .find(|job| job.finished.lock().unwrap().is_none())
+        {
+            return Err(StartError::AlreadyRunning(running.id));
+        }
+        // Nothing runs, so all jobs are finished: keep the most recent, with room for this one
+        if jobs.len() >= KEPT_FINISHED_JOBS {
+            let mut ids: Vec<u64> = jobs.keys().copied().collect();
+            ids.sort_unstable();
+            for id in &ids[..=ids.len() - KEPT_FINISHED_JOBS] {
+                jobs.remove(id);
+            }
+        }
+
+        let job = Arc::new(SeedJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            total: params.count,
+            inserted: AtomicUsize::new(0),
+            started: Instant::now(),
+            finished: Mutex::new(None),
+        });
+        jobs.insert(job.id, job.clone());
+        drop(jobs);
+
+        let store = store.clone();
+        let worker = job.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("seed-job-{}", job.id))
+            .spawn(move || {
+                info!("Seed job {} started: {} users", worker.id, params.count);
+                let result = store
+                    .seed_users(&params, &worker.inserted)
+                    .map_err(|e| e.to_string());
+                match &result {
+                    Ok(count) => info!("Seed job {} created {} users", worker.id, count),
+                    Err(e) => error!("Seed job {} failed: {}", worker.id, e),
+                }
+                *worker.finished.lock().unwrap() = Some((worker.started.elapsed(), result));
+            });
+        if let Err(e) = spawned {
+            *job.finished.lock().unwrap() = Some((Duration::ZERO, Err(e.to_string())));
+        }
+
+        Ok(job.status())
+    }
+
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.status())
+    }
+}
@@ -0,0 +1,246 @@
+use rusqlite::{Connection, OptionalExtension};
+use std::fmt;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+use crate::admin::constant_time_eq;
+use crate::error::{StoreError, StoreResult};
+use crate::store::{hash_password, UserStore};
+
+/// Random bytes behind each refresh token (and MFA challenge id).
+const SECRET_LEN: usize = 32;
+
+/// A live session, as handed back to the client after a login or a refresh.
+#[derive(Debug)]
+pub struct Session {
+    pub user_id: i64,
+    /// `<session id>.<secret>`; only the SHA-256 of the secret is stored.
+    pub refresh_token: String,
+    pub expires_in: Duration,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// Sessions are not enabled on this server.
+    Disabled,
+    /// Unknown, malformed, expired, revoked or already rotated token.
+    InvalidToken,
+    Store(StoreError),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Disabled => f.write_str("sessions are disabled"),
+            SessionError::InvalidToken => f.write_str("invalid or expired refresh token"),
+            SessionError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Into<StoreError>> From<E> for SessionError {
+    fn from(e: E) -> Self {
+        SessionError::Store(e.into())
+    }
+}
+
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS session (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            refresh_hash TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            revoked INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_session_user ON session(user_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_session_expires ON session(expires_at)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Keeps the hash a session had before its last rotation, so a replay of the
+/// rotated token can be told from a guess.
+pub(crate) fn add_previous_hash(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("ALTER TABLE session ADD COLUMN previous_hash TEXT", [])?;
+    Ok(())
+}
+
+/// Revokes every session of a user, inside the caller's transaction.
+pub(crate) fn revoke_user_sessions(conn: &Connection, user_id: i64) -> rusqlite::Result<()> {
+    conn.prepare_cached("UPDATE session SET revoked = 1 WHERE user_id = ?1")?
+        .execute([user_id])?;
+    Ok(())
+}
+
+/// Deletes every session of a user, inside the caller's transaction.
+pub(crate) fn delete_user_sessions(conn: &Connection, user_id: i64) -> rusqlite::Result<()> {
+    conn.prepare_cached("DELETE FROM session WHERE user_id = ?1")?
+        .execute([user_id])?;
+    Ok(())
+}
+
+impl UserStore {
+    /// Opens a session for a user that just logged in, or returns `None` when
+    /// sessions are disabled so the login stays read-only.
+    pub fn start_session(&self, user_id: i64) -> StoreResult<Option<Session>> {
+        let Some(ttl) = self.session_ttl() else {
+            return Ok(None);
+        };
+        let secret = new_secret();
+
+        let conn = self.writer()?;
+        let id: i64 = conn
+            .prepare_cached(
+                "INSERT INTO session (user_id, refresh_hash, expires_at) VALUES (?1, ?2, ?3) RETURNING id",
+            )?
+            .query_row(
+                (user_id, hash_password(&secret), expires_at(ttl)),
+                |row| row.get(0),
+            )?;
+
+        Ok(Some(Session {
+            user_id,
+            refresh_token: format!("{}.{}", id, secret),
+            expires_in: ttl,
+        }))
+    }
+
+    /// Exchanges a refresh token for a new one (rotation).
+    ///
+    /// Presenting the token a session was last rotated from means it leaked:
+    /// the whole session is revoked, so neither party can keep using it. Any
+    /// other wrong secret is only refused, or guessing session ids would log
+    /// everyone out.
+    pub fn refresh_session(&self, refresh_token: &str) -> Result<Session, SessionError> {
+        let ttl = self.session_ttl().ok_or(SessionError::Disabled)?;
+        let (id, secret) = parse_token(refresh_token).ok_or(SessionError::InvalidToken)?;
+
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        let row = tx
+            .prepare_cached(
+                "SELECT user_id, refresh_hash, previous_hash, expires_at, revoked FROM session WHERE id = ?1",
+            )?
+            .query_row([id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            })
+            .optional()?;
+
+        let Some((user_id, refresh_hash, previous_hash, expires, revoked)) = row else {
+            return Err(SessionError::InvalidToken);
+        };
+        if revoked || expires <= now() {
+            return Err(SessionError::InvalidToken);
+        }
+        let presented = hash_password(secret);
+        if !constant_time_eq(presented.as_bytes(), refresh_hash.as_bytes()) {
+            let replayed = previous_hash.is_some_and(|previous| {
+                constant_time_eq(presented.as_bytes(), previous.as_bytes())
+            });
+            if replayed {
+                tx.prepare_cached("UPDATE session SET revoked = 1 WHERE id = ?1")?
+                    .execute([id])?;
+                tx.commit()?;
+            }
+            return Err(SessionError::InvalidToken);
+        }
+
+        let secret = new_secret();
+        tx.prepare_cached(
+            "UPDATE session SET previous_hash = refresh_hash, refresh_hash = ?2, expires_at = ?3 WHERE id = ?1",
+        )?
+        .execute((id, hash_password(&secret), expires_at(ttl)))?;
+        tx.commit()?;
+
+        Ok(Session {
+            user_id,
+            refresh_token: format!("{}.{}", id, secret),
+            expires_in: ttl,
+        })
+    }
+
+    /// Revokes the session behind `refresh_token` (logout) and returns its user id.
+    pub fn end_session(&self, refresh_token: &str) -> Result<i64, SessionError> {
+        self.session_ttl().ok_or(SessionError::Disabled)?;
+        let (id, secret) = parse_token(refresh_token).ok_or(SessionError::InvalidToken)?;
+
+        let conn = self.writer()?;
+        let user_id = conn
+            .prepare_cached(
+                "UPDATE session SET revoked = 1 WHERE id = ?1 AND refresh_hash = ?2 RETURNING user_id",
+            )?
+            .query_row((id, hash_password(secret)), |row| row.get(0))
+            .optional()?;
+        user_id.ok_or(SessionError::InvalidToken)
+    }
+
+    /// Deletes expired sessions; revoked ones are kept until they expire so a
+    /// replayed token is still recognised.
+    pub fn purge_expired_sessions(&self) -> StoreResult<usize> {
+        let conn = self.writer()?;
+        let deleted = conn
+            .prepare_cached("DELETE FROM session WHERE expires_at <= ?1")?
+            .execute([now()])?;
+        Ok(deleted)
+    }
+
+    /// Starts the background thread that purges expired sessions every `interval`.
+    pub fn spawn_session_gc(&self, interval: Duration) -> std::io::Result<JoinHandle<()>> {
+        let store = self.clone();
+        std::thread::Builder::new()
+            .name("session-gc".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                match store.purge_expired_sessions() {
+                    Ok(0) => {}
+                    Ok(deleted) => info!("Purged {} expired sessions", deleted),
+                    Err(e) => error!("Session cleanup failed: {}", e),
+                }
+            })
+    }
+}
+
+fn parse_token(token: &str) -> Option<(i64, &str)> {
+    let (id, secret) = token.split_once('.')?;
+    Some((id.parse().ok()?, secret))
+}
+
+pub(crate) fn new_secret() -> String {
+    let mut bytes = [0u8; SECRET_LEN];
+    getrandom::fill(&mut bytes).expect("operating system RNG unavailable");
+    hex::encode(bytes)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn expires_at(ttl: Duration) -> i64 {
+    now().saturating_add(ttl.as_secs() as i64)
+}
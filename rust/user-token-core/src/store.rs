@@ -0,0 +1,697 @@
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug_span, error, info, warn};
+
+use crate::account::normalize_mail;
+use crate::cache::{CacheStats, CredentialCache};
+use crate::config::{CacheConfig, MailConfig, SessionConfig};
+use crate::error::StoreResult;
+use crate::metrics::{Metrics, Query};
+use crate::mfa::MfaChallenges;
+use crate::migrations;
+use crate::seed::SeedParams;
+use crate::session;
+
+type DbPool = Pool<SqliteConnectionManager>;
+
+/// Rows inserted per transaction by [`UserStore::seed_users`].
+const SEED_CHUNK: usize = 10_000;
+
+/// Connection wait of a store without [`UserStore::with_pool_timeout`], r2d2's default.
+const DEFAULT_POOL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Migration 1: the original table, plus the index logins are served from.
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            mail TEXT NOT NULL UNIQUE,
+            hashed_password TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_user_mail_password ON user(mail, hashed_password)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Size and occupancy of one connection pool.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PoolState {
+    pub max_size: u32,
+    /// Connections currently open.
+    pub connections: u32,
+    /// Open connections not checked out.
+    pub idle: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PoolStats {
+    pub read: PoolState,
+    pub write: PoolState,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct User {
+    pub id: i64,
+    /// TOTP is enabled: a password login must be completed with a code.
+    pub mfa: bool,
+}
+
+/// SQLite-backed user store.
+///
+/// Reads and writes go through separate pools: logins use a read-only pool
+/// (`SQLITE_OPEN_READ_ONLY` + `query_only`), while mutations such as
+/// `seed_users` are funnelled through a single writer connection.
+/// With WAL enabled, a reseed never blocks the readers: they keep seeing the
+/// previous snapshot until the writer commits.
+///
+/// An optional credential cache can be put in front of the read path with
+/// [`UserStore::with_cache`]; every mutation made through the store
+/// invalidates it.
+///
+/// Refresh-token sessions are enabled with [`UserStore::with_sessions`]; they
+/// live in the `session` table of the same database and go through the writer.
+///
+/// Accounts with TOTP enabled get an MFA challenge instead of a token at
+/// login; pending challenges are held in memory by the store.
+///
+/// Mail addresses are normalised (see [`normalize_mail`]) by every method
+/// that takes one, so lookups match however the address was typed.
+/// [`UserStore::with_mail_config`] sets the policy and brings the stored
+/// rows in line with it, once per policy.
+///
+/// With [`UserStore::with_metrics`], connection checkouts and the user-table
+/// statements are timed into the server's [`Metrics`].
+#[derive(Clone)]
+pub struct UserStore {
+    reader: DbPool,
+    writer: DbPool,
+    cache: Option<Arc<CredentialCache>>,
+    session_ttl: Option<Duration>,
+    mfa: Arc<MfaChallenges>,
+    mail: MailConfig,
+    metrics: Option<Arc<Metrics>>,
+    pool_timeout: Duration,
+}
+
+impl UserStore {
+    pub fn open(path: &str, read_pool_size: u32) -> StoreResult<Self> {
+        // The writer must be opened first: it creates the file, switches it to
+        // WAL and creates the schema, none of which a read-only connection can do.
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.pragma_update(None, "journal_mode", "WAL")?; // Enable WAL mode for better concurrency
+            conn.pragma_update(None, "synchronous", "NORMAL")?; // Balance durability vs performance
+            configure_connection(conn)
+        });
+        let writer = Pool::builder().max_size(1).build(manager)?;
+
+        {
+            let mut conn = writer.get()?;
+            migrations::migrate(&mut conn)?;
+
+            // Run ANALYZE to update query planner statistics
+            let _ = conn.execute("ANALYZE", []);
+
+            // Run PRAGMA optimize for automatic database optimization
+            let _ = conn.pragma_update(None, "optimize", "");
+        }
+
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .with_init(|conn| {
+                conn.pragma_update(None, "query_only", true)?; // Refuse writes even if the file is writable
+                configure_connection(conn)
+            });
+        let reader = Pool::builder().max_size(read_pool_size).build(manager)?;
+
+        Ok(UserStore {
+            reader,
+            writer,
+            cache: None,
+            session_ttl: None,
+            mfa: Arc::new(MfaChallenges::new()),
+            mail: MailConfig::default(),
+            metrics: None,
+            pool_timeout: DEFAULT_POOL_TIMEOUT,
+        })
+    }
+
+    pub fn with_cache(self, config: &CacheConfig) -> Self {
+        UserStore {
+            cache: Some(Arc::new(CredentialCache::new(config))),
+            ..self
+        }
+    }
+
+    /// How long a request waits for a free connection before failing with
+    /// [`StoreError::Pool`](crate::StoreError::Pool), which the servers answer
+    /// with `503`. Defaults to r2d2's 30 seconds.
+    pub fn with_pool_timeout(self, timeout: Duration) -> Self {
+        UserStore {
+            pool_timeout: timeout,
+            ..self
+        }
+    }
+
+    pub fn with_metrics(self, metrics: &Arc<Metrics>) -> Self {
+        UserStore {
+            metrics: Some(metrics.clone()),
+            ..self
+        }
+    }
+
+    pub fn with_sessions(self, config: &SessionConfig) -> Self {
+        UserStore {
+            session_ttl: Some(config.ttl),
+            ..self
+        }
+    }
+
+    /// Sets the mail normalisation policy. The first time a database is opened
+    /// with a policy, the stored mails that do not follow it are rewritten and
+    /// the policy is recorded in `mail_policy`, so later starts skip the scan.
+    /// A row whose normalised mail already belongs to another account is left
+    /// alone and reported: merging accounts is not ours to decide.
+    pub fn with_mail_config(self, config: &MailConfig) -> StoreResult<Self> {
+        let store = UserStore {
+            mail: *config,
+            ..self
+        };
+        store.normalize_stored_mails()?;
+        Ok(store)
+    }
+
+    fn normalize_stored_mails(&self) -> StoreResult<()> {
+        let config = &self.mail;
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        let recorded: Option<(bool, bool)> = tx
+            .query_row(
+                "SELECT lowercase, punycode FROM mail_policy WHERE id = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if recorded == Some((config.lowercase, config.punycode)) {
+            return Ok(());
+        }
+
+        // Only mails with an uppercase letter, whitespace or non-ASCII can change
+        let candidates = tx
+            .prepare("SELECT id, mail FROM user WHERE mail GLOB '*[A-Z]*' OR mail GLOB '*[^!-~]*'")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut rewritten = 0;
+        let mut conflicts = Vec::new();
+        {
+            let mut stmt = tx.prepare("UPDATE OR IGNORE user SET mail = ?2 WHERE id = ?1")?;
+            for (id, mail) in &candidates {
+                let normalized = normalize_mail(mail, config);
+                if normalized == mail.as_str() {
+                    continue;
+                }
+                if stmt.execute((id, normalized.as_ref()))? == 0 {
+                    conflicts.push((*id, mail.clone(), normalized.into_owned()));
+                } else {
+                    rewritten += 1;
+                }
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO mail_policy (id, lowercase, punycode) VALUES (1, ?1, ?2)",
+            (config.lowercase, config.punycode),
+        )?;
+        tx.commit()?;
+
+        info!(
+            "Mail policy (lowercase: {}, punycode: {}) applied to the stored mails: {} rewritten, {} left",
+            config.lowercase,
+            config.punycode,
+            rewritten,
+            conflicts.len()
+        );
+        for (id, mail, normalized) in &conflicts {
+            warn!(
+                "Account {} keeps the mail {:?}: {:?} belongs to another account",
+                id, mail, normalized
+            );
+        }
+        Ok(())
+    }
+
+    /// `mail` normalised with the store's policy.
+    pub fn normalize_mail<'a>(&self, mail: &'a str) -> Cow<'a, str> {
+        normalize_mail(mail, &self.mail)
+    }
+
+    pub(crate) fn mail_config(&self) -> &MailConfig {
+        &self.mail
+    }
+
+    /// Current `PRAGMA user_version` of the database, see [`SCHEMA_VERSION`](crate::SCHEMA_VERSION).
+    pub fn schema_version(&self) -> StoreResult<u32> {
+        let conn = self.reader()?;
+        Ok(migrations::user_version(&conn)?)
+    }
+
+    /// Lifetime of refresh tokens, `None` when sessions are disabled.
+    pub fn session_ttl(&self) -> Option<Duration> {
+        self.session_ttl
+    }
+
+    /// Size and occupancy of the read pool and of the writer.
+    pub fn pool_stats(&self) -> PoolStats {
+        let state = |pool: &DbPool| {
+            let state = pool.state();
+            PoolState {
+                max_size: pool.max_size(),
+                connections: state.connections,
+                idle: state.idle_connections,
+            }
+        };
+        PoolStats {
+            read: state(&self.reader),
+            write: state(&self.writer),
+        }
+    }
+
+    /// Checkpoints the WAL into `users.db` and truncates it, then drops this
+    /// handle's pools, so the file can be copied or reopened on its own.
+    ///
+    /// Call it once the server has stopped serving and the audit writer is
+    /// closed. Clones still alive, such as the session cleanup thread's, keep
+    /// their connections; a checkpoint one of their readers holds up is logged
+    /// as incomplete.
+    pub fn close(self) -> StoreResult<()> {
+        let conn = self.writer()?;
+        let (busy, frames, checkpointed): (i64, i64, i64) =
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+        if busy != 0 {
+            warn!(
+                "WAL checkpoint incomplete: {} of {} frames copied, a reader is still open",
+                checkpointed, frames
+            );
+        }
+        Ok(())
+    }
+
+    /// The single writer connection; every mutation must go through it.
+    pub(crate) fn writer(&self) -> StoreResult<PooledConnection<SqliteConnectionManager>> {
+        self.checkout(&self.writer, true)
+    }
+
+    /// A connection of the read-only pool.
+    pub(crate) fn reader(&self) -> StoreResult<PooledConnection<SqliteConnectionManager>> {
+        self.checkout(&self.reader, false)
+    }
+
+    /// A connection of the read-only pool, giving up after `timeout`. Not timed
+    /// into the metrics, so health probes do not skew the pool waits.
+    pub(crate) fn reader_within(
+        &self,
+        timeout: Duration,
+    ) -> StoreResult<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.reader.get_timeout(timeout)?)
+    }
+
+    fn checkout(
+        &self,
+        pool: &DbPool,
+        writer: bool,
+    ) -> StoreResult<PooledConnection<SqliteConnectionManager>> {
+        let pool_label = if writer { "write" } else { "read" };
+        let _span = debug_span!("db.pool.checkout", pool = pool_label).entered();
+        let Some(metrics) = &self.metrics else {
+            return Ok(pool.get_timeout(self.pool_timeout)?);
+        };
+        let start = Instant::now();
+        let conn = pool.get_timeout(self.pool_timeout);
+        metrics.observe_pool_wait(writer, start.elapsed());
+        Ok(conn?)
+    }
+
+    /// Runs `statement` in a `db.query` span, timing it when metrics are enabled.
+    fn timed<T>(&self, query: Query, statement: impl FnOnce() -> T) -> T {
+        let _span = debug_span!("db.query", query = query.label()).entered();
+        let Some(metrics) = &self.metrics else {
+            return statement();
+        };
+        let start = Instant::now();
+        let result = statement();
+        metrics.observe_query(query, start.elapsed());
+        result
+    }
+
+    pub(crate) fn mfa_challenges(&self) -> &MfaChallenges {
+        &self.mfa
+    }
+
+    /// Drops the cached credentials of `mail`, once a write to its row has committed.
+    pub(crate) fn forget_cached(&self, mail: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(mail);
+        }
+    }
+
+    /// Empties the credential cache, after a bulk write has committed.
+    pub(crate) fn forget_all_cached(&self) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_all();
+        }
+    }
+
+    /// Hit/miss counters of the credential cache (all zero when it is disabled).
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.stats())
+            .unwrap_or_default()
+    }
+
+    pub fn get_user_by_credentials(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+    ) -> StoreResult<Option<User>> {
+        // Borrowed, without allocating, for mails that are already normal
+        let mail = &*self.normalize_mail(mail);
+        if let Some(cache) = &self.cache {
+            return self.get_user_cached(cache, mail, hashed_password);
+        }
+
+        let conn = self.reader()?;
+
+        // Use prepare_cached for automatic statement caching
+        // Optimized query: only select the columns we need
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, totp_secret IS NOT NULL FROM user WHERE mail = ?1 AND hashed_password = ?2 LIMIT 1",
+        )?;
+
+        let user = self.timed(Query::Login, || {
+            stmt.query_row([mail, hashed_password], user_from_row)
+        });
+
+        match user {
+            Ok(u) => Ok(Some(u)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Cached variant of the login lookup: the cache is keyed on `mail` only
+    /// and holds the stored hash, so wrong passwords are answered from memory too.
+    fn get_user_cached(
+        &self,
+        cache: &CredentialCache,
+        mail: &str,
+        hashed_password: &str,
+    ) -> StoreResult<Option<User>> {
+        if let Some((user, stored_hash)) = cache.get(mail) {
+            return Ok((stored_hash == hashed_password).then_some(user));
+        }
+
+        let generation = cache.generation();
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, totp_secret IS NOT NULL, hashed_password FROM user WHERE mail = ?1 LIMIT 1",
+        )?;
+
+        let row = self.timed(Query::Login, || {
+            stmt.query_row([mail], |row| {
+                Ok((user_from_row(row)?, row.get::<_, String>(2)?))
+            })
+        });
+
+        match row {
+            Ok((user, stored_hash)) => {
+                cache.insert(mail, user, &stored_hash, generation);
+                Ok((stored_hash == hashed_password).then_some(user))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Inserts one user through the writer, or returns `None` when the mail
+    /// is already taken (`UNIQUE` constraint on `user.mail`).
+    pub(crate) fn insert_user(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+    ) -> StoreResult<Option<User>> {
+        let conn = self.writer()?;
+        let mut stmt =
+            conn.prepare_cached("INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)")?;
+
+        match self.timed(Query::InsertUser, || stmt.execute([mail, hashed_password])) {
+            Ok(_) => Ok(Some(User {
+                id: conn.last_insert_rowid(),
+                mfa: false,
+            })),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the password hash of `mail` if `hashed_password` is the current
+    /// one, and revokes the sessions of the account. The check and the update
+    /// are a single statement on the writer, so two concurrent changes cannot
+    /// both succeed against the same old hash.
+    pub(crate) fn update_password(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+        new_hashed_password: &str,
+    ) -> StoreResult<Option<User>> {
+        let conn = self.writer()?;
+        let user = self.timed(Query::UpdatePassword, || -> StoreResult<_> {
+            let tx = conn.unchecked_transaction()?;
+            let user = tx
+                .prepare_cached(
+                    "UPDATE user SET hashed_password = ?3 WHERE mail = ?1 AND hashed_password = ?2 RETURNING id, totp_secret IS NOT NULL",
+                )?
+                .query_row([mail, hashed_password, new_hashed_password], user_from_row);
+            if let Ok(user) = &user {
+                session::revoke_user_sessions(&tx, user.id)?;
+            }
+            tx.commit()?;
+            Ok(user)
+        })?;
+        self.after_account_write(mail, user)
+    }
+
+    /// Deletes `mail` and its sessions if `hashed_password` matches its stored hash.
+    pub(crate) fn delete_user(
+        &self,
+        mail: &str,
+        hashed_password: &str,
+    ) -> StoreResult<Option<User>> {
+        let conn = self.writer()?;
+        let user = self.timed(Query::DeleteUser, || -> StoreResult<_> {
+            let tx = conn.unchecked_transaction()?;
+            let user = tx
+                .prepare_cached(
+                    "DELETE FROM user WHERE mail = ?1 AND hashed_password = ?2 RETURNING id, totp_secret IS NOT NULL",
+                )?
+                .query_row([mail, hashed_password], user_from_row);
+            if let Ok(user) = &user {
+                session::delete_user_sessions(&tx, user.id)?;
+            }
+            tx.commit()?;
+            Ok(user)
+        })?;
+        self.after_account_write(mail, user)
+    }
+
+    /// Maps the outcome of a single-row mutation and drops the cached
+    /// credentials of `mail` once it has been committed.
+    fn after_account_write(
+        &self,
+        mail: &str,
+        user: rusqlite::Result<User>,
+    ) -> StoreResult<Option<User>> {
+        match user {
+            Ok(u) => {
+                self.forget_cached(mail);
+                Ok(Some(u))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces every user with the rows described by `params`, publishing
+    /// the number of rows inserted so far through `progress`.
+    ///
+    /// The rows are written to a staging copy of `user`, `SEED_CHUNK` per
+    /// transaction, so the writer is free for sessions and account changes
+    /// between chunks. Logins keep reading the old rows until the copy is
+    /// swapped in, in one transaction that also deletes every session. Users
+    /// written meanwhile are dropped with the old table.
+    pub fn seed_users(&self, params: &SeedParams, progress: &AtomicUsize) -> StoreResult<usize> {
+        {
+            let conn = self.writer()?;
+            // Use WAL checkpoint for better performance before bulk insert
+            let _ = conn.execute("PRAGMA wal_checkpoint(TRUNCATE)", []);
+            create_seed_table(&conn)?;
+        }
+
+        let mut inserted = 0;
+        let mut indices = params.indices();
+        loop {
+            // Hashed before the writer is checked out, which is then held for the inserts only
+            let rows: Vec<(String, String)> = indices
+                .by_ref()
+                .take(SEED_CHUNK)
+                .map(|i| {
+                    (
+                        format!("user{}@example.com", i),
+                        hash_password(&params.password(i)),
+                    )
+                })
+                .collect();
+            if rows.is_empty() {
+                break;
+            }
+
+            let conn = self.writer()?;
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "INSERT INTO user_seed (mail, hashed_password) VALUES (?1, ?2)",
+                )?;
+                for (email, hashed_password) in &rows {
+                    match stmt.execute([email, hashed_password]) {
+                        Ok(_) => inserted += 1,
+                        Err(e) => error!("Failed to insert user {}: {}", email, e),
+                    }
+                }
+            } // stmt is dropped here
+            tx.commit()?;
+            progress.store(inserted, Ordering::Relaxed);
+        }
+
+        let conn = self.writer()?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(
+            "DROP TABLE user;
+             ALTER TABLE user_seed RENAME TO user;
+             DELETE FROM session;",
+        )?;
+        tx.commit()?;
+
+        self.forget_all_cached();
+
+        // Run ANALYZE to update query planner statistics, sampled so that it
+        // does not hold the writer for a scan of every row
+        let _ = conn.pragma_update(None, "analysis_limit", 1000);
+        let _ = conn.execute("ANALYZE", []);
+
+        // Run PRAGMA optimize after bulk insert
+        let _ = conn.pragma_update(None, "optimize", "");
+
+        Ok(inserted)
+    }
+}
+
+/// Creates `user_seed`, an empty copy of `user` with its columns, indexes
+/// and id sequence, replacing what a failed reseed left. The indexes cannot
+/// be renamed once swapped in, so their names alternate with a `_seed`
+/// suffix from one reseed to the next.
+fn create_seed_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("DROP TABLE IF EXISTS user_seed", [])?;
+    // Everything from the column list on, whatever the quoting of the name
+    let body = |sql: &str| sql[sql.find('(').unwrap_or(sql.len())..].to_owned();
+
+    let table: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'user'",
+        [],
+        |row| row.get(0),
+    )?;
+    let indexes: Vec<(String, String)> = conn
+        .prepare(
+            "SELECT name, sql FROM sqlite_master
+             WHERE type = 'index' AND tbl_name = 'user' AND sql IS NOT NULL",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(&format!("CREATE TABLE user_seed {}", body(&table)), [])?;
+    // Ids carry on from the old table, so nothing keyed on an old id (an
+    // MFA challenge) reaches a new user
+    tx.execute(
+        "INSERT INTO sqlite_sequence (name, seq)
+         SELECT 'user_seed', seq FROM sqlite_sequence WHERE name = 'user'",
+        [],
+    )?;
+    for (name, sql) in indexes {
+        let name = match name.strip_suffix("_seed") {
+            Some(name) => name.to_owned(),
+            None => format!("{}_seed", name),
+        };
+        let unique = if sql.starts_with("CREATE UNIQUE") {
+            "UNIQUE "
+        } else {
+            ""
+        };
+        tx.execute(
+            &format!(
+                "CREATE {}INDEX {} ON user_seed {}",
+                unique,
+                name,
+                body(&sql)
+            ),
+            [],
+        )?;
+    }
+    tx.commit()
+}
+
+/// Maps `id, totp_secret IS NOT NULL`, the leading columns of user queries.
+fn user_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        mfa: row.get(1)?,
+    })
+}
+
+/// Per-connection settings, applied to every connection of both pools.
+fn configure_connection(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.pragma_update(None, "cache_size", "-64000")?; // 64MB cache (negative = KB)
+    conn.pragma_update(None, "temp_store", "MEMORY")?; // Store temp tables in memory
+    conn.pragma_update(None, "mmap_size", "268435456")?; // 256MB memory map
+
+    // Set busy timeout for handling concurrent access
+    conn.busy_timeout(Duration::from_millis(30000)) // 30 second timeout
+}
+
+pub fn hash_password(password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(password.as_bytes());
+    hex::encode(hasher.finalize())
+}
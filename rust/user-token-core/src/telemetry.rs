@@ -0,0 +1,87 @@
+use std::error::Error;
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+use tracing_subscriber::prelude::*;
+
+use crate::config::Config;
+
+/// Returned by [`init_tracing`]; keep it until the server stops. Dropping it
+/// flushes the spans the exporter has not sent yet.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Whether spans are exported. The servers only open a span per request
+    /// when they are, so a build without a collector pays nothing for it.
+    pub fn exporting(&self) -> bool {
+        #[cfg(feature = "otlp")]
+        return self.provider.is_some();
+        #[cfg(not(feature = "otlp"))]
+        false
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                warn!("Failed to flush the span exporter: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber: log lines at `INFO` on stdout
+/// and, in builds with the `otlp` feature and `MAXREQ_OTLP_ENDPOINT` set, the
+/// `DEBUG` spans of the `user_token*` crates exported in batches over
+/// OTLP/HTTP. Those are:
+///
+/// - `http.request`, opened by the servers around each request;
+/// - `json.parse` and `json.serialize` around the request and response bodies;
+/// - `db.pool.checkout` (`pool` = `read` or `write`);
+/// - `db.query` (`query` = `login`, `insert_user`, `update_password` or `delete_user`).
+///
+/// Other crates' spans are not exported, the exporter's HTTP client included.
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+pub fn init_tracing(config: &Config, service: &'static str) -> Result<Telemetry, Box<dyn Error>> {
+    let logs = tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO);
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &config.otlp_endpoint {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::trace::SdkTracerProvider;
+        use opentelemetry_sdk::Resource;
+        use tracing_subscriber::filter::Targets;
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder_empty().with_service_name(service).build())
+            .build();
+        let spans = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(service))
+            .with_filter(Targets::new().with_target("user_token", LevelFilter::DEBUG));
+        tracing_subscriber::registry()
+            .with(logs)
+            .with(spans)
+            .try_init()?;
+        tracing::info!("Exporting spans to {}", endpoint);
+        return Ok(Telemetry {
+            provider: Some(provider),
+        });
+    }
+
+    tracing_subscriber::registry().with(logs).try_init()?;
+    if config.otlp_endpoint.is_some() {
+        warn!("MAXREQ_OTLP_ENDPOINT is set but this build has no `otlp` feature");
+    }
+    Ok(Telemetry::default())
+}
@@ -0,0 +1,517 @@
+use rusqlite::Connection;
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+use crate::account::{normalize_mail, AccountError};
+use crate::audit::{flush_queue, Queued};
+use crate::config::{MailConfig, ThrottleConfig};
+use crate::error::StoreResult;
+use crate::mfa::MfaError;
+use crate::store::UserStore;
+
+/// Independent locks per table, so concurrent logins rarely contend.
+const SHARDS: usize = 64;
+/// Shard size above which idle entries are swept before recording a failure.
+const SWEEP_THRESHOLD: usize = 4096;
+/// Lockout writes waiting for the persisting thread; more are dropped.
+const PERSIST_QUEUE: usize = 1024;
+
+/// What a login attempt amounted to, as reported back to the throttle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginOutcome {
+    Authenticated,
+    /// Wrong mail or password. Database errors are neither and are not reported.
+    Rejected,
+}
+
+impl LoginOutcome {
+    /// What an account operation that checks the password amounted to; `None`
+    /// when it failed before the password was checked. A wrong TOTP code counts
+    /// like a wrong password.
+    pub fn of_account<T>(result: &Result<T, AccountError>) -> Option<Self> {
+        match result {
+            Ok(_) => Some(LoginOutcome::Authenticated),
+            Err(AccountError::InvalidCredentials | AccountError::InvalidCode) => {
+                Some(LoginOutcome::Rejected)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Same for TOTP enrolment.
+    pub fn of_mfa<T>(result: &Result<T, MfaError>) -> Option<Self> {
+        match result {
+            Ok(_) | Err(MfaError::NotEnrolled | MfaError::AlreadyEnrolled) => {
+                Some(LoginOutcome::Authenticated)
+            }
+            Err(MfaError::InvalidCredentials | MfaError::InvalidCode) => {
+                Some(LoginOutcome::Rejected)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ThrottleStats {
+    pub enabled: bool,
+    /// Lockouts started since the server started.
+    pub lockouts: u64,
+    /// Attempts refused with `429` because their account or IP was locked.
+    pub rejected: u64,
+    /// Accounts and IPs locked right now.
+    pub locked: u64,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// Failures inside the sliding window, oldest first (at most the limit).
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+    /// Lockouts in a row; each one doubles the next.
+    strikes: u32,
+}
+
+impl Entry {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let until = self.locked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Strikes are forgiven once a lockout has been over for `max_lockout`.
+    fn forgive(&mut self, now: Instant, config: &ThrottleConfig) {
+        if self
+            .locked_until
+            .is_some_and(|until| now >= until + config.max_lockout)
+        {
+            self.locked_until = None;
+            self.strikes = 0;
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant, config: &ThrottleConfig) -> bool {
+        self.forgive(now, config);
+        self.locked_until.is_none()
+            && self
+                .failures
+                .back()
+                .is_none_or(|last| now.duration_since(*last) >= config.window)
+    }
+}
+
+/// Sliding-window failure counters and lockouts for one kind of key.
+struct Table<K> {
+    /// Failures allowed per window; `0` disables the table.
+    limit: u32,
+    shards: Vec<Mutex<HashMap<K, Entry>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq> Table<K> {
+    fn new(limit: u32) -> Self {
+        Table {
+            limit,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &Mutex<HashMap<K, Entry>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    fn remaining<Q>(&self, key: &Q, now: Instant) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.limit == 0 {
+            return None;
+        }
+        self.shard(key).lock().unwrap().get(key)?.remaining(now)
+    }
+
+    /// Records a failure and returns `(strikes, lockout)` when it starts a lockout.
+    fn fail<Q>(&self, key: &Q, now: Instant, config: &ThrottleConfig) -> Option<(u32, Duration)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if self.limit == 0 {
+            return None;
+        }
+        let mut shard = self.shard(key).lock().unwrap();
+        if shard.len() >= SWEEP_THRESHOLD {
+            shard.retain(|_, entry| !entry.is_idle(now, config));
+        }
+
+        let entry = shard.entry(key.to_owned()).or_default();
+        entry.forgive(now, config);
+        if entry.remaining(now).is_some() {
+            return None;
+        }
+        while entry
+            .failures
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= config.window)
+        {
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+        if entry.failures.len() < self.limit as usize {
+            return None;
+        }
+
+        entry.failures.clear();
+        entry.strikes += 1;
+        let lockout = lockout_for(config, entry.strikes);
+        entry.locked_until = Some(now + lockout);
+        Some((entry.strikes, lockout))
+    }
+
+    /// Forgets `key`; returns whether it had strikes (and so may be persisted).
+    fn clear<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.limit == 0 {
+            return false;
+        }
+        let removed = self.shard(key).lock().unwrap().remove(key);
+        removed.is_some_and(|entry| entry.strikes > 0)
+    }
+
+    fn restore(&self, key: K, strikes: u32, locked_until: Instant) {
+        let entry = Entry {
+            failures: VecDeque::new(),
+            locked_until: Some(locked_until),
+            strikes,
+        };
+        self.shard(&key).lock().unwrap().insert(key, entry);
+    }
+
+    fn locked(&self, now: Instant) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .values()
+                    .filter(|entry| entry.remaining(now).is_some())
+                    .count() as u64
+            })
+            .sum()
+    }
+}
+
+/// A change to the `login_lockout` table.
+enum LockoutWrite {
+    Lock {
+        key: String,
+        locked_until: i64,
+        strikes: u32,
+    },
+    Clear(String),
+}
+
+/// Background writer of the persisted mode, so that a login never waits for
+/// the store's writer. Writes are applied in order, those queued together in
+/// one transaction.
+struct Persister {
+    sender: Option<SyncSender<Queued<LockoutWrite>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Persister {
+    fn start(store: &UserStore) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Queued<LockoutWrite>>(PERSIST_QUEUE);
+        let store = store.clone();
+        let writer = std::thread::Builder::new()
+            .name("lockout-writer".to_string())
+            .spawn(move || {
+                let mut batch = Vec::new();
+                while let Ok(first) = receiver.recv() {
+                    let mut flushed = Vec::new();
+                    for queued in std::iter::once(first).chain(receiver.try_iter()) {
+                        match queued {
+                            Queued::Item(write) => batch.push(write),
+                            Queued::Flush(done) => flushed.push(done),
+                        }
+                    }
+                    if !batch.is_empty() {
+                        if let Err(e) = write_lockouts(&store, &batch) {
+                            error!("Failed to persist {} lockout changes: {}", batch.len(), e);
+                        }
+                        batch.clear();
+                    }
+                    for done in flushed {
+                        let _ = done.send(());
+                    }
+                }
+            })?;
+        Ok(Persister {
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    fn send(&self, write: LockoutWrite) {
+        let Some(sender) = &self.sender else { return };
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) =
+            sender.try_send(Queued::Item(write))
+        {
+            error!("Lockout queue full; a lockout change is not persisted");
+        }
+    }
+}
+
+impl Drop for Persister {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lockouts(store: &UserStore, batch: &[LockoutWrite]) -> StoreResult<()> {
+    let mut conn = store.writer()?;
+    let tx = conn.transaction()?;
+    {
+        let mut lock = tx.prepare_cached(
+            "INSERT OR REPLACE INTO login_lockout (key, locked_until, strikes) VALUES (?1, ?2, ?3)",
+        )?;
+        let mut clear = tx.prepare_cached("DELETE FROM login_lockout WHERE key = ?1")?;
+        for write in batch {
+            match write {
+                LockoutWrite::Lock {
+                    key,
+                    locked_until,
+                    strikes,
+                } => lock.execute((key, locked_until, strikes))?,
+                LockoutWrite::Clear(key) => clear.execute([key])?,
+            };
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Failed-login throttling keyed by account and by client IP.
+///
+/// Framework-agnostic: a server asks [`LoginThrottle::check`] before running
+/// the login and reports its [`LoginOutcome`] afterwards. Once a key collects
+/// the configured number of failures inside the sliding window it is locked
+/// out; each further lockout doubles, up to `max_lockout`. A successful login
+/// clears the account (not the IP, which may be guessing several accounts).
+///
+/// State lives in memory. In persisted mode lockouts are also written to the
+/// `login_lockout` table, so a restart does not reset them. The writes go
+/// through a queue to a background thread; [`LoginThrottle::flush`] waits
+/// for them.
+pub struct LoginThrottle {
+    config: ThrottleConfig,
+    accounts: Table<String>,
+    ips: Table<IpAddr>,
+    persister: Option<Persister>,
+    /// Accounts are keyed on their normalised mail, so case variants share a lockout.
+    mail: MailConfig,
+    lockouts: AtomicU64,
+    rejected: AtomicU64,
+}
+
+pub(crate) fn create_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS login_lockout (
+            key TEXT PRIMARY KEY,
+            locked_until INTEGER NOT NULL,
+            strikes INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+impl LoginThrottle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        LoginThrottle {
+            config: config.clone(),
+            accounts: Table::new(config.account_failures),
+            ips: Table::new(config.ip_failures),
+            persister: None,
+            mail: MailConfig::default(),
+            lockouts: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// In-memory or persisted throttle, as `config.persist` says.
+    /// Accounts are keyed with the mail policy of `store`.
+    pub fn open(config: &ThrottleConfig, store: &UserStore) -> StoreResult<Self> {
+        let throttle = if config.persist {
+            LoginThrottle::persistent(config, store)?
+        } else {
+            LoginThrottle::new(config)
+        };
+        Ok(LoginThrottle {
+            mail: *store.mail_config(),
+            ..throttle
+        })
+    }
+
+    /// Persisted mode: reloads the lockouts (and strikes) of `login_lockout`
+    /// that are still relevant.
+    pub fn persistent(config: &ThrottleConfig, store: &UserStore) -> StoreResult<Self> {
+        let throttle = LoginThrottle {
+            persister: Some(
+                Persister::start(store).expect("Failed to start the lockout writer thread"),
+            ),
+            ..LoginThrottle::new(config)
+        };
+
+        let conn = store.writer()?;
+        // Rows whose strikes would already have been forgiven
+        let (unix_now, now) = (unix_now(), Instant::now());
+        conn.execute(
+            "DELETE FROM login_lockout WHERE locked_until <= ?1",
+            [unix_now - config.max_lockout.as_secs() as i64],
+        )?;
+
+        let mut stmt = conn.prepare("SELECT key, locked_until, strikes FROM login_lockout")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, u32>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (key, locked_until, strikes) = row?;
+            let offset = Duration::from_secs(locked_until.abs_diff(unix_now));
+            let locked_until = if locked_until >= unix_now {
+                now + offset
+            } else {
+                now.checked_sub(offset).unwrap_or(now)
+            };
+            if let Some(mail) = key.strip_prefix("account:") {
+                throttle
+                    .accounts
+                    .restore(mail.to_owned(), strikes, locked_until);
+            } else if let Some(ip) = key.strip_prefix("ip:").and_then(|ip| ip.parse().ok()) {
+                throttle.ips.restore(ip, strikes, locked_until);
+            }
+        }
+
+        Ok(throttle)
+    }
+
+    /// `Err(retry_after)` when the account or the client IP is locked out.
+    pub fn check(&self, mail: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let mail = &*normalize_mail(mail, &self.mail);
+        let now = Instant::now();
+        let account = self.accounts.remaining(mail, now);
+        let ip = ip.and_then(|ip| self.ips.remaining(&ip, now));
+        match account.max(ip) {
+            None => Ok(()),
+            Some(retry_after) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(retry_after)
+            }
+        }
+    }
+
+    pub fn record(&self, mail: &str, ip: Option<IpAddr>, outcome: LoginOutcome) {
+        let mail = &*normalize_mail(mail, &self.mail);
+        match outcome {
+            LoginOutcome::Authenticated => {
+                if self.accounts.clear(mail) {
+                    self.forget(format!("account:{}", mail));
+                }
+            }
+            LoginOutcome::Rejected => {
+                let now = Instant::now();
+                if let Some((strikes, lockout)) = self.accounts.fail(mail, now, &self.config) {
+                    self.locked_out(format!("account:{}", mail), strikes, lockout);
+                }
+                if let Some(ip) = ip {
+                    if let Some((strikes, lockout)) = self.ips.fail(&ip, now, &self.config) {
+                        self.locked_out(format!("ip:{}", ip), strikes, lockout);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        let now = Instant::now();
+        ThrottleStats {
+            enabled: true,
+            lockouts: self.lockouts.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            locked: self.accounts.locked(now) + self.ips.locked(now),
+        }
+    }
+
+    /// Waits until the lockout changes queued so far are persisted; returns
+    /// at once in memory-only mode.
+    pub fn flush(&self) {
+        if let Some(sender) = self.persister.as_ref().and_then(|p| p.sender.as_ref()) {
+            flush_queue(sender);
+        }
+    }
+
+    fn locked_out(&self, key: String, strikes: u32, lockout: Duration) {
+        self.lockouts.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Login lockout: {} locked for {:?} (strike {})",
+            key, lockout, strikes
+        );
+
+        let Some(persister) = &self.persister else {
+            return;
+        };
+        persister.send(LockoutWrite::Lock {
+            key,
+            locked_until: unix_now() + lockout.as_secs() as i64,
+            strikes,
+        });
+    }
+
+    fn forget(&self, key: String) {
+        if let Some(persister) = &self.persister {
+            persister.send(LockoutWrite::Clear(key));
+        }
+    }
+}
+
+/// `Retry-After` value for a lockout, rounded up so that a client retrying on
+/// time is never refused again.
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+fn lockout_for(config: &ThrottleConfig, strikes: u32) -> Duration {
+    let factor = 1u32.checked_shl(strikes - 1).unwrap_or(u32::MAX);
+    config
+        .lockout
+        .saturating_mul(factor)
+        .min(config.max_lockout)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
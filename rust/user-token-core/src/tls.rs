@@ -0,0 +1,86 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{NoServerSessionStorage, ServerConfig, ServerSessionMemoryCache};
+
+use crate::config::TlsConfig;
+
+/// ALPN id of HTTP/1.1, the protocol the axum and khttp servers speak.
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// Error loading the certificate or key of `MAXREQ_TLS_*`.
+#[derive(Debug)]
+pub enum TlsError {
+    /// The certificate file could not be read, or holds no certificate.
+    Cert { path: String, error: pem::Error },
+    /// The key file could not be read, or holds no private key.
+    Key { path: String, error: pem::Error },
+    /// rustls refused the key or the certificate, e.g. when they do not match.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Cert { path, error } => write!(f, "TLS certificate {}: {}", path, error),
+            TlsError::Key { path, error } => write!(f, "TLS private key {}: {}", path, error),
+            TlsError::Rustls(e) => write!(f, "TLS configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Cert { error, .. } | TlsError::Key { error, .. } => Some(error),
+            TlsError::Rustls(e) => Some(e),
+        }
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+/// rustls server configuration of `MAXREQ_TLS_*`, with the ring provider:
+/// TLS 1.2 and 1.3, no client certificates, `alpn` offered in order.
+///
+/// A client resumes a session without a full handshake either by session
+/// ID, from a cache of `session_cache` entries, or by a ticket encrypted
+/// with keys that rotate every 6 hours. TLS 1.3 clients get tickets either
+/// way: cached IDs when tickets are off.
+pub fn tls_server_config(config: &TlsConfig, alpn: &[&[u8]]) -> Result<ServerConfig, TlsError> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .and_then(|certs| match certs.is_empty() {
+            true => Err(pem::Error::NoItemsFound),
+            false => Ok(certs),
+        })
+        .map_err(|error| TlsError::Cert {
+            path: config.cert_path.clone(),
+            error,
+        })?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path).map_err(|error| TlsError::Key {
+        path: config.key_path.clone(),
+        error,
+    })?;
+
+    let mut server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    server.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    server.session_storage = match config.session_cache {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if config.tickets {
+        server.ticketer = ring::Ticketer::new()?;
+    }
+    Ok(server)
+}
@@ -0,0 +1,128 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+// RFC 6238 time-based one-time passwords (TOTP), on top of RFC 4226 HOTP.
+
+/// Step used by authenticator apps (and by the RFC test vectors).
+pub const TOTP_STEP_SECS: u64 = 30;
+/// Digits used by authenticator apps.
+pub const TOTP_DIGITS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// HOTP value of `counter` (RFC 4226 section 5.3), truncated to `digits`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32, algorithm: TotpAlgorithm) -> u32 {
+    let message = counter.to_be_bytes();
+    let digest = match algorithm {
+        TotpAlgorithm::Sha1 => hmac::<Hmac<Sha1>>(secret, &message),
+        TotpAlgorithm::Sha256 => hmac::<Hmac<Sha256>>(secret, &message),
+        TotpAlgorithm::Sha512 => hmac::<Hmac<Sha512>>(secret, &message),
+    };
+
+    // Dynamic truncation: the low nibble of the last byte picks 4 bytes
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(digits)
+}
+
+/// TOTP value at `unix_time` (RFC 6238 section 4), with T0 = 0.
+pub fn totp(
+    secret: &[u8],
+    unix_time: u64,
+    step_secs: u64,
+    digits: u32,
+    algorithm: TotpAlgorithm,
+) -> u32 {
+    hotp(secret, unix_time / step_secs, digits, algorithm)
+}
+
+/// Checks a 6-digit SHA-1 code, as produced by authenticator apps, allowing
+/// one step of clock skew either way. Returns the matching time step so the
+/// caller can refuse to accept it twice.
+pub fn verify_totp(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = unix_time / TOTP_STEP_SECS;
+    [current.saturating_sub(1), current, current + 1]
+        .into_iter()
+        .find(|&step| hotp(secret, step, TOTP_DIGITS, TotpAlgorithm::Sha1) == code)
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <M as hmac::digest::KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, the encoding of `secret=` in otpauth URIs.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// Decodes base32, ignoring case and padding; `None` on any other character.
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// `otpauth://totp/...` URI understood by authenticator apps (usually shown as a QR code).
+pub fn otpauth_uri(issuer: &str, account: &str, secret_base32: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret_base32}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECS}",
+        issuer = uri_escape(issuer),
+        account = uri_escape(account),
+    )
+}
+
+fn uri_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+use user_token_core::{Admission, AdmissionConfig};
+
+fn admission(max_in_flight: usize, max_queued: usize, queue_timeout_ms: u64) -> Admission {
+    Admission::new(&AdmissionConfig {
+        max_in_flight,
+        max_queued,
+        queue_timeout: Duration::from_millis(queue_timeout_ms),
+        adaptive: false,
+    })
+}
+
+#[test]
+fn full_queue_is_rejected_at_once_and_waiters_expire() {
+    let admission = admission(1, 1, 50);
+    let _running = admission.acquire_blocking().unwrap();
+
+    std::thread::scope(|scope| {
+        let waiter = scope.spawn(|| {
+            let started = Instant::now();
+            (admission.acquire_blocking().is_some(), started.elapsed())
+        });
+        while admission.stats().queued == 0 {
+            std::thread::yield_now();
+        }
+        // The only queue slot is taken
+        let started = Instant::now();
+        assert!(admission.acquire_blocking().is_none());
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let (admitted, waited) = waiter.join().unwrap();
+        assert!(!admitted);
+        assert!(waited >= Duration::from_millis(50), "{:?}", waited);
+    });
+
+    let stats = admission.stats();
+    assert_eq!(
+        (stats.admitted, stats.rejected, stats.expired, stats.queued),
+        (1, 1, 1, 0)
+    );
+}
+
+#[test]
+fn released_slot_goes_to_the_oldest_waiter() {
+    let admission = admission(1, 4, 5_000);
+    let running = admission.acquire_blocking().unwrap();
+
+    std::thread::scope(|scope| {
+        let first = scope.spawn(|| admission.acquire_blocking().map(|_| Instant::now()));
+        while admission.stats().queued < 1 {
+            std::thread::yield_now();
+        }
+        let second = scope.spawn(|| admission.acquire_blocking().map(|_| Instant::now()));
+        while admission.stats().queued < 2 {
+            std::thread::yield_now();
+        }
+        drop(running);
+
+        let first = first.join().unwrap().expect("first waiter shed");
+        let second = second.join().unwrap().expect("second waiter shed");
+        assert!(first <= second);
+    });
+    assert_eq!(admission.stats().admitted, 3);
+}
+
+#[test]
+fn adaptive_limit_shrinks_on_overload_and_grows_back() {
+    let admission = Admission::new(&AdmissionConfig {
+        max_in_flight: 16,
+        max_queued: 0,
+        queue_timeout: Duration::ZERO,
+        adaptive: true,
+    });
+
+    for _ in 0..200 {
+        let mut permit = admission.acquire_blocking().unwrap();
+        permit.overloaded();
+    }
+    let shrunk = admission.stats().limit;
+    assert!(shrunk < 16, "limit stayed at {}", shrunk);
+
+    // Fast requests that fill the limit raise it again
+    for _ in 0..2_000 {
+        let limit = admission.stats().limit as usize;
+        let permits: Vec<_> = (0..limit)
+            .map(|_| admission.acquire_blocking().unwrap())
+            .collect();
+        drop(permits);
+    }
+    assert_eq!(admission.stats().limit, 16);
+}
@@ -0,0 +1,20 @@
+//! Helpers shared by the tests that open a database.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use user_token_core::UserStore;
+
+/// Path of a fresh database file for the test `name`.
+pub fn db_path(name: &str) -> String {
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("user-token-core-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("users.db").to_string_lossy().into_owned()
+}
+
+/// A store on a fresh database file for the test `name`.
+pub fn store(name: &str) -> UserStore {
+    UserStore::open(&db_path(name), 2).unwrap()
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+use user_token_core::{CorsConfig, CorsPolicy};
+
+fn policy(origins: &[&str], headers: &[&str]) -> CorsPolicy {
+    CorsPolicy::new(&CorsConfig {
+        allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+        allowed_methods: vec!["GET".into(), "post".into()],
+        allowed_headers: headers.iter().map(|h| h.to_string()).collect(),
+        max_age: Duration::from_secs(600),
+    })
+}
+
+#[test]
+fn only_listed_origins_are_allowed() {
+    let policy = policy(&["https://app.example.com/"], &["Content-Type"]);
+    assert_eq!(
+        policy.allow_origin("https://APP.example.com"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(policy.allow_origin("https://evil.example.com"), None);
+    assert_eq!(
+        policy.response_headers("https://app.example.com"),
+        [
+            ("access-control-allow-origin", "https://app.example.com"),
+            ("access-control-expose-headers", "Retry-After"),
+            ("vary", "Origin"),
+        ]
+    );
+    assert!(policy.response_headers("null").is_empty());
+}
+
+#[test]
+fn wildcard_origin_needs_no_vary() {
+    let policy = policy(&["*"], &["*"]);
+    assert_eq!(
+        policy.response_headers("https://any.example.com"),
+        [
+            ("access-control-allow-origin", "*"),
+            ("access-control-expose-headers", "Retry-After"),
+        ]
+    );
+    let preflight = policy
+        .preflight("https://any.example.com", "POST", Some("x-anything"))
+        .unwrap();
+    assert!(preflight.contains(&("access-control-allow-headers", "*")));
+}
+
+#[test]
+fn preflight_checks_origin_method_and_headers() {
+    let policy = policy(
+        &["https://app.example.com"],
+        &["Content-Type", "Authorization"],
+    );
+    let origin = "https://app.example.com";
+    assert_eq!(
+        policy.preflight(origin, "post", Some("content-type, Authorization")),
+        Some(vec![
+            ("access-control-allow-origin", origin),
+            ("access-control-allow-methods", "GET, POST"),
+            (
+                "access-control-allow-headers",
+                "content-type, authorization"
+            ),
+            ("access-control-max-age", "600"),
+            (
+                "vary",
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"
+            ),
+        ])
+    );
+    assert!(policy.preflight(origin, "GET", None).is_some());
+    assert!(policy.preflight(origin, "DELETE", None).is_none());
+    assert!(policy
+        .preflight(origin, "POST", Some("content-type, x-secret"))
+        .is_none());
+    assert!(policy
+        .preflight("https://evil.example.com", "POST", None)
+        .is_none());
+}
@@ -0,0 +1,193 @@
+//! Mail normalisation: the policy itself, and the backfill that brings the
+//! stored mails in line with it once per policy.
+
+mod common;
+
+use rusqlite::Connection;
+use user_token_core::{hash_password, normalize_mail, MailConfig, UserStore};
+
+const LOWERCASE: MailConfig = MailConfig {
+    lowercase: true,
+    punycode: false,
+};
+const PUNYCODE: MailConfig = MailConfig {
+    lowercase: false,
+    punycode: true,
+};
+
+/// Stored mails, by id.
+fn mails(path: &str) -> Vec<String> {
+    let conn = Connection::open(path).unwrap();
+    let mut stmt = conn.prepare("SELECT mail FROM user ORDER BY id").unwrap();
+    stmt.query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+}
+
+/// Inserts `mail` as is, the way an older build or an import could have.
+fn insert_raw(path: &str, mail: &str) {
+    let conn = Connection::open(path).unwrap();
+    conn.execute(
+        "INSERT INTO user (mail, hashed_password) VALUES (?1, ?2)",
+        (mail, hash_password("correct horse 42")),
+    )
+    .unwrap();
+}
+
+#[test]
+fn domain_is_always_lowercased_and_trimmed() {
+    let config = MailConfig::default();
+    assert_eq!(
+        normalize_mail("  Alice@Example.COM ", &config),
+        "Alice@example.com"
+    );
+    assert_eq!(
+        normalize_mail("user1@example.com", &config),
+        "user1@example.com"
+    );
+    // Without `@`, only the opt-in lowercasing applies
+    assert_eq!(normalize_mail(" Alice ", &config), "Alice");
+    assert_eq!(normalize_mail(" Alice ", &LOWERCASE), "alice");
+}
+
+#[test]
+fn local_part_is_lowercased_when_enabled() {
+    assert_eq!(
+        normalize_mail("Alice.Smith@Example.com", &LOWERCASE),
+        "alice.smith@example.com"
+    );
+    assert_eq!(
+        normalize_mail("Ärger@example.com", &LOWERCASE),
+        "ärger@example.com"
+    );
+    assert_eq!(
+        normalize_mail("Alice.Smith@Example.com", &MailConfig::default()),
+        "Alice.Smith@example.com"
+    );
+}
+
+#[test]
+fn idn_domains_become_punycode_when_enabled() {
+    assert_eq!(
+        normalize_mail("anna@Bücher.de", &PUNYCODE),
+        "anna@xn--bcher-kva.de"
+    );
+    assert_eq!(
+        normalize_mail("anna@例え.jp", &PUNYCODE),
+        "anna@xn--r8jz45g.jp"
+    );
+    // Off, the domain is only lowercased and registration rejects it
+    assert_eq!(
+        normalize_mail("anna@Bücher.de", &MailConfig::default()),
+        "anna@bücher.de"
+    );
+    // A domain IDNA refuses is kept, lowercased
+    assert_eq!(
+        normalize_mail("anna@\u{301}Bücher.de", &PUNYCODE),
+        "anna@\u{301}bücher.de"
+    );
+}
+
+#[test]
+fn lookups_use_the_policy() {
+    let store = common::store("mail-lookup")
+        .with_mail_config(&LOWERCASE)
+        .unwrap();
+    let user = store
+        .register(" Alice@Example.com", "correct horse 42")
+        .unwrap();
+    let found = store
+        .get_user_by_credentials("ALICE@example.COM", &hash_password("correct horse 42"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.id, user.id);
+    assert!(store
+        .register("alice@example.com", "correct horse 42")
+        .is_err());
+}
+
+#[test]
+fn backfill_rewrites_mails_and_leaves_collisions() {
+    let path = common::db_path("mail-backfill");
+    drop(UserStore::open(&path, 2).unwrap());
+    insert_raw(&path, "Bob@Example.com");
+    insert_raw(&path, "bob@example.com");
+    insert_raw(&path, " Carol@Example.com ");
+    insert_raw(&path, "dave@Bücher.de");
+
+    // The default policy only trims and lowercases domains
+    let store = UserStore::open(&path, 2)
+        .unwrap()
+        .with_mail_config(&MailConfig::default())
+        .unwrap();
+    assert_eq!(
+        mails(&path),
+        [
+            "Bob@example.com",
+            "bob@example.com",
+            "Carol@example.com",
+            "dave@bücher.de"
+        ]
+    );
+    drop(store);
+
+    // Lowercased, Bob's collides with the other account and is left alone
+    let store = UserStore::open(&path, 2)
+        .unwrap()
+        .with_mail_config(&MailConfig {
+            lowercase: true,
+            punycode: true,
+        })
+        .unwrap();
+    assert_eq!(
+        mails(&path),
+        [
+            "Bob@example.com",
+            "bob@example.com",
+            "carol@example.com",
+            "dave@xn--bcher-kva.de"
+        ]
+    );
+    let dave = store
+        .get_user_by_credentials("Dave@bücher.DE", &hash_password("correct horse 42"))
+        .unwrap();
+    assert!(dave.is_some());
+}
+
+#[test]
+fn backfill_runs_once_per_policy() {
+    let path = common::db_path("mail-once");
+    drop(
+        UserStore::open(&path, 2)
+            .unwrap()
+            .with_mail_config(&LOWERCASE)
+            .unwrap(),
+    );
+
+    // Same policy: the rows are not scanned again
+    insert_raw(&path, "Erin@Example.com");
+    drop(
+        UserStore::open(&path, 2)
+            .unwrap()
+            .with_mail_config(&LOWERCASE)
+            .unwrap(),
+    );
+    assert_eq!(mails(&path), ["Erin@Example.com"]);
+
+    // A changed policy is applied, and recorded
+    drop(
+        UserStore::open(&path, 2)
+            .unwrap()
+            .with_mail_config(&PUNYCODE)
+            .unwrap(),
+    );
+    assert_eq!(mails(&path), ["Erin@example.com"]);
+    let conn = Connection::open(&path).unwrap();
+    let policy: (bool, bool) = conn
+        .query_row("SELECT lowercase, punycode FROM mail_policy", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!(policy, (false, true));
+}
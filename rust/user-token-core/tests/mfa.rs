@@ -0,0 +1,131 @@
+//! TOTP enrolment and the account changes it protects: once TOTP is enabled,
+//! the password alone can neither replace the secret nor change the account.
+
+mod common;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use user_token_core::{
+    base32_decode, hash_password, totp, AccountError, MfaError, TotpAlgorithm, UserStore,
+    TOTP_DIGITS, TOTP_STEP_SECS,
+};
+
+const MAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse 42";
+
+/// The code of `secret` `steps` time steps from now.
+fn code(secret: &str, steps: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let time = now.saturating_add_signed(steps * TOTP_STEP_SECS as i64);
+    let secret = base32_decode(secret).unwrap();
+    let code = totp(
+        &secret,
+        time,
+        TOTP_STEP_SECS,
+        TOTP_DIGITS,
+        TotpAlgorithm::Sha1,
+    );
+    format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+/// A store with one account that has TOTP enabled; returns its secret.
+fn enrolled(name: &str) -> (UserStore, String) {
+    let store = common::store(name);
+    store.register(MAIL, PASSWORD).unwrap();
+    let enrollment = store.enroll_totp(MAIL, &hash_password(PASSWORD)).unwrap();
+    store
+        .confirm_totp(MAIL, &hash_password(PASSWORD), &code(&enrollment.secret, 0))
+        .unwrap();
+    (store, enrollment.secret)
+}
+
+#[test]
+fn enrolment_is_refused_while_totp_is_enabled() {
+    let (store, _) = enrolled("mfa-reenroll");
+    let hashed = hash_password(PASSWORD);
+
+    assert!(matches!(
+        store.enroll_totp(MAIL, &hashed),
+        Err(MfaError::AlreadyEnrolled)
+    ));
+    assert!(matches!(
+        store.enroll_totp(MAIL, "wrong"),
+        Err(MfaError::InvalidCredentials)
+    ));
+    // Logins still ask for the confirmed secret
+    let user = store
+        .get_user_by_credentials(MAIL, &hashed)
+        .unwrap()
+        .unwrap();
+    assert!(user.mfa);
+}
+
+#[test]
+fn pending_enrolment_can_be_replaced() {
+    let store = common::store("mfa-pending");
+    store.register(MAIL, PASSWORD).unwrap();
+    let hashed = hash_password(PASSWORD);
+    let first = store.enroll_totp(MAIL, &hashed).unwrap();
+    let second = store.enroll_totp(MAIL, &hashed).unwrap();
+    assert_ne!(first.secret, second.secret);
+
+    assert!(matches!(
+        store.confirm_totp(MAIL, &hashed, &code(&first.secret, 0)),
+        Err(MfaError::InvalidCode)
+    ));
+    store
+        .confirm_totp(MAIL, &hashed, &code(&second.secret, 0))
+        .unwrap();
+}
+
+#[test]
+fn account_changes_need_the_code_once_enabled() {
+    let (store, secret) = enrolled("mfa-account");
+    let hashed = hash_password(PASSWORD);
+    let new_password = "battery staple 7";
+
+    for code in [None, Some("000000")] {
+        assert!(matches!(
+            store.change_password(MAIL, &hashed, new_password, code),
+            Err(AccountError::InvalidCode)
+        ));
+        assert!(matches!(
+            store.delete_account(MAIL, &hashed, code),
+            Err(AccountError::InvalidCode)
+        ));
+    }
+    // A wrong password is reported as such, whatever the code
+    assert!(matches!(
+        store.change_password(MAIL, "wrong", new_password, None),
+        Err(AccountError::InvalidCredentials)
+    ));
+
+    // The next step's code is accepted once
+    let next = code(&secret, 1);
+    store
+        .change_password(MAIL, &hashed, new_password, Some(&next))
+        .unwrap();
+    let hashed = hash_password(new_password);
+    assert!(matches!(
+        store.delete_account(MAIL, &hashed, Some(&next)),
+        Err(AccountError::InvalidCode)
+    ));
+    assert!(store
+        .get_user_by_credentials(MAIL, &hashed)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn accounts_without_totp_need_no_code() {
+    let store = common::store("mfa-none");
+    store.register(MAIL, PASSWORD).unwrap();
+    store
+        .change_password(MAIL, &hash_password(PASSWORD), "battery staple 7", None)
+        .unwrap();
+    store
+        .delete_account(MAIL, &hash_password("battery staple 7"), None)
+        .unwrap();
+}
@@ -0,0 +1,102 @@
+//! Schema migrations: a database from before versioning is brought to the
+//! current version with its data, and one from a newer build is refused.
+
+mod common;
+
+use rusqlite::Connection;
+use user_token_core::{hash_password, StoreError, UserStore, SCHEMA_VERSION};
+
+fn user_version(path: &str) -> u32 {
+    Connection::open(path)
+        .unwrap()
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .unwrap()
+}
+
+fn columns(path: &str, table: &str) -> Vec<String> {
+    let conn = Connection::open(path).unwrap();
+    let mut stmt = conn
+        .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+        .unwrap();
+    stmt.query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+}
+
+#[test]
+fn new_database_is_created_at_the_current_version() {
+    let path = common::db_path("migrations-new");
+    let store = UserStore::open(&path, 2).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+    drop(store);
+
+    // Opening again applies nothing
+    let store = UserStore::open(&path, 2).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+}
+
+#[test]
+fn unversioned_database_is_upgraded_with_its_data() {
+    // What a build from before the migrations left: the user and session
+    // tables, without the later columns, at version 0
+    let path = common::db_path("migrations-upgrade");
+    let hashed = hash_password("correct horse 42");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE user (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            mail TEXT NOT NULL UNIQUE,
+            hashed_password TEXT NOT NULL
+        );
+        CREATE TABLE session (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            refresh_hash TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            revoked INTEGER NOT NULL DEFAULT 0
+        );",
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO user (mail, hashed_password) VALUES ('alice@example.com', ?1)",
+        [&hashed],
+    )
+    .unwrap();
+    drop(conn);
+    assert_eq!(user_version(&path), 0);
+
+    let store = UserStore::open(&path, 2).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+    let user = store
+        .get_user_by_credentials("alice@example.com", &hashed)
+        .unwrap()
+        .expect("the existing user was lost");
+    assert!(!user.mfa);
+    assert!(columns(&path, "session").contains(&"previous_hash".to_owned()));
+    assert!(columns(&path, "user").contains(&"totp_secret".to_owned()));
+    for table in ["api_key", "auth_event", "login_lockout", "mail_policy"] {
+        assert!(!columns(&path, table).is_empty(), "{} is missing", table);
+    }
+}
+
+#[test]
+fn newer_database_is_refused() {
+    let path = common::db_path("migrations-too-new");
+    drop(UserStore::open(&path, 2).unwrap());
+    Connection::open(&path)
+        .unwrap()
+        .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+        .unwrap();
+
+    let Err(error) = UserStore::open(&path, 2) else {
+        panic!("a database from a newer build was opened");
+    };
+    assert!(
+        matches!(error, StoreError::SchemaTooNew { found } if found == SCHEMA_VERSION + 1),
+        "{}",
+        error
+    );
+    // Left as it was
+    assert_eq!(user_version(&path), SCHEMA_VERSION + 1);
+}
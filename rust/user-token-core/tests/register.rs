@@ -0,0 +1,80 @@
+//! Registration: the mail and password rules, and one account per mail.
+
+mod common;
+
+use user_token_core::{hash_password, AccountError};
+
+#[test]
+fn invalid_mails_are_rejected() {
+    let store = common::store("register-mail");
+    let long_local = format!("{}@example.com", "a".repeat(65));
+    let long_mail = format!("a@{}.com", "b".repeat(250));
+    for mail in [
+        "",
+        "alice.example.com",
+        "@example.com",
+        long_local.as_str(),
+        long_mail.as_str(),
+        ".alice@example.com",
+        "alice.@example.com",
+        "al..ice@example.com",
+        "al ice@example.com",
+        "alice@localhost",
+        "alice@-example.com",
+        "alice@example..com",
+        "alice@exa_mple.com",
+    ] {
+        assert!(
+            matches!(
+                store.register(mail, "correct horse 42"),
+                Err(AccountError::Invalid(_))
+            ),
+            "{:?} was accepted",
+            mail
+        );
+    }
+}
+
+#[test]
+fn weak_passwords_are_rejected() {
+    let store = common::store("register-password");
+    let long = format!("a1{}", "b".repeat(127));
+    for password in ["short1", "no digits at all", "12345678", long.as_str()] {
+        assert!(
+            matches!(
+                store.register("alice@example.com", password),
+                Err(AccountError::Invalid(_))
+            ),
+            "{:?} was accepted",
+            password
+        );
+    }
+    // Nothing was created by the rejected attempts
+    assert!(store
+        .register("alice@example.com", "correct horse 42")
+        .is_ok());
+}
+
+#[test]
+fn a_mail_has_one_account() {
+    let store = common::store("register-taken");
+    let user = store
+        .register("alice@example.com", "correct horse 42")
+        .unwrap();
+    assert!(!user.mfa);
+    assert!(matches!(
+        store.register("alice@example.com", "another one 43"),
+        Err(AccountError::MailTaken)
+    ));
+    // The same mail once normalised
+    assert!(matches!(
+        store.register("  alice@EXAMPLE.com ", "another one 43"),
+        Err(AccountError::MailTaken)
+    ));
+
+    // The first password still logs in
+    let login = store
+        .get_user_by_credentials("alice@example.com", &hash_password("correct horse 42"))
+        .unwrap();
+    assert_eq!(login.map(|login| login.id), Some(user.id));
+}
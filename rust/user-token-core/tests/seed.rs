@@ -0,0 +1,167 @@
+//! Seed jobs: logins, and the sessions they write, keep working while a job
+//! runs, and the seeded users replace the old ones once it ends.
+
+mod common;
+
+use rusqlite::Connection;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+use user_token_core::{
+    hash_password, JobState, JobStatus, SeedJobs, SeedParams, SessionConfig, SessionError,
+    UserStore, KEPT_FINISHED_JOBS,
+};
+
+fn index_names(path: &str) -> Vec<String> {
+    let conn = Connection::open(path).unwrap();
+    let mut stmt = conn
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'user' AND sql IS NOT NULL")
+        .unwrap();
+    stmt.query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+}
+
+fn wait(jobs: &SeedJobs, id: u64) -> JobStatus {
+    loop {
+        let status = jobs.status(id).unwrap();
+        if status.state != JobState::Running {
+            return status;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn logins_start_sessions_while_a_seed_runs() {
+    let path = common::db_path("seed-logins");
+    // Far shorter than the job: a login waiting for the writer until the end fails
+    let store = UserStore::open(&path, 2)
+        .unwrap()
+        .with_sessions(&SessionConfig {
+            ttl: Duration::from_secs(3600),
+            gc_interval: Duration::from_secs(60),
+        })
+        .with_pool_timeout(Duration::from_millis(500));
+    let alice = store
+        .register("alice@example.com", "correct horse 42")
+        .unwrap();
+    let hashed = hash_password("correct horse 42");
+    let old_session = store.start_session(alice.id).unwrap().unwrap();
+
+    let jobs = SeedJobs::new();
+    let params = SeedParams {
+        count: 100_000,
+        ..SeedParams::default()
+    };
+    let job = jobs.start(&store, params).unwrap();
+    let mut logins_during = 0;
+    loop {
+        let status = jobs.status(job.job_id).unwrap();
+        if status.state != JobState::Running {
+            break;
+        }
+        let Some(user) = store
+            .get_user_by_credentials("alice@example.com", &hashed)
+            .unwrap()
+        else {
+            // Swapped out once every row is in, before the job ends
+            let inserted = jobs.status(job.job_id).unwrap().inserted;
+            assert_eq!(inserted, 100_000, "the old rows are gone mid-job");
+            break;
+        };
+        store
+            .start_session(user.id)
+            .expect("a login waited for the seed")
+            .unwrap();
+        if status.inserted > 0 {
+            logins_during += 1;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let status = wait(&jobs, job.job_id);
+    assert_eq!(status.state, JobState::Completed, "{:?}", status.error);
+    assert_eq!(status.inserted, 100_000);
+    assert!(logins_during > 0, "no login ran between two chunks");
+
+    // The old users and their sessions are gone
+    assert!(store
+        .get_user_by_credentials("alice@example.com", &hashed)
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        store.refresh_session(&old_session.refresh_token),
+        Err(SessionError::InvalidToken)
+    ));
+    let user = store
+        .get_user_by_credentials("user70000@example.com", &hash_password("password70000"))
+        .unwrap()
+        .expect("a seeded user cannot log in");
+    assert!(user.id > alice.id, "ids started over");
+    assert!(store.start_session(user.id).unwrap().is_some());
+}
+
+#[test]
+fn reseeding_keeps_the_schema() {
+    let path = common::db_path("seed-schema");
+    let store = UserStore::open(&path, 2).unwrap();
+    let progress = AtomicUsize::new(0);
+    assert_eq!(index_names(&path), ["idx_user_mail_password"]);
+
+    for (round, count) in [(1, 20_001), (2, 3)] {
+        let params = SeedParams {
+            count,
+            seed: Some(round),
+            ..SeedParams::default()
+        };
+        assert_eq!(store.seed_users(&params, &progress).unwrap(), count);
+        assert_eq!(
+            store
+                .get_user_by_credentials("user3@example.com", &hash_password("password3"))
+                .unwrap()
+                .map(|user| user.mfa),
+            Some(false)
+        );
+    }
+    // The index names alternate between reseeds
+    assert_eq!(index_names(&path), ["idx_user_mail_password"]);
+
+    // The swapped-in table takes the later migrations' columns and constraints
+    let user = store
+        .register("bob@example.com", "correct horse 42")
+        .unwrap();
+    store
+        .enroll_totp("bob@example.com", &hash_password("correct horse 42"))
+        .unwrap();
+    assert!(matches!(
+        store.register("user1@example.com", "correct horse 42"),
+        Err(user_token_core::AccountError::MailTaken)
+    ));
+    assert!(user.id > 20_001);
+}
+
+#[test]
+fn only_the_last_finished_jobs_are_kept() {
+    let store = common::store("seed-jobs");
+    let jobs = SeedJobs::new();
+    let params = SeedParams {
+        count: 3,
+        ..SeedParams::default()
+    };
+    let ids: Vec<u64> = (0..KEPT_FINISHED_JOBS + 2)
+        .map(|_| {
+            let job = jobs.start(&store, params.clone()).unwrap();
+            assert_eq!(wait(&jobs, job.job_id).state, JobState::Completed);
+            job.job_id
+        })
+        .collect();
+
+    let (forgotten, kept) = ids.split_at(2);
+    for id in forgotten {
+        assert!(jobs.status(*id).is_none(), "job {} is still kept", id);
+    }
+    for id in kept {
+        assert_eq!(jobs.status(*id).unwrap().inserted, 3);
+    }
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::time::Duration;
+use user_token_core::{SessionConfig, SessionError, UserStore};
+
+fn store(name: &str) -> (UserStore, String) {
+    let path = common::db_path(name);
+    let store = UserStore::open(&path, 2)
+        .unwrap()
+        .with_sessions(&SessionConfig {
+            ttl: Duration::from_secs(3600),
+            gc_interval: Duration::from_secs(60),
+        });
+    (store, path)
+}
+
+/// The id part of a `<session id>.<secret>` token.
+fn session_id(token: &str) -> &str {
+    token.split_once('.').unwrap().0
+}
+
+#[test]
+fn refresh_rotates_the_token() {
+    let (store, _) = store("session-rotate");
+    let first = store.start_session(7).unwrap().unwrap();
+    let second = store.refresh_session(&first.refresh_token).unwrap();
+    assert_eq!(second.user_id, 7);
+    assert_eq!(
+        session_id(&second.refresh_token),
+        session_id(&first.refresh_token)
+    );
+    assert_ne!(second.refresh_token, first.refresh_token);
+
+    let third = store.refresh_session(&second.refresh_token).unwrap();
+    assert_eq!(third.user_id, 7);
+}
+
+#[test]
+fn replaying_a_rotated_token_revokes_the_session() {
+    let (store, _) = store("session-replay");
+    let first = store.start_session(7).unwrap().unwrap();
+    let second = store.refresh_session(&first.refresh_token).unwrap();
+
+    assert!(matches!(
+        store.refresh_session(&first.refresh_token),
+        Err(SessionError::InvalidToken)
+    ));
+    // The legitimate holder is logged out too
+    assert!(matches!(
+        store.refresh_session(&second.refresh_token),
+        Err(SessionError::InvalidToken)
+    ));
+}
+
+#[test]
+fn guessed_secrets_do_not_revoke_sessions() {
+    let (store, _) = store("session-guess");
+    let session = store.start_session(7).unwrap().unwrap();
+    let id = session_id(&session.refresh_token);
+
+    for guess in ["x", "00", &"0".repeat(64)] {
+        assert!(matches!(
+            store.refresh_session(&format!("{}.{}", id, guess)),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+    for malformed in ["", "x", "1", ".", "abc.def"] {
+        assert!(matches!(
+            store.refresh_session(malformed),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+    assert!(store.refresh_session(&session.refresh_token).is_ok());
+}
+
+#[test]
+fn expired_sessions_are_refused_and_purged() {
+    let (store, path) = store("session-expiry");
+    let expired = store.start_session(7).unwrap().unwrap();
+    let live = store.start_session(8).unwrap().unwrap();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute(
+        "UPDATE session SET expires_at = 0 WHERE id = ?1",
+        [session_id(&expired.refresh_token)],
+    )
+    .unwrap();
+
+    assert!(matches!(
+        store.refresh_session(&expired.refresh_token),
+        Err(SessionError::InvalidToken)
+    ));
+    assert_eq!(store.purge_expired_sessions().unwrap(), 1);
+    assert!(store.refresh_session(&live.refresh_token).is_ok());
+}
+
+#[test]
+fn logout_ends_the_session() {
+    let (store, _) = store("session-logout");
+    let session = store.start_session(7).unwrap().unwrap();
+    let id = session_id(&session.refresh_token);
+
+    assert!(matches!(
+        store.end_session(&format!("{}.wrong", id)),
+        Err(SessionError::InvalidToken)
+    ));
+    assert_eq!(store.end_session(&session.refresh_token).unwrap(), 7);
+    assert!(matches!(
+        store.refresh_session(&session.refresh_token),
+        Err(SessionError::InvalidToken)
+    ));
+}
+
+#[test]
+fn sessions_can_be_disabled() {
+    let store = common::store("session-disabled");
+    assert!(store.start_session(7).unwrap().is_none());
+    assert!(matches!(
+        store.refresh_session("1.x"),
+        Err(SessionError::Disabled)
+    ));
+}
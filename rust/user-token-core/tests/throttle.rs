@@ -0,0 +1,53 @@
+//! Persisted throttle: lockouts survive a restart, and recording one never
+//! waits for the database writer.
+
+mod common;
+
+use rusqlite::Connection;
+use std::time::{Duration, Instant};
+use user_token_core::{LoginOutcome, LoginThrottle, ThrottleConfig, UserStore};
+
+fn config() -> ThrottleConfig {
+    ThrottleConfig {
+        account_failures: 2,
+        ip_failures: 0,
+        window: Duration::from_secs(60),
+        lockout: Duration::from_secs(60),
+        max_lockout: Duration::from_secs(600),
+        persist: true,
+    }
+}
+
+#[test]
+fn lockouts_are_persisted_off_the_login_path() {
+    let path = common::db_path("throttle-persist");
+    let store = UserStore::open(&path, 2).unwrap();
+    let throttle = LoginThrottle::open(&config(), &store).unwrap();
+
+    // Another process holds the write lock for longer than a login may take
+    let blocker = Connection::open(&path).unwrap();
+    blocker.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let start = Instant::now();
+    for _ in 0..2 {
+        throttle.record("alice@example.com", None, LoginOutcome::Rejected);
+    }
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "recording a lockout waited {:?} for the writer",
+        start.elapsed()
+    );
+    assert!(throttle.check("alice@example.com", None).is_err());
+    blocker.execute_batch("COMMIT").unwrap();
+    throttle.flush();
+    drop(throttle);
+
+    // Still locked after a restart, until a successful login clears it
+    let throttle = LoginThrottle::open(&config(), &store).unwrap();
+    assert!(throttle.check("alice@example.com", None).is_err());
+    throttle.record("alice@example.com", None, LoginOutcome::Authenticated);
+    throttle.flush();
+    drop(throttle);
+
+    let throttle = LoginThrottle::open(&config(), &store).unwrap();
+    assert!(throttle.check("alice@example.com", None).is_ok());
+}
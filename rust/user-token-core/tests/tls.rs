@@ -0,0 +1,130 @@
+//! TLS configuration: the certificate and key load, ALPN is offered and
+//! sessions resume. `fixtures/` holds a test CA and a `localhost`
+//! certificate it signed, valid for a hundred years.
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, HandshakeKind, RootCertStore, ServerConnection};
+use std::sync::Arc;
+use user_token_core::{tls_server_config, TlsConfig, TlsError, ALPN_HTTP1};
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_config(session_cache: usize, tickets: bool) -> TlsConfig {
+    TlsConfig {
+        cert_path: fixture("server.pem"),
+        key_path: fixture("server.key"),
+        session_cache,
+        tickets,
+    }
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap())
+        .unwrap();
+    let mut client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client.alpn_protocols = vec![b"h2".to_vec(), ALPN_HTTP1.to_vec()];
+    Arc::new(client)
+}
+
+/// Runs a handshake in memory, then a request and its response, so that the
+/// TLS 1.3 tickets sent after the handshake reach the client.
+fn connect(server: &Arc<rustls::ServerConfig>, client: &Arc<ClientConfig>) -> ClientConnection {
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut client = ClientConnection::new(client.clone(), name).unwrap();
+    let mut server = ServerConnection::new(server.clone()).unwrap();
+    let mut request_sent = false;
+    for _ in 0..10 {
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
+        }
+        server.read_tls(&mut buf.as_slice()).unwrap();
+        server.process_new_packets().unwrap();
+        let mut buf = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut buf).unwrap();
+        }
+        client.read_tls(&mut buf.as_slice()).unwrap();
+        client.process_new_packets().unwrap();
+        if !client.is_handshaking() && !request_sent {
+            std::io::Write::write_all(&mut client.writer(), b"ping").unwrap();
+            std::io::Write::write_all(&mut server.writer(), b"pong").unwrap();
+            request_sent = true;
+        }
+    }
+    assert!(!client.is_handshaking());
+    client
+}
+
+#[test]
+fn offers_alpn_and_resumes_sessions() {
+    for (session_cache, tickets) in [(0, true), (64, false)] {
+        let server = Arc::new(
+            tls_server_config(&tls_config(session_cache, tickets), &[ALPN_HTTP1]).unwrap(),
+        );
+        let client = client_config();
+
+        let first = connect(&server, &client);
+        assert_eq!(first.alpn_protocol(), Some(ALPN_HTTP1));
+        assert_eq!(first.handshake_kind(), Some(HandshakeKind::Full));
+        let second = connect(&server, &client);
+        assert_eq!(
+            second.handshake_kind(),
+            Some(HandshakeKind::Resumed),
+            "cache {}, tickets {}",
+            session_cache,
+            tickets
+        );
+    }
+}
+
+#[test]
+fn no_resumption_when_both_are_off() {
+    let server = Arc::new(tls_server_config(&tls_config(0, false), &[ALPN_HTTP1]).unwrap());
+    let client = client_config();
+    connect(&server, &client);
+    assert_eq!(
+        connect(&server, &client).handshake_kind(),
+        Some(HandshakeKind::Full)
+    );
+}
+
+#[test]
+fn unreadable_or_mismatched_files_are_reported() {
+    let missing = TlsConfig {
+        cert_path: fixture("missing.pem"),
+        ..tls_config(0, false)
+    };
+    assert!(matches!(
+        tls_server_config(&missing, &[]),
+        Err(TlsError::Cert { .. })
+    ));
+
+    // A certificate file holds no private key
+    let no_key = TlsConfig {
+        key_path: fixture("server.pem"),
+        ..tls_config(0, false)
+    };
+    let error = tls_server_config(&no_key, &[]).unwrap_err();
+    assert!(matches!(error, TlsError::Key { .. }), "{}", error);
+
+    // The CA certificate does not go with the server key
+    let mismatched = TlsConfig {
+        cert_path: fixture("ca.pem"),
+        ..tls_config(0, false)
+    };
+    assert!(matches!(
+        tls_server_config(&mismatched, &[]),
+        Err(TlsError::Rustls(_))
+    ));
+}
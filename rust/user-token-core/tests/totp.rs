@@ -0,0 +1,94 @@
+use user_token_core::{
+    base32_decode, base32_encode, hotp, otpauth_uri, totp, verify_totp, TotpAlgorithm,
+};
+
+const SEED_SHA1: &[u8] = b"12345678901234567890";
+const SEED_SHA256: &[u8] = b"12345678901234567890123456789012";
+const SEED_SHA512: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+/// RFC 6238 appendix B: 8 digits, 30-second steps, T0 = 0.
+#[test]
+fn rfc6238_test_vectors() {
+    let vectors = [
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826"),
+    ];
+    for (time, sha1, sha256, sha512) in vectors {
+        let code = |seed, algorithm| format!("{:08}", totp(seed, time, 30, 8, algorithm));
+        assert_eq!(
+            code(SEED_SHA1, TotpAlgorithm::Sha1),
+            sha1,
+            "SHA1 at {}",
+            time
+        );
+        assert_eq!(
+            code(SEED_SHA256, TotpAlgorithm::Sha256),
+            sha256,
+            "SHA256 at {}",
+            time
+        );
+        assert_eq!(
+            code(SEED_SHA512, TotpAlgorithm::Sha512),
+            sha512,
+            "SHA512 at {}",
+            time
+        );
+    }
+}
+
+/// RFC 4226 appendix D: 6-digit HOTP values for counters 0 to 9.
+#[test]
+fn rfc4226_test_vectors() {
+    let expected = [
+        755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+    for (counter, code) in expected.into_iter().enumerate() {
+        assert_eq!(
+            hotp(SEED_SHA1, counter as u64, 6, TotpAlgorithm::Sha1),
+            code
+        );
+    }
+}
+
+#[test]
+fn verify_totp_allows_one_step_of_skew() {
+    // 287082 is the 6-digit code of step 1 (times 30 to 59)
+    assert_eq!(verify_totp(SEED_SHA1, "287082", 59), Some(1));
+    assert_eq!(verify_totp(SEED_SHA1, "287082", 89), Some(1));
+    assert_eq!(verify_totp(SEED_SHA1, "287082", 0), Some(1));
+    assert_eq!(verify_totp(SEED_SHA1, "287082", 90), None);
+    assert_eq!(verify_totp(SEED_SHA1, "28708", 59), None);
+    assert_eq!(verify_totp(SEED_SHA1, "+28708", 59), None);
+}
+
+#[test]
+fn base32_round_trip() {
+    // RFC 4648 section 10, without padding
+    let vectors = [
+        ("", ""),
+        ("f", "MY"),
+        ("fo", "MZXQ"),
+        ("foo", "MZXW6"),
+        ("foob", "MZXW6YQ"),
+        ("fooba", "MZXW6YTB"),
+        ("foobar", "MZXW6YTBOI"),
+    ];
+    for (plain, encoded) in vectors {
+        assert_eq!(base32_encode(plain.as_bytes()), encoded);
+        assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+    }
+    assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar");
+    assert_eq!(base32_decode("MZXW1"), None);
+}
+
+#[test]
+fn otpauth_uri_escapes_the_label() {
+    assert_eq!(
+        otpauth_uri("UserTokenApi", "alice+mfa@example.com", "GEZDGNBV"),
+        "otpauth://totp/UserTokenApi:alice%2Bmfa%40example.com?secret=GEZDGNBV&issuer=UserTokenApi&algorithm=SHA1&digits=6&period=30"
+    );
+}